@@ -1,10 +1,11 @@
 // src/main.rs
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use futures_util::stream::StreamExt;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 use thiserror::Error;
@@ -17,17 +18,54 @@ use url::Url;
 enum FluxError {
     #[error("Package '{0}' not found in the repository.")]
     PackageNotFound(String),
-    #[error("Checksum mismatch for {package_name}! Expected: {expected}, Found: {found}")]
+    #[error("No packages in the repository match '{0}'.")]
+    NoPackagesMatch(String),
+    #[error("Dependencies not found in the repository: {}", describe_missing_dependencies(.0))]
+    MissingDependencies(Vec<MissingDependency>),
+    #[error("Invalid dependency specification '{0}'")]
+    InvalidDependencySpec(String),
+    #[error("'{package_name}' requires {dependency}, but the repository has {available}")]
+    UnsatisfiableDependency {
+        package_name: String,
+        dependency: String,
+        available: String,
+    },
+    #[error("'{package_name}' requires {dependency}, but {installed} is installed. Run 'flux upgrade' first.")]
+    OutdatedDependency {
+        package_name: String,
+        dependency: String,
+        installed: String,
+    },
+    #[error("Cannot satisfy the requirements on '{package_name}': {conflict}")]
+    DependencyConflict {
+        package_name: String,
+        conflict: String,
+    },
+    #[error("Cannot install '{package_name}': it conflicts with '{conflicts_with}'")]
+    PackageConflict {
+        package_name: String,
+        conflicts_with: String,
+    },
+    #[error("Checksum mismatch ({algorithm}) for {package_name}! Expected: {expected}, Found: {found}")]
     ChecksumMismatch {
         package_name: String,
+        algorithm: &'static str,
         expected: String,
         found: String,
     },
+    #[error("File conflict: {path} from '{incoming_package}' is already owned by '{owning_package}'")]
+    FileConflict {
+        path: PathBuf,
+        owning_package: String,
+        incoming_package: String,
+    },
     #[error("Cannot remove '{package_name}'. It is a dependency for: {dependents:?}")]
     DependencyInUse {
         package_name: String,
         dependents: Vec<String>,
     },
+    #[error("Another flux process is working on this root (lock held on {}); retry later or pass --wait", .0.display())]
+    Locked(PathBuf),
     #[error("I/O Error: {0}")]
     Io(#[from] io::Error),
     #[error("Network request failed: {0}")]
@@ -43,16 +81,94 @@ enum FluxError {
         package_name: String,
         message: String,
     },
+    #[error("Pre-remove script failed for '{package_name}': {message}")]
+    PreRemoveScriptFailed {
+        package_name: String,
+        message: String,
+    },
+    #[error("Post-remove script failed for '{package_name}': {message}")]
+    PostRemoveScriptFailed {
+        package_name: String,
+        message: String,
+    },
+    #[error("Cannot downgrade '{package_name}': {reason}")]
+    DowngradeRefused {
+        package_name: String,
+        reason: String,
+    },
+    #[error("Cannot undo transaction #{id}: {reason}")]
+    UndoRefused {
+        id: u64,
+        reason: String,
+    },
     #[error("System hook failed for '{package_name}' with hook '{hook_script}': {message}")]
     HookFailed {
         package_name: String,
         hook_script: String,
         message: String,
     },
+    #[error("Failed to set up the script sandbox: {0}")]
+    SandboxSetup(String),
     #[error("Invalid URL in config: {0}")]
     InvalidUrl(#[from] url::ParseError),
     #[error("Configuration Error: {0}")]
     Config(String),
+    #[error("Package database {path} is corrupt ({message}). Run 'flux rebuild-db' to reconstruct it.")]
+    CorruptDatabase {
+        path: PathBuf,
+        message: String,
+    },
+    #[error("Failed to update the repository index: {0}")]
+    IndexUpdateFailed(String),
+    #[error("Failed to update the repository index from any source: {}", describe_mirror_failures(.failures, *.untried))]
+    AllMirrorsFailed {
+        failures: Vec<MirrorFailure>,
+        // Sources skipped because the total attempt limit was reached first.
+        untried: usize,
+    },
+    #[error("Signature check failed for {url}: {reason}")]
+    SignatureInvalid {
+        url: String,
+        reason: String,
+    },
+    #[error("The index of repository '{repo}' has an unusable entry {entry:?}: {reason}")]
+    InvalidIndexEntry {
+        repo: String,
+        entry: String,
+        reason: String,
+    },
+    #[error("The index of repository '{repo}' lists {package} more than once with different archives")]
+    DuplicateIndexEntry {
+        repo: String,
+        package: String,
+    },
+    #[error("Cannot prompt for confirmation: {0}")]
+    PromptUnavailable(String),
+    #[error("Verification failed: {0}")]
+    VerificationFailed(String),
+    #[error("Cached archives do not match the index: {0}. Run 'flux cache verify --prune' to remove them.")]
+    CorruptCache(String),
+}
+
+const DEFAULT_INDEX_TTL_HOURS: u64 = 24;
+const DEFAULT_MAX_NAME_LENGTH: usize = 128;
+const DEFAULT_INDEX_RETRIES: u32 = 3;
+
+#[derive(Debug)]
+struct MirrorFailure {
+    source: String,
+    attempts: u32,
+    last_error: String,
+}
+
+fn describe_mirror_failures(failures: &[MirrorFailure], untried: usize) -> String {
+    let mut parts: Vec<String> = failures.iter()
+        .map(|f| format!("{} ({} attempt{}: {})", f.source, f.attempts, if f.attempts == 1 { "" } else { "s" }, f.last_error))
+        .collect();
+    if untried > 0 {
+        parts.push(format!("{} more source(s) not tried, the total attempt limit was reached", untried));
+    }
+    parts.join("; ")
 }
 
 // --- Metadata Structures ---
@@ -61,20 +177,15 @@ struct PackageIndex {
     packages: Vec<PackageInfo>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
 #[serde(rename_all = "lowercase")]
 enum PackageType {
+    #[default]
     System,
     App,
 }
 
-impl Default for PackageType {
-    fn default() -> Self {
-        PackageType::System
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ValueEnum)]
 enum InstallReason {
     Explicit,
     Dependency,
@@ -90,457 +201,9595 @@ struct PackageInfo {
     url: String,
     checksum: String,
     dependencies: Option<Vec<String>>,
+    #[serde(default)]
+    optional_dependencies: Option<Vec<String>>,
+    // Packages (optionally with a version constraint) that can't be installed alongside this one.
+    #[serde(default)]
+    conflicts: Option<Vec<String>>,
     description: String,
     icon_url: String,
     changelog_url: String,
-    post_install: Option<String>,
+    post_install: Option<ScriptSpec>,
+    // Scripts inside the archive run before and after the package's files are removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pre_remove: Option<ScriptSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    post_remove: Option<ScriptSpec>,
+    // Archive size and unpacked size in bytes, if the index provides them.
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    installed_size: Option<u64>,
+    // Opts this package's scripts out of `sandbox_scripts`.
+    #[serde(default)]
+    privileged_scripts: bool,
+    // Target architecture; unset means the package runs anywhere.
+    #[serde(default)]
+    arch: Option<String>,
+    // How the tar archive is compressed; unset means it is taken from the URL's extension.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct InstalledPackageInfo {
-    name: String,
-    version: String,
-    package_type: PackageType,
-    install_reason: InstallReason,
-    files: Vec<PathBuf>,
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Compression {
+    Zstd,
+    Gzip,
+    Xz,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct FluxConfig {
-    repository_url: String,
-    hooks: Option<HashMap<String, String>>,
-}
+impl Compression {
+    const ALL: [Compression; 3] = [Self::Zstd, Self::Gzip, Self::Xz];
 
-// --- Application Context ---
-struct AppContext {
-    host_cache_path: PathBuf,
-    target_root: PathBuf,
-    target_apps_root: PathBuf,
-    target_db_path: PathBuf,
-    config: FluxConfig,
-    package_index: HashMap<String, PackageInfo>,
-}
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => ".tar.zst",
+            Compression::Gzip => ".tar.gz",
+            Compression::Xz => ".tar.xz",
+        }
+    }
 
-impl AppContext {
-    async fn new(root: PathBuf) -> Result<Self, FluxError> {
-        let home_dir = dirs::home_dir().ok_or_else(|| FluxError::Config("Could not find home directory".to_string()))?;
-        let host_cache_dir = home_dir.join(".cache/flux");
-        fs::create_dir_all(&host_cache_dir).await?;
-        let host_cache_path = host_cache_dir.join("repo.yaml");
+    fn from_url(url: &str) -> Option<Self> {
+        let path = url.split(['?', '#']).next().unwrap_or_default();
+        if path.ends_with(".tgz") { return Some(Compression::Gzip); }
+        Self::ALL.into_iter().find(|c| path.ends_with(c.extension()))
+    }
 
-        let target_apps_root = root.join("flux/apps");
-        let target_db_dir = root.join("var/lib/flux");
-        let target_db_path = target_db_dir.join("db.json");
+    // The decoder is picked from the archive's leading bytes, so a mislabelled archive still unpacks.
+    fn from_magic(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+}
 
-        let config_content = fs::read_to_string("flux.conf").await.map_err(|_| FluxError::Config("Could not read flux.conf".to_string()))?;
-        let config: FluxConfig = serde_yaml::from_str(&config_content)?;
+// Either a script path, or `[script, arg1, arg2, ...]` to pass fixed arguments.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum ScriptSpec {
+    Script(String),
+    WithArgs(Vec<String>),
+}
 
-        if !host_cache_path.exists() {
-            println!("No local repository cache found. Please run 'flux update' to fetch it.");
+impl ScriptSpec {
+    fn script_and_args(&self) -> Option<(&str, &[String])> {
+        match self {
+            ScriptSpec::Script(script) => Some((script, &[])),
+            ScriptSpec::WithArgs(parts) => parts.split_first().map(|(script, args)| (script.as_str(), args)),
         }
+    }
+}
 
-        let package_index = if host_cache_path.exists() {
-            let index_content = fs::read_to_string(&host_cache_path).await?;
-            let index: PackageIndex = serde_yaml::from_str(&index_content)?;
-            index.packages.into_iter().map(|p| (p.name.clone(), p)).collect()
-        } else {
-            HashMap::new()
-        };
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum ScriptFailurePolicy {
+    // Remove the files of every package installed so far in the batch and abort.
+    #[default]
+    AbortAndRollback,
+    // Abort, but keep the files and record the package as installed.
+    AbortKeep,
+    // Record the package, warn and carry on with the batch.
+    WarnContinue,
+}
 
-        Ok(Self {
-            host_cache_path,
-            target_root: root,
-            target_apps_root,
-            target_db_path,
-            config,
-            package_index,
-        })
-    }
+// What to do when a system package ships a file another package already owns.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum FileConflictPolicy {
+    // Refuse the whole install.
+    #[default]
+    Error,
+    // Leave the installed file alone; the new package doesn't own it.
+    Skip,
+    // Install the new file, keeping the old one as `<path>.flux-old`; the new package takes ownership.
+    Overwrite,
+    // Leave the installed file alone and install the new one as `<path>.flux-new`, owned by the new package.
+    BackupBoth,
+}
 
-    fn get_install_path(&self, info: &PackageInfo) -> PathBuf {
-        match info.package_type {
-            PackageType::System => self.target_root.clone(),
-            PackageType::App => self.target_apps_root.join(format!("{}-{}", info.name, info.version)),
-        }
+impl PackageInfo {
+    fn compression(&self) -> Compression {
+        self.compression.or_else(|| Compression::from_url(&self.url)).unwrap_or(Compression::Zstd)
     }
 
-    async fn get_installed_packages(&self) -> Result<Vec<InstalledPackageInfo>, FluxError> {
-        if !self.target_db_path.exists() { return Ok(Vec::new()); }
-        let content = fs::read_to_string(&self.target_db_path).await?;
-        if content.trim().is_empty() { return Ok(Vec::new()); }
-        Ok(serde_json::from_str(&content)?)
+    // Names only; constraints are checked during resolution.
+    fn dependency_names(&self) -> impl Iterator<Item = &str> {
+        self.dependencies.iter().flatten().map(|dep| DependencySpec::name_of(dep))
     }
+}
 
-    async fn write_installed_packages(&self, packages: &[InstalledPackageInfo]) -> Result<(), FluxError> {
-        fs::create_dir_all(&self.target_db_path.parent().unwrap()).await?;
-        let content = serde_json::to_string_pretty(packages)?;
-        fs::write(&self.target_db_path, content).await?;
-        Ok(())
-    }
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct InstalledPackageInfo {
+    name: String,
+    version: String,
+    package_type: PackageType,
+    install_reason: InstallReason,
+    // Unix time of the install; None for records written before it was stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    installed_at: Option<u64>,
+    files: Vec<FileEntry>,
+    // File path -> content hash, for files hardlinked from the content store.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty", serialize_with = "serialize_path_map", deserialize_with = "deserialize_path_map")]
+    content_hashes: BTreeMap<PathBuf, String>,
+    // Copied from the index at install time, which may no longer list this version at removal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remove_scripts: Option<RemoveScripts>,
 }
 
-// --- CLI Structure ---
-#[derive(Parser)]
-#[command(author, version, about = "A fast and reliable package manager for your system.", long_about = None)]
-struct Cli {
-    #[arg(long, global = true, default_value = "/")]
-    root: PathBuf,
-    #[command(subcommand)]
-    command: Commands,
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct RemoveScripts {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pre_remove: Option<ScriptSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    post_remove: Option<ScriptSpec>,
+    #[serde(default)]
+    privileged: bool,
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    Install { package: String },
-    Remove { package: String },
-    Update,
-    Upgrade,
-    List,
-    Autoremove,
+impl RemoveScripts {
+    fn from_info(info: &PackageInfo) -> Option<Self> {
+        if info.pre_remove.is_none() && info.post_remove.is_none() { return None; }
+        Some(Self { pre_remove: info.pre_remove.clone(), post_remove: info.post_remove.clone(), privileged: info.privileged_scripts })
+    }
 }
 
-// --- Core Logic ---
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(from = "FileRecord")]
+struct FileEntry {
+    #[serde(serialize_with = "serialize_path")]
+    path: PathBuf,
+    // Hash of the file as installed. None for directories and symlinks, and for records written
+    // before hashes were stored.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
 
-async fn download_file(url: &Url, dest_path: &Path) -> Result<(), FluxError> {
-    if url.scheme() == "file" {
-        let source_path = url.to_file_path().map_err(|_| FluxError::Config(format!("Invalid file path in URL: {}", url)))?;
-        fs::copy(&source_path, dest_path).await?;
-    } else {
-        let response = reqwest::get(url.clone()).await?.error_for_status()?;
-        let mut stream = response.bytes_stream();
-        let mut dest_file = File::create(dest_path).await?;
-        while let Some(chunk) = stream.next().await {
-            dest_file.write_all(&chunk?).await?;
+// Older databases store bare paths.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileRecord {
+    Path(#[serde(deserialize_with = "deserialize_path")] PathBuf),
+    Entry {
+        #[serde(deserialize_with = "deserialize_path")]
+        path: PathBuf,
+        #[serde(default)]
+        sha256: Option<String>,
+    },
+}
+
+impl From<FileRecord> for FileEntry {
+    fn from(record: FileRecord) -> Self {
+        match record {
+            FileRecord::Path(path) => Self { path, sha256: None },
+            FileRecord::Entry { path, sha256 } => Self { path, sha256 },
         }
     }
-    Ok(())
 }
 
-async fn verify_checksum(info: &PackageInfo, file_path: &Path) -> Result<(), FluxError> {
-    println!("Verifying checksum for {}...", info.name);
-    let mut file = File::open(file_path).await?;
-    let mut hasher = Sha256::new();
-    let mut buffer = [0; 1024];
-    loop {
-        let n = file.read(&mut buffer).await?;
-        if n == 0 { break; }
-        hasher.update(&buffer[..n]);
-    }
-    let hash = hasher.finalize();
-    let calculated_checksum = format!("{:x}", hash);
+// Paths are stored as strings. One that isn't valid UTF-8 (legal on Unix) is stored as a NUL
+// followed by the hex of its bytes; no real path contains a NUL, so the two can't be confused.
+const NON_UTF8_PATH_MARKER: char = '\0';
 
-    if calculated_checksum == info.checksum {
-        println!("Checksum verified.");
-        Ok(())
-    } else {
-        Err(FluxError::ChecksumMismatch {
-            package_name: info.name.clone(),
-            expected: info.checksum.clone(),
-            found: calculated_checksum,
-        })
+fn encode_path(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    match path.to_str() {
+        Some(utf8) => utf8.to_string(),
+        None => std::iter::once(NON_UTF8_PATH_MARKER.to_string())
+            .chain(path.as_os_str().as_bytes().iter().map(|b| format!("{:02x}", b)))
+            .collect(),
     }
 }
 
-async fn extract_package(archive_path: &Path, extract_to: &Path) -> Result<Vec<PathBuf>, FluxError> {
-    println!("Decompressing and extracting to {}...", extract_to.display());
-    let compressed_bytes = fs::read(archive_path).await?;
-    let extract_to_owned = extract_to.to_owned();
-
-    let extracted_files = tokio::task::spawn_blocking(move || -> Result<Vec<PathBuf>, FluxError> {
-        let cursor = std::io::Cursor::new(&compressed_bytes);
-        let decoder = zstd::stream::read::Decoder::new(cursor).map_err(|e| FluxError::Archive(e.to_string()))?;
-        let mut archive = tar::Archive::new(decoder);
+fn decode_path(stored: &str) -> Result<PathBuf, String> {
+    use std::os::unix::ffi::OsStringExt;
+    let Some(hex) = stored.strip_prefix(NON_UTF8_PATH_MARKER) else { return Ok(PathBuf::from(stored)) };
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| format!("invalid encoded path {:?}", stored))?;
+    Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
 
-        let mut files = Vec::new();
-        for entry in archive.entries().map_err(|e| FluxError::Archive(e.to_string()))? {
-            let mut entry = entry.map_err(|e| FluxError::Archive(e.to_string()))?;
-            let path = entry.path()?.into_owned();
-            entry.unpack_in(&extract_to_owned).map_err(|e| FluxError::Archive(e.to_string()))?;
-            files.push(path);
-        }
-        Ok(files)
-    }).await.unwrap()?;
+fn serialize_path<S: serde::Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode_path(path))
+}
 
-    println!("Extraction complete.");
-    Ok(extracted_files)
+fn deserialize_path<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    decode_path(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
 }
 
-fn run_script(script_path: &Path, package_name: &str, error_type: fn(String, String, String) -> FluxError) -> Result<(), FluxError> {
-    let output = process::Command::new("sh").arg(script_path).output().map_err(|e| error_type(package_name.to_string(), script_path.to_string_lossy().to_string(), e.to_string()))?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(error_type(package_name.to_string(), script_path.to_string_lossy().to_string(), stderr.to_string()));
-    }
-    Ok(())
+fn serialize_paths<S: serde::Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(paths.iter().map(|path| encode_path(path)))
 }
 
-async fn handle_install(package_name: &str, ctx: &AppContext) -> Result<(), FluxError> {
-    let mut to_install_names = HashSet::new();
-    resolve_dependencies(package_name, ctx, &mut to_install_names)?;
+fn deserialize_paths<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?.iter().map(|stored| decode_path(stored).map_err(serde::de::Error::custom)).collect()
+}
 
-    let installed_packages = ctx.get_installed_packages().await?;
-    let installed_names: HashSet<_> = installed_packages.iter().map(|p| p.name.as_str()).collect();
+fn serialize_path_map<S: serde::Serializer>(map: &BTreeMap<PathBuf, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.iter().map(|(path, value)| (encode_path(path), value)))
+}
 
-    let packages_to_process: Vec<_> = to_install_names.iter()
-        .filter(|name| !installed_names.contains(name.as_str()))
-        .map(|name| ctx.package_index.get(name).unwrap().clone())
-        .collect();
+fn deserialize_path_map<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<PathBuf, String>, D::Error> {
+    BTreeMap::<String, String>::deserialize(deserializer)?.into_iter()
+        .map(|(stored, value)| decode_path(&stored).map(|path| (path, value)).map_err(serde::de::Error::custom))
+        .collect()
+}
 
-    if packages_to_process.is_empty() {
-        println!("Package '{}' and all its dependencies are already installed.", package_name);
-        return Ok(());
-    }
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct FluxConfig {
+    repository_url: Option<String>,
+    // Additional (or instead of repository_url, all) repositories; higher priority wins when names collide.
+    #[serde(default)]
+    repositories: Vec<RepoEntry>,
+    hooks: Option<HashMap<String, HookSpec>>,
+    // Run each matching hook once after the whole install batch instead of once per package.
+    #[serde(default)]
+    batch_hooks: bool,
+    // Deduplicate system package files through a content-addressed store under var/lib/flux/store.
+    #[serde(default)]
+    content_store: bool,
+    // How old the cached index may get before `status` reports it as stale.
+    index_ttl_hours: Option<u64>,
+    on_script_failure: Option<ScriptFailurePolicy>,
+    on_file_conflict: Option<FileConflictPolicy>,
+    // Keep downloaded archives in the cache after installing them.
+    #[serde(default)]
+    keep_downloads: bool,
+    // Fallback index URLs tried in order when repository_url can't be fetched.
+    #[serde(default)]
+    index_mirrors: Vec<String>,
+    // Attempts per index URL, with exponential backoff between them.
+    index_retries: Option<u32>,
+    // Cap on attempts across the repository URL and all its mirrors; unset means no cap.
+    index_max_total_attempts: Option<u32>,
+    // Run post-install and hook scripts in a restricted sandbox.
+    #[serde(default)]
+    sandbox_scripts: bool,
+    // Concurrent connections allowed per download host, plus stricter per-host overrides.
+    max_connections_per_host: Option<usize>,
+    #[serde(default)]
+    host_connection_limits: HashMap<String, usize>,
+    // Caching proxy that http(s) package downloads, and unless bypassed the index, are routed through.
+    cache_proxy_url: Option<String>,
+    #[serde(default)]
+    cache_proxy_bypass_index: bool,
+    // Address family tried first when a host has both; the other one is the fallback.
+    ip_preference: Option<IpPreference>,
+    // Seconds before a connection attempt to one address gives up and the next is tried.
+    connect_timeout_secs: Option<u64>,
+    // Bytes read per call when hashing archives and buffered before writing downloads to disk.
+    io_buffer_size: Option<usize>,
+    // 1 decompresses and unpacks on one thread; more overlaps the two and decodes the frames of a
+    // multi-frame zstd archive in parallel. Defaults to the number of cores.
+    decompress_threads: Option<usize>,
+    // Where App packages and the package database live, relative to --root.
+    apps_dir: Option<PathBuf>,
+    db_dir: Option<PathBuf>,
+    // Architecture to install packages for (defaults to the host's), and compatible fallbacks in order of preference.
+    arch: Option<String>,
+    #[serde(default)]
+    arch_compat: Vec<String>,
+    on_interrupted_install: Option<InterruptedInstallPolicy>,
+    // Re-hash all cached archives before any command that changes the system, and refuse to run on a mismatch.
+    #[serde(default)]
+    verify_cache_on_start: bool,
+    // Unpack archives straight into the target instead of staging them under var/lib/flux/staging first.
+    #[serde(default)]
+    extract_in_place: bool,
+    cross_package_hardlinks: Option<HardlinkPolicy>,
+    // Minisign public key files trusted to sign package archives (`<archive url>.minisig`).
+    #[serde(default)]
+    trusted_keys: Vec<PathBuf>,
+    // Minisign public key the repository index must be signed with (`<index url>.sig`). Unset means unsigned indexes are accepted.
+    index_signing_key: Option<PathBuf>,
+    notify: Option<NotifyConfig>,
+    duplicate_index_entries: Option<DuplicateEntryPolicy>,
+    // Longest package name or version accepted from an index; both end up in file names.
+    max_name_length: Option<usize>,
+}
 
-    let mut new_install_records = Vec::new();
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RepoEntry {
+    name: String,
+    url: String,
+    #[serde(default)]
+    priority: i32,
+    // An unreachable optional repository only warns during update.
+    #[serde(default)]
+    optional: bool,
+}
 
-    for info in &packages_to_process {
-        let install_path = ctx.get_install_path(info);
-        fs::create_dir_all(&install_path).await?;
+// The repository configured through the old single `repository_url` key.
+const DEFAULT_REPO_NAME: &str = "default";
 
-        let archive_name = format!("{}-{}.tar.zst", &info.name, &info.version);
-        let archive_path = ctx.host_cache_path.parent().unwrap().join(&archive_name);
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum IpPreference {
+    // Whatever order the system resolver returns.
+    #[default]
+    Dual,
+    Ipv4,
+    Ipv6,
+}
 
-        let is_placeholder = info.checksum.starts_with("some_") || info.checksum.starts_with("a_real_");
-        let mut extracted_files = Vec::new();
+// What to do when an index lists the same name, version and architecture more than once with
+// different archives.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum DuplicateEntryPolicy {
+    // Keep the entry listed first and warn.
+    #[default]
+    Warn,
+    Error,
+}
 
-        if is_placeholder {
-            println!("Skipping download and extraction for {} due to placeholder checksum.", info.name);
-        } else {
-            println!("Downloading {} from {}", info.name, info.url);
-            download_file(&Url::parse(&info.url)?, &archive_path).await?;
-            verify_checksum(info, &archive_path).await?;
-            extracted_files = extract_package(&archive_path, &install_path).await?;
-            fs::remove_file(&archive_path).await?;
-        }
+// How to unpack a hardlink whose target was installed by another package.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum HardlinkPolicy {
+    #[default]
+    Link,
+    Copy,
+    Reject,
+}
 
-        if let Some(script_name) = &info.post_install {
-            let script_path = install_path.join(script_name);
-            if script_path.exists() {
-                run_script(&script_path, &info.name, |pkg, _, msg| FluxError::PostInstallScriptFailed { package_name: pkg, message: msg })?;
-            }
-        }
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum HookPhase {
+    #[default]
+    Install,
+    Remove,
+}
 
-        if let Some(hooks) = &ctx.config.hooks {
-            for (pattern, hook_script) in hooks {
-                if info.name.starts_with(&pattern.replace('*', "")) {
-                    let full_hook_path = ctx.target_root.join(hook_script.strip_prefix('/').unwrap_or(hook_script));
-                    run_script(&full_hook_path, &info.name, |pkg, hook, msg| FluxError::HookFailed { package_name: pkg, hook_script: hook, message: msg })?;
-                }
-            }
-        }
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct HookEntry {
+    script: String,
+    // Only run for packages of this type; any type when unset.
+    #[serde(rename = "type")]
+    package_type: Option<PackageType>,
+    #[serde(default)]
+    phase: HookPhase,
+}
 
-        let reason = if info.name == package_name {
-            InstallReason::Explicit
-        } else {
-            InstallReason::Dependency
-        };
-        new_install_records.push(InstalledPackageInfo {
-            name: info.name.clone(),
-            version: info.version.clone(),
-            package_type: info.package_type.clone(),
-            install_reason: reason,
-            files: extracted_files,
-        });
+impl HookEntry {
+    fn applies_to(&self, package_type: &PackageType, phase: HookPhase) -> bool {
+        self.phase == phase && self.package_type.as_ref().is_none_or(|t| t == package_type)
     }
+}
 
-    let mut all_installed = installed_packages;
-    all_installed.extend(new_install_records);
+// Where to report each transaction that changed packages. Both may be set.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct NotifyConfig {
+    // POSTed the transaction as JSON.
+    webhook: Option<String>,
+    // Run with `sh -c`, with the transaction as JSON on stdin.
+    command: Option<String>,
+    webhook_attempts: Option<u32>,
+}
 
-    ctx.write_installed_packages(&all_installed).await?;
-    println!("Package database updated.");
-    Ok(())
+const DEFAULT_WEBHOOK_ATTEMPTS: u32 = 3;
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+// A hook is either a bare script path (run after installing matching packages of any type) or
+// one or more entries scoped by package type and lifecycle phase.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum HookSpec {
+    Script(String),
+    Entry(HookEntry),
+    Entries(Vec<HookEntry>),
 }
 
-fn resolve_dependencies<'a>(pkg_name: &'a str, ctx: &'a AppContext, resolved: &mut HashSet<String>) -> Result<(), FluxError> {
-    if resolved.contains(pkg_name) { return Ok(()); }
-    let info = ctx.package_index.get(pkg_name).ok_or_else(|| FluxError::PackageNotFound(pkg_name.to_string()))?;
-    if let Some(deps) = &info.dependencies {
-        for dep in deps { resolve_dependencies(dep, ctx, resolved)?; }
+impl HookSpec {
+    fn entries(&self) -> Vec<HookEntry> {
+        match self {
+            HookSpec::Script(script) => vec![HookEntry { script: script.clone(), package_type: None, phase: HookPhase::Install }],
+            HookSpec::Entry(entry) => vec![entry.clone()],
+            HookSpec::Entries(entries) => entries.clone(),
+        }
     }
-    resolved.insert(pkg_name.to_string());
-    Ok(())
 }
 
-async fn handle_remove(package_name: &str, ctx: &AppContext) -> Result<(), FluxError> {
-    let mut installed = ctx.get_installed_packages().await?;
+impl FluxConfig {
+    // A missing or empty config is not an error: read-only commands work on defaults, and
+    // commands that actually need a repository complain when `repositories()` is empty.
+    async fn load(path: &Path) -> Result<Self, FluxError> {
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(FluxError::Config(format!("Could not read {}: {}", path.display(), e))),
+        };
+        let config: Option<FluxConfig> = serde_yaml::from_str(&content)?;
+        Ok(config.unwrap_or_default())
+    }
 
-    let mut dependents = Vec::new();
-    for pkg in &installed {
-        if pkg.name == package_name { continue; }
-        if let Some(info) = ctx.package_index.get(&pkg.name) {
-            if let Some(deps) = &info.dependencies {
-                if deps.contains(&package_name.to_string()) {
-                    dependents.push(pkg.name.clone());
-                }
+    // All configured repositories, highest priority first; ties keep their order in flux.conf.
+    fn repositories(&self) -> Result<Vec<RepoEntry>, FluxError> {
+        let mut repos = self.repositories.clone();
+        if let Some(url) = &self.repository_url {
+            if !repos.iter().any(|r| r.name == DEFAULT_REPO_NAME) {
+                repos.insert(0, RepoEntry { name: DEFAULT_REPO_NAME.to_string(), url: url.clone(), priority: 0, optional: false });
+            }
+        }
+        for repo in &repos {
+            if repo.name.is_empty() || !repo.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err(FluxError::Config(format!("Invalid repository name '{}': use letters, digits, '-' and '_'", repo.name)));
             }
         }
+        repos.sort_by_key(|r| std::cmp::Reverse(r.priority));
+        Ok(repos)
     }
+}
 
-    if !dependents.is_empty() {
-        return Err(FluxError::DependencyInUse { package_name: package_name.to_string(), dependents });
-    }
+// --- Versions and Dependency Specs ---
 
-    if let Some(index) = installed.iter().position(|p| p.name == package_name) {
-        let pkg_to_remove = installed.remove(index);
-
-        println!("Removing package: {}", pkg_to_remove.name);
-        if pkg_to_remove.package_type == PackageType::App {
-            let info_from_repo = ctx.package_index.get(&pkg_to_remove.name).ok_or_else(|| FluxError::PackageNotFound(pkg_to_remove.name.clone()))?;
-            let install_path = ctx.get_install_path(info_from_repo);
-            if install_path.exists() {
-                fs::remove_dir_all(&install_path).await?;
-                println!("Removed directory: {}", install_path.display());
-            }
-        } else { // System package
-            println!("Removing files for system package {}...", pkg_to_remove.name);
-            for file_path in pkg_to_remove.files.iter().rev() {
-                let full_path = ctx.target_root.join(file_path);
-                if full_path.exists() {
-                    if full_path.is_dir() {
-                        if fs::read_dir(&full_path).await?.next_entry().await?.is_none() {
-                            println!("Removing empty directory: {}", full_path.display());
-                            fs::remove_dir(&full_path).await?;
-                        }
-                    } else {
-                        println!("Removing file: {}", full_path.display());
-                        fs::remove_file(&full_path).await?;
-                    }
+// Dotted numeric-aware comparison; a `-pre` suffix sorts before the release and `+build` is ignored.
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    fn compare_segments(a: &str, b: &str) -> Ordering {
+        let mut a_parts = a.split(['.', '_']);
+        let mut b_parts = b.split(['.', '_']);
+        loop {
+            match (a_parts.next(), b_parts.next()) {
+                (None, None) => return Ordering::Equal,
+                (Some(_), None) => return Ordering::Greater,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(x), Some(y)) => {
+                    let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+                        (Ok(x), Ok(y)) => x.cmp(&y),
+                        (Ok(_), Err(_)) => Ordering::Greater,
+                        (Err(_), Ok(_)) => Ordering::Less,
+                        (Err(_), Err(_)) => x.cmp(y),
+                    };
+                    if ordering != Ordering::Equal { return ordering; }
                 }
             }
         }
-
-        ctx.write_installed_packages(&installed).await?;
-        println!("Successfully removed '{}'.", pkg_to_remove.name);
-    } else {
-        return Err(FluxError::PackageNotFound(format!("{} (not installed)", package_name)));
     }
 
-    Ok(())
+    let strip_build = |v: &'_ str| -> String { v.split('+').next().unwrap_or_default().to_string() };
+    let (a, b) = (strip_build(a), strip_build(b));
+    let (a_core, a_pre) = a.split_once('-').map_or((a.as_str(), None), |(c, p)| (c, Some(p)));
+    let (b_core, b_pre) = b.split_once('-').map_or((b.as_str(), None), |(c, p)| (c, Some(p)));
+
+    compare_segments(a_core, b_core).then_with(|| match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(x), Some(y)) => compare_segments(x, y),
+    })
 }
 
-async fn handle_list(ctx: &AppContext) -> Result<(), FluxError> {
-    println!("Listing installed packages...");
-    let installed = ctx.get_installed_packages().await?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VersionOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
 
-    if installed.is_empty() {
-        println!("No packages are currently installed.");
-        return Ok(());
+impl std::fmt::Display for VersionOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VersionOp::Eq => "=",
+            VersionOp::Ne => "!=",
+            VersionOp::Lt => "<",
+            VersionOp::Le => "<=",
+            VersionOp::Gt => ">",
+            VersionOp::Ge => ">=",
+        })
     }
+}
 
-    for pkg in installed {
-        println!("- {} (version: {}, type: {:?}, reason: {:?})", pkg.name, pkg.version, pkg.package_type, pkg.install_reason);
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VersionConstraint {
+    op: VersionOp,
+    version: String,
+}
+
+impl VersionConstraint {
+    fn matches(&self, version: &str) -> bool {
+        let ordering = compare_versions(version, &self.version);
+        match self.op {
+            VersionOp::Eq => ordering.is_eq(),
+            VersionOp::Ne => ordering.is_ne(),
+            VersionOp::Lt => ordering.is_lt(),
+            VersionOp::Le => ordering.is_le(),
+            VersionOp::Gt => ordering.is_gt(),
+            VersionOp::Ge => ordering.is_ge(),
+        }
     }
-    Ok(())
 }
 
-async fn handle_update(ctx: &mut AppContext) -> Result<(), FluxError> {
-    println!("Updating repository index from {}...", ctx.config.repository_url);
+// A dependency entry such as `libssl >= 1.1`, `zlib = 1.2.13` or plain `libc`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DependencySpec {
+    name: String,
+    constraint: Option<VersionConstraint>,
+}
+
+impl DependencySpec {
+    const OPERATOR_CHARS: [char; 4] = ['<', '>', '=', '!'];
 
-    let url = if ctx.config.repository_url.starts_with("file://./") {
-        let current_dir = std::env::current_dir()?;
-        let file_path = ctx.config.repository_url.strip_prefix("file://./").unwrap();
-        Url::from_file_path(current_dir.join(file_path)).map_err(|_| FluxError::Config("Could not create absolute file URL".to_string()))?
-    } else {
-        Url::parse(&ctx.config.repository_url)?
+    fn name_of(spec: &str) -> &str {
+        spec.split(Self::OPERATOR_CHARS).next().unwrap_or_default().trim()
+    }
+
+    fn parse(spec: &str) -> Result<Self, FluxError> {
+        let invalid = || FluxError::InvalidDependencySpec(spec.to_string());
+        let Some(op_start) = spec.find(Self::OPERATOR_CHARS) else {
+            let name = spec.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) { return Err(invalid()); }
+            return Ok(Self { name: name.to_string(), constraint: None });
+        };
+
+        let name = spec[..op_start].trim();
+        let rest = &spec[op_start..];
+        let (op, version) = [("==", VersionOp::Eq), (">=", VersionOp::Ge), ("<=", VersionOp::Le), ("!=", VersionOp::Ne), ("=", VersionOp::Eq), (">", VersionOp::Gt), ("<", VersionOp::Lt)]
+            .into_iter()
+            .find_map(|(token, op)| rest.strip_prefix(token).map(|version| (op, version.trim())))
+            .ok_or_else(invalid)?;
+        if name.is_empty() || version.is_empty() || version.contains(char::is_whitespace) || version.contains(Self::OPERATOR_CHARS) {
+            return Err(invalid());
+        }
+        Ok(Self { name: name.to_string(), constraint: Some(VersionConstraint { op, version: version.to_string() }) })
+    }
+}
+
+impl std::fmt::Display for DependencySpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.constraint {
+            Some(constraint) => write!(f, "{} {} {}", self.name, constraint.op, constraint.version),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+// --- Application Context ---
+struct AppContext {
+    host_cache_dir: PathBuf,
+    target_root: PathBuf,
+    target_apps_root: PathBuf,
+    // The shared database directory; a profile keeps its database in profiles/<name> below it.
+    target_db_dir: PathBuf,
+    profile: Option<String>,
+    target_db_path: PathBuf,
+    target_store_dir: PathBuf,
+    target_staging_dir: PathBuf,
+    target_snapshot_dir: PathBuf,
+    target_history_path: PathBuf,
+    config: FluxConfig,
+    package_index: HashMap<String, PackageInfo>,
+    // Every version the index offers, oldest first; package_index has the last of each.
+    package_versions: HashMap<String, Vec<PackageInfo>>,
+    // The native architecture first, then the compatible fallbacks.
+    accepted_arches: Vec<String>,
+    downloader: Downloader,
+    assume_yes: bool,
+    no_verify: bool,
+    // Print the mutating steps of install/remove/upgrade instead of performing them.
+    dry_run: bool,
+    jobs: usize,
+}
+
+// Every version of each package the index offers, oldest first, each in its best build for `accepted_arches`.
+fn select_for_arches(packages: Vec<PackageInfo>, accepted_arches: &[String]) -> HashMap<String, Vec<PackageInfo>> {
+    let rank = |info: &PackageInfo| match &info.arch {
+        None => Some(1),
+        Some(arch) if *arch == accepted_arches[0] => Some(0),
+        Some(arch) => accepted_arches[1..].iter().position(|a| a == arch).map(|i| i + 2),
     };
+    let mut selected: HashMap<(String, String), (usize, PackageInfo)> = HashMap::new();
+    for info in packages {
+        let Some(info_rank) = rank(&info) else { continue };
+        let key = (info.name.clone(), info.version.clone());
+        match selected.get(&key) {
+            Some((best, _)) if *best <= info_rank => {}
+            _ => { selected.insert(key, (info_rank, info)); }
+        }
+    }
+    let mut versions: HashMap<String, Vec<PackageInfo>> = HashMap::new();
+    for ((name, _), (_, info)) in selected {
+        versions.entry(name).or_default().push(info);
+    }
+    for infos in versions.values_mut() {
+        infos.sort_by(|a, b| compare_versions(&a.version, &b.version));
+    }
+    versions
+}
+
+// What installs and upgrades go to: the newest version of each package.
+fn newest_versions(versions: &HashMap<String, Vec<PackageInfo>>) -> HashMap<String, PackageInfo> {
+    versions.iter().filter_map(|(name, infos)| Some((name.clone(), infos.last()?.clone()))).collect()
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    name: String,
+    version: String,
+    package_type: PackageType,
+    #[serde(serialize_with = "serialize_path", deserialize_with = "deserialize_path")]
+    root: PathBuf,
+    // Archive entries that did not exist before extraction, i.e. what cleaning up may remove.
+    #[serde(serialize_with = "serialize_paths", deserialize_with = "deserialize_paths")]
+    new_paths: Vec<PathBuf>,
+}
+
+// What to do on startup about an install that was interrupted between extraction and recording it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum InterruptedInstallPolicy {
+    #[default]
+    Ask,
+    Auto,
+    Never,
+}
 
-    download_file(&url, &ctx.host_cache_path).await?;
-    println!("Repository index updated successfully.");
+// Top-level directories under the target root that system packages install into. Flux's own
+// directories must stay out of these, otherwise removing a system package could delete App
+// files or the database, and removing an App could delete system files.
+const SYSTEM_OWNED_DIRS: &[&str] = &["bin", "boot", "dev", "etc", "lib", "lib32", "lib64", "opt", "proc", "run", "sbin", "srv", "sys", "tmp", "usr"];
 
-    let index_content = fs::read_to_string(&ctx.host_cache_path).await?;
-    let index: PackageIndex = serde_yaml::from_str(&index_content)?;
-    ctx.package_index = index.packages.into_iter().map(|p| (p.name.clone(), p)).collect();
+fn layout_dir(configured: Option<&Path>, default: &str, key: &str) -> Result<PathBuf, FluxError> {
+    let Some(configured) = configured else { return Ok(PathBuf::from(default)) };
+    match sanitize_relative_path(configured) {
+        Some(dir) if !dir.as_os_str().is_empty() => Ok(dir),
+        _ => Err(FluxError::Config(format!("{} must be a directory below the target root, got '{}'", key, configured.display()))),
+    }
+}
 
+fn validate_layout(root: &Path, apps_root: &Path, db_dir: &Path, cache_dir: &Path) -> Result<(), FluxError> {
+    let nested = |a: &Path, b: &Path| a.starts_with(b) || b.starts_with(a);
+    for (label, dir) in [("apps_dir", apps_root), ("db_dir", db_dir)] {
+        let relative = dir.strip_prefix(root).unwrap_or(dir);
+        if let Some(top) = relative.components().next() {
+            let top = top.as_os_str().to_string_lossy();
+            if SYSTEM_OWNED_DIRS.contains(&top.as_ref()) {
+                return Err(FluxError::Config(format!("{} ({}) is inside /{}, which system packages may own", label, dir.display(), top)));
+            }
+        }
+        if nested(dir, cache_dir) {
+            return Err(FluxError::Config(format!("{} ({}) overlaps the download cache ({})", label, dir.display(), cache_dir.display())));
+        }
+    }
+    if nested(apps_root, db_dir) {
+        return Err(FluxError::Config(format!("apps_dir ({}) and db_dir ({}) must not overlap", apps_root.display(), db_dir.display())));
+    }
     Ok(())
 }
 
-async fn handle_upgrade(ctx: &AppContext) -> Result<(), FluxError> {
-    let installed = ctx.get_installed_packages().await?;
-    let mut packages_to_update = Vec::new();
+// --cache-dir, then $XDG_CACHE_HOME/flux, then ~/.cache/flux, so service accounts without a home
+// directory still work. Relative XDG paths are ignored, as the spec asks.
+fn host_cache_dir(cli: &Cli) -> Result<PathBuf, FluxError> {
+    if let Some(dir) = &cli.cache_dir {
+        return Ok(dir.clone());
+    }
+    if let Some(xdg) = std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from).filter(|p| p.is_absolute()) {
+        return Ok(xdg.join("flux"));
+    }
+    dirs::home_dir().map(|home| home.join(".cache/flux"))
+        .ok_or_else(|| FluxError::Config("Could not determine a cache directory: no home directory and XDG_CACHE_HOME is unset. Pass --cache-dir.".to_string()))
+}
 
-    for pkg in &installed {
-        if let Some(repo_pkg) = ctx.package_index.get(&pkg.name) {
-            if repo_pkg.version != pkg.version {
-                println!("- {} (Installed: {}, Available: {})", pkg.name, pkg.version, repo_pkg.version);
-                packages_to_update.push(pkg.name.clone());
+impl AppContext {
+    async fn new(cli: &Cli) -> Result<Self, FluxError> {
+        let root = cli.root.clone();
+        let host_cache_dir = host_cache_dir(cli)?;
+        fs::create_dir_all(&host_cache_dir).await?;
+        remove_stale_partial_downloads(&host_cache_dir).await?;
+
+        let config = FluxConfig::load(Path::new("flux.conf")).await?;
+
+        let target_apps_root = root.join(layout_dir(config.apps_dir.as_deref(), "flux/apps", "apps_dir")?);
+        let target_db_dir = root.join(layout_dir(config.db_dir.as_deref(), "var/lib/flux", "db_dir")?);
+        validate_layout(&root, &target_apps_root, &target_db_dir, &host_cache_dir)?;
+        // Files on disk, the content store and staging are shared; the database, journal, history
+        // and snapshots belong to the profile.
+        let profile_dir = match &cli.profile {
+            Some(profile) if !is_safe_file_name(profile) => {
+                return Err(FluxError::Config(format!("Invalid profile name '{}': use letters, digits, '-', '_' and '.'", profile)));
             }
+            Some(profile) => target_db_dir.join("profiles").join(profile),
+            None => target_db_dir.clone(),
+        };
+        let target_db_path = profile_dir.join("db.json");
+        let target_store_dir = target_db_dir.join("store");
+        let target_staging_dir = target_db_dir.join("staging");
+        let target_snapshot_dir = profile_dir.join("snapshots");
+        let target_history_path = profile_dir.join("history.jsonl");
+
+        let repos = config.repositories()?;
+        if !repos.iter().any(|repo| repo_cache_path(&host_cache_dir, repo).exists()) {
+            eprintln!("No local repository cache found. Please run 'flux update' to fetch it.");
         }
+
+        let mut accepted_arches = vec![config.arch.clone().unwrap_or_else(|| std::env::consts::ARCH.to_string())];
+        let arch_compat = if cli.arch_compat.is_empty() { &config.arch_compat } else { &cli.arch_compat };
+        accepted_arches.extend(arch_compat.iter().filter(|a| !accepted_arches.contains(a)).cloned().collect::<Vec<_>>());
+
+        let package_versions = load_package_index(&repos, &host_cache_dir, &accepted_arches, config.max_name_length.unwrap_or(DEFAULT_MAX_NAME_LENGTH)).await?;
+        let package_index = newest_versions(&package_versions);
+
+        let ip_preference = if cli.prefer_ipv4 {
+            IpPreference::Ipv4
+        } else if cli.prefer_ipv6 {
+            IpPreference::Ipv6
+        } else {
+            config.ip_preference.unwrap_or_default()
+        };
+        let downloader = Downloader::new(&config, cli.offline, ip_preference)?;
+        Ok(Self {
+            host_cache_dir,
+            target_root: root,
+            target_apps_root,
+            target_db_dir,
+            profile: cli.profile.clone(),
+            target_db_path,
+            target_store_dir,
+            target_staging_dir,
+            target_snapshot_dir,
+            target_history_path,
+            config,
+            package_index,
+            package_versions,
+            accepted_arches,
+            downloader,
+            assume_yes: cli.yes,
+            no_verify: cli.no_verify,
+            dry_run: cli.dry_run,
+            jobs: cli.jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())).max(1),
+        })
     }
 
-    if packages_to_update.is_empty() {
-        println!("All packages are up to date.");
-        return Ok(());
+    fn matching_hooks(&self, package_name: &str, package_type: &PackageType, phase: HookPhase) -> Vec<String> {
+        let mut scripts = Vec::new();
+        if let Some(hooks) = &self.config.hooks {
+            for (pattern, spec) in hooks {
+                if !glob_match(pattern, package_name) { continue; }
+                for entry in spec.entries() {
+                    if entry.applies_to(package_type, phase) && !scripts.contains(&entry.script) {
+                        scripts.push(entry.script);
+                    }
+                }
+            }
+        }
+        scripts
     }
 
-    println!("\nStarting upgrade...");
-    for package_name in packages_to_update {
-        println!("\nUpgrading {}...", package_name);
-        handle_remove(&package_name, ctx).await?;
-        handle_install(&package_name, ctx).await?;
+    fn run_hook(&self, hook_script: &str, triggered_by: &str) -> Result<(), FluxError> {
+        if self.dry_run {
+            println!("[dry-run] would run hook {} (triggered by {})", hook_script, triggered_by);
+            return Ok(());
+        }
+        run_script(&self.get_hook_path(hook_script), &[], triggered_by, self.hook_sandbox(), |pkg, hook, msg| FluxError::HookFailed { package_name: pkg, hook_script: hook, message: msg })
     }
 
-    println!("\nUpgrade complete.");
-    Ok(())
-}
+    fn package_url(&self, url: &str) -> Result<Url, FluxError> {
+        let resolved = resolve_repo_url(url)?;
+        match &self.config.cache_proxy_url {
+            Some(proxy) => proxied_url(proxy, &resolved),
+            None => Ok(resolved),
+        }
+    }
 
-async fn handle_autoremove(ctx: &AppContext) -> Result<(), FluxError> {
-    println!("Checking for unused dependencies...");
-    let installed = ctx.get_installed_packages().await?;
-    let mut required_deps = HashSet::new();
+    fn hook_sandbox(&self) -> Option<&Path> {
+        self.config.sandbox_scripts.then_some(self.target_root.as_path())
+    }
 
-    for pkg in &installed {
-        if let Some(info) = ctx.package_index.get(&pkg.name) {
-            if let Some(deps) = &info.dependencies {
-                for dep in deps {
-                    required_deps.insert(dep.clone());
-                }
+    // Only multi-frame zstd archives use more than two threads, so the default of one per core
+    // costs the others nothing.
+    fn decompress_threads(&self) -> usize {
+        self.config.decompress_threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())).max(1)
+    }
+
+    fn get_hook_path(&self, hook_script: &str) -> PathBuf {
+        self.target_root.join(hook_script.strip_prefix('/').unwrap_or(hook_script))
+    }
+
+    fn get_installed_path(&self, pkg: &InstalledPackageInfo) -> PathBuf {
+        match pkg.package_type {
+            PackageType::System => self.target_root.clone(),
+            PackageType::App => self.target_apps_root.join(format!("{}-{}", pkg.name, pkg.version)),
+        }
+    }
+
+    fn get_install_path(&self, info: &PackageInfo) -> PathBuf {
+        match info.package_type {
+            PackageType::System => self.target_root.clone(),
+            PackageType::App => self.target_apps_root.join(format!("{}-{}", info.name, info.version)),
+        }
+    }
+
+    // Any version the index offers, where `package_index` only has the one installs use.
+    fn indexed_version(&self, name: &str, version: &str) -> Option<&PackageInfo> {
+        self.package_versions.get(name)?.iter().find(|info| info.version == version)
+    }
+
+    async fn get_installed_packages(&self) -> Result<Vec<InstalledPackageInfo>, FluxError> {
+        if !self.target_db_path.exists() { return Ok(Vec::new()); }
+        let content = fs::read_to_string(&self.target_db_path).await?;
+        if content.trim().is_empty() { return Ok(Vec::new()); }
+        serde_json::from_str(&content).map_err(|e| FluxError::CorruptDatabase {
+            path: self.target_db_path.clone(),
+            message: e.to_string(),
+        })
+    }
+
+    // The returned file holds the lock until it is dropped or the process exits.
+    fn lock_database(&self, wait: bool) -> Result<std::fs::File, FluxError> {
+        use std::os::unix::io::AsRawFd;
+
+        // One lock for all profiles, since they share the files on disk.
+        let path = self.target_db_dir.join("db.lock");
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+        let try_lock = |flags| if unsafe { libc::flock(file.as_raw_fd(), flags) } == 0 { Ok(()) } else { Err(std::io::Error::last_os_error()) };
+        match try_lock(libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && wait => {
+                eprintln!("Waiting for another flux process to release {}...", path.display());
+                try_lock(libc::LOCK_EX)?;
             }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(FluxError::Locked(path)),
+            Err(e) => return Err(e.into()),
         }
+        Ok(file)
     }
 
-    let mut orphans_to_remove = Vec::new();
-    for pkg in &installed {
-        if pkg.install_reason == InstallReason::Dependency && !required_deps.contains(&pkg.name) {
-            orphans_to_remove.push(pkg.name.clone());
+    // Packages recorded in the default database and in every profile other than the active one.
+    // Their files stay on disk when the active profile removes the same package.
+    async fn packages_in_other_profiles(&self) -> Result<Vec<InstalledPackageInfo>, FluxError> {
+        let mut db_paths = Vec::new();
+        if self.profile.is_some() {
+            db_paths.push(self.target_db_dir.join("db.json"));
+        }
+        if let Ok(mut entries) = fs::read_dir(self.target_db_dir.join("profiles")).await {
+            while let Some(entry) = entries.next_entry().await? {
+                if self.profile.as_deref().is_some_and(|p| entry.file_name() == p) { continue; }
+                db_paths.push(entry.path().join("db.json"));
+            }
         }
+        let mut packages = Vec::new();
+        for path in db_paths {
+            let content = match fs::read_to_string(&path).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if content.trim().is_empty() { continue; }
+            let recorded: Vec<InstalledPackageInfo> = serde_json::from_str(&content)
+                .map_err(|e| FluxError::CorruptDatabase { path: path.clone(), message: e.to_string() })?;
+            packages.extend(recorded);
+        }
+        Ok(packages)
     }
 
-    if orphans_to_remove.is_empty() {
-        println!("No unused dependencies to remove.");
-        return Ok(());
+    fn install_journal_path(&self) -> PathBuf {
+        self.target_db_path.with_file_name("install.journal")
     }
 
-    println!("\nThe following packages are no longer required and will be removed:");
-    for orphan in &orphans_to_remove {
-        println!("- {}", orphan);
+    async fn read_install_journal(&self) -> Result<Vec<JournalEntry>, FluxError> {
+        match fs::read_to_string(self.install_journal_path()).await {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
     }
 
-    println!("\nRemoving unused dependencies...");
-    for package_name in orphans_to_remove {
-        handle_remove(&package_name, ctx).await?;
+    // Written before a package is extracted and cleared once the database records it, so a hard
+    // kill in between can be cleaned up on the next run.
+    async fn journal_install(&self, info: &PackageInfo, install_path: &Path, archive_path: &Path) -> Result<(), FluxError> {
+        let mut new_paths = Vec::new();
+        for (path, _) in read_archive_entries(archive_path).await? {
+            let Some(path) = sanitize_relative_path(&path).filter(|p| !p.as_os_str().is_empty()) else { continue };
+            if fs::symlink_metadata(install_path.join(&path)).await.is_err() {
+                new_paths.push(path);
+            }
+        }
+        let mut journal = self.read_install_journal().await?;
+        // A retry after a failed extraction keeps the paths the failed attempt created.
+        if let Some(entry) = journal.iter_mut().find(|e| e.name == info.name && e.root == install_path) {
+            entry.version = info.version.clone();
+            new_paths.retain(|path| !entry.new_paths.contains(path));
+            entry.new_paths.extend(new_paths);
+        } else {
+            journal.push(JournalEntry { name: info.name.clone(), version: info.version.clone(), package_type: info.package_type.clone(), root: install_path.to_path_buf(), new_paths });
+        }
+        fs::create_dir_all(self.target_db_path.parent().unwrap()).await?;
+        write_atomic(&self.install_journal_path(), serde_json::to_string_pretty(&journal)?.as_bytes()).await?;
+        Ok(())
     }
 
-    Ok(())
+    async fn clear_install_journal(&self) -> Result<(), FluxError> {
+        match fs::remove_file(self.install_journal_path()).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn write_installed_packages(&self, packages: &[InstalledPackageInfo]) -> Result<(), FluxError> {
+        fs::create_dir_all(&self.target_db_path.parent().unwrap()).await?;
+        // A crash mid-write must leave either the old database or the new one, never a truncated file.
+        let content = serde_json::to_string_pretty(packages)?;
+        write_atomic(&self.target_db_path, content.as_bytes()).await?;
+        Ok(())
+    }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-    let mut ctx = AppContext::new(cli.root).await?;
+// --- CLI Structure ---
+#[derive(Parser)]
+#[command(author, version, about = "A fast and reliable package manager for your system.", long_about = None)]
+struct Cli {
+    #[arg(long, global = true, default_value = "/")]
+    root: PathBuf,
+    /// Answer yes to all confirmation prompts
+    #[arg(short, long, global = true)]
+    yes: bool,
+    /// Skip checksum verification of downloaded archives (local development only)
+    #[arg(long, global = true)]
+    no_verify: bool,
+    /// Never access the network; only local file:// repositories and cached data are used
+    #[arg(long, global = true)]
+    offline: bool,
+    /// Compatible fallback architectures, in order of preference (overrides arch_compat in flux.conf)
+    #[arg(long, global = true, value_delimiter = ',')]
+    arch_compat: Vec<String>,
+    /// Number of parallel jobs for hashing and downloads [default: number of CPUs]
+    #[arg(short, long, global = true)]
+    jobs: Option<usize>,
+    /// Print what install, remove, upgrade, autoremove and mark would do without changing anything
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Wait for another flux process to finish instead of failing when the package database is locked
+    #[arg(long, global = true)]
+    wait: bool,
+    /// Try IPv4 addresses first when connecting (overrides ip_preference in flux.conf)
+    #[arg(long, global = true, conflicts_with = "prefer_ipv6")]
+    prefer_ipv4: bool,
+    /// Try IPv6 addresses first when connecting (overrides ip_preference in flux.conf)
+    #[arg(long, global = true)]
+    prefer_ipv6: bool,
+    /// Track packages in the named profile's own database (var/lib/flux/profiles/<NAME>) instead of the default one
+    #[arg(long, global = true, env = "FLUX_PROFILE", value_name = "NAME")]
+    profile: Option<String>,
+    /// Keep downloaded archives and repository indexes here instead of $XDG_CACHE_HOME/flux or ~/.cache/flux
+    #[arg(long, global = true, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Commands,
+}
 
-    let result = match cli.command {
-        Commands::Install { package } => handle_install(&package, &ctx).await,
-        Commands::Remove { package } => handle_remove(&package, &ctx).await,
-        Commands::List => handle_list(&ctx).await,
-        Commands::Update => handle_update(&mut ctx).await,
-        Commands::Upgrade => handle_upgrade(&ctx).await,
-        Commands::Autoremove => handle_autoremove(&ctx).await,
-    };
+#[derive(Subcommand)]
+enum Commands {
+    Install {
+        package: String,
+        #[command(flatten)]
+        options: InstallOptions,
+    },
+    Remove {
+        package: String,
+        /// Also remove every dependency that only this package kept installed, recursively
+        #[arg(long)]
+        recursive_orphans: bool,
+    },
+    Update {
+        /// Fail instead of falling back to the cached index when the update fails
+        #[arg(long)]
+        strict_update: bool,
+        /// Attempts per index URL before moving on to the next mirror (overrides index_retries)
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        max_download_attempts_per_mirror: Option<u32>,
+        /// Attempts across the repository URL and all mirrors together (overrides index_max_total_attempts)
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        max_total_attempts: Option<u32>,
+    },
+    Upgrade {
+        /// Only upgrade the named packages
+        packages: Vec<String>,
+        #[command(flatten)]
+        options: UpgradeOptions,
+    },
+    List {
+        #[arg(long, value_enum, default_value_t = ListFormat::Text)]
+        format: ListFormat,
+        /// Emit one JSON object per line instead of a single array
+        #[arg(long, conflicts_with = "format")]
+        json_lines: bool,
+        /// Show how many installed packages depend on each package
+        #[arg(long)]
+        reverse_deps: bool,
+        /// With --reverse-deps, also name the dependents
+        #[arg(long, short)]
+        verbose: bool,
+        /// Only list the packages `autoremove` would remove
+        #[arg(long)]
+        orphans: bool,
+        /// Order of the output [default: name]
+        #[arg(long, value_enum)]
+        sort: Option<ListSort>,
+        /// Only packages installed after this time: RFC 3339 (2024-05-01, 2024-05-01T12:00:00Z) or relative (30m, 24h, 7d, 2w)
+        #[arg(long, value_name = "TIME", value_parser = parse_time_spec)]
+        newer_than: Option<u64>,
+    },
+    Autoremove,
+    /// Summarize pending upgrades, orphans, index freshness and integrity problems
+    Status {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Find packages whose name or description contains the query (case-insensitive)
+    Search {
+        #[arg(required_unless_present = "by_dependency")]
+        query: Option<String>,
+        /// Instead, list the packages in the index that depend on PACKAGE, directly or as an optional dependency
+        #[arg(long, value_name = "PACKAGE", conflicts_with = "query")]
+        by_dependency: Option<String>,
+        /// With --by-dependency, only list packages that are installed
+        #[arg(long, requires = "by_dependency")]
+        installed_only: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        /// Emit one JSON object per line instead of a single array
+        #[arg(long, conflicts_with = "format")]
+        json_lines: bool,
+    },
+    /// Show details about a package from the repository index
+    Info {
+        package: String,
+        /// Print machine-readable JSON including computed fields
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the files an installed package put on the system
+    Files {
+        package: String,
+    },
+    /// Show which installed package put a file on the system
+    #[command(alias = "which")]
+    Owns {
+        /// An absolute path inside --root (with or without the root prefix), or a path relative to the current directory
+        path: PathBuf,
+    },
+    /// Export the installed packages as a repository index, e.g. for an offline mirror
+    ExportInstalled {
+        /// Include checksums, computed from cached archives where available
+        #[arg(long)]
+        with_checksums: bool,
+        /// Point package URLs at <URL>/<archive> instead of their original location
+        #[arg(long, value_name = "URL")]
+        url_base: Option<String>,
+        #[arg(long, value_enum, default_value_t = IndexFormat::Yaml)]
+        format: IndexFormat,
+        /// Emit one JSON package record per line instead of a whole index
+        #[arg(long, conflicts_with = "format")]
+        json_lines: bool,
+    },
+    /// Export a dependency graph in DOT or JSON-graph format
+    Tree {
+        /// Graph the dependency closure of this package from the index
+        #[arg(required_unless_present = "installed", conflicts_with = "installed")]
+        package: Option<String>,
+        /// Graph every installed package
+        #[arg(long)]
+        installed: bool,
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+    /// Download and verify the archives of all available upgrades without applying them
+    PrefetchUpdates,
+    /// Delete all downloaded archives from the cache
+    Clean,
+    /// Inspect the local archive cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+    /// Save, list and delete named snapshots of the installed package set
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotCommand,
+    },
+    /// List past transactions, or reverse one of them
+    History {
+        /// Reverse the transaction with this id, if no later transaction depends on its changes
+        #[arg(long, value_name = "ID")]
+        undo: Option<u64>,
+    },
+    /// Install, remove and re-version packages until the system matches a snapshot
+    Restore {
+        name: String,
+    },
+    /// Install, remove and re-version packages until the system matches a desired-state file
+    Reconcile {
+        /// YAML file with `packages` (explicit) and `dependencies` lists of specs such as `curl >= 8.0`
+        file: PathBuf,
+    },
+    /// Read or change settings in flux.conf
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    /// Diagnose common problems with the installation and the cache
+    Doctor {
+        /// Repair what can be repaired safely; deleting files asks for confirmation first
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Reconstruct the package database by scanning the install locations
+    RebuildDb {
+        /// Rebuild even if the current database is readable
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check that the files of installed packages are still present and unmodified
+    Verify {
+        /// Only verify this package
+        package: Option<String>,
+        /// Re-hash every file and compare it against the hash recorded at install time
+        #[arg(long)]
+        checksums: bool,
+    },
+    /// Check the minisign signatures of the installed packages' archives against trusted_keys
+    VerifySignatures {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Replace an installed package with an older version the index still has
+    Downgrade {
+        package: String,
+        version: String,
+        /// Keep downloaded archives in the cache instead of deleting them after downgrading
+        #[arg(long)]
+        keep_downloads: bool,
+    },
+    /// Change whether a package counts as explicitly installed or as a dependency
+    Mark {
+        package: String,
+        reason: InstallReason,
+    },
+}
 
-    if let Err(e) = result {
-        eprintln!("Error: {}", e);
-        process::exit(1);
+impl Commands {
+    fn is_mutating(&self) -> bool {
+        match self {
+            Commands::Install { options, .. } => !options.print_plan_json,
+            Commands::Doctor { fix } => *fix,
+            Commands::Upgrade { options, .. } => !options.list,
+            Commands::History { undo } => undo.is_some(),
+            Commands::Remove { .. } | Commands::Autoremove | Commands::RebuildDb { .. } | Commands::Mark { .. } | Commands::Downgrade { .. } | Commands::Restore { .. } | Commands::Reconcile { .. } => true,
+            _ => false,
+        }
     }
 
-    Ok(())
+    fn supports_dry_run(&self) -> bool {
+        matches!(self, Commands::Install { .. } | Commands::Remove { .. } | Commands::Upgrade { .. } | Commands::Downgrade { .. } | Commands::Autoremove | Commands::Mark { .. } | Commands::Reconcile { .. } | Commands::PrefetchUpdates | Commands::Clean)
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print a setting's effective value; nested keys are dotted, e.g. hooks.libc
+    Get { key: String },
+    /// Change a setting; the value is parsed as YAML and checked before flux.conf is rewritten
+    Set { key: String, value: String },
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    /// Record the installed packages, their versions and install reasons
+    Create { name: String },
+    /// List saved snapshots
+    List,
+    /// Delete a saved snapshot
+    Delete { name: String },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Re-hash every cached archive and compare it against the index
+    Verify {
+        /// Delete archives whose checksum does not match
+        #[arg(long)]
+        prune: bool,
+    },
+}
+
+#[derive(Args, Default, Clone)]
+struct UpgradeOptions {
+    /// Choose which upgrades to apply one by one
+    #[arg(long)]
+    interactive: bool,
+    /// Show a changelog excerpt for each package before upgrading
+    #[arg(long)]
+    changelog: bool,
+    /// Also apply index versions that are lower than the installed ones
+    #[arg(long)]
+    allow_downgrade: bool,
+    /// Only list the available upgrades; exits with status 100 if there are any
+    #[arg(long, conflicts_with_all = ["interactive", "changelog"])]
+    list: bool,
+    /// Only consider packages whose name matches this glob, e.g. 'kernel-*' (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    only: Vec<String>,
+    /// Leave packages whose name matches this glob at their current version; applied after --only (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Only apply upgrades whose archives are already in the cache (see prefetch-updates)
+    #[arg(long)]
+    use_cached: bool,
+    /// Keep downloaded archives in the cache instead of deleting them after upgrading
+    #[arg(long)]
+    keep_downloads: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum IndexFormat {
+    Yaml,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Args, Default, Clone)]
+struct InstallOptions {
+    /// Also install the optional dependencies of the requested package
+    #[arg(long)]
+    recommended: bool,
+    /// What to do when a post-install script fails [default: abort-and-rollback]
+    #[arg(long, value_enum)]
+    on_script_failure: Option<ScriptFailurePolicy>,
+    /// What to do when a system package ships a file another package owns [default: error]
+    #[arg(long, value_enum)]
+    on_file_conflict: Option<FileConflictPolicy>,
+    /// Keep downloaded archives in the cache instead of deleting them after install
+    #[arg(long)]
+    keep_downloads: bool,
+    /// Print the resolved install plan as JSON and exit without installing anything
+    #[arg(long)]
+    print_plan_json: bool,
+    /// Don't refresh the index and retry when a package isn't found in it
+    #[arg(long)]
+    no_auto_update: bool,
+    /// Print how long resolution, downloads, verification, extraction and scripts took
+    #[arg(long)]
+    time: bool,
+    /// Install what the requested package depends on, but not the package itself
+    #[arg(long)]
+    dependency_only: bool,
+    /// Record the requested packages (with --dependency-only, their direct dependencies) as dependencies, so autoremove reaps them once nothing needs them
+    #[arg(long, conflicts_with = "reinstall")]
+    as_dependency: bool,
+    /// Restore the files of an installed package from its archive, rewriting only those that differ
+    #[arg(long, conflicts_with_all = ["recommended", "print_plan_json", "dependency_only"])]
+    reinstall: bool,
+    /// Check that already-installed packages in the dependency tree still meet its version requirements and restore any with missing or modified files
+    #[arg(long, conflicts_with = "reinstall")]
+    recheck_existing: bool,
+}
+
+// --- Core Logic ---
+
+const DEFAULT_MAX_CONNECTIONS_PER_HOST: usize = 4;
+const MAX_THROTTLED_ATTEMPTS: u32 = 5;
+const DEFAULT_IO_BUFFER_SIZE: usize = 64 * 1024;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+// Puts the preferred address family first. The connector tries the first family and races the
+// other one shortly after (happy eyeballs), so a broken family costs a short delay, not a hang.
+struct FamilyOrderResolver {
+    preference: IpPreference,
+}
+
+impl IpPreference {
+    // Stable, so the resolver's order is kept within each family.
+    fn order(self, addrs: &mut [std::net::SocketAddr]) {
+        match self {
+            IpPreference::Dual => {}
+            IpPreference::Ipv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            IpPreference::Ipv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
+    }
+}
+
+impl reqwest::dns::Resolve for FamilyOrderResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let preference = self.preference;
+        Box::pin(async move {
+            let mut addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            preference.order(&mut addrs);
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+// Connection slots for one host; `limit` shrinks when the server answers 429.
+struct HostSlots {
+    semaphore: tokio::sync::Semaphore,
+    limit: std::sync::atomic::AtomicUsize,
+}
+
+// Shared HTTP client that caps concurrent connections per host.
+struct Downloader {
+    client: reqwest::Client,
+    config_limits: HashMap<String, usize>,
+    default_limit: usize,
+    hosts: std::sync::Mutex<HashMap<String, std::sync::Arc<HostSlots>>>,
+    offline: bool,
+    buffer_size: usize,
+    // Stacks the bars of concurrent downloads instead of letting them overwrite each other.
+    progress: indicatif::MultiProgress,
+}
+
+impl Downloader {
+    fn new(config: &FluxConfig, offline: bool, ip_preference: IpPreference) -> Result<Self, FluxError> {
+        let connect_timeout = std::time::Duration::from_secs(config.connect_timeout_secs.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS).max(1));
+        let client = reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .dns_resolver(std::sync::Arc::new(FamilyOrderResolver { preference: ip_preference }))
+            .build()?;
+        Ok(Self {
+            offline,
+            buffer_size: config.io_buffer_size.unwrap_or(DEFAULT_IO_BUFFER_SIZE).max(1),
+            client,
+            config_limits: config.host_connection_limits.clone(),
+            default_limit: config.max_connections_per_host.unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_HOST).max(1),
+            hosts: std::sync::Mutex::new(HashMap::new()),
+            progress: indicatif::MultiProgress::new(),
+        })
+    }
+
+    fn slots_for(&self, host: &str) -> std::sync::Arc<HostSlots> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.entry(host.to_string()).or_insert_with(|| {
+            // The most restrictive of the global and the per-host limit applies.
+            let limit = self.config_limits.get(host).map_or(self.default_limit, |&l| l.clamp(1, self.default_limit));
+            std::sync::Arc::new(HostSlots { semaphore: tokio::sync::Semaphore::new(limit), limit: limit.into() })
+        }).clone()
+    }
+
+    async fn download_file(&self, url: &Url, dest_path: &Path) -> Result<(), FluxError> {
+        if url.scheme() == "file" {
+            let source_path = url.to_file_path().map_err(|_| FluxError::Config(format!("Invalid file path in URL: {}", url)))?;
+            let size = fs::copy(&source_path, dest_path).await?;
+            if std::io::stderr().is_terminal() {
+                eprintln!("Copied {} ({})", download_label(url), indicatif::HumanBytes(size));
+            }
+            return Ok(());
+        }
+        if self.offline {
+            return Err(FluxError::Config(format!("Not downloading {} because --offline is set", url)));
+        }
+
+        let slots = self.slots_for(url.host_str().unwrap_or_default());
+        let mut delay = std::time::Duration::from_secs(1);
+        for attempt in 1..=MAX_THROTTLED_ATTEMPTS {
+            let permit = slots.semaphore.acquire().await.expect("host semaphore is never closed");
+            let response = self.client.get(url.clone()).send().await?;
+
+            if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS && attempt < MAX_THROTTLED_ATTEMPTS {
+                let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .map(std::time::Duration::from_secs);
+                // Give this slot up for good so fewer downloads hit the host at once.
+                if slots.limit.load(std::sync::atomic::Ordering::SeqCst) > 1 {
+                    slots.limit.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                    permit.forget();
+                } else {
+                    drop(permit);
+                }
+                let wait = retry_after.unwrap_or(delay);
+                eprintln!("Warning: {} is rate limiting downloads, retrying in {}s", url.host_str().unwrap_or_default(), wait.as_secs());
+                tokio::time::sleep(wait).await;
+                delay *= 2;
+                continue;
+            }
+
+            let response = response.error_for_status()?;
+            let progress = self.progress.add(download_progress(url, response.content_length()));
+            let mut stream = response.bytes_stream();
+            let mut dest_file = tokio::io::BufWriter::with_capacity(self.buffer_size, File::create(dest_path).await?);
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                dest_file.write_all(&chunk).await?;
+                progress.inc(chunk.len() as u64);
+            }
+            dest_file.flush().await?;
+            progress.finish_and_clear();
+            return Ok(());
+        }
+        unreachable!("the last attempt always returns")
+    }
+
+    // Small documents such as changelogs, fetched under the same host limits as archives.
+    async fn fetch_text(&self, url: &Url) -> Result<String, FluxError> {
+        let temp = tempfile::NamedTempFile::new()?;
+        self.download_file(url, temp.path()).await?;
+        Ok(fs::read_to_string(temp.path()).await?)
+    }
+}
+
+fn download_label(url: &Url) -> String {
+    url.path_segments().and_then(|mut segments| segments.next_back()).filter(|s| !s.is_empty()).unwrap_or(url.as_str()).to_string()
+}
+
+// Drawn on stderr, and only when it is a terminal; without a Content-Length there is no bar or ETA.
+fn download_progress(url: &Url, length: Option<u64>) -> indicatif::ProgressBar {
+    let progress = match length {
+        Some(length) => indicatif::ProgressBar::new(length).with_style(
+            indicatif::ProgressStyle::with_template("{msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}")
+                .expect("valid progress template")
+                .progress_chars("=> "),
+        ),
+        None => indicatif::ProgressBar::new_spinner().with_style(
+            indicatif::ProgressStyle::with_template("{spinner} {msg} {bytes} {bytes_per_sec}").expect("valid progress template"),
+        ),
+    };
+    progress.set_message(download_label(url));
+    progress
+}
+
+fn partial_download_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    PathBuf::from(partial)
+}
+
+// Downloads into `<dest>.part` and only moves it to `dest` once the checksum matches.
+async fn download_verified(downloader: &Downloader, info: &PackageInfo, url: &Url, dest: &Path, verify: bool, timings: &mut InstallTimings) -> Result<(), FluxError> {
+    let partial_path = partial_download_path(dest);
+    let result = async {
+        let started = std::time::Instant::now();
+        downloader.download_file(url, &partial_path).await?;
+        timings.record(&info.name, InstallPhase::Download, started.elapsed());
+        if !verify { return Ok(()); }
+        let started = std::time::Instant::now();
+        let verified = verify_checksum(info, &partial_path, downloader.buffer_size).await;
+        timings.record(&info.name, InstallPhase::Verify, started.elapsed());
+        verified
+    }.await;
+    match result {
+        Ok(()) => Ok(fs::rename(&partial_path, dest).await?),
+        Err(e) => {
+            let _ = fs::remove_file(&partial_path).await;
+            Err(e)
+        }
+    }
+}
+
+// Reuses a cached archive whose checksum still matches, e.g. one an upgrade fetched ahead of time,
+// and downloads it otherwise.
+async fn fetch_archive(info: &PackageInfo, ctx: &AppContext, timings: &mut InstallTimings) -> Result<PathBuf, FluxError> {
+    if let Some(archive_path) = cached_archive(info, ctx).await? {
+        return Ok(archive_path);
+    }
+    let archive_path = ctx.host_cache_dir.join(archive_file_name(info));
+    println!("Downloading {} from {}", info.name, info.url);
+    download_verified(&ctx.downloader, info, &ctx.package_url(&info.url)?, &archive_path, !ctx.no_verify, timings).await?;
+    Ok(archive_path)
+}
+
+async fn cached_archive(info: &PackageInfo, ctx: &AppContext) -> Result<Option<PathBuf>, FluxError> {
+    let archive_path = ctx.host_cache_dir.join(archive_file_name(info));
+    let cached = archive_path.exists() && (ctx.no_verify || compute_checksum(&archive_path, &info.checksum, ctx.downloader.buffer_size).await? == info.checksum);
+    Ok(cached.then_some(archive_path))
+}
+
+// Leftovers of downloads that were interrupted before they could be verified.
+async fn remove_stale_partial_downloads(cache_dir: &Path) -> Result<(), FluxError> {
+    let mut entries = fs::read_dir(cache_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().ends_with(".part") {
+            eprintln!("Removing interrupted download {}", entry.path().display());
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+fn archive_file_name(info: &PackageInfo) -> String {
+    format!("{}-{}{}", info.name, info.version, info.compression().extension())
+}
+
+async fn verify_checksum(info: &PackageInfo, file_path: &Path, buffer_size: usize) -> Result<(), FluxError> {
+    println!("Verifying checksum for {}...", info.name);
+    let calculated_checksum = compute_checksum(file_path, &info.checksum, buffer_size).await?;
+
+    if calculated_checksum == info.checksum {
+        println!("Checksum verified.");
+        Ok(())
+    } else {
+        Err(FluxError::ChecksumMismatch {
+            package_name: info.name.clone(),
+            algorithm: ChecksumAlgorithm::of(&info.checksum)?.name(),
+            expected: info.checksum.clone(),
+            found: calculated_checksum,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    // Index checksums may carry an `algorithm:` prefix; unprefixed ones are sha256.
+    fn of(checksum: &str) -> Result<Self, FluxError> {
+        match checksum.split_once(':') {
+            None => Ok(Self::Sha256),
+            Some(("sha256", _)) => Ok(Self::Sha256),
+            Some(("sha512", _)) => Ok(Self::Sha512),
+            Some(("blake3", _)) => Ok(Self::Blake3),
+            Some((other, _)) => Err(FluxError::Config(format!("Unsupported checksum algorithm '{}'", other))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        }
+    }
+}
+
+enum ChecksumHasher {
+    Sha256(Sha256),
+    Sha512(sha2::Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChecksumHasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Sha512 => Self::Sha512(sha2::Sha512::new()),
+            ChecksumAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => { hasher.update(data); }
+        }
+    }
+
+    fn finalize_hex(self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+// Hashes the file with the algorithm of `like` and formats the result the same way (with or
+// without its prefix), so it can be compared against `like` directly.
+async fn compute_checksum(file_path: &Path, like: &str, buffer_size: usize) -> Result<String, FluxError> {
+    let algorithm = ChecksumAlgorithm::of(like)?;
+    let mut file = File::open(file_path).await?;
+    let mut hasher = ChecksumHasher::new(algorithm);
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 { break; }
+        hasher.update(&buffer[..n]);
+    }
+    let hash = hasher.finalize_hex();
+    Ok(if like.contains(':') { format!("{}:{}", algorithm.name(), hash) } else { hash })
+}
+
+// Makes an archive or database path relative, rejecting anything that climbs out with `..`.
+fn sanitize_relative_path(path: &Path) -> Option<PathBuf> {
+    use std::path::Component;
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => return None,
+        }
+    }
+    Some(clean)
+}
+
+// Where a self-describing archive keeps its own metadata. Nothing under it is installed.
+const EMBEDDED_METADATA_DIR: &str = ".flux";
+const EMBEDDED_METADATA_PATH: &str = ".flux/metadata.yaml";
+
+fn is_embedded_metadata(path: &Path) -> bool {
+    path.starts_with(EMBEDDED_METADATA_DIR)
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddedMetadata {
+    name: String,
+    version: String,
+    #[serde(default)]
+    dependencies: Vec<String>,
+    #[serde(default)]
+    post_install: Option<ScriptSpec>,
+}
+
+async fn read_embedded_metadata(archive_path: &Path) -> Result<Option<EmbeddedMetadata>, FluxError> {
+    let compressed_bytes = fs::read(archive_path).await?;
+    tokio::task::spawn_blocking(move || -> Result<Option<EmbeddedMetadata>, FluxError> {
+        let mut archive = tar::Archive::new(decompressed_reader(compressed_bytes, 1)?);
+        for entry in archive.entries().map_err(|e| FluxError::Archive(e.to_string()))? {
+            let mut entry = entry.map_err(|e| FluxError::Archive(e.to_string()))?;
+            if sanitize_relative_path(&entry.path()?).is_some_and(|p| p == Path::new(EMBEDDED_METADATA_PATH)) {
+                let mut content = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut content)?;
+                return Ok(Some(serde_yaml::from_str(&content)?));
+            }
+        }
+        Ok(None)
+    }).await.unwrap()
+}
+
+// The index entry is what gets installed; disagreements are only reported.
+async fn check_embedded_metadata(info: &PackageInfo, archive_path: &Path) {
+    let metadata = match read_embedded_metadata(archive_path).await {
+        Ok(Some(metadata)) => metadata,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Warning: could not read {} in the archive of {}: {}", EMBEDDED_METADATA_PATH, info.name, e);
+            return;
+        }
+    };
+    let mismatches = embedded_metadata_mismatches(info, &metadata);
+    if !mismatches.is_empty() {
+        eprintln!("Warning: the archive of {} describes itself differently from the index: {}", info.name, mismatches.join("; "));
+    }
+}
+
+fn embedded_metadata_mismatches(info: &PackageInfo, metadata: &EmbeddedMetadata) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    if metadata.name != info.name {
+        mismatches.push(format!("name '{}' (index: '{}')", metadata.name, info.name));
+    }
+    if metadata.version != info.version {
+        mismatches.push(format!("version {} (index: {})", metadata.version, info.version));
+    }
+    let normalize = |deps: &[String]| {
+        let mut deps: Vec<String> = deps.iter().map(|d| DependencySpec::parse(d).map_or_else(|_| d.trim().to_string(), |spec| spec.to_string())).collect();
+        deps.sort();
+        deps
+    };
+    let archive_deps = normalize(&metadata.dependencies);
+    let index_deps = normalize(info.dependencies.as_deref().unwrap_or_default());
+    if archive_deps != index_deps {
+        mismatches.push(format!("dependencies [{}] (index: [{}])", archive_deps.join(", "), index_deps.join(", ")));
+    }
+    let script = |spec: Option<&ScriptSpec>| spec.and_then(|s| s.script_and_args()).map(|(script, args)| (script.to_string(), args.to_vec()));
+    if script(metadata.post_install.as_ref()) != script(info.post_install.as_ref()) {
+        mismatches.push("post_install script".to_string());
+    }
+    mismatches
+}
+
+// The entry's path, made relative. Both it and a hardlink's target must stay inside the package.
+fn checked_entry_path<R: std::io::Read>(entry: &tar::Entry<R>) -> Result<PathBuf, FluxError> {
+    let raw_path = entry.path()?.into_owned();
+    let path = sanitize_relative_path(&raw_path)
+        .ok_or_else(|| FluxError::Archive(format!("Entry '{}' escapes the extraction directory", raw_path.display())))?;
+    if entry.header().entry_type() == tar::EntryType::Link {
+        let raw_target = entry.link_name()?.map(|t| t.into_owned()).unwrap_or_default();
+        if sanitize_relative_path(&raw_target).is_none_or(|t| t.as_os_str().is_empty()) {
+            return Err(FluxError::Archive(format!("Hardlink '{}' points outside the package ('{}')", raw_path.display(), raw_target.display())));
+        }
+    }
+    Ok(path)
+}
+
+// Lists (path, is_dir) for every entry without unpacking anything, failing on entries that would
+// escape the destination so a bad archive is rejected before anything is extracted.
+async fn read_archive_entries(archive_path: &Path) -> Result<Vec<(PathBuf, bool)>, FluxError> {
+    let compressed_bytes = fs::read(archive_path).await?;
+    tokio::task::spawn_blocking(move || -> Result<Vec<(PathBuf, bool)>, FluxError> {
+        let mut archive = tar::Archive::new(decompressed_reader(compressed_bytes, 1)?);
+
+        let mut entries = Vec::new();
+        // An entry beneath a symlink from the same archive would be written wherever the link points.
+        let mut symlinks: Vec<PathBuf> = Vec::new();
+        for entry in archive.entries().map_err(|e| FluxError::Archive(e.to_string()))? {
+            let entry = entry.map_err(|e| FluxError::Archive(e.to_string()))?;
+            let path = checked_entry_path(&entry)?;
+            if let Some(link) = symlinks.iter().find(|link| path.starts_with(link) && path != **link) {
+                return Err(FluxError::Archive(format!("Entry '{}' is beneath the symlink '{}' in the same archive", path.display(), link.display())));
+            }
+            if entry.header().entry_type() == tar::EntryType::Symlink {
+                symlinks.push(path.clone());
+            }
+            if !path.as_os_str().is_empty() && !is_embedded_metadata(&path) {
+                entries.push((path, entry.header().entry_type().is_dir()));
+            }
+        }
+        Ok(entries)
+    }).await.unwrap()
+}
+
+// Reads what decoder threads send, so decompression runs alongside unpacking. Chunk i comes from
+// channel i % channels.len(), which keeps the output in order however many threads decode.
+struct ChannelReader {
+    channels: Vec<std::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>>,
+    next: usize,
+    current: std::io::Cursor<Vec<u8>>,
+}
+
+impl ChannelReader {
+    fn new(channels: Vec<std::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>>) -> Self {
+        ChannelReader { channels, next: 0, current: std::io::Cursor::new(Vec::new()) }
+    }
+}
+
+impl std::io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = std::io::Read::read(&mut self.current, buf)?;
+            if n > 0 || buf.is_empty() { return Ok(n); }
+            match self.channels[self.next].recv() {
+                Ok(chunk) => {
+                    self.current = std::io::Cursor::new(chunk?);
+                    self.next = (self.next + 1) % self.channels.len();
+                }
+                // The thread owing the next chunk hung up, so the last one has been read.
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+// The frames of a zstd stream, or None if it has fewer than two or can't be split (the streaming
+// decoder then reports what is wrong with it).
+fn zstd_frames(bytes: &[u8]) -> Option<Vec<std::ops::Range<usize>>> {
+    let mut frames = Vec::new();
+    let mut start = 0;
+    while start < bytes.len() {
+        let size = zstd::zstd_safe::find_frame_compressed_size(&bytes[start..]).ok().filter(|size| *size > 0)?;
+        frames.push(start..start + size);
+        start += size;
+    }
+    (frames.len() > 1).then_some(frames)
+}
+
+// Frame i is decoded by worker i % threads, each at most two frames ahead of the reader.
+fn decode_zstd_frames(compressed_bytes: Vec<u8>, frames: Vec<std::ops::Range<usize>>, threads: usize) -> ChannelReader {
+    let compressed_bytes = std::sync::Arc::new(compressed_bytes);
+    let frames = std::sync::Arc::new(frames);
+    let channels = (0..threads).map(|worker| {
+        let (sender, decoded) = std::sync::mpsc::sync_channel(2);
+        let (compressed_bytes, frames) = (compressed_bytes.clone(), frames.clone());
+        std::thread::spawn(move || {
+            for frame in frames.iter().skip(worker).step_by(threads) {
+                let result = zstd::stream::decode_all(&compressed_bytes[frame.clone()]);
+                let failed = result.is_err();
+                if sender.send(result).is_err() || failed { break; }
+            }
+        });
+        decoded
+    }).collect();
+    ChannelReader::new(channels)
+}
+
+// A zstd archive made of several frames (as pzstd writes them) is decoded on up to `threads`
+// threads. Anything else has to be decoded front to back, so extra threads beyond one decoder
+// running alongside unpacking add nothing.
+fn decompressed_reader(compressed_bytes: Vec<u8>, threads: usize) -> Result<Box<dyn std::io::Read + Send>, FluxError> {
+    let compression = Compression::from_magic(&compressed_bytes)
+        .ok_or_else(|| FluxError::Archive("Unrecognized archive compression (expected zstd, gzip or xz)".to_string()))?;
+    if compression == Compression::Zstd && threads > 1 {
+        if let Some(frames) = zstd_frames(&compressed_bytes) {
+            let threads = threads.min(frames.len());
+            return Ok(Box::new(decode_zstd_frames(compressed_bytes, frames, threads)));
+        }
+    }
+    let cursor = std::io::Cursor::new(compressed_bytes);
+    let decoder: Box<dyn std::io::Read + Send> = match compression {
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(cursor).map_err(|e| FluxError::Archive(e.to_string()))?),
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(cursor)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(cursor)),
+    };
+    if threads <= 1 {
+        return Ok(Box::new(decoder));
+    }
+    let (sender, chunks) = std::sync::mpsc::sync_channel(8);
+    std::thread::spawn(move || {
+        let mut decoder = decoder;
+        loop {
+            let mut chunk = vec![0; DEFAULT_IO_BUFFER_SIZE];
+            match std::io::Read::read(&mut decoder, &mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    if sender.send(Ok(chunk)).is_err() { break; }
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    break;
+                }
+            }
+        }
+    });
+    Ok(Box::new(ChannelReader::new(vec![chunks])))
+}
+
+// Hardlink entries whose target isn't in the archive itself are resolved against `live_root`,
+// where another package may already have installed the target.
+async fn extract_package(archive_path: &Path, extract_to: &Path, live_root: &Path, hardlinks: HardlinkPolicy, threads: usize) -> Result<Vec<PathBuf>, FluxError> {
+    println!("Decompressing and extracting to {}...", extract_to.display());
+    let compressed_bytes = fs::read(archive_path).await?;
+    let extract_to_owned = extract_to.to_owned();
+    let live_root = live_root.to_owned();
+
+    let extracted_files = tokio::task::spawn_blocking(move || -> Result<Vec<PathBuf>, FluxError> {
+        let mut archive = tar::Archive::new(decompressed_reader(compressed_bytes, threads)?);
+
+        let mut files = Vec::new();
+        for entry in archive.entries().map_err(|e| FluxError::Archive(e.to_string()))? {
+            let mut entry = entry.map_err(|e| FluxError::Archive(e.to_string()))?;
+            let path = checked_entry_path(&entry)?;
+            if is_embedded_metadata(&path) { continue; }
+            if entry.header().entry_type() == tar::EntryType::Link {
+                let target = sanitize_relative_path(&entry.link_name()?.unwrap_or_default()).unwrap_or_default();
+                if !extract_to_owned.join(&target).exists() {
+                    link_to_installed_file(&live_root.join(&target), &extract_to_owned.join(&path), hardlinks)?;
+                    files.push(path);
+                    continue;
+                }
+            }
+            entry.unpack_in(&extract_to_owned).map_err(|e| FluxError::Archive(e.to_string()))?;
+            if !path.as_os_str().is_empty() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }).await.unwrap()?;
+
+    println!("Extraction complete.");
+    Ok(extracted_files)
+}
+
+fn link_to_installed_file(existing: &Path, link_path: &Path, policy: HardlinkPolicy) -> Result<(), FluxError> {
+    if !std::fs::symlink_metadata(existing).is_ok_and(|m| m.is_file()) {
+        return Err(FluxError::Archive(format!("Hardlink '{}' points at '{}', which is neither in the package nor installed", link_path.display(), existing.display())));
+    }
+    if let Some(parent) = link_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match policy {
+        HardlinkPolicy::Reject => Err(FluxError::Archive(format!("Hardlink '{}' points at '{}' from another package", link_path.display(), existing.display()))),
+        // Falls back to a copy when the target is on another filesystem.
+        HardlinkPolicy::Link => std::fs::hard_link(existing, link_path).or_else(|_| std::fs::copy(existing, link_path).map(|_| ())).map_err(FluxError::from),
+        HardlinkPolicy::Copy => std::fs::copy(existing, link_path).map(|_| ()).map_err(FluxError::from),
+    }
+}
+
+// Unpacks into a private staging directory first and only then moves the files into `dest`, so a
+// broken archive never leaves partial files in the live root.
+async fn extract_package_staged(archive_path: &Path, dest: &Path, staging_root: &Path, hardlinks: HardlinkPolicy, threads: usize) -> Result<Vec<PathBuf>, FluxError> {
+    let archive_name = archive_path.file_name().unwrap_or_default().to_string_lossy();
+    let staging_dir = staging_root.join(format!("{}.{}", archive_name, process::id()));
+    let _ = fs::remove_dir_all(&staging_dir).await;
+    fs::create_dir_all(&staging_dir).await?;
+
+    let result = match extract_package(archive_path, &staging_dir, dest, hardlinks, threads).await {
+        Ok(files) => move_staged_files(&staging_dir, dest, &files).await.map(|()| files),
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_dir_all(&staging_dir).await;
+    result
+}
+
+async fn move_staged_files(staging_dir: &Path, dest: &Path, files: &[PathBuf]) -> Result<(), FluxError> {
+    println!("Moving files into {}...", dest.display());
+    let (staging_dir, dest, files) = (staging_dir.to_owned(), dest.to_owned(), files.to_vec());
+    tokio::task::spawn_blocking(move || -> Result<(), FluxError> {
+        // Check everything before touching the target, so a conflict leaves it unmodified.
+        let mut entries = Vec::with_capacity(files.len());
+        for path in &files {
+            let is_dir = std::fs::symlink_metadata(staging_dir.join(path))?.is_dir();
+            if let Ok(existing) = std::fs::symlink_metadata(dest.join(path)) {
+                if existing.is_dir() != is_dir {
+                    let (found, wanted) = if is_dir { ("file", "directory") } else { ("directory", "file") };
+                    return Err(FluxError::Archive(format!("{} is a {} but the package installs a {} there", dest.join(path).display(), found, wanted)));
+                }
+            }
+            entries.push((path, is_dir));
+        }
+
+        for (path, is_dir) in entries {
+            let target = dest.join(path);
+            if is_dir {
+                std::fs::create_dir_all(&target)?;
+            } else {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::rename(staging_dir.join(path), &target)?;
+            }
+        }
+        Ok(())
+    }).await.unwrap()
+}
+
+// Unpacks the installed version's archive into staging and moves over only the recorded files whose
+// content (or symlink target) differs from what is on disk; intact files are never rewritten.
+// Scripts and hooks don't run again.
+async fn reinstall_package(package_name: &str, options: &InstallOptions, ctx: &AppContext) -> Result<(), FluxError> {
+    let mut installed = ctx.get_installed_packages().await?;
+    let index = installed.iter().position(|p| p.name == package_name)
+        .ok_or_else(|| FluxError::PackageNotFound(format!("{} (not installed)", package_name)))?;
+    let version = installed[index].version.clone();
+    let info = ctx.indexed_version(package_name, &version)
+        .ok_or_else(|| FluxError::PackageNotFound(format!("{} {} (the installed version, needed to reinstall)", package_name, version)))?;
+
+    let archive_path = fetch_archive(info, ctx, &mut InstallTimings::default()).await?;
+
+    let live_root = ctx.get_installed_path(&installed[index]);
+    let staging_dir = ctx.target_staging_dir.join(format!("{}.reinstall.{}", info.name, process::id()));
+    let _ = fs::remove_dir_all(&staging_dir).await;
+    fs::create_dir_all(&staging_dir).await?;
+    let hardlinks = ctx.config.cross_package_hardlinks.unwrap_or_default();
+    let threads = ctx.decompress_threads();
+    let staged = extract_package(&archive_path, &staging_dir, &live_root, hardlinks, threads).await;
+
+    let recorded: HashSet<PathBuf> = installed[index].files.iter().filter_map(|f| sanitize_relative_path(&f.path)).collect();
+    let dry_run = ctx.dry_run;
+    let compared = match staged {
+        Ok(files) => {
+            let (staging_dir, live_root) = (staging_dir.clone(), live_root.clone());
+            tokio::task::spawn_blocking(move || -> Result<(Vec<FileEntry>, usize), FluxError> {
+                let mut restored = Vec::new();
+                let mut unchanged = 0;
+                for path in files.iter().filter(|path| recorded.contains(*path)) {
+                    let (staged_path, live_path) = (staging_dir.join(path), live_root.join(path));
+                    let staged_meta = std::fs::symlink_metadata(&staged_path)?;
+                    let live_meta = std::fs::symlink_metadata(&live_path).ok();
+                    if staged_meta.is_dir() {
+                        if live_meta.is_none() && !dry_run {
+                            std::fs::create_dir_all(&live_path)?;
+                        }
+                        continue;
+                    }
+                    let staged_hash = staged_meta.is_file().then(|| hash_file_sync(&staged_path)).transpose()?;
+                    let intact = match (&live_meta, &staged_hash) {
+                        (Some(meta), Some(hash)) if meta.is_file() => hash_file_sync(&live_path)? == *hash,
+                        (Some(meta), None) if meta.file_type().is_symlink() => std::fs::read_link(&live_path)? == std::fs::read_link(&staged_path)?,
+                        _ => false,
+                    };
+                    if intact {
+                        unchanged += 1;
+                        continue;
+                    }
+                    if !dry_run {
+                        // Unlinking first keeps a content-store blob from being rewritten through its hardlink.
+                        if live_meta.as_ref().is_some_and(|meta| !meta.is_dir()) {
+                            std::fs::remove_file(&live_path)?;
+                        }
+                        if let Some(parent) = live_path.parent() {
+                            std::fs::create_dir_all(parent)?;
+                        }
+                        std::fs::rename(&staged_path, &live_path)?;
+                    }
+                    restored.push(FileEntry { path: path.clone(), sha256: staged_hash });
+                }
+                Ok((restored, unchanged))
+            }).await.unwrap()
+        }
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_dir_all(&staging_dir).await;
+    let (restored, unchanged) = compared?;
+
+    if dry_run {
+        for file in &restored {
+            println!("[dry-run] would restore {}", live_root.join(&file.path).display());
+        }
+        println!("[dry-run] would restore {} file(s) of {} {} and skip {} unchanged file(s)", restored.len(), info.name, info.version, unchanged);
+        return Ok(());
+    }
+    let record = &mut installed[index];
+    for restored_file in &restored {
+        println!("Restored {}", live_root.join(&restored_file.path).display());
+        // A restored file is a copy of its own, no longer linked from the content store.
+        record.content_hashes.retain(|p, _| sanitize_relative_path(p).as_ref() != Some(&restored_file.path));
+        for file in record.files.iter_mut().filter(|f| sanitize_relative_path(&f.path).as_ref() == Some(&restored_file.path)) {
+            file.sha256 = restored_file.sha256.clone();
+        }
+    }
+    ctx.write_installed_packages(&installed).await?;
+    if !(options.keep_downloads || ctx.config.keep_downloads) {
+        fs::remove_file(&archive_path).await?;
+    }
+    println!("Reinstalled {} {}: restored {} file(s), skipped {} unchanged file(s).", info.name, info.version, restored.len(), unchanged);
+    Ok(())
+}
+
+// Returns Ok(false) on anything but an explicit yes.
+fn prompt_yes_no(question: &str) -> Result<bool, FluxError> {
+    if !std::io::stdin().is_terminal() {
+        return Err(FluxError::PromptUnavailable("stdin is not a terminal. Re-run with --yes to skip confirmations.".to_string()));
+    }
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// Asked once the affected packages are listed and before anything changes. A dry run changes nothing, so it doesn't ask.
+fn confirm_changes(ctx: &AppContext) -> Result<bool, FluxError> {
+    Ok(ctx.assume_yes || ctx.dry_run || prompt_yes_no("Continue?")?)
+}
+
+fn hash_file_sync(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Config files get edited in place, and an edit to a hardlinked file changes every copy, so they
+// stay out of the store.
+fn kept_out_of_content_store(file: &Path) -> bool {
+    file.starts_with("etc")
+}
+
+// Records every extracted path with the hash of regular files, reusing hashes the content store already computed.
+async fn hash_installed_files(root: &Path, files: Vec<PathBuf>, known: &BTreeMap<PathBuf, String>) -> Result<Vec<FileEntry>, FluxError> {
+    let (root, known) = (root.to_owned(), known.clone());
+    tokio::task::spawn_blocking(move || -> Result<Vec<FileEntry>, FluxError> {
+        files.into_iter().map(|path| {
+            let sha256 = match known.get(&path) {
+                Some(hash) => Some(hash.clone()),
+                None if std::fs::symlink_metadata(root.join(&path))?.is_file() => Some(hash_file_sync(&root.join(&path))?),
+                None => None,
+            };
+            Ok(FileEntry { path, sha256 })
+        }).collect()
+    }).await.unwrap()
+}
+
+// Replaces each regular file with a hardlink to its blob in the store, adding new blobs as needed.
+// Hardlinks share mode and owner as well as contents, so a file whose mode or owner differs from
+// the blob's keeps its own copy.
+async fn link_into_content_store(root: &Path, store_dir: &Path, files: &[PathBuf]) -> Result<BTreeMap<PathBuf, String>, FluxError> {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::create_dir_all(store_dir).await?;
+    let root = root.to_owned();
+    let store_dir = store_dir.to_owned();
+    let files = files.to_vec();
+
+    tokio::task::spawn_blocking(move || -> Result<BTreeMap<PathBuf, String>, FluxError> {
+        let mut hashes = BTreeMap::new();
+        for file in files {
+            let full_path = root.join(&file);
+            let metadata = std::fs::symlink_metadata(&full_path)?;
+            if !metadata.file_type().is_file() || kept_out_of_content_store(&file) { continue; }
+            let hash = hash_file_sync(&full_path)?;
+            let blob_path = store_dir.join(&hash);
+            match std::fs::symlink_metadata(&blob_path) {
+                Ok(blob) if (blob.mode(), blob.uid(), blob.gid()) == (metadata.mode(), metadata.uid(), metadata.gid()) => {
+                    std::fs::remove_file(&full_path)?;
+                    std::fs::hard_link(&blob_path, &full_path)?;
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::hard_link(&full_path, &blob_path)?,
+                Err(e) => return Err(e.into()),
+            }
+            hashes.insert(file, hash);
+        }
+        Ok(hashes)
+    }).await.unwrap()
+}
+
+// Deletes the blobs of a removed package that no remaining package references.
+async fn release_content_blobs(removed: &InstalledPackageInfo, remaining: &[&InstalledPackageInfo], store_dir: &Path) -> Result<(), FluxError> {
+    let still_referenced: HashSet<&String> = remaining.iter().flat_map(|p| p.content_hashes.values()).collect();
+    for hash in removed.content_hashes.values() {
+        let blob_path = store_dir.join(hash);
+        if !still_referenced.contains(hash) && blob_path.exists() {
+            fs::remove_file(&blob_path).await?;
+        }
+    }
+    Ok(())
+}
+
+const SANDBOX_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// Restricts a script to a scrubbed environment, `work_dir` as cwd and home, no stdin, no
+// inherited descriptors, tight rlimits and (when running as root on Linux) no network.
+fn configure_sandbox(command: &mut process::Command, work_dir: &Path) -> Result<(), FluxError> {
+    if !work_dir.is_dir() {
+        return Err(FluxError::SandboxSetup(format!("working directory {} does not exist", work_dir.display())));
+    }
+    command.env_clear()
+        .env("PATH", SANDBOX_PATH)
+        .env("HOME", work_dir)
+        .env("LANG", "C")
+        .current_dir(work_dir)
+        .stdin(process::Stdio::null());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: the closure only makes async-signal-safe libc calls between fork and exec.
+        unsafe {
+            command.pre_exec(|| {
+                let no_core = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+                let few_files = libc::rlimit { rlim_cur: 256, rlim_max: 256 };
+                if libc::setrlimit(libc::RLIMIT_CORE, &no_core) != 0 || libc::setrlimit(libc::RLIMIT_NOFILE, &few_files) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                for fd in 3..1024 {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+                #[cfg(target_os = "linux")]
+                if libc::geteuid() == 0 && libc::unshare(libc::CLONE_NEWNET) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    Ok(())
+}
+
+fn run_script(script_path: &Path, args: &[String], package_name: &str, sandbox: Option<&Path>, error_type: fn(String, String, String) -> FluxError) -> Result<(), FluxError> {
+    let mut command = process::Command::new("sh");
+    command.arg(script_path).args(args);
+    if let Some(work_dir) = sandbox {
+        configure_sandbox(&mut command, work_dir)?;
+    }
+    let output = command.output().map_err(|e| error_type(package_name.to_string(), script_path.to_string_lossy().to_string(), e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(error_type(package_name.to_string(), script_path.to_string_lossy().to_string(), stderr.to_string()));
+    }
+    Ok(())
+}
+
+fn run_post_install(info: &PackageInfo, install_path: &Path, ctx: &AppContext) -> Result<(), FluxError> {
+    if let Some(spec) = &info.post_install {
+        let (script_name, args) = spec.script_and_args().ok_or_else(|| FluxError::PostInstallScriptFailed {
+            package_name: info.name.clone(),
+            message: "post_install is an empty list; it must start with the script to run".to_string(),
+        })?;
+        let script_path = install_path.join(script_name);
+        if script_path.exists() {
+            let sandbox = (ctx.config.sandbox_scripts && !info.privileged_scripts).then_some(install_path);
+            run_script(&script_path, args, &info.name, sandbox, |pkg, _, msg| FluxError::PostInstallScriptFailed { package_name: pkg, message: msg })?;
+        }
+    }
+    Ok(())
+}
+
+// Shell-style matching of `*`, `?` and `[...]` classes (with `!` or `^` negation) against a whole name.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+        let negated = matches!(class.first(), Some('!') | Some('^'));
+        let mut i = usize::from(negated);
+        let mut matched = false;
+        let mut first = true;
+        while i < class.len() {
+            if class[i] == ']' && !first {
+                return Some((matched != negated, i + 1));
+            }
+            if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+                matched |= class[i] <= c && c <= class[i + 2];
+                i += 3;
+            } else {
+                matched |= class[i] == c;
+                i += 1;
+            }
+            first = false;
+        }
+        None
+    }
+
+    fn matches(p: &[char], n: &[char]) -> bool {
+        match p.first() {
+            None => n.is_empty(),
+            Some('*') => (0..=n.len()).any(|skip| matches(&p[1..], &n[skip..])),
+            Some('?') => !n.is_empty() && matches(&p[1..], &n[1..]),
+            Some('[') => match (n.first(), match_class(&p[1..], *n.first().unwrap_or(&'\0'))) {
+                (Some(_), Some((true, len))) => matches(&p[1 + len..], &n[1..]),
+                (Some(_), Some((false, _))) | (None, _) => false,
+                // An unterminated class is a literal '['.
+                (Some(c), None) => *c == '[' && matches(&p[1..], &n[1..]),
+            },
+            Some(c) => n.first() == Some(c) && matches(&p[1..], &n[1..]),
+        }
+    }
+
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    matches(&p, &n)
+}
+
+fn is_glob_pattern(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+// Queues a hook to run once at the end of a batch, remembering which packages triggered it.
+fn defer_hook(deferred_hooks: &mut Vec<(String, Vec<String>)>, hook_script: String, package_name: &str) {
+    match deferred_hooks.iter_mut().find(|(script, _)| *script == hook_script) {
+        Some((_, triggered_by)) => triggered_by.push(package_name.to_string()),
+        None => deferred_hooks.push((hook_script, vec![package_name.to_string()])),
+    }
+}
+
+fn run_deferred_hooks(deferred_hooks: &[(String, Vec<String>)], ctx: &AppContext) -> Result<(), FluxError> {
+    for (hook_script, triggered_by) in deferred_hooks {
+        println!("Running hook {} (triggered by: {})", hook_script, triggered_by.join(", "));
+        ctx.run_hook(hook_script, &triggered_by.join(", "))?;
+    }
+    Ok(())
+}
+
+// A package missing from the index is most often just a stale cache, so refresh it once and retry.
+async fn handle_install(package_name: &str, options: &InstallOptions, ctx: &mut AppContext) -> Result<(), FluxError> {
+    if options.reinstall {
+        return reinstall_package(package_name, options, ctx).await;
+    }
+    let retry = !options.no_auto_update && !options.print_plan_json && !ctx.downloader.offline && !ctx.dry_run;
+    select_satisfying_versions(&matching_names(package_name, ctx), ctx);
+    match install_matching(package_name, options, ctx).await {
+        Err(FluxError::PackageNotFound(missing) | FluxError::NoPackagesMatch(missing)) if retry => {
+            println!("'{}' is not in the local index, refreshing it...", missing);
+            handle_update(false, ctx).await?;
+            select_satisfying_versions(&matching_names(package_name, ctx), ctx);
+            install_matching(package_name, options, ctx).await
+        }
+        Err(FluxError::MissingDependencies(missing)) if retry => {
+            let names: Vec<&str> = missing.iter().map(|m| m.name.as_str()).collect();
+            println!("Dependencies {} are not in the local index, refreshing it...", names.join(", "));
+            handle_update(false, ctx).await?;
+            select_satisfying_versions(&matching_names(package_name, ctx), ctx);
+            install_matching(package_name, options, ctx).await
+        }
+        result => result,
+    }
+}
+
+// What an install argument names: the package itself, or every package a glob pattern matches.
+fn matching_names(package_name: &str, ctx: &AppContext) -> Vec<String> {
+    if !is_glob_pattern(package_name) || ctx.package_index.contains_key(package_name) {
+        return vec![package_name.to_string()];
+    }
+    let mut matching: Vec<String> = ctx.package_index.keys().filter(|name| glob_match(package_name, name)).cloned().collect();
+    matching.sort();
+    matching
+}
+
+async fn install_matching(package_name: &str, options: &InstallOptions, ctx: &AppContext) -> Result<(), FluxError> {
+    if !is_glob_pattern(package_name) || ctx.package_index.contains_key(package_name) {
+        return install_packages(&[package_name.to_string()], options, ctx).await;
+    }
+
+    let matching = matching_names(package_name, ctx);
+    if matching.is_empty() {
+        return Err(FluxError::NoPackagesMatch(package_name.to_string()));
+    }
+
+    if options.print_plan_json {
+        return install_packages(&matching, options, ctx).await;
+    }
+
+    println!("'{}' matches {} package(s):", package_name, matching.len());
+    for name in &matching {
+        println!("- {}", name);
+    }
+    if !ctx.assume_yes && !prompt_yes_no("Install all of them?")? {
+        println!("Aborted.");
+        return Ok(());
+    }
+    install_packages(&matching, options, ctx).await
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PlanAction {
+    Install,
+    Skip,
+}
+
+#[derive(Serialize)]
+struct PlannedPackage {
+    name: String,
+    version: String,
+    action: PlanAction,
+    url: String,
+    size: Option<u64>,
+    // Why the package is part of the plan: requested, a dependency, or a recommendation.
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct InstallPlan {
+    requested: Vec<String>,
+    packages: Vec<PlannedPackage>,
+}
+
+// Resolves what an install of `requested` would do, in installation order. Both the real install
+// and `--print-plan-json` go through here.
+fn plan_install(requested: &[String], options: &InstallOptions, installed: &[InstalledPackageInfo], ctx: &AppContext) -> Result<InstallPlan, FluxError> {
+    let mut to_install_names = Vec::new();
+    let mut requirements = Requirements::new();
+    for package_name in requested {
+        resolve_dependencies(package_name, ctx, &mut to_install_names, &mut requirements)?;
+    }
+
+    let new_names: Vec<&String> = to_install_names.iter().filter(|name| !installed.iter().any(|p| p.name == **name)).collect();
+    for name in new_names {
+        if let Some(other) = find_conflict(name, &to_install_names, installed, ctx)? {
+            return Err(FluxError::PackageConflict { package_name: name.clone(), conflicts_with: other });
+        }
+    }
+
+    // Optionals are tried in priority order (requested package order, then list order) and each one
+    // that would conflict with what is already selected is dropped instead of failing the install.
+    let mut recommended_by = HashMap::new();
+    for package_name in requested.iter().filter(|_| options.recommended) {
+        let info = ctx.package_index.get(package_name).ok_or_else(|| FluxError::PackageNotFound(package_name.to_string()))?;
+        for optional in info.optional_dependencies.iter().flatten().map(|o| DependencySpec::name_of(o)) {
+            if !ctx.package_index.contains_key(optional) {
+                eprintln!("Warning: recommended package '{}' is not in the repository, skipping.", optional);
+                continue;
+            }
+            let mut trial_names = to_install_names.clone();
+            let mut trial_requirements = requirements.clone();
+            resolve_dependencies(optional, ctx, &mut trial_names, &mut trial_requirements)?;
+            let mut conflict = None;
+            for name in trial_names.iter().filter(|name| !to_install_names.contains(name)) {
+                if let Some(other) = find_conflict(name, &trial_names, installed, ctx)? {
+                    conflict = Some((name.clone(), other));
+                    break;
+                }
+            }
+            if let Some((name, other)) = conflict {
+                let via = if name == optional { String::new() } else { format!(" (via its dependency {})", name) };
+                eprintln!("Note: skipping recommended package '{}'{}: it conflicts with '{}'.", optional, via, other);
+                continue;
+            }
+            if !to_install_names.iter().any(|name| name == optional) {
+                recommended_by.insert(optional.to_string(), package_name.clone());
+            }
+            to_install_names = trial_names;
+            requirements = trial_requirements;
+        }
+    }
+    check_requirements(&requirements, ctx)?;
+    if options.recheck_existing {
+        check_installed_requirements(&to_install_names, &requirements, installed)?;
+    }
+
+    let mut packages = Vec::new();
+    for name in &to_install_names {
+        let info = &ctx.package_index[name];
+        let reason = if requested.contains(name) && options.dependency_only {
+            "requested, dependencies only".to_string()
+        } else if requested.contains(name) {
+            "requested".to_string()
+        } else if let Some(parent) = recommended_by.get(name) {
+            format!("recommended by {}", parent)
+        } else {
+            let parent = to_install_names.iter().find(|other| ctx.package_index[*other].dependency_names().any(|dep| dep == name));
+            format!("dependency of {}", parent.map_or("a requested package", |p| p.as_str()))
+        };
+        let skipped = installed.iter().any(|p| p.name == *name) || (options.dependency_only && requested.contains(name));
+        let action = if skipped { PlanAction::Skip } else { PlanAction::Install };
+        packages.push(PlannedPackage {
+            name: name.clone(),
+            version: info.version.clone(),
+            action,
+            url: ctx.package_url(&info.url)?.to_string(),
+            size: info.size,
+            reason,
+        });
+    }
+    Ok(InstallPlan { requested: requested.to_vec(), packages })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum InstallPhase {
+    Download,
+    Verify,
+    Extract,
+    Scripts,
+}
+
+impl InstallPhase {
+    const ALL: [InstallPhase; 4] = [Self::Download, Self::Verify, Self::Extract, Self::Scripts];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Download => "download",
+            Self::Verify => "verify",
+            Self::Extract => "extract",
+            Self::Scripts => "scripts",
+        }
+    }
+}
+
+// Wall-clock time per package and phase, reported by `install --time`.
+#[derive(Default)]
+struct InstallTimings {
+    resolution: std::time::Duration,
+    // Package (or "(batch hooks)") -> time per phase, in install order.
+    packages: Vec<(String, [std::time::Duration; 4])>,
+}
+
+impl InstallTimings {
+    fn record(&mut self, package: &str, phase: InstallPhase, elapsed: std::time::Duration) {
+        let index = InstallPhase::ALL.iter().position(|p| *p == phase).expect("every phase is listed");
+        match self.packages.iter_mut().find(|(name, _)| name == package) {
+            Some((_, phases)) => phases[index] += elapsed,
+            None => {
+                let mut phases = [std::time::Duration::ZERO; 4];
+                phases[index] = elapsed;
+                self.packages.push((package.to_string(), phases));
+            }
+        }
+    }
+
+    fn merge(&mut self, other: InstallTimings) {
+        for (package, phases) in other.packages {
+            for (phase, elapsed) in InstallPhase::ALL.into_iter().zip(phases) {
+                self.record(&package, phase, elapsed);
+            }
+        }
+    }
+
+    fn report(&self) -> String {
+        use std::fmt::Write as _;
+        let mut out = String::new();
+        let secs = |d: std::time::Duration| format!("{:.3}s", d.as_secs_f64());
+        let width = self.packages.iter().map(|(name, _)| name.len()).chain(["resolution".len()]).max().unwrap_or_default();
+        let _ = writeln!(out, "\nTiming:");
+        let _ = write!(out, "  {:<width$}", "package");
+        for phase in InstallPhase::ALL {
+            let _ = write!(out, " {:>10}", phase.label());
+        }
+        let _ = writeln!(out, " {:>10}", "total");
+
+        let mut totals = [std::time::Duration::ZERO; 4];
+        for (name, phases) in &self.packages {
+            let _ = write!(out, "  {:<width$}", name);
+            for (total, elapsed) in totals.iter_mut().zip(phases) {
+                *total += *elapsed;
+                let _ = write!(out, " {:>10}", secs(*elapsed));
+            }
+            let _ = writeln!(out, " {:>10}", secs(phases.iter().sum()));
+        }
+        let _ = writeln!(out, "  {:<width$} {:>10}", "resolution", secs(self.resolution));
+        let _ = write!(out, "  {:<width$}", "total");
+        for total in totals {
+            let _ = write!(out, " {:>10}", secs(total));
+        }
+        let _ = writeln!(out, " {:>10}", secs(totals.iter().sum::<std::time::Duration>() + self.resolution));
+        out
+    }
+}
+
+// System packages share the target root, so no two of them may ship the same non-directory path,
+// whether one is already installed or both are in this batch.
+struct FileClaim {
+    path: PathBuf,
+    owning_package: String,
+    incoming_package: String,
+}
+
+async fn check_file_conflicts(batch: &[PackageInfo], installed: &[InstalledPackageInfo], ctx: &AppContext) -> Result<Vec<FileClaim>, FluxError> {
+    let mut owners: HashMap<PathBuf, &str> = HashMap::new();
+    for pkg in installed.iter().filter(|p| p.package_type == PackageType::System) {
+        for file in &pkg.files {
+            if let Some(path) = sanitize_relative_path(&file.path).filter(|p| !p.as_os_str().is_empty()) {
+                owners.insert(path, &pkg.name);
+            }
+        }
+    }
+
+    let mut conflicts = Vec::new();
+    for info in batch {
+        let archive_path = ctx.host_cache_dir.join(archive_file_name(info));
+        // Reading the entries also rejects archives with paths escaping the destination.
+        let entries = read_archive_entries(&archive_path).await?;
+        if info.package_type != PackageType::System { continue; }
+        for (path, is_dir) in entries {
+            let Some(path) = sanitize_relative_path(&path).filter(|p| !p.as_os_str().is_empty()) else { continue };
+            if is_dir { continue; }
+            match owners.get(&path) {
+                // Database records list directories too; sharing those is fine.
+                Some(_) if fs::symlink_metadata(ctx.target_root.join(&path)).await.is_ok_and(|m| m.is_dir()) => {}
+                Some(owner) if *owner != info.name => {
+                    conflicts.push(FileClaim { path, owning_package: owner.to_string(), incoming_package: info.name.clone() });
+                }
+                _ => { owners.insert(path, &info.name); }
+            }
+        }
+    }
+    Ok(conflicts)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Runs before extraction, so the incoming copy doesn't simply replace the installed file.
+fn set_aside_conflicting_files(claims: &[&FileClaim], policy: FileConflictPolicy, ctx: &AppContext) -> Result<(), FluxError> {
+    for claim in claims {
+        let full_path = ctx.target_root.join(&claim.path);
+        match policy {
+            FileConflictPolicy::Error => {}
+            FileConflictPolicy::Skip | FileConflictPolicy::BackupBoth => std::fs::rename(&full_path, with_suffix(&full_path, ".flux-aside"))?,
+            FileConflictPolicy::Overwrite => {
+                let backup = with_suffix(&full_path, ".flux-old");
+                std::fs::rename(&full_path, &backup)?;
+                println!("Replacing {} of '{}' with the one from '{}'; the old file is kept as {}", full_path.display(), claim.owning_package, claim.incoming_package, backup.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Runs after extraction and adjusts `extracted_files`, which become the incoming package's record.
+fn settle_conflicting_files(claims: &[&FileClaim], policy: FileConflictPolicy, extracted_files: &mut Vec<PathBuf>, ctx: &AppContext) -> Result<(), FluxError> {
+    for claim in claims {
+        let full_path = ctx.target_root.join(&claim.path);
+        let aside = with_suffix(&full_path, ".flux-aside");
+        match policy {
+            FileConflictPolicy::Error | FileConflictPolicy::Overwrite => {}
+            FileConflictPolicy::Skip => {
+                std::fs::rename(&aside, &full_path)?;
+                extracted_files.retain(|path| *path != claim.path);
+                println!("Keeping {} of '{}'; '{}' does not install its copy", full_path.display(), claim.owning_package, claim.incoming_package);
+            }
+            FileConflictPolicy::BackupBoth => {
+                let incoming = with_suffix(&full_path, ".flux-new");
+                std::fs::rename(&full_path, &incoming)?;
+                std::fs::rename(&aside, &full_path)?;
+                for path in extracted_files.iter_mut().filter(|path| **path == claim.path) {
+                    *path = with_suffix(path, ".flux-new");
+                }
+                println!("Keeping {} of '{}'; the copy from '{}' is installed as {}", full_path.display(), claim.owning_package, claim.incoming_package, incoming.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Overwritten files now belong to the incoming package only, once that package is recorded.
+fn disown_files(packages: &mut [InstalledPackageInfo], claims: &[FileClaim]) {
+    for claim in claims {
+        if !packages.iter().any(|p| p.name == claim.incoming_package) { continue; }
+        for pkg in packages.iter_mut().filter(|p| p.name == claim.owning_package) {
+            pkg.files.retain(|f| sanitize_relative_path(&f.path).as_ref() != Some(&claim.path));
+            pkg.content_hashes.retain(|path, _| sanitize_relative_path(path).as_ref() != Some(&claim.path));
+        }
+    }
+}
+
+// File conflicts and script outcomes can't be known without downloading, so they aren't checked.
+fn print_dry_run_install(packages: &[PackageInfo], ctx: &AppContext) -> Result<(), FluxError> {
+    for info in packages {
+        println!("[dry-run] would download {} {} from {}", info.name, info.version, ctx.package_url(&info.url)?);
+        println!("[dry-run] would extract {} into {}", info.name, ctx.get_install_path(info).display());
+        if let Some((script, _)) = info.post_install.as_ref().and_then(|spec| spec.script_and_args()) {
+            println!("[dry-run] would run post-install script {} of {}", script, info.name);
+        }
+        for hook_script in ctx.matching_hooks(&info.name, &info.package_type, HookPhase::Install) {
+            ctx.run_hook(&hook_script, &info.name)?;
+        }
+    }
+    println!("[dry-run] would record {} package(s) in the package database", packages.len());
+    Ok(())
+}
+
+// Undoes the extractions recorded in the install journal, last package first, and puts back files
+// that were set aside for file conflicts. Post-install scripts that already ran are not undone.
+async fn roll_back_install_batch(conflicts: &[FileClaim], linked_blobs: &BTreeSet<String>, installed: &[InstalledPackageInfo], ctx: &AppContext) -> Result<(), FluxError> {
+    let journal = ctx.read_install_journal().await?;
+    let names: Vec<String> = journal.iter().map(|e| e.name.clone()).collect();
+    println!("Rolling back the install of {}...", names.join(", "));
+    for entry in journal.iter().rev() {
+        remove_partial_install(entry).await?;
+    }
+    // Blobs the batch linked that no installed package of any profile shares were created by it,
+    // so go with it.
+    let other_profiles = ctx.packages_in_other_profiles().await?;
+    let still_referenced: HashSet<&String> = installed.iter().chain(&other_profiles).flat_map(|p| p.content_hashes.values()).collect();
+    for hash in linked_blobs.iter().filter(|hash| !still_referenced.contains(hash)) {
+        let blob_path = ctx.target_store_dir.join(hash);
+        if blob_path.exists() {
+            fs::remove_file(&blob_path).await?;
+        }
+    }
+    for claim in conflicts.iter().filter(|c| names.contains(&c.incoming_package)) {
+        let full_path = ctx.target_root.join(&claim.path);
+        let _ = fs::remove_file(with_suffix(&full_path, ".flux-new")).await;
+        for suffix in [".flux-aside", ".flux-old"] {
+            let set_aside = with_suffix(&full_path, suffix);
+            if fs::symlink_metadata(&set_aside).await.is_ok() {
+                fs::rename(&set_aside, &full_path).await?;
+            }
+        }
+    }
+    ctx.clear_install_journal().await
+}
+
+async fn install_packages(requested: &[String], options: &InstallOptions, ctx: &AppContext) -> Result<(), FluxError> {
+    let mut timings = InstallTimings::default();
+    install_packages_timed(requested, options, ctx, &mut timings).await?;
+    if options.time {
+        print!("{}", timings.report());
+    }
+    Ok(())
+}
+
+async fn install_packages_timed(requested: &[String], options: &InstallOptions, ctx: &AppContext, timings: &mut InstallTimings) -> Result<(), FluxError> {
+    let installed_packages = ctx.get_installed_packages().await?;
+    let started = std::time::Instant::now();
+    let plan = plan_install(requested, options, &installed_packages, ctx)?;
+    timings.resolution = started.elapsed();
+    if options.print_plan_json {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+        return Ok(());
+    }
+
+    if options.recheck_existing {
+        let existing: Vec<&InstalledPackageInfo> = installed_packages.iter()
+            .filter(|pkg| plan.packages.iter().any(|planned| planned.name == pkg.name))
+            .collect();
+        let mut broken: Vec<String> = check_installed_files(&existing, true, ctx).await.into_iter()
+            .filter(|(_, _, problem)| problem.is_some())
+            .map(|(name, _, _)| name)
+            .collect();
+        broken.dedup();
+        for name in &broken {
+            println!("{} has missing or modified files, restoring them...", name);
+            reinstall_package(name, options, ctx).await?;
+        }
+    }
+
+    let packages_to_process: Vec<PackageInfo> = plan.packages.iter()
+        .filter(|planned| planned.action == PlanAction::Install)
+        .map(|planned| ctx.package_index[&planned.name].clone())
+        .collect();
+
+    if packages_to_process.is_empty() && options.dependency_only {
+        println!("All dependencies of '{}' are already installed.", requested.join("', '"));
+        return Ok(());
+    }
+    if packages_to_process.is_empty() {
+        println!("Package '{}' and all its dependencies are already installed.", requested.join("', '"));
+        return Ok(());
+    }
+    for planned in plan.packages.iter().filter(|p| p.action == PlanAction::Install && p.reason.starts_with("recommended")) {
+        println!("Adding recommended package: {}", planned.name);
+    }
+    if ctx.dry_run {
+        print_dry_run_install(&packages_to_process, ctx)?;
+        return Ok(());
+    }
+
+    let policy = options.on_script_failure.or(ctx.config.on_script_failure).unwrap_or_default();
+    let mut new_install_records = Vec::new();
+    // Hook script -> packages that triggered it, in first-seen order.
+    let mut deferred_hooks: Vec<(String, Vec<String>)> = Vec::new();
+
+    // Everything is downloaded first, up to --jobs at a time, so file conflicts across the whole
+    // batch are found before anything is extracted. Extraction then follows dependency order.
+    let mut downloads = futures_util::stream::iter(&packages_to_process)
+        .map(|info| async move {
+            if let Some(arch) = info.arch.as_ref().filter(|arch| **arch != ctx.accepted_arches[0]) {
+                println!("Note: no {} build of {} is available, installing the compatible {} build.", ctx.accepted_arches[0], info.name, arch);
+            }
+            if ctx.no_verify {
+                eprintln!("WARNING: --no-verify is set, {} is installed WITHOUT checksum verification. Only use this for local development.", info.name);
+            }
+            let mut timings = InstallTimings::default();
+            let was_cached = ctx.host_cache_dir.join(archive_file_name(info)).exists();
+            let archive_path = fetch_archive(info, ctx, &mut timings).await?;
+            check_embedded_metadata(info, &archive_path).await;
+            Ok::<_, FluxError>((info.name.as_str(), timings, (!was_cached).then_some(archive_path)))
+        })
+        .buffer_unordered(ctx.jobs);
+    let mut download_timings = HashMap::new();
+    let mut downloaded = Vec::new();
+    while let Some(result) = downloads.next().await {
+        let (name, package_timings, archive_path) = result?;
+        download_timings.insert(name, package_timings);
+        downloaded.extend(archive_path);
+    }
+    for info in &packages_to_process {
+        if let Some(package_timings) = download_timings.remove(info.name.as_str()) {
+            timings.merge(package_timings);
+        }
+    }
+    let file_policy = options.on_file_conflict.or(ctx.config.on_file_conflict).unwrap_or_default();
+    let conflicts = check_file_conflicts(&packages_to_process, &installed_packages, ctx).await.and_then(|conflicts| match conflicts.first() {
+        Some(first) if file_policy == FileConflictPolicy::Error => Err(FluxError::FileConflict {
+            path: Path::new("/").join(&first.path),
+            owning_package: first.owning_package.clone(),
+            incoming_package: first.incoming_package.clone(),
+        }),
+        _ => Ok(conflicts),
+    });
+    let conflicts = match conflicts {
+        Ok(conflicts) => conflicts,
+        Err(e) => {
+            discard_prefetched(&downloaded, options.keep_downloads, ctx).await;
+            return Err(e);
+        }
+    };
+
+    // The batch is all-or-nothing: if any package fails, every package extracted so far is rolled
+    // back (abort-keep excepted), so the database never lags behind what is on disk.
+    let mut kept = false;
+    let mut linked_blobs = BTreeSet::new();
+    let batch = async {
+        for info in &packages_to_process {
+            let install_path = ctx.get_install_path(info);
+            fs::create_dir_all(&install_path).await?;
+            let archive_path = ctx.host_cache_dir.join(archive_file_name(info));
+            ctx.journal_install(info, &install_path, &archive_path).await?;
+            let started = std::time::Instant::now();
+            let hardlinks = ctx.config.cross_package_hardlinks.unwrap_or_default();
+            let threads = ctx.decompress_threads();
+            let claims: Vec<&FileClaim> = conflicts.iter().filter(|c| c.incoming_package == info.name).collect();
+            set_aside_conflicting_files(&claims, file_policy, ctx)?;
+            let mut extracted_files = if ctx.config.extract_in_place {
+                extract_package(&archive_path, &install_path, &install_path, hardlinks, threads).await?
+            } else {
+                extract_package_staged(&archive_path, &install_path, &ctx.target_staging_dir, hardlinks, threads).await?
+            };
+            settle_conflicting_files(&claims, file_policy, &mut extracted_files, ctx)?;
+            if !(options.keep_downloads || ctx.config.keep_downloads) {
+                fs::remove_file(&archive_path).await?;
+            }
+            let mut content_hashes = BTreeMap::new();
+            if ctx.config.content_store && info.package_type == PackageType::System {
+                content_hashes = link_into_content_store(&install_path, &ctx.target_store_dir, &extracted_files).await?;
+                linked_blobs.extend(content_hashes.values().cloned());
+            }
+
+            // With --dependency-only the direct dependencies stand in for the requested packages.
+            let explicit = !options.as_dependency && if options.dependency_only {
+                requested.iter().any(|r| ctx.package_index[r].dependency_names().any(|dep| dep == info.name))
+            } else {
+                requested.contains(&info.name)
+            };
+            let reason = if explicit { InstallReason::Explicit } else { InstallReason::Dependency };
+            let record = InstalledPackageInfo {
+                name: info.name.clone(),
+                version: info.version.clone(),
+                package_type: info.package_type.clone(),
+                files: hash_installed_files(&install_path, extracted_files, &content_hashes).await?,
+                install_reason: reason,
+                installed_at: Some(unix_now()),
+                content_hashes,
+                remove_scripts: RemoveScripts::from_info(info),
+            };
+            timings.record(&info.name, InstallPhase::Extract, started.elapsed());
+
+            let started = std::time::Instant::now();
+            let post_install = run_post_install(info, &install_path, ctx);
+            timings.record(&info.name, InstallPhase::Scripts, started.elapsed());
+            if let Err(e) = post_install {
+                if policy == ScriptFailurePolicy::WarnContinue {
+                    eprintln!("Warning: {}", e);
+                } else if policy == ScriptFailurePolicy::AbortKeep {
+                    let mut all_installed = installed_packages.clone();
+                    all_installed.append(&mut new_install_records);
+                    println!("Keeping the files of {} and recording it as installed.", info.name);
+                    all_installed.push(record);
+                    if file_policy == FileConflictPolicy::Overwrite {
+                        disown_files(&mut all_installed, &conflicts);
+                    }
+                    ctx.write_installed_packages(&all_installed).await?;
+                    ctx.clear_install_journal().await?;
+                    kept = true;
+                    return Err(e);
+                } else {
+                    return Err(e);
+                }
+            }
+
+            let started = std::time::Instant::now();
+            for hook_script in ctx.matching_hooks(&info.name, &info.package_type, HookPhase::Install) {
+                if ctx.config.batch_hooks {
+                    defer_hook(&mut deferred_hooks, hook_script, &info.name);
+                } else {
+                    ctx.run_hook(&hook_script, &info.name)?;
+                }
+            }
+            timings.record(&info.name, InstallPhase::Scripts, started.elapsed());
+
+            new_install_records.push(record);
+        }
+
+        if !deferred_hooks.is_empty() {
+            let started = std::time::Instant::now();
+            run_deferred_hooks(&deferred_hooks, ctx)?;
+            timings.record("(batch hooks)", InstallPhase::Scripts, started.elapsed());
+        }
+        Ok::<(), FluxError>(())
+    }.await;
+    if let Err(e) = batch {
+        if !kept {
+            roll_back_install_batch(&conflicts, &linked_blobs, &installed_packages, ctx).await?;
+        }
+        return Err(e);
+    }
+
+    let mut all_installed = installed_packages;
+    all_installed.extend(new_install_records);
+    if file_policy == FileConflictPolicy::Overwrite {
+        disown_files(&mut all_installed, &conflicts);
+    }
+
+    ctx.write_installed_packages(&all_installed).await?;
+    ctx.clear_install_journal().await?;
+    println!("Package database updated.");
+    if options.dependency_only {
+        println!("Installed the dependencies only; '{}' itself was not installed.", requested.join("', '"));
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Requirement {
+    required_by: String,
+    constraint: VersionConstraint,
+}
+
+// Version requirements collected while walking the closure, keyed by dependency name.
+type Requirements = HashMap<String, Vec<Requirement>>;
+
+#[derive(Debug)]
+struct MissingDependency {
+    name: String,
+    required_by: Vec<String>,
+}
+
+fn describe_missing_dependencies(missing: &[MissingDependency]) -> String {
+    missing.iter().map(|m| format!("{} (required by {})", m.name, m.required_by.join(", "))).collect::<Vec<_>>().join(", ")
+}
+
+// Collects the closure of `pkg_name` into `resolved`, dependencies before their dependents. The
+// whole closure is walked before failing, so every missing dependency is reported at once.
+fn resolve_dependencies<'a>(pkg_name: &'a str, ctx: &'a AppContext, resolved: &mut Vec<String>, requirements: &mut Requirements) -> Result<(), FluxError> {
+    if !ctx.package_index.contains_key(pkg_name) {
+        return Err(FluxError::PackageNotFound(pkg_name.to_string()));
+    }
+    let mut missing = Vec::new();
+    collect_dependencies(pkg_name, ctx, resolved, requirements, &mut missing)?;
+    if !missing.is_empty() {
+        return Err(FluxError::MissingDependencies(missing));
+    }
+    Ok(())
+}
+
+// Installs use the newest version of each package unless what is being installed requires an older
+// one; then the highest version satisfying every requirement on it is pinned for this run. The
+// closure is resolved again after each change, since the pinned version has dependencies of its own.
+fn select_satisfying_versions(requested: &[String], ctx: &mut AppContext) {
+    let max_rounds = ctx.package_versions.values().map(Vec::len).sum::<usize>();
+    for _ in 0..max_rounds {
+        let mut names = Vec::new();
+        let mut requirements = Requirements::new();
+        for name in requested {
+            // Resolution errors are reported by the install itself.
+            if resolve_dependencies(name, ctx, &mut names, &mut requirements).is_err() { return; }
+        }
+
+        let mut pins = Vec::new();
+        for (name, set) in &requirements {
+            let satisfies = |info: &PackageInfo| set.iter().all(|r| r.constraint.matches(&info.version));
+            if satisfies(&ctx.package_index[name]) { continue; }
+            let Some(best) = ctx.package_versions.get(name).and_then(|versions| versions.iter().rev().find(|info| satisfies(info))) else { continue };
+            let needs: Vec<String> = set.iter().map(|r| format!("{} needs {} {} {}", r.required_by, name, r.constraint.op, r.constraint.version)).collect();
+            eprintln!("Note: using {} {} instead of the newest {}: {}.", name, best.version, ctx.package_index[name].version, needs.join(", "));
+            pins.push(best.clone());
+        }
+        if pins.is_empty() { return; }
+        for info in pins {
+            ctx.package_index.insert(info.name.clone(), info);
+        }
+    }
+}
+
+fn collect_dependencies(pkg_name: &str, ctx: &AppContext, resolved: &mut Vec<String>, requirements: &mut Requirements, missing: &mut Vec<MissingDependency>) -> Result<(), FluxError> {
+    if resolved.iter().any(|name| name == pkg_name) { return Ok(()); }
+    let info = &ctx.package_index[pkg_name];
+    if let Some(deps) = &info.dependencies {
+        for dep in deps {
+            let spec = DependencySpec::parse(dep)?;
+            if !ctx.package_index.contains_key(&spec.name) {
+                match missing.iter_mut().find(|m| m.name == spec.name) {
+                    Some(m) => m.required_by.push(info.name.clone()),
+                    None => missing.push(MissingDependency { name: spec.name, required_by: vec![info.name.clone()] }),
+                }
+                continue;
+            }
+            if let Some(constraint) = spec.constraint {
+                requirements.entry(spec.name.clone()).or_default().push(Requirement { required_by: info.name.clone(), constraint });
+            }
+            collect_dependencies(&spec.name, ctx, resolved, requirements, missing)?;
+        }
+    }
+    resolved.push(pkg_name.to_string());
+    Ok(())
+}
+
+fn declares_conflict(info: &PackageInfo, name: &str, version: &str) -> Result<bool, FluxError> {
+    for entry in info.conflicts.iter().flatten() {
+        let spec = DependencySpec::parse(entry)?;
+        if spec.name == name && spec.constraint.as_ref().is_none_or(|c| c.matches(version)) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// The first package among `selected` and the installed ones that conflicts with `name`, in either direction.
+fn find_conflict(name: &str, selected: &[String], installed: &[InstalledPackageInfo], ctx: &AppContext) -> Result<Option<String>, FluxError> {
+    let info = &ctx.package_index[name];
+    let others = selected.iter()
+        .map(|other| (other.as_str(), ctx.package_index[other].version.as_str()))
+        .chain(installed.iter().filter(|p| !selected.contains(&p.name)).map(|p| (p.name.as_str(), p.version.as_str())));
+    for (other, version) in others {
+        if other == name { continue; }
+        let reverse = match ctx.package_index.get(other) {
+            Some(other_info) => declares_conflict(other_info, name, &info.version)?,
+            None => false,
+        };
+        if declares_conflict(info, other, version)? || reverse {
+            return Ok(Some(other.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+// Installed packages are normally kept whatever their version; --recheck-existing holds them to the
+// same requirements as the packages about to be installed.
+fn check_installed_requirements(names: &[String], requirements: &Requirements, installed: &[InstalledPackageInfo]) -> Result<(), FluxError> {
+    for name in names {
+        let Some(pkg) = installed.iter().find(|p| p.name == *name) else { continue };
+        let unmet = requirements.get(name).into_iter().flatten().find(|r| !r.constraint.matches(&pkg.version));
+        if let Some(requirement) = unmet {
+            return Err(FluxError::OutdatedDependency {
+                package_name: requirement.required_by.clone(),
+                dependency: format!("{} {} {}", name, requirement.constraint.op, requirement.constraint.version),
+                installed: format!("{} {}", name, pkg.version),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn check_requirements(requirements: &Requirements, ctx: &AppContext) -> Result<(), FluxError> {
+    let mut names: Vec<&String> = requirements.keys().collect();
+    names.sort();
+    for name in names {
+        let versions: Vec<&str> = ctx.package_versions.get(name).into_iter().flatten().map(|info| info.version.as_str()).collect();
+        let pinned: Vec<&str> = ctx.package_index.get(name).map(|info| info.version.as_str()).into_iter().collect();
+        let fits = |candidates: &[&str], set: &[&Requirement]| candidates.iter().any(|v| set.iter().all(|r| r.constraint.matches(v)));
+
+        let mut conflict: Vec<&Requirement> = requirements[name].iter().collect();
+        if fits(&pinned, &conflict) { continue; }
+        // The conflict is the smallest set no indexed version fits. If one does fit them all, this
+        // run just didn't pick it, and the clash is with the version it did.
+        let indexed: Vec<&str> = versions.iter().chain(&pinned).copied().collect();
+        let candidates = if fits(&indexed, &conflict) { pinned } else { indexed };
+        let satisfiable = |set: &[&Requirement]| fits(&candidates, set);
+        // Deletion-based shrinking: drop every requirement the conflict doesn't need, leaving a minimal set.
+        let mut i = 0;
+        while i < conflict.len() {
+            let mut without = conflict.clone();
+            without.remove(i);
+            if satisfiable(&without) { i += 1; } else { conflict = without; }
+        }
+
+        let available = if versions.is_empty() { "none".to_string() } else { versions.join(", ") };
+        // A single requirement nothing in the index satisfies isn't a clash between packages.
+        if let [requirement] = conflict[..] {
+            return Err(FluxError::UnsatisfiableDependency {
+                package_name: requirement.required_by.clone(),
+                dependency: format!("{} {} {}", name, requirement.constraint.op, requirement.constraint.version),
+                available: if versions.is_empty() { "no version of it".to_string() } else { available },
+            });
+        }
+        let needs: Vec<String> = conflict.iter()
+            .map(|r| format!("{} needs {} {} {}", r.required_by, name, r.constraint.op, r.constraint.version))
+            .collect();
+        return Err(FluxError::DependencyConflict {
+            package_name: name.clone(),
+            conflict: format!("{} (available: {})", needs.join(" but "), available),
+        });
+    }
+    Ok(())
+}
+
+fn find_dependents(package_name: &str, installed: &[InstalledPackageInfo], ctx: &AppContext) -> Vec<String> {
+    let mut dependents = Vec::new();
+    for pkg in installed {
+        if pkg.name == package_name { continue; }
+        if let Some(info) = ctx.package_index.get(&pkg.name) {
+            if info.dependency_names().any(|d| d == package_name) {
+                dependents.push(pkg.name.clone());
+            }
+        }
+    }
+    dependents
+}
+
+// Deletes what `pkg` put on disk; `remaining` are the records that stay installed afterwards.
+async fn remove_package_files(pkg: &InstalledPackageInfo, remaining: &[InstalledPackageInfo], ctx: &AppContext) -> Result<(), FluxError> {
+    let other_profiles = ctx.packages_in_other_profiles().await?;
+    let remaining: Vec<&InstalledPackageInfo> = remaining.iter().chain(&other_profiles).collect();
+    if pkg.package_type == PackageType::App {
+        let install_path = ctx.get_installed_path(pkg);
+        if other_profiles.iter().any(|other| other.name == pkg.name && other.version == pkg.version && other.package_type == PackageType::App) {
+            println!("Keeping {}, another profile also has it installed", install_path.display());
+        } else if install_path.exists() {
+            fs::remove_dir_all(&install_path).await?;
+            println!("Removed directory: {}", install_path.display());
+        }
+    } else { // System package
+        println!("Removing files for system package {}...", pkg.name);
+        for file_path in pkg.files.iter().rev().map(|f| &f.path) {
+            let Some(relative_path) = sanitize_relative_path(file_path).filter(|p| !p.as_os_str().is_empty()) else {
+                println!("Warning: refusing to remove '{}', it resolves outside {}", file_path.display(), ctx.target_root.display());
+                continue;
+            };
+            let full_path = ctx.target_root.join(&relative_path);
+            // Symlinks (and hardlinks) are removed themselves, never what they point at.
+            let Ok(metadata) = fs::symlink_metadata(&full_path).await else { continue };
+            if metadata.is_dir() {
+                if fs::read_dir(&full_path).await?.next_entry().await?.is_none() {
+                    println!("Removing empty directory: {}", full_path.display());
+                    fs::remove_dir(&full_path).await?;
+                }
+            } else if remaining.iter().any(|other| other.files.iter().any(|f| sanitize_relative_path(&f.path).as_ref() == Some(&relative_path))) {
+                println!("Keeping {}, it is also owned by another package", full_path.display());
+            } else {
+                println!("Removing file: {}", full_path.display());
+                fs::remove_file(&full_path).await?;
+            }
+        }
+        release_content_blobs(pkg, &remaining, &ctx.target_store_dir).await?;
+    }
+    Ok(())
+}
+
+const REPLACED_SUFFIX: &str = ".flux-replaced";
+
+// Moves the files of a system package that is being replaced out of the way of the new version,
+// recording each in `set_aside`. Files other packages also own stay where they are. An App needs
+// nothing moved, since each version has its own directory.
+async fn set_aside_replaced_files(pkg: &InstalledPackageInfo, remaining: &[InstalledPackageInfo], set_aside: &mut Vec<PathBuf>, ctx: &AppContext) -> Result<(), FluxError> {
+    if pkg.package_type == PackageType::App { return Ok(()); }
+    let other_profiles = ctx.packages_in_other_profiles().await?;
+    let owners: Vec<&InstalledPackageInfo> = remaining.iter().chain(&other_profiles).collect();
+    for file_path in pkg.files.iter().map(|f| &f.path) {
+        let Some(relative_path) = sanitize_relative_path(file_path).filter(|p| !p.as_os_str().is_empty()) else { continue };
+        let full_path = ctx.target_root.join(&relative_path);
+        let Ok(metadata) = fs::symlink_metadata(&full_path).await else { continue };
+        if metadata.is_dir() || owners.iter().any(|other| other.files.iter().any(|f| sanitize_relative_path(&f.path).as_ref() == Some(&relative_path))) {
+            continue;
+        }
+        fs::rename(&full_path, with_suffix(&full_path, REPLACED_SUFFIX)).await?;
+        set_aside.push(full_path);
+    }
+    Ok(())
+}
+
+// Puts the old versions back, files and database records, after their replacements failed.
+async fn restore_replaced_packages(replaced: Vec<(InstalledPackageInfo, Vec<PathBuf>)>, mut installed: Vec<InstalledPackageInfo>, ctx: &AppContext) -> Result<(), FluxError> {
+    for (old, set_aside) in replaced {
+        for full_path in &set_aside {
+            fs::rename(with_suffix(full_path, REPLACED_SUFFIX), full_path).await?;
+        }
+        println!("Kept {} {}.", old.name, old.version);
+        installed.push(old);
+    }
+    ctx.write_installed_packages(&installed).await
+}
+
+// Deletes what is left of a replaced version once its replacement is recorded: the set-aside
+// files, directories that are now empty and content store blobs nothing references any more.
+async fn finish_replacement(old: &InstalledPackageInfo, set_aside: &[PathBuf], installed: &[InstalledPackageInfo], ctx: &AppContext) -> Result<(), FluxError> {
+    if old.package_type == PackageType::App {
+        return remove_package_files(old, installed, ctx).await;
+    }
+    for full_path in set_aside {
+        fs::remove_file(with_suffix(full_path, REPLACED_SUFFIX)).await?;
+    }
+    for file_path in old.files.iter().rev().map(|f| &f.path) {
+        let Some(relative_path) = sanitize_relative_path(file_path).filter(|p| !p.as_os_str().is_empty()) else { continue };
+        let full_path = ctx.target_root.join(&relative_path);
+        if fs::symlink_metadata(&full_path).await.is_ok_and(|m| m.is_dir()) && fs::read_dir(&full_path).await?.next_entry().await?.is_none() {
+            fs::remove_dir(&full_path).await?;
+        }
+    }
+    let other_profiles = ctx.packages_in_other_profiles().await?;
+    let remaining: Vec<&InstalledPackageInfo> = installed.iter().chain(&other_profiles).collect();
+    release_content_blobs(old, &remaining, &ctx.target_store_dir).await
+}
+
+// Removes one package's files and database record, refusing while anything still depends on it.
+async fn remove_installed_package(package_name: &str, installed: &mut Vec<InstalledPackageInfo>, ctx: &AppContext) -> Result<InstalledPackageInfo, FluxError> {
+    let dependents = find_dependents(package_name, installed, ctx);
+
+    if !dependents.is_empty() {
+        return Err(FluxError::DependencyInUse { package_name: package_name.to_string(), dependents });
+    }
+
+    let index = installed.iter().position(|p| p.name == package_name)
+        .ok_or_else(|| FluxError::PackageNotFound(format!("{} (not installed)", package_name)))?;
+    let pkg_to_remove = installed.remove(index);
+    let scripts = pkg_to_remove.remove_scripts.clone().unwrap_or_default();
+
+    if ctx.dry_run {
+        println!("[dry-run] would remove {} {} ({} file(s)) and update the package database", pkg_to_remove.name, pkg_to_remove.version, pkg_to_remove.files.len());
+        for (phase, spec) in [("pre_remove", &scripts.pre_remove), ("post_remove", &scripts.post_remove)] {
+            if spec.is_some() {
+                println!("[dry-run] would run the {} script of {}", phase, pkg_to_remove.name);
+            }
+        }
+        return Ok(pkg_to_remove);
+    }
+    println!("Removing package: {}", pkg_to_remove.name);
+    let root = ctx.get_installed_path(&pkg_to_remove);
+    let sandboxed = ctx.config.sandbox_scripts && !scripts.privileged;
+
+    // The pre-remove script runs while all the files are still there; if it fails nothing is removed.
+    let pre_remove = match &scripts.pre_remove {
+        Some(spec) => removal_script(spec, &root, &pkg_to_remove.name, "pre_remove", |pkg, msg| FluxError::PreRemoveScriptFailed { package_name: pkg, message: msg })
+            .and_then(|script| match script {
+                Some((path, args)) => run_script(&path, &args, &pkg_to_remove.name, sandboxed.then_some(root.as_path()), |pkg, _, msg| FluxError::PreRemoveScriptFailed { package_name: pkg, message: msg }),
+                None => Ok(()),
+            }),
+        None => Ok(()),
+    };
+    if let Err(e) = pre_remove {
+        installed.insert(index, pkg_to_remove);
+        return Err(e);
+    }
+
+    // The post-remove script ships with the package too, so it is copied out before the files go.
+    let post_remove = match &scripts.post_remove {
+        Some(spec) => match removal_script(spec, &root, &pkg_to_remove.name, "post_remove", |pkg, msg| FluxError::PostRemoveScriptFailed { package_name: pkg, message: msg }) {
+            Ok(Some((path, args))) => {
+                // A fresh 0700 directory, so no other user can plant or swap the copy before it runs.
+                let dir = tempfile::Builder::new().prefix("flux-post-remove-").permissions(std::os::unix::fs::PermissionsExt::from_mode(0o700)).tempdir()?;
+                let copy = dir.path().join(&pkg_to_remove.name);
+                let mut source = File::open(&path).await?;
+                let mut target = fs::OpenOptions::new().write(true).create_new(true).mode(0o700).open(&copy).await?;
+                io::copy(&mut source, &mut target).await?;
+                target.sync_all().await?;
+                Some((dir, copy, args))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                installed.insert(index, pkg_to_remove);
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
+    remove_package_files(&pkg_to_remove, installed, ctx).await?;
+    ctx.write_installed_packages(installed).await?;
+    println!("Successfully removed '{}'.", pkg_to_remove.name);
+
+    if let Some((dir, copy, args)) = post_remove {
+        let work_dir = if root.is_dir() { root.clone() } else { dir.path().to_path_buf() };
+        run_script(&copy, &args, &pkg_to_remove.name, sandboxed.then_some(work_dir.as_path()), |pkg, _, msg| FluxError::PostRemoveScriptFailed { package_name: pkg, message: msg })?;
+    }
+    Ok(pkg_to_remove)
+}
+
+// Resolves a pre_remove or post_remove spec to the script inside `root`; a script the package
+// doesn't ship is skipped, as with post_install.
+fn removal_script(spec: &ScriptSpec, root: &Path, package_name: &str, field: &str, error_type: fn(String, String) -> FluxError) -> Result<Option<(PathBuf, Vec<String>)>, FluxError> {
+    let (script_name, args) = spec.script_and_args()
+        .ok_or_else(|| error_type(package_name.to_string(), format!("{} is an empty list; it must start with the script to run", field)))?;
+    let script_path = root.join(script_name);
+    Ok(script_path.exists().then(|| (script_path, args.to_vec())))
+}
+
+// Remove hooks run once per removal batch, however many of the removed packages matched.
+fn run_remove_hooks(removed: &[InstalledPackageInfo], ctx: &AppContext) -> Result<(), FluxError> {
+    let mut deferred_hooks = Vec::new();
+    for pkg in removed {
+        for hook_script in ctx.matching_hooks(&pkg.name, &pkg.package_type, HookPhase::Remove) {
+            defer_hook(&mut deferred_hooks, hook_script, &pkg.name);
+        }
+    }
+    run_deferred_hooks(&deferred_hooks, ctx)
+}
+
+async fn handle_remove(package_name: &str, recursive_orphans: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let mut installed = ctx.get_installed_packages().await?;
+    let Some(pkg) = installed.iter().find(|p| p.name == package_name) else {
+        return Err(FluxError::PackageNotFound(format!("{} (not installed)", package_name)));
+    };
+    let freed = if recursive_orphans {
+        // Packages that were already orphaned before are autoremove's business, not this removal's.
+        let already_orphaned: HashSet<String> = find_orphans(&installed, ctx).into_iter().collect();
+        orphan_closure(&installed, &[package_name.to_string()], &already_orphaned, ctx)
+    } else {
+        Vec::new()
+    };
+
+    println!("The following packages will be removed:");
+    println!("- {} {}", pkg.name, pkg.version);
+    for name in &freed {
+        println!("- {} (no longer needed)", name);
+    }
+    if !confirm_changes(ctx)? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let mut removed = vec![remove_installed_package(package_name, &mut installed, ctx).await?];
+    for name in &freed {
+        removed.push(remove_installed_package(name, &mut installed, ctx).await?);
+    }
+    run_remove_hooks(&removed, ctx)
+}
+
+#[derive(Serialize)]
+struct ListEntry<'a> {
+    name: &'a str,
+    version: &'a str,
+    package_type: &'a PackageType,
+    install_reason: &'a InstallReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    installed_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dependents: Option<Vec<String>>,
+}
+
+impl<'a> From<&'a InstalledPackageInfo> for ListEntry<'a> {
+    fn from(pkg: &'a InstalledPackageInfo) -> Self {
+        Self { name: &pkg.name, version: &pkg.version, package_type: &pkg.package_type, install_reason: &pkg.install_reason, installed_at: pkg.installed_at, dependents: None }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ListOptions {
+    format: ListFormat,
+    json_lines: bool,
+    reverse_deps: bool,
+    verbose: bool,
+    orphans: bool,
+    sort: Option<ListSort>,
+    newer_than: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListSort {
+    Name,
+    Type,
+    Reason,
+    // Database order, i.e. roughly the order packages were installed in.
+    Installed,
+}
+
+// Writes each record as its own JSON line as soon as it is produced.
+fn write_json_lines<T: Serialize>(records: impl IntoIterator<Item = T>) -> Result<(), FluxError> {
+    write_json_lines_to(&mut std::io::stdout().lock(), records)
+}
+
+fn write_json_lines_to<T: Serialize>(out: &mut impl Write, records: impl IntoIterator<Item = T>) -> Result<(), FluxError> {
+    for record in records {
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Quotes a CSV field (RFC 4180) when it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// The packages `list` shows, in the order it shows them.
+fn list_order<'a>(installed: &'a [InstalledPackageInfo], options: &ListOptions, ctx: &AppContext) -> Vec<&'a InstalledPackageInfo> {
+    // Orphans are listed in the order autoremove would remove them unless a sort order is given.
+    let mut listed: Vec<&InstalledPackageInfo> = if options.orphans {
+        find_orphans(installed, ctx).iter().filter_map(|name| installed.iter().find(|pkg| pkg.name == *name)).collect()
+    } else {
+        installed.iter().collect()
+    };
+    if let Some(since) = options.newer_than {
+        listed.retain(|pkg| pkg.installed_at.is_some_and(|at| at > since));
+    }
+    match options.sort.or((!options.orphans).then_some(ListSort::Name)) {
+        Some(ListSort::Name) => listed.sort_by(|a, b| a.name.cmp(&b.name)),
+        Some(ListSort::Type) => listed.sort_by(|a, b| format!("{:?}", a.package_type).cmp(&format!("{:?}", b.package_type)).then_with(|| a.name.cmp(&b.name))),
+        Some(ListSort::Reason) => listed.sort_by(|a, b| format!("{:?}", a.install_reason).cmp(&format!("{:?}", b.install_reason)).then_with(|| a.name.cmp(&b.name))),
+        Some(ListSort::Installed) | None => {}
+    }
+    listed
+}
+
+// A header row, then one row per entry with the same columns the text format shows.
+fn list_csv<'a>(entries: impl Iterator<Item = ListEntry<'a>>, options: &ListOptions) -> String {
+    let mut header = vec!["name", "version", "type", "reason"];
+    if options.newer_than.is_some() { header.push("installed"); }
+    if options.reverse_deps {
+        header.push("dependents");
+        if options.verbose { header.push("dependent_names"); }
+    }
+    let mut out = header.join(",") + "\n";
+    for entry in entries {
+        let mut row = vec![entry.name.to_string(), entry.version.to_string(), format!("{:?}", entry.package_type), format!("{:?}", entry.install_reason)];
+        if options.newer_than.is_some() {
+            row.push(entry.installed_at.map(format_unix_time).unwrap_or_default());
+        }
+        if let Some(dependents) = &entry.dependents {
+            row.push(dependents.len().to_string());
+            if options.verbose { row.push(dependents.join(", ")); }
+        }
+        out.push_str(&row.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","));
+        out.push('\n');
+    }
+    out
+}
+
+async fn handle_list(options: ListOptions, ctx: &AppContext) -> Result<(), FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let listed = list_order(&installed, &options, ctx);
+    let entries = listed.iter().map(|&pkg| ListEntry {
+        dependents: options.reverse_deps.then(|| find_dependents(&pkg.name, &installed, ctx)),
+        ..ListEntry::from(pkg)
+    });
+
+    if options.json_lines {
+        return write_json_lines(entries);
+    }
+    if options.format == ListFormat::Json {
+        let entries: Vec<ListEntry> = entries.collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    if options.format == ListFormat::Csv {
+        print!("{}", list_csv(entries, &options));
+        return Ok(());
+    }
+
+    let unknown_install_time = installed.iter().filter(|pkg| pkg.installed_at.is_none()).count();
+    if options.newer_than.is_some() && unknown_install_time > 0 {
+        eprintln!("Note: {} package(s) were installed before install times were recorded and are never shown with --newer-than.", unknown_install_time);
+    }
+    if options.orphans {
+        println!("Packages that 'flux autoremove' would remove:");
+        if listed.is_empty() {
+            println!("No orphaned packages.");
+            return Ok(());
+        }
+    } else {
+        println!("Listing installed packages...");
+        if listed.is_empty() {
+            println!("No packages are currently installed.");
+            return Ok(());
+        }
+    }
+
+    for entry in entries {
+        let mut line = format!("- {} (version: {}, type: {:?}, reason: {:?}", entry.name, entry.version, entry.package_type, entry.install_reason);
+        if let Some(at) = entry.installed_at.filter(|_| options.newer_than.is_some()) {
+            line.push_str(&format!(", installed: {}", format_unix_time(at)));
+        }
+        line.push(')');
+        if let Some(dependents) = &entry.dependents {
+            line.push_str(&format!(" [{} dependent(s)", dependents.len()));
+            if options.verbose && !dependents.is_empty() {
+                line.push_str(&format!(": {}", dependents.join(", ")));
+            }
+            line.push(']');
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn resolve_repo_url(repository_url: &str) -> Result<Url, FluxError> {
+    if let Some(file_path) = repository_url.strip_prefix("file://./") {
+        let current_dir = std::env::current_dir()?;
+        Url::from_file_path(current_dir.join(file_path)).map_err(|_| FluxError::Config("Could not create absolute file URL".to_string()))
+    } else {
+        Ok(Url::parse(repository_url)?)
+    }
+}
+
+// Routes `url` through a caching proxy as `<proxy>/<host>/<path>`, so the same archive always maps
+// to the same proxy path. Only http(s) URLs are rewritten.
+fn proxied_url(proxy: &str, url: &Url) -> Result<Url, FluxError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Ok(url.clone());
+    }
+    let mut base = Url::parse(proxy)?;
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    // "./" keeps a `host:port` prefix from being parsed as a URL scheme.
+    let mut proxied = base.join(&format!("./{}{}", host, url.path()))?;
+    proxied.set_query(url.query());
+    Ok(proxied)
+}
+
+// The default repository keeps the cache file name it had before multiple repositories existed.
+fn repo_cache_path(cache_dir: &Path, repo: &RepoEntry) -> PathBuf {
+    if repo.name == DEFAULT_REPO_NAME {
+        cache_dir.join("repo.yaml")
+    } else {
+        cache_dir.join(format!("repo-{}.yaml", repo.name))
+    }
+}
+
+// Records where the cached index came from, next to the cache itself.
+fn index_source_path(cache_path: &Path) -> PathBuf {
+    cache_path.with_extension("source")
+}
+
+// Merges the cached indexes, highest priority first so its packages win on name collisions.
+// `file://./...` package URLs are made absolute against the local index they came from.
+async fn load_package_index(repos: &[RepoEntry], cache_dir: &Path, accepted_arches: &[String], max_name_length: usize) -> Result<HashMap<String, Vec<PackageInfo>>, FluxError> {
+    let mut package_index = HashMap::new();
+    for repo in repos {
+        let cache_path = repo_cache_path(cache_dir, repo);
+        if !cache_path.exists() { continue; }
+        let mut index: PackageIndex = serde_yaml::from_str(&fs::read_to_string(&cache_path).await?)?;
+        // Update refuses such indexes; one cached before that check existed loses just the bad entries.
+        index.packages.retain(|info| match check_index_entry(info, max_name_length) {
+            Ok(()) => true,
+            Err(reason) => {
+                eprintln!("Warning: ignoring {:?} from the cached index of '{}': {}", info.name, repo.name, reason);
+                false
+            }
+        });
+        let base_dir = read_index_base_dir(&index_source_path(&cache_path)).await;
+        for (name, mut infos) in select_for_arches(index.packages, accepted_arches) {
+            for info in &mut infos {
+                if let (Some(relative), Some(base_dir)) = (info.url.strip_prefix("file://./"), &base_dir) {
+                    let absolute = Url::from_file_path(base_dir.join(relative))
+                        .map_err(|_| FluxError::Config(format!("Could not resolve {} against {}", info.url, base_dir.display())))?;
+                    info.url = absolute.to_string();
+                }
+            }
+            package_index.entry(name).or_insert(infos);
+        }
+    }
+    Ok(package_index)
+}
+
+// Names and versions become directory and cache file names, so anything that could escape those
+// paths or break them is refused.
+fn check_index_entry(info: &PackageInfo, max_length: usize) -> Result<(), String> {
+    for (field, value) in [("name", &info.name), ("version", &info.version)] {
+        if value.is_empty() {
+            return Err(format!("the {} is empty", field));
+        }
+        if value == "." || value == ".." {
+            return Err(format!("the {} is '{}'", field, value));
+        }
+        if value.contains(['/', '\\']) {
+            return Err(format!("the {} contains a path separator", field));
+        }
+        if value.chars().any(|c| c.is_control() || c.is_whitespace()) {
+            return Err(format!("the {} contains whitespace or control characters", field));
+        }
+        if value.chars().count() > max_length {
+            return Err(format!("the {} is longer than {} characters", field, max_length));
+        }
+    }
+    Ok(())
+}
+
+// Checked when an index is downloaded, before it replaces the cached one. Identical repeats are
+// harmless; entries that disagree on the archive are resolved to the first listed, which is also
+// what loading the cache does.
+fn check_duplicate_entries(packages: &[PackageInfo], repo: &RepoEntry, policy: DuplicateEntryPolicy) -> Result<(), FluxError> {
+    let mut seen: HashMap<(&str, &str, Option<&str>), &PackageInfo> = HashMap::new();
+    for info in packages {
+        let key = (info.name.as_str(), info.version.as_str(), info.arch.as_deref());
+        let Some(first) = seen.get(&key) else {
+            seen.insert(key, info);
+            continue;
+        };
+        if first.url == info.url && first.checksum == info.checksum { continue; }
+        let package = match &info.arch {
+            Some(arch) => format!("{} {} ({})", info.name, info.version, arch),
+            None => format!("{} {}", info.name, info.version),
+        };
+        let error = FluxError::DuplicateIndexEntry { repo: repo.name.clone(), package };
+        match policy {
+            DuplicateEntryPolicy::Error => return Err(error),
+            DuplicateEntryPolicy::Warn => eprintln!("Warning: {}; using the first one ({}).", error, first.url),
+        }
+    }
+    Ok(())
+}
+
+// Age of the oldest cached index, or None if any required repository has never been fetched.
+async fn index_age_hours(ctx: &AppContext) -> Result<Option<u64>, FluxError> {
+    let mut oldest = None;
+    for repo in ctx.config.repositories()? {
+        let age = fs::metadata(repo_cache_path(&ctx.host_cache_dir, &repo)).await.ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|modified| modified.elapsed().ok())
+            .map(|age| age.as_secs() / 3600);
+        let Some(age) = age else {
+            if repo.optional { continue; }
+            return Ok(None);
+        };
+        oldest = Some(oldest.map_or(age, |o: u64| o.max(age)));
+    }
+    Ok(oldest)
+}
+
+async fn read_index_base_dir(source_path: &Path) -> Option<PathBuf> {
+    let source = fs::read_to_string(source_path).await.ok()?;
+    let url = Url::parse(source.trim()).ok()?;
+    if url.scheme() != "file" { return None; }
+    Some(url.to_file_path().ok()?.parent()?.to_path_buf())
+}
+
+// Downloads and parses the index into `dest`, so a bad response never replaces a good cache.
+async fn fetch_index(downloader: &Downloader, url: &Url, dest: &Path, signing_key: Option<&minisign_verify::PublicKey>) -> Result<PackageIndex, FluxError> {
+    downloader.download_file(url, dest).await?;
+    if let Some(key) = signing_key {
+        verify_index_signature(downloader, url, dest, key).await?;
+    }
+    let index_content = fs::read_to_string(dest).await?;
+    Ok(serde_yaml::from_str(&index_content)?)
+}
+
+// Checks the downloaded index against `<url>.sig` before anything reads it; the signature is not kept.
+async fn verify_index_signature(downloader: &Downloader, url: &Url, index_path: &Path, key: &minisign_verify::PublicKey) -> Result<(), FluxError> {
+    let signature_url = Url::parse(&format!("{}.sig", url))?;
+    let mut signature_path = index_path.as_os_str().to_owned();
+    signature_path.push(".sig");
+    let signature_path = PathBuf::from(signature_path);
+
+    let invalid = |reason: String| FluxError::SignatureInvalid { url: url.to_string(), reason };
+    let fetched = downloader.download_file(&signature_url, &signature_path).await;
+    let status = match fetched {
+        Ok(_) => {
+            let (index, signature, keys, buffer_size) = (index_path.to_path_buf(), signature_path.clone(), vec![(String::new(), key.clone())], downloader.buffer_size);
+            tokio::task::spawn_blocking(move || verify_archive_signature(&index, &signature, &keys, buffer_size)).await.unwrap()
+        }
+        Err(e) => Err(invalid(format!("could not download {}: {}", signature_url, e))),
+    };
+    let _ = fs::remove_file(&signature_path).await;
+    match status? {
+        SignatureStatus::Valid { .. } => Ok(()),
+        SignatureStatus::UntrustedKey => Err(invalid("signed by a key other than index_signing_key".to_string())),
+        _ => Err(invalid("the signature does not match the index".to_string())),
+    }
+}
+
+async fn handle_update(strict: bool, ctx: &mut AppContext) -> Result<(), FluxError> {
+    let repos = ctx.config.repositories()?;
+    if repos.is_empty() {
+        return Err(FluxError::Config("No repository_url or repositories set in flux.conf".to_string()));
+    }
+
+    let mut failures = Vec::new();
+    for repo in &repos {
+        if let Err(e) = update_repository(repo, repos.len() > 1, strict, ctx).await {
+            if repo.optional {
+                eprintln!("Warning: optional repository '{}' is unavailable, continuing without it: {}", repo.name, e);
+                continue;
+            }
+            failures.push((repo.name.clone(), e));
+        }
+    }
+    ctx.package_versions = load_package_index(&repos, &ctx.host_cache_dir, &ctx.accepted_arches, ctx.config.max_name_length.unwrap_or(DEFAULT_MAX_NAME_LENGTH)).await?;
+    ctx.package_index = newest_versions(&ctx.package_versions);
+    if repos.len() == 1 {
+        return failures.pop().map_or(Ok(()), |(_, e)| Err(e));
+    }
+    if !failures.is_empty() {
+        let reasons: Vec<String> = failures.into_iter().map(|(name, e)| match e {
+            FluxError::IndexUpdateFailed(reason) => format!("{}: {}", name, reason),
+            FluxError::AllMirrorsFailed { failures, untried } => format!("{}: {}", name, describe_mirror_failures(&failures, untried)),
+            other => format!("{}: {}", name, other),
+        }).collect();
+        return Err(FluxError::IndexUpdateFailed(reasons.join("; ")));
+    }
+    Ok(())
+}
+
+async fn update_repository(repo: &RepoEntry, named: bool, strict: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let mut sources = vec![repo.url.clone()];
+    // The mirrors predate multiple repositories and belong to the default one.
+    if repo.name == DEFAULT_REPO_NAME {
+        sources.extend(ctx.config.index_mirrors.iter().cloned());
+    }
+    let attempts = ctx.config.index_retries.unwrap_or(DEFAULT_INDEX_RETRIES).max(1);
+    let total_limit = ctx.config.index_max_total_attempts.map(|n| n.max(1));
+    let cache_path = repo_cache_path(&ctx.host_cache_dir, repo);
+    let download_path = partial_download_path(&cache_path);
+    let signing_key = match &ctx.config.index_signing_key {
+        Some(path) => Some(minisign_verify::PublicKey::from_file(path)
+            .map_err(|e| FluxError::Config(format!("Could not load index signing key {}: {}", path.display(), e)))?),
+        None => None,
+    };
+    let duplicates = ctx.config.duplicate_index_entries.unwrap_or_default();
+    let max_name_length = ctx.config.max_name_length.unwrap_or(DEFAULT_MAX_NAME_LENGTH);
+
+    let mut failures = Vec::new();
+    let mut total_attempts = 0;
+    let mut untried = 0;
+    'sources: for (index, source) in sources.iter().enumerate() {
+        if total_limit.is_some_and(|limit| total_attempts >= limit) {
+            untried = sources.len() - index;
+            break;
+        }
+        if named {
+            println!("Updating repository index '{}' from {}...", repo.name, source);
+        } else {
+            println!("Updating repository index from {}...", source);
+        }
+        let url = match resolve_repo_url(source) {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Warning: skipping {}: {}", source, e);
+                failures.push(MirrorFailure { source: source.clone(), attempts: 0, last_error: e.to_string() });
+                continue;
+            }
+        };
+
+        let mut delay = std::time::Duration::from_millis(500);
+        for attempt in 1..=attempts {
+            total_attempts += 1;
+            let fetch_url = match &ctx.config.cache_proxy_url {
+                Some(proxy) if !ctx.config.cache_proxy_bypass_index => proxied_url(proxy, &url)?,
+                _ => url.clone(),
+            };
+            let fetched = fetch_index(&ctx.downloader, &fetch_url, &download_path, signing_key.as_ref()).await
+                .and_then(|index| {
+                    for info in &index.packages {
+                        check_index_entry(info, max_name_length).map_err(|reason| FluxError::InvalidIndexEntry { repo: repo.name.clone(), entry: info.name.clone(), reason })?;
+                    }
+                    check_duplicate_entries(&index.packages, repo, duplicates)
+                });
+            match fetched {
+                Ok(_) => {
+                    fs::rename(&download_path, &cache_path).await?;
+                    fs::write(index_source_path(&cache_path), url.as_str()).await?;
+                    println!("Repository index updated successfully.");
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("Warning: attempt {}/{} from {} failed: {}", attempt, attempts, source, e);
+                    let out_of_attempts = total_limit.is_some_and(|limit| total_attempts >= limit);
+                    // A bad signature or index won't fix itself on retry; move on to the next mirror.
+                    let rejected = matches!(e, FluxError::SignatureInvalid { .. } | FluxError::InvalidIndexEntry { .. } | FluxError::DuplicateIndexEntry { .. });
+                    if attempt == attempts || out_of_attempts || rejected {
+                        failures.push(MirrorFailure { source: source.clone(), attempts: attempt, last_error: e.to_string() });
+                        continue 'sources;
+                    }
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+    }
+
+    let _ = fs::remove_file(&download_path).await;
+    let error = FluxError::AllMirrorsFailed { failures, untried };
+    if strict || !cache_path.exists() {
+        return Err(error);
+    }
+    eprintln!("Warning: {}. Continuing with the existing cached index.", error);
+    Ok(())
+}
+
+struct UpgradeCandidate<'a> {
+    installed: &'a InstalledPackageInfo,
+    available: &'a PackageInfo,
+}
+
+impl UpgradeCandidate<'_> {
+    // The index going backwards usually means a rolled-back repository, not a wanted change.
+    fn is_downgrade(&self) -> bool {
+        compare_versions(&self.available.version, &self.installed.version).is_lt()
+    }
+}
+
+fn plan_upgrades<'a>(installed: &'a [InstalledPackageInfo], ctx: &'a AppContext) -> Vec<UpgradeCandidate<'a>> {
+    installed.iter()
+        .filter_map(|pkg| {
+            let repo_pkg = ctx.package_index.get(&pkg.name)?;
+            (repo_pkg.version != pkg.version).then_some(UpgradeCandidate { installed: pkg, available: repo_pkg })
+        })
+        .collect()
+}
+
+const CHANGELOG_EXCERPT_CHARS: usize = 800;
+const CHANGELOG_EXCERPT_LINES: usize = 12;
+
+fn changelog_excerpt(text: &str) -> String {
+    let mut excerpt: String = text.lines().take(CHANGELOG_EXCERPT_LINES).collect::<Vec<_>>().join("\n");
+    if let Some((cut, _)) = excerpt.char_indices().nth(CHANGELOG_EXCERPT_CHARS) {
+        excerpt.truncate(cut);
+    }
+    if excerpt.len() < text.trim_end().len() {
+        excerpt.push_str("\n...");
+    }
+    excerpt
+}
+
+// Fetches each changelog URL at most once per run; None means it's unavailable.
+async fn fetch_changelog(downloader: &Downloader, changelog_url: &str, cache: &mut HashMap<String, Option<String>>) -> Option<String> {
+    if changelog_url.is_empty() { return None; }
+    if let Some(cached) = cache.get(changelog_url) { return cached.clone(); }
+    let excerpt = match Url::parse(changelog_url) {
+        Ok(url) => downloader.fetch_text(&url).await.ok().map(|text| changelog_excerpt(&text)),
+        Err(_) => None,
+    };
+    cache.insert(changelog_url.to_string(), excerpt.clone());
+    excerpt
+}
+
+// What the upgrade plan shows under a package for --changelog.
+async fn changelog_plan_lines(downloader: &Downloader, changelog_url: &str, cache: &mut HashMap<String, Option<String>>) -> Vec<String> {
+    match fetch_changelog(downloader, changelog_url, cache).await {
+        Some(excerpt) => excerpt.lines().map(|line| format!("    {}", line)).collect(),
+        None => vec!["    No changelog available.".to_string()],
+    }
+}
+
+// Exit status of `upgrade --list` when upgrades are available, for scripts.
+const UPGRADES_AVAILABLE_EXIT_CODE: i32 = 100;
+
+// Narrows the upgrade candidates to the named packages and drops downgrades unless they are allowed.
+fn select_upgrades<'a>(packages: &[String], options: &UpgradeOptions, installed: &'a [InstalledPackageInfo], ctx: &'a AppContext) -> Result<Vec<UpgradeCandidate<'a>>, FluxError> {
+    let mut candidates = plan_upgrades(installed, ctx);
+
+    if !packages.is_empty() {
+        for name in packages {
+            if !installed.iter().any(|p| &p.name == name) {
+                return Err(FluxError::PackageNotFound(format!("{} (not installed)", name)));
+            }
+            if !candidates.iter().any(|c| &c.installed.name == name) {
+                println!("{} is already up to date.", name);
+            }
+        }
+        candidates.retain(|c| packages.contains(&c.installed.name));
+    }
+    if !options.only.is_empty() || !options.exclude.is_empty() {
+        let wanted = |name: &str| (options.only.is_empty() || options.only.iter().any(|p| glob_match(p, name))) && !options.exclude.iter().any(|p| glob_match(p, name));
+        let filtered: Vec<&str> = candidates.iter().map(|c| c.installed.name.as_str()).filter(|name| !wanted(name)).collect();
+        if !filtered.is_empty() {
+            println!("Left at their current version by --only/--exclude: {}", filtered.join(", "));
+        }
+        candidates.retain(|c| wanted(&c.installed.name));
+    }
+    if options.use_cached {
+        // Only presence is checked here; the checksum is verified again before installing.
+        let is_cached = |name: &str| replacement_packages(name, installed, ctx)
+            .is_ok_and(|infos| infos.iter().all(|info| ctx.host_cache_dir.join(archive_file_name(info)).exists()));
+        let missing: Vec<&str> = candidates.iter().map(|c| c.installed.name.as_str()).filter(|name| !is_cached(name)).collect();
+        if !missing.is_empty() {
+            println!("Not prefetched, left for later: {}", missing.join(", "));
+        }
+        candidates.retain(|c| is_cached(&c.installed.name));
+    }
+    if !options.allow_downgrade {
+        for candidate in candidates.iter().filter(|c| c.is_downgrade()) {
+            eprintln!("Warning: skipping {}: the index has {}, older than the installed {}. Use --allow-downgrade to apply it.",
+                candidate.installed.name, candidate.available.version, candidate.installed.version);
+        }
+        candidates.retain(|c| !c.is_downgrade());
+    }
+    Ok(candidates)
+}
+
+// Returns whether any upgrades are available.
+async fn handle_upgrade_list(packages: &[String], options: &UpgradeOptions, ctx: &AppContext) -> Result<bool, FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let candidates = select_upgrades(packages, options, &installed, ctx)?;
+    if candidates.is_empty() {
+        println!("All packages are up to date.");
+        return Ok(false);
+    }
+    for candidate in &candidates {
+        let downgrade = if candidate.is_downgrade() { " (downgrade)" } else { "" };
+        println!("- {} {} -> {}{}", candidate.installed.name, candidate.installed.version, candidate.available.version, downgrade);
+    }
+    Ok(true)
+}
+
+async fn handle_upgrade(packages: &[String], options: &UpgradeOptions, ctx: &AppContext) -> Result<(), FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let candidates = select_upgrades(packages, options, &installed, ctx)?;
+    if candidates.is_empty() {
+        println!("All packages are up to date.");
+        return Ok(());
+    }
+
+    let mut changelogs = HashMap::new();
+    let mut packages_to_update = Vec::new();
+    for candidate in &candidates {
+        let downgrade = if candidate.is_downgrade() { ", downgrade" } else { "" };
+        println!("- {} (Installed: {}, Available: {}{})", candidate.installed.name, candidate.installed.version, candidate.available.version, downgrade);
+        if options.changelog {
+            for line in changelog_plan_lines(&ctx.downloader, &candidate.available.changelog_url, &mut changelogs).await {
+                println!("{}", line);
+            }
+        }
+        if options.interactive && !ctx.assume_yes {
+            if !options.changelog && !candidate.available.changelog_url.is_empty() {
+                println!("  Changelog: {}", candidate.available.changelog_url);
+            }
+            if !prompt_yes_no(&format!("  Upgrade {}?", candidate.installed.name))? {
+                continue;
+            }
+        }
+        packages_to_update.push(candidate.installed.name.clone());
+    }
+
+    if packages_to_update.is_empty() {
+        println!("No upgrades selected.");
+        return Ok(());
+    }
+    // --interactive already asked about each package.
+    if !options.interactive && !confirm_changes(ctx)? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    if ctx.dry_run {
+        for package_name in &packages_to_update {
+            let mut installed = ctx.get_installed_packages().await?;
+            installed.retain(|p| p.name != *package_name);
+            let plan = plan_install(std::slice::from_ref(package_name), &InstallOptions::default(), &installed, ctx)?;
+            let to_install: Vec<PackageInfo> = plan.packages.iter()
+                .filter(|planned| planned.action == PlanAction::Install)
+                .map(|planned| ctx.package_index[&planned.name].clone())
+                .collect();
+            println!("[dry-run] would remove the files of the installed {}", package_name);
+            print_dry_run_install(&to_install, ctx)?;
+        }
+        return Ok(());
+    }
+
+    println!("\nStarting upgrade...");
+    for package_name in packages_to_update {
+        println!("\nUpgrading {}...", package_name);
+        // The new version and anything new it needs are downloaded and verified while the old one is
+        // still installed, so a failed download leaves the system as it was.
+        let installed = ctx.get_installed_packages().await?;
+        prefetch_replacement(&package_name, &installed, options.keep_downloads, ctx).await?;
+        // Swapped in place: dependents stay installed, remove scripts don't run and the install
+        // reason is kept.
+        let reason = installed.iter().find(|p| p.name == package_name).map_or(InstallReason::Explicit, |p| p.install_reason.clone());
+        let reasons = HashMap::from([(package_name.clone(), reason)]);
+        apply_package_changes(Vec::new(), vec![package_name], Vec::new(), &reasons, options.keep_downloads, ctx).await?;
+    }
+
+    println!("\nUpgrade complete.");
+    Ok(())
+}
+
+// Downloads and verifies what installing the index version of `package_name` in place of the
+// installed one needs, so the installed version is only touched once all of it is here. If that
+// fails, only the archives downloaded here are deleted again; ones cached before, e.g. by an earlier
+// run, stay.
+async fn prefetch_replacement(package_name: &str, installed: &[InstalledPackageInfo], keep_downloads: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let mut downloaded = Vec::new();
+    for info in replacement_packages(package_name, installed, ctx)? {
+        let was_cached = ctx.host_cache_dir.join(archive_file_name(info)).exists();
+        match fetch_archive(info, ctx, &mut InstallTimings::default()).await {
+            Ok(archive_path) if !was_cached => downloaded.push(archive_path),
+            Ok(_) => {}
+            Err(e) => {
+                discard_prefetched(&downloaded, keep_downloads, ctx).await;
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The index version of `package_name` and whatever new it depends on, i.e. what replacing the
+// installed version would install.
+fn replacement_packages<'a>(package_name: &str, installed: &[InstalledPackageInfo], ctx: &'a AppContext) -> Result<Vec<&'a PackageInfo>, FluxError> {
+    let without: Vec<InstalledPackageInfo> = installed.iter().filter(|p| p.name != package_name).cloned().collect();
+    let plan = plan_install(&[package_name.to_string()], &InstallOptions::default(), &without, ctx)?;
+    Ok(plan.packages.iter()
+        .filter(|planned| planned.action == PlanAction::Install)
+        .map(|planned| &ctx.package_index[&planned.name])
+        .collect())
+}
+
+// Downloads every archive the available upgrades would need without applying any of them, so a
+// later `upgrade --use-cached` doesn't touch the network.
+async fn handle_prefetch_updates(ctx: &AppContext) -> Result<(), FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let candidates = select_upgrades(&[], &UpgradeOptions::default(), &installed, ctx)?;
+    if candidates.is_empty() {
+        println!("All packages are up to date.");
+        return Ok(());
+    }
+    let mut needed: Vec<&PackageInfo> = Vec::new();
+    for candidate in &candidates {
+        for info in replacement_packages(&candidate.installed.name, &installed, ctx)? {
+            if !needed.iter().any(|n| n.name == info.name) {
+                needed.push(info);
+            }
+        }
+    }
+
+    let mut to_fetch = Vec::new();
+    let mut cached = 0;
+    for info in needed {
+        if cached_archive(info, ctx).await?.is_some() {
+            cached += 1;
+        } else if ctx.dry_run {
+            println!("[dry-run] would download {} {} from {}", info.name, info.version, ctx.package_url(&info.url)?);
+        } else {
+            to_fetch.push(info);
+        }
+    }
+    if ctx.dry_run {
+        return Ok(());
+    }
+
+    let sizes: Vec<u64> = futures_util::stream::iter(&to_fetch)
+        .map(|info| async move {
+            let archive_path = fetch_archive(info, ctx, &mut InstallTimings::default()).await?;
+            Ok::<_, FluxError>(fs::metadata(&archive_path).await?.len())
+        })
+        .buffer_unordered(ctx.jobs)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+    println!("Prefetched {} archive(s) ({}) for {} upgrade(s); {} were already cached.",
+        sizes.len(), indicatif::HumanBytes(sizes.iter().sum()), candidates.len(), cached);
+    Ok(())
+}
+
+async fn discard_prefetched(downloaded: &[PathBuf], keep_downloads: bool, ctx: &AppContext) {
+    if !(keep_downloads || ctx.config.keep_downloads) {
+        for archive_path in downloaded {
+            let _ = fs::remove_file(archive_path).await;
+        }
+    }
+}
+
+// Pins `package_name` to `version` for this run and swaps it in place, so dependents stay installed.
+async fn handle_downgrade(package_name: &str, version: &str, keep_downloads: bool, ctx: &mut AppContext) -> Result<(), FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let pkg = installed.iter().find(|p| p.name == package_name)
+        .ok_or_else(|| FluxError::PackageNotFound(format!("{} (not installed)", package_name)))?;
+    let available = ctx.package_versions.get(package_name).map(Vec::as_slice).unwrap_or_default();
+    let Some(info) = available.iter().find(|info| info.version == version).cloned() else {
+        let versions: Vec<&str> = available.iter().map(|info| info.version.as_str()).collect();
+        let has = if versions.is_empty() { "no version of it".to_string() } else { versions.join(", ") };
+        return Err(FluxError::PackageNotFound(format!("{} {} (the index has {})", package_name, version, has)));
+    };
+    let refused = |reason: String| FluxError::DowngradeRefused { package_name: package_name.to_string(), reason };
+    match compare_versions(version, &pkg.version) {
+        std::cmp::Ordering::Equal => {
+            println!("{} {} is already installed.", package_name, version);
+            return Ok(());
+        }
+        std::cmp::Ordering::Greater => return Err(refused(format!("{} is newer than the installed {}; use 'flux upgrade'", version, pkg.version))),
+        std::cmp::Ordering::Less => {}
+    }
+    for dependent in find_dependents(package_name, &installed, ctx) {
+        let specs = ctx.package_index[&dependent].dependencies.iter().flatten().filter_map(|dep| DependencySpec::parse(dep).ok());
+        for spec in specs.filter(|spec| spec.name == package_name) {
+            if spec.constraint.as_ref().is_some_and(|constraint| !constraint.matches(version)) {
+                return Err(refused(format!("the installed '{}' requires {}", dependent, spec)));
+            }
+        }
+    }
+
+    println!("{} {} will be replaced by {}.", package_name, pkg.version, version);
+    if !confirm_changes(ctx)? {
+        println!("Aborted.");
+        return Ok(());
+    }
+    let reasons = HashMap::from([(package_name.to_string(), pkg.install_reason.clone())]);
+    ctx.package_index.insert(package_name.to_string(), info);
+    if ctx.dry_run {
+        let without: Vec<InstalledPackageInfo> = installed.iter().filter(|p| p.name != package_name).cloned().collect();
+        let plan = plan_install(&[package_name.to_string()], &InstallOptions::default(), &without, ctx)?;
+        let to_install: Vec<PackageInfo> = plan.packages.iter()
+            .filter(|planned| planned.action == PlanAction::Install)
+            .map(|planned| ctx.package_index[&planned.name].clone())
+            .collect();
+        println!("[dry-run] would remove the files of the installed {} {}", package_name, pkg.version);
+        return print_dry_run_install(&to_install, ctx);
+    }
+    prefetch_replacement(package_name, &installed, keep_downloads, ctx).await?;
+    apply_package_changes(Vec::new(), vec![package_name.to_string()], Vec::new(), &reasons, keep_downloads, ctx).await?;
+    println!("Downgraded {} to {}.", package_name, version);
+    Ok(())
+}
+
+// Dependencies nothing requires any more, including those only required by other orphans. Repeats
+// until nothing changes, so the result is in a safe removal order: dependents before their dependencies.
+// Both `autoremove` and `list --orphans` use this.
+fn find_orphans(installed: &[InstalledPackageInfo], ctx: &AppContext) -> Vec<String> {
+    orphan_closure(installed, &[], &HashSet::new(), ctx)
+}
+
+// The packages that become orphaned once `removing` is gone. Packages in `keep` are never
+// reported, but still count as requiring their dependencies.
+fn orphan_closure(installed: &[InstalledPackageInfo], removing: &[String], keep: &HashSet<String>, ctx: &AppContext) -> Vec<String> {
+    let mut orphans: Vec<String> = Vec::new();
+    loop {
+        let remaining = installed.iter().filter(|pkg| !orphans.contains(&pkg.name) && !removing.contains(&pkg.name));
+        let mut required_deps = HashSet::new();
+        for pkg in remaining.clone() {
+            if let Some(info) = ctx.package_index.get(&pkg.name) {
+                required_deps.extend(info.dependency_names());
+            }
+        }
+
+        let new_orphans: Vec<String> = remaining
+            .filter(|pkg| pkg.install_reason == InstallReason::Dependency && !required_deps.contains(pkg.name.as_str()) && !keep.contains(&pkg.name))
+            .map(|pkg| pkg.name.clone())
+            .collect();
+        if new_orphans.is_empty() {
+            return orphans;
+        }
+        orphans.extend(new_orphans);
+    }
+}
+
+async fn handle_autoremove(ctx: &AppContext) -> Result<(), FluxError> {
+    println!("Checking for unused dependencies...");
+    let installed = ctx.get_installed_packages().await?;
+    let orphans_to_remove = find_orphans(&installed, ctx);
+
+    if orphans_to_remove.is_empty() {
+        println!("No unused dependencies to remove.");
+        return Ok(());
+    }
+
+    println!("\nThe following packages are no longer required and will be removed:");
+    for orphan in &orphans_to_remove {
+        println!("- {}", orphan);
+    }
+    if !confirm_changes(ctx)? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    println!("\nRemoving unused dependencies...");
+    let mut installed = installed;
+    let mut removed = Vec::new();
+    for package_name in orphans_to_remove {
+        removed.push(remove_installed_package(&package_name, &mut installed, ctx).await?);
+    }
+
+    run_remove_hooks(&removed, ctx)
+}
+
+async fn handle_mark(package_name: &str, reason: InstallReason, ctx: &AppContext) -> Result<(), FluxError> {
+    let mut installed = ctx.get_installed_packages().await?;
+    let pkg = installed.iter_mut().find(|p| p.name == package_name)
+        .ok_or_else(|| FluxError::PackageNotFound(format!("{} (not installed)", package_name)))?;
+
+    if pkg.install_reason == reason {
+        println!("'{}' is already marked as {:?}.", package_name, reason);
+        return Ok(());
+    }
+    pkg.install_reason = reason.clone();
+    if ctx.dry_run {
+        println!("[dry-run] would mark '{}' as {:?}", package_name, reason);
+        return Ok(());
+    }
+    ctx.write_installed_packages(&installed).await?;
+    println!("Marked '{}' as {:?}.", package_name, reason);
+
+    if reason == InstallReason::Dependency && find_dependents(package_name, &installed, ctx).is_empty() {
+        println!("Note: no installed package depends on '{}', so 'flux autoremove' will now remove it.", package_name);
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct StatusReport {
+    upgradable: Vec<String>,
+    orphans: Vec<String>,
+    index_age_hours: Option<u64>,
+    index_stale: bool,
+    integrity_problems: Vec<String>,
+}
+
+// Cheap existence checks only; this must stay fast enough to run from monitoring.
+fn quick_integrity_check(installed: &[InstalledPackageInfo], ctx: &AppContext) -> Vec<String> {
+    let mut problems = Vec::new();
+    for pkg in installed {
+        match pkg.package_type {
+            PackageType::App => {
+                let install_path = ctx.get_installed_path(pkg);
+                if !install_path.exists() {
+                    problems.push(format!("{}: missing install directory {}", pkg.name, install_path.display()));
+                }
+            }
+            PackageType::System => {
+                let missing = pkg.files.iter()
+                    .filter_map(|f| sanitize_relative_path(&f.path))
+                    .filter(|f| std::fs::symlink_metadata(ctx.target_root.join(f)).is_err())
+                    .count();
+                if missing > 0 {
+                    problems.push(format!("{}: {} missing file(s)", pkg.name, missing));
+                }
+            }
+        }
+    }
+    problems
+}
+
+async fn status_report(installed: &[InstalledPackageInfo], ctx: &AppContext) -> Result<StatusReport, FluxError> {
+    let index_age_hours = index_age_hours(ctx).await?;
+    let ttl = ctx.config.index_ttl_hours.unwrap_or(DEFAULT_INDEX_TTL_HOURS);
+
+    Ok(StatusReport {
+        upgradable: plan_upgrades(installed, ctx).iter().filter(|c| !c.is_downgrade()).map(|c| c.installed.name.clone()).collect(),
+        orphans: find_orphans(installed, ctx),
+        index_age_hours,
+        index_stale: index_age_hours.is_none_or(|age| age >= ttl),
+        integrity_problems: quick_integrity_check(installed, ctx),
+    })
+}
+
+async fn handle_status(format: OutputFormat, ctx: &AppContext) -> Result<(), FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let report = status_report(&installed, ctx).await?;
+
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("Installed packages: {}", installed.len());
+    println!("Upgradable packages: {}", report.upgradable.len());
+    println!("Autoremovable orphans: {}", report.orphans.len());
+    match report.index_age_hours {
+        Some(age) if report.index_stale => println!("Repository index: stale ({}h old), run 'flux update'", age),
+        Some(age) => println!("Repository index: up to date ({}h old)", age),
+        None => println!("Repository index: missing, run 'flux update'"),
+    }
+    if report.integrity_problems.is_empty() {
+        println!("Integrity: no problems found");
+    } else {
+        println!("Integrity problems:");
+        for problem in &report.integrity_problems {
+            println!("- {}", problem);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct PackageDetails<'a> {
+    #[serde(flatten)]
+    info: &'a PackageInfo,
+    dependency_closure: Vec<String>,
+    // None when any package in the closure has no size in the index.
+    total_download_size: Option<u64>,
+    total_installed_size: Option<u64>,
+    installed_version: Option<String>,
+    install_reason: Option<InstallReason>,
+    upgrade_available: bool,
+}
+
+const SEARCH_DESCRIPTION_WIDTH: usize = 60;
+
+// Index packages whose name or description contains `query`, ignoring case, sorted by name.
+fn search_index<'a>(query: &str, ctx: &'a AppContext) -> Vec<&'a PackageInfo> {
+    let needle = query.to_lowercase();
+    let mut matches: Vec<&PackageInfo> = ctx.package_index.values()
+        .filter(|info| info.name.to_lowercase().contains(&needle) || info.description.to_lowercase().contains(&needle))
+        .collect();
+    matches.sort_by(|a, b| a.name.cmp(&b.name));
+    matches
+}
+
+fn search_result_line(info: &PackageInfo) -> String {
+    let description = match info.description.char_indices().nth(SEARCH_DESCRIPTION_WIDTH) {
+        Some((cut, _)) => format!("{}...", info.description[..cut].trim_end()),
+        None => info.description.clone(),
+    };
+    format!("{} {} - {}", info.name, info.version, description)
+}
+
+fn handle_search(query: &str, format: OutputFormat, json_lines: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let matches = search_index(query, ctx);
+    if json_lines {
+        return write_json_lines(matches);
+    }
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&matches)?);
+        return Ok(());
+    }
+    if matches.is_empty() {
+        println!("No packages match '{}'. Run 'flux update' if the index may be out of date.", query);
+        return Ok(());
+    }
+    for info in matches {
+        println!("{}", search_result_line(info));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct DependentMatch<'a> {
+    name: &'a str,
+    version: &'a str,
+    // The dependency as declared, with its version constraint if there is one.
+    requirement: &'a str,
+    optional: bool,
+    installed: bool,
+}
+
+// Index packages that declare `dependency`, one match per declaration, sorted by name with hard
+// dependencies before optional ones.
+fn dependents_in_index<'a>(dependency: &str, installed_only: bool, installed: &[InstalledPackageInfo], ctx: &'a AppContext) -> Vec<DependentMatch<'a>> {
+    let mut matches = Vec::new();
+    for info in ctx.package_index.values() {
+        let is_installed = installed.iter().any(|p| p.name == info.name);
+        if installed_only && !is_installed { continue; }
+        let declared = info.dependencies.iter().flatten().map(|d| (d, false))
+            .chain(info.optional_dependencies.iter().flatten().map(|d| (d, true)));
+        for (requirement, optional) in declared {
+            if DependencySpec::name_of(requirement) == dependency {
+                matches.push(DependentMatch { name: &info.name, version: &info.version, requirement, optional, installed: is_installed });
+            }
+        }
+    }
+    matches.sort_by(|a, b| a.name.cmp(b.name).then(a.optional.cmp(&b.optional)));
+    matches
+}
+
+async fn handle_search_by_dependency(dependency: &str, installed_only: bool, format: OutputFormat, json_lines: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let matches = dependents_in_index(dependency, installed_only, &installed, ctx);
+    if json_lines {
+        return write_json_lines(matches);
+    }
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&matches)?);
+        return Ok(());
+    }
+    if matches.is_empty() {
+        let scope = if installed_only { "No installed packages" } else { "No packages in the index" };
+        println!("{} depend on '{}'.", scope, dependency);
+        return Ok(());
+    }
+    for m in &matches {
+        let mut notes = Vec::new();
+        if m.optional { notes.push("optional"); }
+        if m.installed { notes.push("installed"); }
+        let notes = if notes.is_empty() { String::new() } else { format!(" [{}]", notes.join(", ")) };
+        println!("{} {} requires {}{}", m.name, m.version, m.requirement.trim(), notes);
+    }
+    Ok(())
+}
+
+// Everything below `dir`, sorted, without following symlinks.
+fn walk_dir(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                pending.push(entry.path());
+            }
+            found.push(entry.path());
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+async fn installed_file_paths(pkg: &InstalledPackageInfo, ctx: &AppContext) -> Result<Vec<PathBuf>, FluxError> {
+    let root = ctx.get_installed_path(pkg);
+    // Apps recovered by rebuild-db have no file list, but own their whole directory.
+    if pkg.files.is_empty() && pkg.package_type == PackageType::App {
+        return Ok(tokio::task::spawn_blocking(move || walk_dir(&root)).await.unwrap()?);
+    }
+    Ok(pkg.files.iter()
+        .filter_map(|file| sanitize_relative_path(&file.path).filter(|p| !p.as_os_str().is_empty()))
+        .map(|relative_path| root.join(relative_path))
+        .collect())
+}
+
+async fn handle_files(package_name: &str, ctx: &AppContext) -> Result<(), FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let pkg = installed.iter().find(|p| p.name == package_name)
+        .ok_or_else(|| FluxError::PackageNotFound(format!("{} (not installed)", package_name)))?;
+    for path in installed_file_paths(pkg, ctx).await? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+// `path` relative to the target root, with `.` and `..` resolved lexically.
+fn root_relative_path(path: &Path, root: &Path) -> Result<PathBuf, FluxError> {
+    use std::path::Component;
+    let absolute = if path.is_absolute() { path.to_path_buf() } else { std::env::current_dir()?.join(path) };
+    let within_root = absolute.strip_prefix(root).unwrap_or(&absolute);
+    let mut clean = PathBuf::new();
+    for component in within_root.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::ParentDir => { clean.pop(); }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    Ok(clean)
+}
+
+fn owners_of<'a>(wanted: &Path, installed: &'a [InstalledPackageInfo], ctx: &AppContext) -> Vec<&'a InstalledPackageInfo> {
+    let mut owners = Vec::new();
+    for pkg in installed {
+        // App files are recorded relative to the App's own directory.
+        let base = ctx.get_installed_path(pkg);
+        let base = base.strip_prefix(&ctx.target_root).unwrap_or(&base);
+        if pkg.files.iter().filter_map(|f| sanitize_relative_path(&f.path)).any(|f| base.join(f) == wanted) {
+            owners.push(pkg);
+        }
+    }
+    owners
+}
+
+async fn handle_owns(path: &Path, ctx: &AppContext) -> Result<(), FluxError> {
+    let wanted = root_relative_path(path, &ctx.target_root)?;
+    let shown = Path::new("/").join(&wanted);
+    let installed = ctx.get_installed_packages().await?;
+    let owners = owners_of(&wanted, &installed, ctx);
+    if owners.is_empty() {
+        println!("{} is not owned by any installed package.", shown.display());
+        return Ok(());
+    }
+    for pkg in owners {
+        println!("{} {}: {}", pkg.name, pkg.version, shown.display());
+    }
+    Ok(())
+}
+
+async fn package_details<'a>(package_name: &str, ctx: &'a AppContext) -> Result<PackageDetails<'a>, FluxError> {
+    let info = ctx.package_index.get(package_name).ok_or_else(|| FluxError::PackageNotFound(package_name.to_string()))?;
+
+    let mut closure = Vec::new();
+    resolve_dependencies(package_name, ctx, &mut closure, &mut Requirements::new())?;
+    let closure_infos: Vec<&PackageInfo> = closure.iter().filter_map(|name| ctx.package_index.get(name)).collect();
+    let total_download_size = closure_infos.iter().map(|p| p.size).sum();
+    let total_installed_size = closure_infos.iter().map(|p| p.installed_size).sum();
+
+    let mut dependency_closure: Vec<String> = closure.into_iter().filter(|name| name != package_name).collect();
+    dependency_closure.sort();
+
+    let installed = ctx.get_installed_packages().await?;
+    let installed_package = installed.iter().find(|p| p.name == package_name);
+    let installed_version = installed_package.map(|p| p.version.clone());
+    let install_reason = installed_package.map(|p| p.install_reason.clone());
+    let upgrade_available = installed_version.as_deref().is_some_and(|v| compare_versions(&info.version, v).is_gt());
+
+    Ok(PackageDetails { info, dependency_closure, total_download_size, total_installed_size, installed_version, install_reason, upgrade_available })
+}
+
+async fn handle_info(package_name: &str, json: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let details = package_details(package_name, ctx).await?;
+    let info = details.info;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&details)?);
+        return Ok(());
+    }
+
+    let format_size = |size: Option<u64>| size.map_or("unknown".to_string(), |bytes| format!("{} bytes", bytes));
+    println!("Name: {}", info.name);
+    println!("Version: {}", info.version);
+    println!("Type: {:?}", info.package_type);
+    println!("Description: {}", info.description);
+    let or_none = |url: &str| if url.is_empty() { "none".to_string() } else { url.to_string() };
+    println!("Changelog: {}", or_none(&info.changelog_url));
+    println!("Icon: {}", or_none(&info.icon_url));
+    println!("Dependencies: {}", info.dependencies.as_ref().filter(|d| !d.is_empty()).map_or("none".to_string(), |d| d.join(", ")));
+    println!("Dependency closure: {}", if details.dependency_closure.is_empty() { "none".to_string() } else { details.dependency_closure.join(", ") });
+    println!("Download size (with dependencies): {}", format_size(details.total_download_size));
+    println!("Installed size (with dependencies): {}", format_size(details.total_installed_size));
+    match &details.installed_version {
+        Some(version) if details.upgrade_available => println!("Installed: yes ({}, upgrade available)", version),
+        Some(version) => println!("Installed: yes ({})", version),
+        None => println!("Installed: no"),
+    }
+    if let Some(reason) = &details.install_reason {
+        println!("Install reason: {:?}", reason);
+    }
+    Ok(())
+}
+
+async fn export_installed_index(with_checksums: bool, url_base: Option<&str>, ctx: &AppContext) -> Result<PackageIndex, FluxError> {
+    let mut installed = ctx.get_installed_packages().await?;
+    installed.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut packages = Vec::new();
+    for pkg in &installed {
+        // Index metadata only describes the installed package if the versions still agree.
+        let mut info = match ctx.package_index.get(&pkg.name) {
+            Some(info) if info.version == pkg.version => info.clone(),
+            _ => PackageInfo {
+                name: pkg.name.clone(),
+                package_type: pkg.package_type.clone(),
+                version: pkg.version.clone(),
+                url: String::new(),
+                checksum: String::new(),
+                dependencies: None,
+                optional_dependencies: None,
+                conflicts: None,
+                description: String::new(),
+                icon_url: String::new(),
+                changelog_url: String::new(),
+                post_install: None,
+                pre_remove: pkg.remove_scripts.as_ref().and_then(|s| s.pre_remove.clone()),
+                post_remove: pkg.remove_scripts.as_ref().and_then(|s| s.post_remove.clone()),
+                size: None,
+                installed_size: None,
+                privileged_scripts: pkg.remove_scripts.as_ref().is_some_and(|s| s.privileged),
+                arch: None,
+                compression: None,
+            },
+        };
+
+        if with_checksums {
+            let archive_path = ctx.host_cache_dir.join(archive_file_name(&info));
+            if archive_path.exists() {
+                info.checksum = compute_checksum(&archive_path, &info.checksum, ctx.downloader.buffer_size).await?;
+            } else if info.checksum.is_empty() {
+                eprintln!("Warning: no checksum known for {} {} and no cached archive to compute one from.", info.name, info.version);
+            }
+        } else {
+            info.checksum.clear();
+        }
+        if let Some(base) = url_base {
+            info.url = format!("{}/{}", base.trim_end_matches('/'), archive_file_name(&info));
+        }
+        packages.push(info);
+    }
+
+    Ok(PackageIndex { packages })
+}
+
+async fn handle_export_installed(with_checksums: bool, url_base: Option<&str>, format: IndexFormat, json_lines: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let index = export_installed_index(with_checksums, url_base, ctx).await?;
+    if json_lines {
+        return write_json_lines(&index.packages);
+    }
+    match format {
+        IndexFormat::Yaml => print!("{}", serde_yaml::to_string(&index)?),
+        IndexFormat::Json => println!("{}", serde_json::to_string_pretty(&index)?),
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct GraphNode {
+    id: String,
+    version: String,
+    #[serde(rename = "type")]
+    package_type: PackageType,
+    install_reason: Option<InstallReason>,
+    // The package is no longer in the index, so its edges are unknown.
+    unknown_deps: bool,
+}
+
+#[derive(Serialize)]
+struct GraphEdge {
+    source: String,
+    target: String,
+}
+
+#[derive(Serialize)]
+struct DependencyGraph {
+    nodes: Vec<GraphNode>,
+    edges: Vec<GraphEdge>,
+}
+
+impl DependencyGraph {
+    fn to_dot(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut dot = String::from("digraph flux {\n");
+        for node in &self.nodes {
+            let mut attrs = vec![
+                format!("label={}", quote(&format!("{} {}", node.id, node.version))),
+                format!("type={}", quote(&format!("{:?}", node.package_type).to_lowercase())),
+            ];
+            if node.package_type == PackageType::App {
+                attrs.push("shape=box".to_string());
+            }
+            if let Some(reason) = &node.install_reason {
+                attrs.push(format!("reason={}", quote(&format!("{:?}", reason).to_lowercase())));
+            }
+            if node.unknown_deps {
+                attrs.push("unknown_deps=true".to_string());
+                attrs.push("style=dashed".to_string());
+            }
+            dot.push_str(&format!("  {} [{}];\n", quote(&node.id), attrs.join(", ")));
+        }
+        for edge in &self.edges {
+            dot.push_str(&format!("  {} -> {};\n", quote(&edge.source), quote(&edge.target)));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+async fn dependency_graph(package: Option<&str>, ctx: &AppContext) -> Result<DependencyGraph, FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let reasons: HashMap<&str, &InstallReason> = installed.iter().map(|p| (p.name.as_str(), &p.install_reason)).collect();
+
+    let mut graph = DependencyGraph { nodes: Vec::new(), edges: Vec::new() };
+    match package {
+        Some(package_name) => {
+            let mut closure = Vec::new();
+            resolve_dependencies(package_name, ctx, &mut closure, &mut Requirements::new())?;
+            let mut names: Vec<String> = closure.into_iter().collect();
+            names.sort();
+            for name in names {
+                let info = &ctx.package_index[&name];
+                graph.nodes.push(GraphNode {
+                    id: name.clone(),
+                    version: info.version.clone(),
+                    package_type: info.package_type.clone(),
+                    install_reason: reasons.get(name.as_str()).map(|&r| r.clone()),
+                    unknown_deps: false,
+                });
+                for dep in info.dependency_names() {
+                    graph.edges.push(GraphEdge { source: name.clone(), target: dep.to_string() });
+                }
+            }
+        }
+        None => {
+            let mut installed_sorted: Vec<&InstalledPackageInfo> = installed.iter().collect();
+            installed_sorted.sort_by(|a, b| a.name.cmp(&b.name));
+            for pkg in installed_sorted {
+                let info = ctx.package_index.get(&pkg.name);
+                graph.nodes.push(GraphNode {
+                    id: pkg.name.clone(),
+                    version: pkg.version.clone(),
+                    package_type: pkg.package_type.clone(),
+                    install_reason: Some(pkg.install_reason.clone()),
+                    unknown_deps: info.is_none(),
+                });
+                for dep in info.into_iter().flat_map(|i| i.dependency_names()) {
+                    graph.edges.push(GraphEdge { source: pkg.name.clone(), target: dep.to_string() });
+                }
+            }
+        }
+    }
+    Ok(graph)
+}
+
+async fn handle_tree(package: Option<&str>, format: GraphFormat, ctx: &AppContext) -> Result<(), FluxError> {
+    let graph = dependency_graph(package, ctx).await?;
+    match format {
+        GraphFormat::Dot => print!("{}", graph.to_dot()),
+        GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph)?),
+    }
+    Ok(())
+}
+
+enum CachedArchiveStatus {
+    Valid,
+    Corrupt { expected: String, found: String },
+    Unknown,
+}
+
+async fn classify_cached_archives(ctx: &AppContext) -> Result<Vec<(PathBuf, CachedArchiveStatus)>, FluxError> {
+    let by_file_name: HashMap<String, &PackageInfo> = ctx.package_index.values()
+        .map(|info| (archive_file_name(info), info))
+        .collect();
+
+    let mut archives = Vec::new();
+    let mut entries = fs::read_dir(&ctx.host_cache_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if !Compression::ALL.iter().any(|c| file_name.ends_with(c.extension())) { continue; }
+        archives.push((entry.path(), by_file_name.get(&file_name).copied()));
+    }
+
+    let buffer_size = ctx.downloader.buffer_size;
+    let mut results: Vec<(PathBuf, CachedArchiveStatus)> = futures_util::stream::iter(archives)
+        .map(|(path, info)| async move {
+            let status = match info {
+                None => CachedArchiveStatus::Unknown,
+                Some(info) => {
+                    let found = compute_checksum(&path, &info.checksum, buffer_size).await?;
+                    if found == info.checksum {
+                        CachedArchiveStatus::Valid
+                    } else {
+                        CachedArchiveStatus::Corrupt { expected: info.checksum.clone(), found }
+                    }
+                }
+            };
+            Ok::<_, FluxError>((path, status))
+        })
+        .buffer_unordered(ctx.jobs)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(results)
+}
+
+// Refuses to go on with a cache that contains archives not matching the index (`verify_cache_on_start`).
+async fn verify_cache_on_start(ctx: &AppContext) -> Result<(), FluxError> {
+    if ctx.package_index.is_empty() { return Ok(()); }
+    let corrupt: Vec<String> = classify_cached_archives(ctx).await?.into_iter()
+        .filter(|(_, status)| matches!(status, CachedArchiveStatus::Corrupt { .. }))
+        .map(|(path, _)| path.file_name().unwrap_or_default().to_string_lossy().to_string())
+        .collect();
+    if corrupt.is_empty() { return Ok(()); }
+    Err(FluxError::CorruptCache(corrupt.join(", ")))
+}
+
+async fn handle_clean(ctx: &AppContext) -> Result<(), FluxError> {
+    let mut entries = match fs::read_dir(&ctx.host_cache_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No cached archives found.");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut archives = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if !Compression::ALL.iter().any(|c| file_name.ends_with(c.extension())) { continue; }
+        if !entry.file_type().await?.is_file() { continue; }
+        archives.push((entry.path(), file_name, entry.metadata().await?.len()));
+    }
+    if archives.is_empty() {
+        println!("No cached archives found.");
+        return Ok(());
+    }
+    archives.sort();
+
+    let mut freed = 0;
+    for (path, file_name, size) in &archives {
+        if ctx.dry_run {
+            println!("[dry-run] would delete {} ({})", file_name, indicatif::HumanBytes(*size));
+        } else {
+            fs::remove_file(path).await?;
+            println!("Deleted {} ({})", file_name, indicatif::HumanBytes(*size));
+        }
+        freed += size;
+    }
+    if ctx.dry_run {
+        println!("[dry-run] would free {} in {} file(s).", indicatif::HumanBytes(freed), archives.len());
+    } else {
+        println!("Freed {} in {} file(s).", indicatif::HumanBytes(freed), archives.len());
+    }
+    Ok(())
+}
+
+async fn handle_cache_verify(prune: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    println!("Verifying cached archives in {}...", ctx.host_cache_dir.display());
+    let results = classify_cached_archives(ctx).await?;
+    if results.is_empty() {
+        println!("No cached archives found.");
+        return Ok(());
+    }
+
+    let (mut valid, mut corrupt, mut unknown) = (0, 0, 0);
+    for (path, status) in &results {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        match status {
+            CachedArchiveStatus::Valid => valid += 1,
+            CachedArchiveStatus::Corrupt { expected, found } => {
+                corrupt += 1;
+                println!("- {}: checksum mismatch (expected {}, found {})", file_name, expected, found);
+                if prune {
+                    fs::remove_file(path).await?;
+                    println!("  Deleted {}", file_name);
+                }
+            }
+            CachedArchiveStatus::Unknown => {
+                unknown += 1;
+                println!("- {}: no matching package in the index", file_name);
+            }
+        }
+    }
+
+    println!("\n{} valid, {} corrupt, {} not in the index.", valid, corrupt, unknown);
+    Ok(())
+}
+
+enum FileProblem {
+    Missing,
+    Modified,
+}
+
+// (package, absolute path, problem) for every recorded file of `packages`. Without `checksums` only
+// presence is checked.
+async fn check_installed_files(packages: &[&InstalledPackageInfo], checksums: bool, ctx: &AppContext) -> Vec<(String, PathBuf, Option<FileProblem>)> {
+    let mut work = Vec::new();
+    for pkg in packages {
+        let root = match pkg.package_type {
+            PackageType::App => ctx.get_installed_path(pkg),
+            PackageType::System => ctx.target_root.clone(),
+        };
+        for file in &pkg.files {
+            let Some(relative_path) = sanitize_relative_path(&file.path).filter(|p| !p.as_os_str().is_empty()) else { continue };
+            let expected = file.sha256.clone().filter(|_| checksums);
+            work.push((pkg.name.clone(), root.join(relative_path), expected));
+        }
+    }
+
+    futures_util::stream::iter(work)
+        .map(|(name, path, expected)| async move {
+            tokio::task::spawn_blocking(move || {
+                let problem = match std::fs::symlink_metadata(&path) {
+                    Err(_) => Some(FileProblem::Missing),
+                    Ok(metadata) => match expected {
+                        Some(expected) if metadata.is_file() => match hash_file_sync(&path) {
+                            Ok(found) if found == expected => None,
+                            _ => Some(FileProblem::Modified),
+                        },
+                        _ => None,
+                    },
+                };
+                (name, path, problem)
+            }).await.unwrap()
+        })
+        .buffered(ctx.jobs)
+        .collect()
+        .await
+}
+
+async fn handle_verify(package: Option<&str>, checksums: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let installed = ctx.get_installed_packages().await?;
+    let mut packages: Vec<&InstalledPackageInfo> = match package {
+        Some(name) => vec![installed.iter().find(|p| p.name == name)
+            .ok_or_else(|| FluxError::PackageNotFound(format!("{} (not installed)", name)))?],
+        None => installed.iter().collect(),
+    };
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let unhashed = packages.iter().filter(|p| checksums && p.files.iter().all(|f| f.sha256.is_none()) && !p.files.is_empty()).count();
+    let results = check_installed_files(&packages, checksums, ctx).await;
+
+    let (mut missing, mut modified) = (0, 0);
+    let mut failed_packages = Vec::new();
+    for (name, path, problem) in &results {
+        let Some(problem) = problem else { continue };
+        let label = match problem {
+            FileProblem::Missing => { missing += 1; "missing" }
+            FileProblem::Modified => { modified += 1; "modified" }
+        };
+        println!("- {}: {} {}", name, label, path.display());
+        if !failed_packages.contains(name) {
+            failed_packages.push(name.clone());
+        }
+    }
+
+    if unhashed > 0 {
+        println!("Note: {} package(s) were installed before per-file hashes were recorded; only their file presence was checked.", unhashed);
+    }
+    println!("Checked {} file(s) of {} package(s).", results.len(), packages.len());
+    if failed_packages.is_empty() {
+        println!("All files are intact.");
+        return Ok(());
+    }
+    Err(FluxError::VerificationFailed(format!("{} missing and {} modified file(s) in {}", missing, modified, failed_packages.join(", "))))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+enum SignatureStatus {
+    Valid { key: String },
+    // Made by a trusted key, but not over this archive.
+    Invalid,
+    // Made by a key that is not (or no longer) in trusted_keys.
+    UntrustedKey,
+    Unsigned,
+    Unavailable { reason: String },
+}
+
+#[derive(Serialize)]
+struct SignatureReport {
+    name: String,
+    version: String,
+    #[serde(flatten)]
+    status: SignatureStatus,
+}
+
+fn load_trusted_keys(ctx: &AppContext) -> Result<Vec<(String, minisign_verify::PublicKey)>, FluxError> {
+    if ctx.config.trusted_keys.is_empty() {
+        return Err(FluxError::Config("No trusted_keys configured in flux.conf".to_string()));
+    }
+    ctx.config.trusted_keys.iter().map(|path| {
+        let key = minisign_verify::PublicKey::from_file(path)
+            .map_err(|e| FluxError::Config(format!("Could not load public key {}: {}", path.display(), e)))?;
+        Ok((path.display().to_string(), key))
+    }).collect()
+}
+
+fn verify_archive_signature(archive_path: &Path, signature_path: &Path, keys: &[(String, minisign_verify::PublicKey)], buffer_size: usize) -> Result<SignatureStatus, FluxError> {
+    use std::io::Read;
+    let Ok(signature) = minisign_verify::Signature::from_file(signature_path) else {
+        return Ok(SignatureStatus::Invalid);
+    };
+    for (key_name, key) in keys {
+        let mut verifier = match key.verify_stream(&signature) {
+            Ok(verifier) => verifier,
+            Err(minisign_verify::Error::UnexpectedKeyId) => continue,
+            Err(_) => return Ok(SignatureStatus::Invalid),
+        };
+        let mut file = std::fs::File::open(archive_path)?;
+        let mut buffer = vec![0; buffer_size];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 { break; }
+            verifier.update(&buffer[..n]);
+        }
+        return Ok(match verifier.finalize() {
+            Ok(()) => SignatureStatus::Valid { key: key_name.clone() },
+            Err(_) => SignatureStatus::Invalid,
+        });
+    }
+    Ok(SignatureStatus::UntrustedKey)
+}
+
+fn signature_file_name(info: &PackageInfo) -> String {
+    format!("{}.minisig", archive_file_name(info))
+}
+
+// Uses the cached archive and signature when present and downloads whatever is missing.
+async fn check_package_signature(pkg: &InstalledPackageInfo, keys: &[(String, minisign_verify::PublicKey)], ctx: &AppContext) -> Result<SignatureStatus, FluxError> {
+    let Some(info) = ctx.indexed_version(&pkg.name, &pkg.version) else {
+        return Ok(SignatureStatus::Unavailable { reason: "this version is no longer in the index".to_string() });
+    };
+    let archive_path = ctx.host_cache_dir.join(archive_file_name(info));
+    let signature_path = ctx.host_cache_dir.join(signature_file_name(info));
+
+    let downloaded_archive = !archive_path.exists();
+    if downloaded_archive {
+        // Quietly, unlike download_verified, so --format json output stays parseable.
+        let partial_path = partial_download_path(&archive_path);
+        let fetched = async {
+            ctx.downloader.download_file(&ctx.package_url(&info.url)?, &partial_path).await?;
+            compute_checksum(&partial_path, &info.checksum, ctx.downloader.buffer_size).await
+        }.await;
+        match fetched {
+            Ok(checksum) if checksum == info.checksum => fs::rename(&partial_path, &archive_path).await?,
+            result => {
+                let _ = fs::remove_file(&partial_path).await;
+                let reason = result.map_or_else(|e| e.to_string(), |_| "the downloaded archive does not match the index checksum".to_string());
+                return Ok(SignatureStatus::Unavailable { reason });
+            }
+        }
+    }
+    let status = async {
+        if !signature_path.exists() {
+            let signature_url = ctx.package_url(&format!("{}.minisig", info.url))?;
+            if ctx.downloader.download_file(&signature_url, &signature_path).await.is_err() {
+                let _ = fs::remove_file(&signature_path).await;
+                return Ok(SignatureStatus::Unsigned);
+            }
+        }
+        let (archive, signature, keys, buffer_size) = (archive_path.clone(), signature_path, keys.to_vec(), ctx.downloader.buffer_size);
+        tokio::task::spawn_blocking(move || verify_archive_signature(&archive, &signature, &keys, buffer_size)).await.unwrap()
+    }.await;
+    if downloaded_archive && !ctx.config.keep_downloads {
+        fs::remove_file(&archive_path).await?;
+    }
+    status
+}
+
+async fn handle_verify_signatures(format: OutputFormat, ctx: &AppContext) -> Result<(), FluxError> {
+    let keys = load_trusted_keys(ctx)?;
+    let mut installed = ctx.get_installed_packages().await?;
+    installed.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let keys = &keys;
+    let reports: Vec<SignatureReport> = futures_util::stream::iter(&installed)
+        .map(|pkg| async move {
+            let status = check_package_signature(pkg, keys, ctx).await?;
+            Ok::<_, FluxError>(SignatureReport { name: pkg.name.clone(), version: pkg.version.clone(), status })
+        })
+        .buffered(ctx.jobs)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else {
+        for report in &reports {
+            let status = match &report.status {
+                SignatureStatus::Valid { key } => format!("valid (signed by {})", key),
+                SignatureStatus::Invalid => "INVALID signature".to_string(),
+                SignatureStatus::UntrustedKey => "signed by an untrusted key".to_string(),
+                SignatureStatus::Unsigned => "no signature available".to_string(),
+                SignatureStatus::Unavailable { reason } => format!("could not check: {}", reason),
+            };
+            println!("- {} {}: {}", report.name, report.version, status);
+        }
+    }
+
+    let failed: Vec<&str> = reports.iter()
+        .filter(|r| !matches!(r.status, SignatureStatus::Valid { .. }))
+        .map(|r| r.name.as_str())
+        .collect();
+    if failed.is_empty() {
+        if format == OutputFormat::Text {
+            println!("All {} package(s) have valid signatures.", reports.len());
+        }
+        return Ok(());
+    }
+    Err(FluxError::VerificationFailed(format!("signatures of {} package(s) did not verify: {}", failed.len(), failed.join(", "))))
+}
+
+// Deleting files needs a yes, either from --yes or interactively.
+fn confirm_fix(question: &str, ctx: &AppContext) -> bool {
+    ctx.assume_yes || prompt_yes_no(question).unwrap_or(false)
+}
+
+async fn handle_doctor(fix: bool, ctx: &mut AppContext) -> Result<(), FluxError> {
+    let mut fixed = Vec::new();
+    let mut needs_attention = Vec::new();
+
+    let db_dir = ctx.target_db_path.parent().unwrap().to_path_buf();
+    for dir in [&ctx.target_apps_root, &db_dir] {
+        if dir.exists() { continue; }
+        if fix {
+            fs::create_dir_all(dir).await?;
+            fixed.push(format!("created missing directory {}", dir.display()));
+        } else {
+            needs_attention.push(format!("missing directory {}", dir.display()));
+        }
+    }
+
+    // Leftovers of interrupted extractions; interrupted downloads are already cleaned up at startup.
+    if let Ok(mut entries) = fs::read_dir(&ctx.target_staging_dir).await {
+        while let Some(entry) = entries.next_entry().await? {
+            if fix {
+                fs::remove_dir_all(entry.path()).await?;
+                fixed.push(format!("removed interrupted extraction {}", entry.path().display()));
+            } else {
+                needs_attention.push(format!("interrupted extraction left in {}", entry.path().display()));
+            }
+        }
+    }
+
+    let stray_archives: Vec<PathBuf> = classify_cached_archives(ctx).await?.into_iter()
+        .filter(|(_, status)| !matches!(status, CachedArchiveStatus::Valid))
+        .map(|(path, _)| path)
+        .collect();
+    if !stray_archives.is_empty() {
+        let description = format!("{} cached archive(s) that are corrupt or not in the index", stray_archives.len());
+        if fix && confirm_fix(&format!("Delete {}?", description), ctx) {
+            for path in &stray_archives {
+                fs::remove_file(path).await?;
+            }
+            fixed.push(format!("deleted {}", description));
+        } else {
+            needs_attention.push(format!("{} (see 'flux cache verify')", description));
+        }
+    }
+
+    let mut installed = Vec::new();
+    match ctx.get_installed_packages().await {
+        Ok(packages) => installed = packages,
+        Err(FluxError::CorruptDatabase { message, .. }) => {
+            // Only a database without any package records is safe to reset; anything else may be recoverable.
+            let content = fs::read_to_string(&ctx.target_db_path).await.unwrap_or_default();
+            if fix && !content.contains("\"name\"") {
+                let backup_path = ctx.target_db_path.with_extension(format!("json.corrupt-{}", unix_now()));
+                fs::copy(&ctx.target_db_path, &backup_path).await?;
+                ctx.write_installed_packages(&[]).await?;
+                fixed.push(format!("reset the malformed, empty package database (backup: {})", backup_path.display()));
+            } else {
+                needs_attention.push(format!("package database is corrupt ({}), run 'flux rebuild-db'", message));
+            }
+        }
+        Err(e) => return Err(e),
+    }
+
+    let index_age_hours = index_age_hours(ctx).await?;
+    if index_age_hours.is_none_or(|age| age >= ctx.config.index_ttl_hours.unwrap_or(DEFAULT_INDEX_TTL_HOURS)) {
+        let description = if index_age_hours.is_some() { "repository index is stale" } else { "repository index is missing" };
+        if fix && handle_update(false, ctx).await.is_ok() {
+            fixed.push(format!("{}: refreshed it", description));
+        } else {
+            needs_attention.push(format!("{}, run 'flux update'", description));
+        }
+    }
+
+    for problem in quick_integrity_check(&installed, ctx) {
+        needs_attention.push(format!("{} (run 'flux verify' for details)", problem));
+    }
+
+    for item in &fixed {
+        println!("Fixed: {}", item);
+    }
+    for item in &needs_attention {
+        println!("Needs attention: {}", item);
+    }
+    if fixed.is_empty() && needs_attention.is_empty() {
+        println!("No problems found.");
+    } else if !fix && !needs_attention.is_empty() {
+        println!("\nRun 'flux doctor --fix' to repair what can be repaired automatically.");
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotPackage {
+    name: String,
+    version: String,
+    package_type: PackageType,
+    install_reason: InstallReason,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    name: String,
+    created_at: u64,
+    packages: Vec<SnapshotPackage>,
+}
+
+// Snapshot and profile names become file and directory names.
+fn is_safe_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+fn snapshot_path(name: &str, ctx: &AppContext) -> Result<PathBuf, FluxError> {
+    if !is_safe_file_name(name) {
+        return Err(FluxError::Config(format!("Invalid snapshot name '{}': use letters, digits, '-', '_' and '.'", name)));
+    }
+    Ok(ctx.target_snapshot_dir.join(format!("{}.json", name)))
+}
+
+async fn read_snapshot(name: &str, ctx: &AppContext) -> Result<Snapshot, FluxError> {
+    let path = snapshot_path(name, ctx)?;
+    let content = fs::read_to_string(&path).await.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FluxError::Config(format!("No snapshot named '{}'", name)),
+        _ => FluxError::Io(e),
+    })?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+// Writes to a temporary file next to `path` and renames it over, so readers never see half a file.
+async fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), FluxError> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".tmp.{}", process::id()));
+    let temp_path = PathBuf::from(temp_path);
+    let result = async {
+        let mut file = File::create(&temp_path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        fs::rename(&temp_path, path).await?;
+        // Persist the rename itself, not just the file contents.
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            File::open(parent).await?.sync_all().await?;
+        }
+        Ok::<(), io::Error>(())
+    }.await;
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+    Ok(result?)
+}
+
+fn known_config_keys() -> Vec<String> {
+    match serde_yaml::to_value(FluxConfig::default()) {
+        Ok(serde_yaml::Value::Mapping(map)) => map.keys().filter_map(|k| k.as_str().map(str::to_string)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn check_config_key(key: &str) -> Result<Vec<&str>, FluxError> {
+    let parts: Vec<&str> = key.split('.').collect();
+    if parts.iter().any(|p| p.is_empty()) {
+        return Err(FluxError::Config(format!("Invalid config key '{}'", key)));
+    }
+    let known = known_config_keys();
+    if !known.iter().any(|k| k == parts[0]) {
+        return Err(FluxError::Config(format!("Unknown config key '{}'. Known keys: {}", parts[0], known.join(", "))));
+    }
+    Ok(parts)
+}
+
+fn handle_config_get(key: &str, ctx: &AppContext) -> Result<(), FluxError> {
+    let parts = check_config_key(key)?;
+    let mut value = serde_yaml::to_value(&ctx.config)?;
+    for part in &parts {
+        value = match value.get(part) {
+            Some(v) => v.clone(),
+            None => return Err(FluxError::Config(format!("'{}' is not set", key))),
+        };
+    }
+    match value {
+        serde_yaml::Value::Null => println!("(not set)"),
+        serde_yaml::Value::String(s) => println!("{}", s),
+        serde_yaml::Value::Bool(_) | serde_yaml::Value::Number(_) => println!("{}", serde_yaml::to_string(&value)?.trim_end()),
+        _ => print!("{}", serde_yaml::to_string(&value)?),
+    }
+    Ok(())
+}
+
+fn set_config_value(doc: &mut serde_yaml::Value, parts: &[&str], value: serde_yaml::Value) -> Result<(), FluxError> {
+    let mut node = doc;
+    for part in &parts[..parts.len() - 1] {
+        if node.is_null() {
+            *node = serde_yaml::Value::Mapping(Default::default());
+        }
+        let map = node.as_mapping_mut().ok_or_else(|| FluxError::Config(format!("'{}' is not a mapping", part)))?;
+        node = map.entry(serde_yaml::Value::String(part.to_string())).or_insert(serde_yaml::Value::Null);
+    }
+    if node.is_null() {
+        *node = serde_yaml::Value::Mapping(Default::default());
+    }
+    let map = node.as_mapping_mut().ok_or_else(|| FluxError::Config(format!("Cannot set '{}' inside a value that is not a mapping", parts.join("."))))?;
+    let last = serde_yaml::Value::String(parts[parts.len() - 1].to_string());
+    if value.is_null() {
+        map.remove(&last);
+    } else {
+        map.insert(last, value);
+    }
+    Ok(())
+}
+
+// Indentation of a YAML line, or None for blank and comment-only lines.
+fn yaml_indent(line: &str) -> Option<usize> {
+    let trimmed = line.trim_start();
+    (!trimmed.is_empty() && !trimmed.starts_with('#')).then(|| line.len() - trimmed.len())
+}
+
+// `key: value` at `indent`, or `key:` followed by the value as an indented block.
+fn render_config_entry(indent: usize, key: &str, value: &serde_yaml::Value) -> Result<Vec<String>, FluxError> {
+    let rendered = serde_yaml::to_string(value)?;
+    let pad = " ".repeat(indent);
+    let nested = matches!(value, serde_yaml::Value::Mapping(m) if !m.is_empty()) || matches!(value, serde_yaml::Value::Sequence(s) if !s.is_empty());
+    if !nested && rendered.trim_end().lines().count() == 1 {
+        return Ok(vec![format!("{}{}: {}", pad, key, rendered.trim_end())]);
+    }
+    let mut lines = vec![format!("{}{}:", pad, key)];
+    lines.extend(rendered.trim_end().lines().map(|line| format!("{}  {}", pad, line)));
+    Ok(lines)
+}
+
+// Replaces, inserts or (for a null value) removes just the lines of `parts` in the file, so
+// comments and the layout of every other setting survive. None if the structure around the key
+// is not plain block YAML this can follow.
+fn edit_config_lines(content: &str, parts: &[&str], value: &serde_yaml::Value) -> Result<Option<String>, FluxError> {
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let (mut start, mut end, mut parent_indent) = (0, lines.len(), None::<usize>);
+    for (depth, part) in parts.iter().enumerate() {
+        let last = depth == parts.len() - 1;
+        let child_indent = lines[start..end].iter().find_map(|l| yaml_indent(l));
+        if child_indent.is_some_and(|indent| parent_indent.is_some_and(|parent| indent <= parent)) {
+            return Ok(None);
+        }
+        let found = lines[start..end].iter().position(|l| {
+            yaml_indent(l) == child_indent && l.trim_start().strip_prefix(part).is_some_and(|rest| rest == ":" || rest.starts_with(": ") || rest.starts_with(":\t"))
+        }).map(|i| start + i);
+
+        let Some(i) = found else {
+            if value.is_null() { return Ok(Some(content.to_string())); }
+            // Insert after the section's last setting, keeping trailing comments where they are.
+            let indent = child_indent.unwrap_or_else(|| parent_indent.map_or(0, |p| p + 2));
+            let at = (start..end).rev().find(|&j| yaml_indent(&lines[j]).is_some()).map_or(start, |j| j + 1);
+            let mut new_lines = Vec::new();
+            for (k, missing) in parts[depth..parts.len() - 1].iter().enumerate() {
+                new_lines.push(format!("{}{}:", " ".repeat(indent + 2 * k), missing));
+            }
+            new_lines.extend(render_config_entry(indent + 2 * (parts.len() - 1 - depth), parts[parts.len() - 1], value)?);
+            lines.splice(at..at, new_lines);
+            break;
+        };
+        let indent = child_indent.unwrap_or_default();
+        // The entry runs until the next line at its own indentation or less; trailing comments belong to what follows.
+        let entry_end = (i + 1..end).find(|&j| yaml_indent(&lines[j]).is_some_and(|n| n <= indent)).unwrap_or(end);
+        let entry_end = (i + 1..entry_end).rev().find(|&j| yaml_indent(&lines[j]).is_some()).map_or(i + 1, |j| j + 1);
+        if last {
+            let replacement = if value.is_null() { Vec::new() } else { render_config_entry(indent, part, value)? };
+            lines.splice(i..entry_end, replacement);
+            break;
+        }
+        if lines[i].trim_start()[part.len() + 1..].split('#').next().is_some_and(|rest| !rest.trim().is_empty()) {
+            return Ok(None);
+        }
+        (start, end, parent_indent) = (i + 1, entry_end, Some(indent));
+    }
+    let mut edited = lines.join("\n");
+    if !edited.is_empty() {
+        edited.push('\n');
+    }
+    Ok(Some(edited))
+}
+
+// The value `set_config_value` left at `parts`, or null once it was removed.
+fn set_config_leaf<'a>(doc: &'a serde_yaml::Value, parts: &[&str]) -> &'a serde_yaml::Value {
+    static NULL: serde_yaml::Value = serde_yaml::Value::Null;
+    parts.iter().try_fold(doc, |node, part| node.get(part)).unwrap_or(&NULL)
+}
+
+// Edits the key's lines in place, but only writes the file once the result still loads as a
+// FluxConfig. Files this can't edit line by line are rewritten whole, which drops their comments.
+async fn handle_config_set(path: &Path, key: &str, raw_value: &str) -> Result<(), FluxError> {
+    let parts = check_config_key(key)?;
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(FluxError::Config(format!("Could not read {}: {}", path.display(), e))),
+    };
+    let original: serde_yaml::Value = serde_yaml::from_str(&content)?;
+
+    // A value that only makes sense as a string, e.g. `arch 386`, is retried as one.
+    let parsed: serde_yaml::Value = serde_yaml::from_str(raw_value).unwrap_or_else(|_| serde_yaml::Value::String(raw_value.to_string()));
+    let mut candidates = vec![parsed];
+    if !candidates[0].is_string() && !candidates[0].is_null() {
+        candidates.push(serde_yaml::Value::String(raw_value.to_string()));
+    }
+
+    let mut last_error = None;
+    for value in candidates {
+        let mut doc = original.clone();
+        set_config_value(&mut doc, &parts, value)?;
+        match serde_yaml::from_value::<FluxConfig>(doc.clone()) {
+            Ok(_) => {
+                let in_place = edit_config_lines(&content, &parts, set_config_leaf(&doc, &parts))?
+                    .filter(|edited| serde_yaml::from_str::<serde_yaml::Value>(edited).ok().as_ref() == Some(&doc));
+                let edited = match in_place {
+                    Some(edited) => edited,
+                    None => {
+                        eprintln!("Note: {} could not be edited line by line, so it was rewritten and its comments were dropped.", path.display());
+                        serde_yaml::to_string(&doc)?
+                    }
+                };
+                write_atomic(path, edited.as_bytes()).await?;
+                println!("Set {} in {}.", key, path.display());
+                return Ok(());
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(FluxError::Config(format!("Invalid value '{}' for {}: {}", raw_value, key, last_error.expect("at least one candidate was tried"))))
+}
+
+async fn handle_snapshot_create(name: &str, ctx: &AppContext) -> Result<(), FluxError> {
+    let path = snapshot_path(name, ctx)?;
+    if path.exists() {
+        return Err(FluxError::Config(format!("A snapshot named '{}' already exists; delete it first", name)));
+    }
+    let mut packages: Vec<SnapshotPackage> = ctx.get_installed_packages().await?.into_iter()
+        .map(|p| SnapshotPackage { name: p.name, version: p.version, package_type: p.package_type, install_reason: p.install_reason })
+        .collect();
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let snapshot = Snapshot { name: name.to_string(), created_at: unix_now(), packages };
+    fs::create_dir_all(&ctx.target_snapshot_dir).await?;
+    write_atomic(&path, serde_json::to_string_pretty(&snapshot)?.as_bytes()).await?;
+    println!("Saved snapshot '{}' with {} package(s).", name, snapshot.packages.len());
+    Ok(())
+}
+
+async fn handle_snapshot_list(ctx: &AppContext) -> Result<(), FluxError> {
+    let mut snapshots = Vec::new();
+    if let Ok(mut entries) = fs::read_dir(&ctx.target_snapshot_dir).await {
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(name) = entry.file_name().to_string_lossy().strip_suffix(".json").map(str::to_string) else { continue };
+            snapshots.push(read_snapshot(&name, ctx).await?);
+        }
+    }
+    if snapshots.is_empty() {
+        println!("No snapshots saved.");
+        return Ok(());
+    }
+    snapshots.sort_by_key(|s| s.created_at);
+    for snapshot in snapshots {
+        println!("- {} ({} package(s), created at {})", snapshot.name, snapshot.packages.len(), snapshot.created_at);
+    }
+    Ok(())
+}
+
+async fn handle_snapshot_delete(name: &str, ctx: &AppContext) -> Result<(), FluxError> {
+    read_snapshot(name, ctx).await?;
+    fs::remove_file(snapshot_path(name, ctx)?).await?;
+    println!("Deleted snapshot '{}'.", name);
+    Ok(())
+}
+
+async fn handle_restore(name: &str, ctx: &mut AppContext) -> Result<(), FluxError> {
+    let snapshot = read_snapshot(name, ctx).await?;
+    let installed = ctx.get_installed_packages().await?;
+
+    let wanted: HashMap<&str, &SnapshotPackage> = snapshot.packages.iter().map(|p| (p.name.as_str(), p)).collect();
+    let extras: Vec<String> = installed.iter().filter(|p| !wanted.contains_key(p.name.as_str())).map(|p| p.name.clone()).collect();
+    let mut to_install = Vec::new();
+    let mut to_replace = Vec::new();
+    for pkg in &snapshot.packages {
+        match installed.iter().find(|p| p.name == pkg.name) {
+            Some(current) if current.version == pkg.version => {}
+            Some(_) => to_replace.push(pkg.name.clone()),
+            None => to_install.push(pkg.name.clone()),
+        }
+    }
+
+    // Everything has to be installable before anything is touched.
+    let mut pins = Vec::new();
+    let mut unavailable = Vec::new();
+    for name in to_install.iter().chain(&to_replace) {
+        let version = &wanted[name.as_str()].version;
+        match ctx.indexed_version(name, version) {
+            Some(info) => pins.push(info.clone()),
+            None => unavailable.push(format!("{} {}", name, version)),
+        }
+    }
+    if !unavailable.is_empty() {
+        return Err(FluxError::PackageNotFound(format!("{} (required by snapshot '{}')", unavailable.join(", "), name)));
+    }
+
+    let reasons_changed = installed.iter().any(|p| wanted.get(p.name.as_str()).is_some_and(|w| w.install_reason != p.install_reason));
+    if extras.is_empty() && to_install.is_empty() && to_replace.is_empty() && !reasons_changed {
+        println!("The system already matches snapshot '{}'.", name);
+        return Ok(());
+    }
+
+    println!("Restoring snapshot '{}':", name);
+    for name in &extras { println!("- remove {}", name); }
+    for name in &to_replace { println!("- change {} to {}", name, wanted[name.as_str()].version); }
+    for name in &to_install { println!("- install {} {}", name, wanted[name.as_str()].version); }
+    if !ctx.assume_yes && !prompt_yes_no("Continue?")? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let reasons = snapshot.packages.iter().map(|p| (p.name.clone(), p.install_reason.clone())).collect();
+    for info in pins {
+        ctx.package_index.insert(info.name.clone(), info);
+    }
+    apply_package_changes(extras, to_replace, to_install, &reasons, false, ctx).await?;
+    println!("Restored snapshot '{}'.", name);
+    Ok(())
+}
+
+// Removes `extras`, swaps `to_replace` to the index version, installs `to_install` and then sets the
+// install reasons in `reasons`. Shared by upgrade, downgrade, restore, reconcile and history --undo,
+// which check everything first.
+async fn apply_package_changes(extras: Vec<String>, to_replace: Vec<String>, to_install: Vec<String>, reasons: &HashMap<String, InstallReason>, keep_downloads: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let mut installed = ctx.get_installed_packages().await?;
+    // Remove extras dependents-first; whatever still has dependents waits for the next round.
+    let mut pending = extras;
+    let mut removed = Vec::new();
+    while !pending.is_empty() {
+        let Some(index) = pending.iter().position(|name| find_dependents(name, &installed, ctx).is_empty()) else {
+            let name = pending.remove(0);
+            return Err(FluxError::DependencyInUse { dependents: find_dependents(&name, &installed, ctx), package_name: name });
+        };
+        let name = pending.remove(index);
+        removed.push(remove_installed_package(&name, &mut installed, ctx).await?);
+    }
+    run_remove_hooks(&removed, ctx)?;
+
+    // Packages changing version are swapped out in place, so their dependents stay installed. Their
+    // files are only moved aside until the new versions are installed, and put back if that fails.
+    let mut replaced = Vec::new();
+    for name in &to_replace {
+        let index = installed.iter().position(|p| p.name == *name).unwrap();
+        let old = installed.remove(index);
+        let mut set_aside = Vec::new();
+        let result = set_aside_replaced_files(&old, &installed, &mut set_aside, ctx).await;
+        replaced.push((old, set_aside));
+        if let Err(e) = result {
+            restore_replaced_packages(replaced, installed, ctx).await?;
+            return Err(e);
+        }
+    }
+    ctx.write_installed_packages(&installed).await?;
+
+    let mut reinstall = to_replace;
+    reinstall.extend(to_install);
+    let options = InstallOptions { keep_downloads, ..InstallOptions::default() };
+    let result = if reinstall.is_empty() { Ok(()) } else { install_packages(&reinstall, &options, ctx).await };
+    let installed = ctx.get_installed_packages().await?;
+    // With abort-keep some of the new versions may have been recorded even though the install failed.
+    let (done, failed): (Vec<_>, Vec<_>) = replaced.into_iter().partition(|(old, _)| installed.iter().any(|p| p.name == old.name));
+    for (old, set_aside) in &done {
+        finish_replacement(old, set_aside, &installed, ctx).await?;
+    }
+    if !failed.is_empty() {
+        restore_replaced_packages(failed, installed, ctx).await?;
+    }
+    result?;
+
+    let mut installed = ctx.get_installed_packages().await?;
+    for pkg in installed.iter_mut() {
+        if let Some(reason) = reasons.get(&pkg.name) {
+            pkg.install_reason = reason.clone();
+        }
+    }
+    ctx.write_installed_packages(&installed).await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct PackageState {
+    version: String,
+    install_reason: InstallReason,
+}
+
+// One package's state before and after a transaction; None means not installed.
+#[derive(Serialize, Deserialize, Clone)]
+struct HistoryChange {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    before: Option<PackageState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    after: Option<PackageState>,
+}
+
+impl HistoryChange {
+    // In the past tense for the history listing, or as a plan ("install", "remove", ...).
+    fn describe(&self, planned: bool) -> String {
+        let verb = |plan: &'static str, past: &'static str| if planned { plan } else { past };
+        let reason = |state: &PackageState| format!("{:?}", state.install_reason).to_lowercase();
+        match (&self.before, &self.after) {
+            (None, Some(after)) => format!("{} {} {} ({})", verb("install", "installed"), self.name, after.version, reason(after)),
+            (Some(before), None) => format!("{} {} {}", verb("remove", "removed"), self.name, before.version),
+            (Some(before), Some(after)) if before.version != after.version => format!("{} {} {} -> {}", verb("change", "changed"), self.name, before.version, after.version),
+            (Some(_), Some(after)) => format!("{} {} as {}", verb("mark", "marked"), self.name, reason(after)),
+            (None, None) => format!("{} uninstalled", self.name),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryEntry {
+    id: u64,
+    timestamp: u64,
+    command: String,
+    changes: Vec<HistoryChange>,
+}
+
+fn package_states(installed: &[InstalledPackageInfo]) -> BTreeMap<String, PackageState> {
+    installed.iter().map(|p| (p.name.clone(), PackageState { version: p.version.clone(), install_reason: p.install_reason.clone() })).collect()
+}
+
+async fn read_history(ctx: &AppContext) -> Result<Vec<HistoryEntry>, FluxError> {
+    let content = match fs::read_to_string(&ctx.target_history_path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    content.lines().filter(|line| !line.trim().is_empty()).map(|line| Ok(serde_json::from_str(line)?)).collect()
+}
+
+// Called after every mutating command, failed ones included, with the database as it was before.
+// Returns the recorded entry, if anything changed.
+async fn record_history(before: &[InstalledPackageInfo], command: &str, ctx: &AppContext) -> Result<Option<HistoryEntry>, FluxError> {
+    let before = package_states(before);
+    let after = package_states(&ctx.get_installed_packages().await?);
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    let changes: Vec<HistoryChange> = names.into_iter()
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| HistoryChange { name: name.clone(), before: before.get(name).cloned(), after: after.get(name).cloned() })
+        .collect();
+    if changes.is_empty() { return Ok(None); }
+
+    let id = read_history(ctx).await?.last().map_or(1, |entry| entry.id + 1);
+    let entry = HistoryEntry { id, timestamp: unix_now(), command: command.to_string(), changes };
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+    if let Some(parent) = ctx.target_history_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(&ctx.target_history_path).await?;
+    file.write_all(line.as_bytes()).await?;
+    file.sync_all().await?;
+    Ok(Some(entry))
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    host: String,
+    root: &'a Path,
+    profile: Option<&'a str>,
+    #[serde(flatten)]
+    transaction: &'a HistoryEntry,
+}
+
+fn host_name() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: gethostname writes at most buffer.len() bytes into the buffer.
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return "unknown".to_string();
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
+
+// Reports a successful transaction to the configured webhook and command. A notification that
+// can't be delivered only warns; the transaction itself already happened.
+async fn notify_transaction(entry: &HistoryEntry, ctx: &AppContext) {
+    let Some(notify) = &ctx.config.notify else { return };
+    let notification = Notification { host: host_name(), root: &ctx.target_root, profile: ctx.profile.as_deref(), transaction: entry };
+    let payload = match serde_json::to_vec(&notification) {
+        Ok(payload) => payload,
+        Err(e) => {
+            eprintln!("Warning: could not encode the notification: {}", e);
+            return;
+        }
+    };
+
+    if let Some(url) = &notify.webhook {
+        if ctx.downloader.offline {
+            eprintln!("Warning: not notifying {} in offline mode.", url);
+        } else if let Err(e) = post_webhook(url, &payload, notify.webhook_attempts.unwrap_or(DEFAULT_WEBHOOK_ATTEMPTS).max(1), ctx).await {
+            eprintln!("Warning: could not notify {}: {}", url, e);
+        }
+    }
+    if let Some(command) = &notify.command {
+        if let Err(e) = run_notify_command(command, &payload) {
+            eprintln!("Warning: notification command '{}' failed: {}", command, e);
+        }
+    }
+}
+
+async fn post_webhook(url: &str, payload: &[u8], attempts: u32, ctx: &AppContext) -> Result<(), FluxError> {
+    let url = Url::parse(url)?;
+    let mut delay = std::time::Duration::from_millis(500);
+    for attempt in 1.. {
+        let sent = ctx.downloader.client.post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(std::time::Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+            .body(payload.to_vec())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match sent {
+            Ok(_) => break,
+            Err(e) if attempt >= attempts => return Err(e.into()),
+            Err(e) => {
+                eprintln!("Warning: notification attempt {}/{} to {} failed: {}", attempt, attempts, url, e);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_notify_command(command: &str, payload: &[u8]) -> std::io::Result<()> {
+    let mut child = process::Command::new("sh")
+        .arg("-c").arg(command)
+        .stdin(process::Stdio::piped())
+        .spawn()?;
+    // A command that doesn't read its stdin closes the pipe early; that's not a failure.
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(payload);
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("exited with {}", status)));
+    }
+    Ok(())
+}
+
+async fn handle_history(ctx: &AppContext) -> Result<(), FluxError> {
+    let history = read_history(ctx).await?;
+    if history.is_empty() {
+        println!("No transactions recorded.");
+        return Ok(());
+    }
+    for entry in &history {
+        println!("#{} {} flux {}", entry.id, format_unix_time(entry.timestamp), entry.command);
+        for change in &entry.changes {
+            println!("    {}", change.describe(false));
+        }
+    }
+    Ok(())
+}
+
+// Puts every package the transaction touched back the way it was. Refused when a later transaction
+// changed one of those packages again, or when something still installed needs a package the undo
+// would remove.
+async fn handle_history_undo(id: u64, ctx: &mut AppContext) -> Result<(), FluxError> {
+    let history = read_history(ctx).await?;
+    let entry = history.iter().find(|e| e.id == id)
+        .ok_or_else(|| FluxError::Config(format!("No transaction #{} in the history", id)))?;
+    let refuse = |reason: String| FluxError::UndoRefused { id, reason };
+    let installed = ctx.get_installed_packages().await?;
+    let current = package_states(&installed);
+
+    for change in &entry.changes {
+        if current.get(&change.name) == change.after.as_ref() { continue; }
+        let later = history.iter().find(|e| e.id > id && e.changes.iter().any(|c| c.name == change.name));
+        return Err(refuse(match later {
+            Some(later) => format!("'{}' was changed again by transaction #{} (flux {})", change.name, later.id, later.command),
+            None => format!("'{}' has changed since", change.name),
+        }));
+    }
+
+    let extras: Vec<String> = entry.changes.iter().filter(|c| c.before.is_none()).map(|c| c.name.clone()).collect();
+    let remaining: Vec<InstalledPackageInfo> = installed.iter().filter(|p| !extras.contains(&p.name)).cloned().collect();
+    for name in &extras {
+        if let Some(dependent) = find_dependents(name, &remaining, ctx).into_iter().next() {
+            return Err(refuse(format!("'{}' depends on '{}', which the undo would remove", dependent, name)));
+        }
+    }
+
+    let mut to_replace = Vec::new();
+    let mut to_install = Vec::new();
+    let mut reasons = HashMap::new();
+    let mut pins = Vec::new();
+    for change in &entry.changes {
+        let Some(before) = &change.before else { continue };
+        reasons.insert(change.name.clone(), before.install_reason.clone());
+        if change.after.as_ref().is_some_and(|after| after.version == before.version) { continue; }
+        let Some(info) = ctx.indexed_version(&change.name, &before.version) else {
+            return Err(refuse(format!("the repository no longer provides {} {}", change.name, before.version)));
+        };
+        pins.push(info.clone());
+        if change.after.is_some() { to_replace.push(change.name.clone()); } else { to_install.push(change.name.clone()); }
+    }
+
+    println!("Undoing transaction #{} (flux {}):", id, entry.command);
+    for change in &entry.changes {
+        let undo = HistoryChange { name: change.name.clone(), before: change.after.clone(), after: change.before.clone() };
+        println!("- {}", undo.describe(true));
+    }
+    if !ctx.assume_yes && !prompt_yes_no("Continue?")? {
+        println!("Aborted.");
+        return Ok(());
+    }
+    for info in pins {
+        ctx.package_index.insert(info.name.clone(), info);
+    }
+    apply_package_changes(extras, to_replace, to_install, &reasons, false, ctx).await?;
+    println!("Undid transaction #{}.", id);
+    Ok(())
+}
+
+// Hand-written, unlike a snapshot: specs may carry version ranges, and anything they pull in
+// is kept as a dependency without being listed.
+#[derive(Deserialize)]
+struct DesiredState {
+    #[serde(default)]
+    packages: Vec<String>,
+    #[serde(default)]
+    dependencies: Vec<String>,
+}
+
+async fn handle_reconcile(path: &Path, ctx: &mut AppContext) -> Result<(), FluxError> {
+    let content = fs::read_to_string(path).await
+        .map_err(|e| FluxError::Config(format!("Could not read {}: {}", path.display(), e)))?;
+    let desired: DesiredState = serde_yaml::from_str(&content)?;
+    let mut wanted: Vec<(DependencySpec, InstallReason)> = Vec::new();
+    for (spec, reason) in desired.packages.iter().map(|s| (s, InstallReason::Explicit)).chain(desired.dependencies.iter().map(|s| (s, InstallReason::Dependency))) {
+        let spec = DependencySpec::parse(spec)?;
+        if wanted.iter().any(|(w, _)| w.name == spec.name) {
+            return Err(FluxError::Config(format!("'{}' is listed more than once in {}", spec.name, path.display())));
+        }
+        wanted.push((spec, reason));
+    }
+
+    let installed = ctx.get_installed_packages().await?;
+    let mut to_install = Vec::new();
+    let mut to_replace = Vec::new();
+    let mut unavailable = Vec::new();
+    // The newest indexed version each spec allows, which need not be the newest overall.
+    let mut chosen: HashMap<String, PackageInfo> = HashMap::new();
+    for (spec, _) in &wanted {
+        let satisfied = |version: &str| spec.constraint.as_ref().is_none_or(|c| c.matches(version));
+        let current = installed.iter().find(|p| p.name == spec.name);
+        if current.is_some_and(|p| satisfied(&p.version)) { continue; }
+        let versions = ctx.package_versions.get(&spec.name).map(Vec::as_slice).unwrap_or_default();
+        match versions.iter().rev().find(|info| satisfied(&info.version)) {
+            Some(info) => {
+                chosen.insert(spec.name.clone(), info.clone());
+                if current.is_some() { to_replace.push(spec.name.clone()) } else { to_install.push(spec.name.clone()) }
+            }
+            None => unavailable.push(spec.to_string()),
+        }
+    }
+    if !unavailable.is_empty() {
+        return Err(FluxError::PackageNotFound(format!("{} (required by {})", unavailable.join(", "), path.display())));
+    }
+    ctx.package_index.extend(chosen);
+    select_satisfying_versions(&[to_install.clone(), to_replace.clone()].concat(), ctx);
+
+    // Everything the wanted packages depend on stays, whether or not it is listed.
+    let mut keep: HashSet<String> = HashSet::new();
+    let mut queue: Vec<String> = wanted.iter().map(|(spec, _)| spec.name.clone()).collect();
+    while let Some(name) = queue.pop() {
+        if !keep.insert(name.clone()) { continue; }
+        if let Some(info) = ctx.package_index.get(&name) {
+            queue.extend(info.dependency_names().map(str::to_string));
+        }
+    }
+    let extras: Vec<String> = installed.iter().filter(|p| !keep.contains(&p.name)).map(|p| p.name.clone()).collect();
+
+    let target_reason = |name: &str| wanted.iter().find(|(spec, _)| spec.name == name).map_or(InstallReason::Dependency, |(_, reason)| reason.clone());
+    let reason_changes: Vec<(String, InstallReason)> = installed.iter()
+        .filter(|p| keep.contains(&p.name) && !to_replace.contains(&p.name) && p.install_reason != target_reason(&p.name))
+        .map(|p| (p.name.clone(), target_reason(&p.name)))
+        .collect();
+    if extras.is_empty() && to_install.is_empty() && to_replace.is_empty() && reason_changes.is_empty() {
+        println!("The system already matches {}.", path.display());
+        return Ok(());
+    }
+
+    let prefix = if ctx.dry_run { "[dry-run] " } else { "" };
+    println!("{}Reconciling with {}:", prefix, path.display());
+    for name in &extras { println!("{}- remove {}", prefix, name); }
+    for name in &to_replace {
+        let current = installed.iter().find(|p| p.name == *name).unwrap();
+        println!("{}- change {} from {} to {}", prefix, name, current.version, ctx.package_index[name].version);
+    }
+    for name in &to_install { println!("{}- install {} {} (and its missing dependencies)", prefix, name, ctx.package_index[name].version); }
+    for (name, reason) in &reason_changes { println!("{}- mark {} as {:?}", prefix, name, reason); }
+    if ctx.dry_run { return Ok(()); }
+    if !ctx.assume_yes && !prompt_yes_no("Continue?")? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let reasons = keep.iter().map(|name| (name.clone(), target_reason(name))).collect();
+    apply_package_changes(extras, to_replace, to_install, &reasons, false, ctx).await?;
+    println!("The system now matches {}.", path.display());
+    Ok(())
+}
+
+// Removes what an interrupted install left on disk without a database record.
+async fn recover_interrupted_installs(mutating: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    let journal = ctx.read_install_journal().await?;
+    if journal.is_empty() { return Ok(()); }
+
+    // With abort-keep or a crash right after the database write, some packages did get recorded.
+    let installed = ctx.get_installed_packages().await?;
+    let unrecorded: Vec<&JournalEntry> = journal.iter()
+        .filter(|entry| !installed.iter().any(|p| p.name == entry.name && p.version == entry.version))
+        .collect();
+    if unrecorded.is_empty() {
+        return ctx.clear_install_journal().await;
+    }
+
+    let names: Vec<String> = unrecorded.iter().map(|e| format!("{} {}", e.name, e.version)).collect();
+    eprintln!("An earlier install was interrupted and left unrecorded files for: {}", names.join(", "));
+    let policy = ctx.config.on_interrupted_install.unwrap_or_default();
+    let clean_up = mutating && match policy {
+        InterruptedInstallPolicy::Auto => true,
+        InterruptedInstallPolicy::Never => false,
+        InterruptedInstallPolicy::Ask => ctx.assume_yes || prompt_yes_no("Remove the partially installed files?").unwrap_or(false),
+    };
+    if !clean_up {
+        eprintln!("Leaving them in place. Run a command that changes the system to clean up, or set on_interrupted_install: auto.");
+        return Ok(());
+    }
+
+    for entry in unrecorded {
+        remove_partial_install(entry).await?;
+        eprintln!("Removed the partial install of {} {}.", entry.name, entry.version);
+    }
+    ctx.clear_install_journal().await
+}
+
+// Deletes the paths a journaled extraction created; directories only once they are empty.
+async fn remove_partial_install(entry: &JournalEntry) -> Result<(), FluxError> {
+    for path in entry.new_paths.iter().rev() {
+        let full_path = entry.root.join(path);
+        let Ok(metadata) = fs::symlink_metadata(&full_path).await else { continue };
+        if metadata.is_dir() {
+            let _ = fs::remove_dir(&full_path).await;
+        } else {
+            fs::remove_file(&full_path).await?;
+        }
+    }
+    if entry.package_type == PackageType::App {
+        let _ = fs::remove_dir(&entry.root).await;
+    }
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date, and back (Howard Hinnant's algorithms).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = month_index + if month_index < 10 { 3 } else { -9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+fn format_unix_time(secs: u64) -> String {
+    let secs = secs as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let time = secs.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+// Parses `7d`-style ages (s, m, h, d, w) relative to now, or RFC 3339 dates and date-times.
+// Date-times without an offset are taken as UTC.
+fn parse_time_spec(spec: &str) -> Result<u64, String> {
+    let spec = spec.trim();
+    let invalid = || format!("'{}' is neither an RFC 3339 time (2024-05-01T12:00:00Z) nor an age like 24h or 7d", spec);
+
+    if let Some(unit_start) = spec.find(|c: char| !c.is_ascii_digit()).filter(|&i| i > 0 && spec.len() - i == 1) {
+        let amount: u64 = spec[..unit_start].parse().map_err(|_| invalid())?;
+        let unit = match &spec[unit_start..] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            "w" => 7 * 86400,
+            _ => return Err(invalid()),
+        };
+        return Ok(unix_now().saturating_sub(amount.saturating_mul(unit)));
+    }
+
+    let number = |s: &str| s.parse::<i64>().ok().filter(|_| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()));
+    let (date, time) = match spec.find(['T', 't', ' ']) {
+        Some(i) => (&spec[..i], Some(&spec[i + 1..])),
+        None => (spec, None),
+    };
+    let date_parts: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = date_parts[..] else { return Err(invalid()) };
+    let (year, month, day) = (number(year).ok_or_else(invalid)?, number(month).ok_or_else(invalid)?, number(day).ok_or_else(invalid)?);
+    if year.to_string().len() != 4 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    let mut secs = days_from_civil(year, month, day) * 86400;
+
+    if let Some(time) = time {
+        let (clock, offset) = match time.find(['Z', 'z', '+', '-']) {
+            Some(i) => (&time[..i], &time[i..]),
+            None => (time, ""),
+        };
+        let clock = clock.split('.').next().unwrap_or_default();
+        let clock_parts: Vec<i64> = clock.split(':').map(|part| number(part).ok_or_else(invalid)).collect::<Result<_, _>>()?;
+        let (hour, minute, second) = match clock_parts[..] {
+            [hour, minute] => (hour, minute, 0),
+            [hour, minute, second] => (hour, minute, second),
+            _ => return Err(invalid()),
+        };
+        if hour > 23 || minute > 59 || second > 60 {
+            return Err(invalid());
+        }
+        secs += hour * 3600 + minute * 60 + second;
+
+        if let Some(sign) = offset.strip_prefix(['+', '-']).map(|_| if offset.starts_with('-') { -1 } else { 1 }) {
+            let (offset_hours, offset_minutes) = offset[1..].split_once(':').ok_or_else(invalid)?;
+            let offset_secs = number(offset_hours).ok_or_else(invalid)? * 3600 + number(offset_minutes).ok_or_else(invalid)? * 60;
+            secs -= sign * offset_secs;
+        } else if !matches!(offset, "" | "Z" | "z") {
+            return Err(invalid());
+        }
+    }
+    u64::try_from(secs).map_err(|_| invalid())
+}
+
+// Splits an App directory name like `hello-2.0.0`, preferring names known to the index.
+fn split_app_dir_name<'a>(dir_name: &'a str, ctx: &AppContext) -> Option<(&'a str, &'a str)> {
+    let mut best: Option<(&str, &str)> = None;
+    for (index, _) in dir_name.match_indices('-') {
+        let (name, version) = (&dir_name[..index], &dir_name[index + 1..]);
+        if version.is_empty() { continue; }
+        if ctx.package_index.get(name).is_some_and(|info| info.package_type == PackageType::App) {
+            best = Some((name, version));
+        }
+    }
+    best.or_else(|| dir_name.rsplit_once('-').filter(|(name, version)| !name.is_empty() && !version.is_empty()))
+}
+
+async fn handle_rebuild_db(force: bool, ctx: &AppContext) -> Result<(), FluxError> {
+    match ctx.get_installed_packages().await {
+        Ok(_) if !force => {
+            println!("The package database is readable; nothing to rebuild. Use --force to rebuild it anyway.");
+            return Ok(());
+        }
+        Ok(_) | Err(FluxError::CorruptDatabase { .. }) => {}
+        Err(e) => return Err(e),
+    }
+
+    if ctx.target_db_path.exists() {
+        let backup_path = ctx.target_db_path.with_extension(format!("json.corrupt-{}", unix_now()));
+        fs::copy(&ctx.target_db_path, &backup_path).await?;
+        println!("Backed up the old database to {}", backup_path.display());
+    }
+
+    let mut recovered = Vec::new();
+
+    if ctx.target_apps_root.exists() {
+        let mut entries = fs::read_dir(&ctx.target_apps_root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() { continue; }
+            let dir_name = entry.file_name().to_string_lossy().to_string();
+            match split_app_dir_name(&dir_name, ctx) {
+                Some((name, version)) => {
+                    println!("Found App package {} {}", name, version);
+                    recovered.push(InstalledPackageInfo {
+                        name: name.to_string(),
+                        version: version.to_string(),
+                        package_type: PackageType::App,
+                        install_reason: InstallReason::Explicit,
+                        installed_at: None,
+                        files: Vec::new(),
+                        content_hashes: BTreeMap::new(),
+                        remove_scripts: ctx.package_index.get(name).filter(|info| info.version == version).and_then(RemoveScripts::from_info),
+                    });
+                }
+                None => println!("Warning: could not parse App directory '{}', skipping.", dir_name),
+            }
+        }
+    }
+
+    // System files can only be attributed to a package if its archive is still in the cache.
+    let mut unchecked = Vec::new();
+    let mut system_packages: Vec<&PackageInfo> = ctx.package_index.values().filter(|p| p.package_type == PackageType::System).collect();
+    system_packages.sort_by(|a, b| a.name.cmp(&b.name));
+    for info in system_packages {
+        let archive_path = ctx.host_cache_dir.join(archive_file_name(info));
+        if !archive_path.exists() {
+            unchecked.push(info.name.clone());
+            continue;
+        }
+        let entries = read_archive_entries(&archive_path).await?;
+        let all_present = entries.iter().filter(|(_, is_dir)| !is_dir).all(|(path, _)| ctx.target_root.join(path).exists());
+        if all_present && entries.iter().any(|(_, is_dir)| !is_dir) {
+            println!("Found system package {} {}", info.name, info.version);
+            recovered.push(InstalledPackageInfo {
+                name: info.name.clone(),
+                version: info.version.clone(),
+                package_type: PackageType::System,
+                install_reason: InstallReason::Explicit,
+                installed_at: None,
+                files: entries.into_iter().map(|(path, _)| FileEntry { path, sha256: None }).collect(),
+                content_hashes: BTreeMap::new(),
+                remove_scripts: RemoveScripts::from_info(info),
+            });
+        }
+    }
+
+    ctx.write_installed_packages(&recovered).await?;
+    println!("\nRebuilt the database with {} package(s).", recovered.len());
+    println!("Install reasons could not be recovered; every package is marked Explicit. Use 'flux mark' to adjust them.");
+    if !unchecked.is_empty() {
+        println!("These system packages have no cached archive and could not be checked: {}", unchecked.join(", "));
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    if cli.dry_run && cli.command.is_mutating() && !cli.command.supports_dry_run() {
+        eprintln!("Error: --dry-run is not supported by this command");
+        process::exit(2);
+    }
+    let mut ctx = AppContext::new(&cli).await?;
+    let mutating = cli.command.is_mutating() && !cli.dry_run;
+    // Taken before recovery, which would otherwise mistake another process's install for an interrupted one.
+    let _db_lock = if mutating {
+        match ctx.lock_database(cli.wait) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    if let Err(e) = recover_interrupted_installs(mutating, &ctx).await {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+    if ctx.config.verify_cache_on_start && mutating {
+        if let Err(e) = verify_cache_on_start(&ctx).await {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+
+    // Mutating commands are diffed against the database as it was, and the difference goes to the history.
+    let history_before = if mutating { ctx.get_installed_packages().await.ok() } else { None };
+    let command_line = std::env::args().skip(1).collect::<Vec<_>>().join(" ");
+
+    let result = match cli.command {
+        Commands::Install { package, options } => handle_install(&package, &options, &mut ctx).await,
+        Commands::Remove { package, recursive_orphans } => handle_remove(&package, recursive_orphans, &ctx).await,
+        Commands::List { format, json_lines, reverse_deps, verbose, orphans, sort, newer_than } => handle_list(ListOptions { format, json_lines, reverse_deps, verbose, orphans, sort, newer_than }, &ctx).await,
+        Commands::Update { strict_update, max_download_attempts_per_mirror, max_total_attempts } => {
+            ctx.config.index_retries = max_download_attempts_per_mirror.or(ctx.config.index_retries);
+            ctx.config.index_max_total_attempts = max_total_attempts.or(ctx.config.index_max_total_attempts);
+            handle_update(strict_update, &mut ctx).await
+        }
+        Commands::Upgrade { packages, options } if options.list => match handle_upgrade_list(&packages, &options, &ctx).await {
+            Ok(true) => process::exit(UPGRADES_AVAILABLE_EXIT_CODE),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        },
+        Commands::Upgrade { packages, options } => handle_upgrade(&packages, &options, &ctx).await,
+        Commands::Autoremove => handle_autoremove(&ctx).await,
+        Commands::Status { format } => handle_status(format, &ctx).await,
+        Commands::Search { by_dependency: Some(dependency), installed_only, format, json_lines, .. } => handle_search_by_dependency(&dependency, installed_only, format, json_lines, &ctx).await,
+        Commands::Search { query, format, json_lines, .. } => handle_search(query.as_deref().unwrap_or_default(), format, json_lines, &ctx),
+        Commands::Info { package, json } => handle_info(&package, json, &ctx).await,
+        Commands::Files { package } => handle_files(&package, &ctx).await,
+        Commands::Owns { path } => handle_owns(&path, &ctx).await,
+        Commands::ExportInstalled { with_checksums, url_base, format, json_lines } => handle_export_installed(with_checksums, url_base.as_deref(), format, json_lines, &ctx).await,
+        Commands::Tree { package, installed: _, format } => handle_tree(package.as_deref(), format, &ctx).await,
+        Commands::PrefetchUpdates => handle_prefetch_updates(&ctx).await,
+        Commands::Clean => handle_clean(&ctx).await,
+        Commands::Cache { action: CacheCommand::Verify { prune } } => handle_cache_verify(prune, &ctx).await,
+        Commands::Snapshot { action: SnapshotCommand::Create { name } } => handle_snapshot_create(&name, &ctx).await,
+        Commands::Snapshot { action: SnapshotCommand::List } => handle_snapshot_list(&ctx).await,
+        Commands::Snapshot { action: SnapshotCommand::Delete { name } } => handle_snapshot_delete(&name, &ctx).await,
+        Commands::History { undo: None } => handle_history(&ctx).await,
+        Commands::History { undo: Some(id) } => handle_history_undo(id, &mut ctx).await,
+        Commands::Downgrade { package, version, keep_downloads } => handle_downgrade(&package, &version, keep_downloads, &mut ctx).await,
+        Commands::Restore { name } => handle_restore(&name, &mut ctx).await,
+        Commands::Reconcile { file } => handle_reconcile(&file, &mut ctx).await,
+        Commands::Config { action: ConfigCommand::Get { key } } => handle_config_get(&key, &ctx),
+        Commands::Config { action: ConfigCommand::Set { key, value } } => handle_config_set(Path::new("flux.conf"), &key, &value).await,
+        Commands::Doctor { fix } => handle_doctor(fix, &mut ctx).await,
+        Commands::RebuildDb { force } => handle_rebuild_db(force, &ctx).await,
+        Commands::Verify { package, checksums } => handle_verify(package.as_deref(), checksums, &ctx).await,
+        Commands::VerifySignatures { format } => handle_verify_signatures(format, &ctx).await,
+        Commands::Mark { package, reason } => handle_mark(&package, reason, &ctx).await,
+    };
+
+    if let Some(before) = history_before {
+        match record_history(&before, &command_line, &ctx).await {
+            Ok(Some(entry)) if result.is_ok() => notify_transaction(&entry, &ctx).await,
+            Ok(_) => {}
+            Err(e) => eprintln!("Warning: could not record the transaction in the history: {}", e),
+        }
+    }
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A target root, download cache and file:// repository in one temporary directory. Packages are
+    // published straight into the in-memory index, so nothing needs an update first.
+    struct Fixture {
+        dir: tempfile::TempDir,
+        ctx: AppContext,
+    }
+
+    fn context(dir: &Path, profile: Option<&str>, config: FluxConfig) -> AppContext {
+        let root = dir.join("root");
+        let target_db_dir = root.join("var/lib/flux");
+        let profile_dir = match profile {
+            Some(profile) => target_db_dir.join("profiles").join(profile),
+            None => target_db_dir.clone(),
+        };
+        AppContext {
+            host_cache_dir: dir.join("cache"),
+            target_apps_root: root.join("flux/apps"),
+            target_db_path: profile_dir.join("db.json"),
+            target_store_dir: target_db_dir.join("store"),
+            target_staging_dir: target_db_dir.join("staging"),
+            target_snapshot_dir: profile_dir.join("snapshots"),
+            target_history_path: profile_dir.join("history.jsonl"),
+            target_db_dir,
+            target_root: root,
+            profile: profile.map(str::to_string),
+            downloader: Downloader::new(&config, false, IpPreference::Dual).unwrap(),
+            config,
+            package_index: HashMap::new(),
+            package_versions: HashMap::new(),
+            accepted_arches: vec![std::env::consts::ARCH.to_string()],
+            assume_yes: true,
+            no_verify: false,
+            dry_run: false,
+            jobs: 1,
+        }
+    }
+
+    // A tar.zst of `files` (path, contents), with an entry for every parent directory.
+    fn tar_zst(files: &[(&str, &str)]) -> Vec<u8> {
+        tar_zst_with_mode(files, 0o755)
+    }
+
+    fn tar_zst_with_mode(files: &[(&str, &str)], mode: u32) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut dirs = HashSet::new();
+        for (path, contents) in files {
+            for dir in Path::new(path).ancestors().skip(1).filter(|d| !d.as_os_str().is_empty()).collect::<Vec<_>>().into_iter().rev() {
+                if dirs.insert(dir.to_path_buf()) {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder.append_data(&mut header, dir, std::io::empty()).unwrap();
+                }
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(mode);
+            header.set_size(contents.len() as u64);
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        }
+        zstd::encode_all(&builder.into_inner().unwrap()[..], 0).unwrap()
+    }
+
+    // A tar.zst of entries named exactly as given, which tar::Builder would refuse or rewrite
+    // (absolute, `..`). An entry with a link target is a symlink, otherwise a file with `contents`.
+    fn raw_tar_zst(entries: &[(&[u8], Option<&str>, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, link, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+            match link {
+                Some(target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_link_name(target).unwrap();
+                    header.set_size(0);
+                }
+                None => header.set_size(contents.len() as u64),
+            }
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        zstd::encode_all(&builder.into_inner().unwrap()[..], 0).unwrap()
+    }
+
+    fn package_info(name: &str, version: &str, package_type: PackageType, dependencies: &[&str]) -> PackageInfo {
+        PackageInfo {
+            name: name.to_string(),
+            package_type,
+            version: version.to_string(),
+            url: String::new(),
+            checksum: String::new(),
+            dependencies: (!dependencies.is_empty()).then(|| dependencies.iter().map(|d| d.to_string()).collect()),
+            optional_dependencies: None,
+            description: format!("The {} package.", name),
+            icon_url: String::new(),
+            changelog_url: String::new(),
+            post_install: None,
+            pre_remove: None,
+            post_remove: None,
+            size: None,
+            installed_size: None,
+            privileged_scripts: false,
+            arch: None,
+            conflicts: None,
+            compression: None,
+        }
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            Self::with_config(FluxConfig::default())
+        }
+
+        fn with_config(config: FluxConfig) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::create_dir_all(dir.path().join("repo")).unwrap();
+            std::fs::create_dir_all(dir.path().join("cache")).unwrap();
+            let ctx = context(dir.path(), None, config);
+            Fixture { dir, ctx }
+        }
+
+        // Another context on the same root and index, working in `profile`.
+        fn profile(&self, profile: &str) -> AppContext {
+            let mut ctx = context(self.dir.path(), Some(profile), self.ctx.config.clone());
+            ctx.package_index = self.ctx.package_index.clone();
+            ctx.package_versions = self.ctx.package_versions.clone();
+            ctx
+        }
+
+        fn repo_dir(&self) -> PathBuf {
+            self.dir.path().join("repo")
+        }
+
+        // Archives `files` as <name>-<version>.tar.zst in the repository and adds it to the index.
+        fn publish(&mut self, info: PackageInfo, files: &[(&str, &str)]) -> PackageInfo {
+            self.publish_archive(info, tar_zst(files))
+        }
+
+        fn publish_archive(&mut self, mut info: PackageInfo, archive: Vec<u8>) -> PackageInfo {
+            let archive_path = self.repo_dir().join(format!("{}-{}.tar.zst", info.name, info.version));
+            std::fs::write(&archive_path, &archive).unwrap();
+            info.url = Url::from_file_path(&archive_path).unwrap().to_string();
+            info.checksum = format!("{:x}", Sha256::digest(&archive));
+            let versions = self.ctx.package_versions.entry(info.name.clone()).or_default();
+            versions.retain(|v| v.version != info.version);
+            versions.push(info.clone());
+            versions.sort_by(|a, b| compare_versions(&a.version, &b.version));
+            self.ctx.package_index.insert(info.name.clone(), versions.last().unwrap().clone());
+            info
+        }
+
+        // Takes one version out of the index again, as a repository that withdrew it would.
+        fn unpublish(&mut self, name: &str, version: &str) {
+            let versions = self.ctx.package_versions.get_mut(name).unwrap();
+            versions.retain(|v| v.version != version);
+            self.ctx.package_index.insert(name.to_string(), versions.last().unwrap().clone());
+        }
+
+        // Where the default repository's index is cached.
+        fn index_cache_path(&self) -> PathBuf {
+            self.ctx.host_cache_dir.join("repo.yaml")
+        }
+
+        // Points the configured repository at an index of exactly `packages`, for tests that go
+        // through update.
+        fn write_index(&mut self, packages: Vec<PackageInfo>) {
+            let index_path = self.repo_dir().join("packages.yaml");
+            std::fs::write(&index_path, serde_yaml::to_string(&PackageIndex { packages }).unwrap()).unwrap();
+            self.ctx.config.repository_url = Some(Url::from_file_path(&index_path).unwrap().to_string());
+            self.ctx.config.index_retries = Some(1);
+        }
+
+        fn path(&self, relative: &str) -> PathBuf {
+            self.ctx.target_root.join(relative)
+        }
+
+        fn read(&self, relative: &str) -> String {
+            std::fs::read_to_string(self.path(relative)).unwrap()
+        }
+
+        // Writes an executable shell script at `relative` below the target root.
+        fn write_script(&self, relative: &str, body: &str) {
+            let path = self.path(relative);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        }
+
+        async fn install(&self, names: &[&str]) -> Result<(), FluxError> {
+            let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+            install_packages(&names, &InstallOptions::default(), &self.ctx).await
+        }
+
+        async fn installed(&self) -> Vec<InstalledPackageInfo> {
+            self.ctx.get_installed_packages().await.unwrap()
+        }
+
+        async fn installed_package(&self, name: &str) -> Option<InstalledPackageInfo> {
+            self.installed().await.into_iter().find(|p| p.name == name)
+        }
+    }
+
+    // A fixture whose "lib*" hook appends a line to hook.log, with libfoo and libbar both matching it.
+    fn hooked_fixture(batch_hooks: bool) -> Fixture {
+        let hooks = HashMap::from([("lib*".to_string(), HookSpec::Script("etc/flux/hooks/ldconfig".to_string()))]);
+        let mut fx = Fixture::with_config(FluxConfig { hooks: Some(hooks), batch_hooks, ..FluxConfig::default() });
+        let log = fx.dir.path().join("hook.log");
+        fx.write_script("etc/flux/hooks/ldconfig", &format!("echo ran >> '{}'", log.display()));
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo", "libbar"]), &[("bin/tool", "echo tool")]);
+        fx
+    }
+
+    fn hook_runs(fx: &Fixture) -> usize {
+        std::fs::read_to_string(fx.dir.path().join("hook.log")).map(|log| log.lines().count()).unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn a_batch_hook_runs_once_for_a_multi_package_install() {
+        let fx = hooked_fixture(true);
+
+        fx.install(&["tool"]).await.unwrap();
+
+        assert_eq!(hook_runs(&fx), 1);
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo");
+        assert_eq!(fx.installed().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn without_batch_hooks_a_hook_runs_once_per_matching_package() {
+        let fx = hooked_fixture(false);
+
+        fx.install(&["tool"]).await.unwrap();
+
+        assert_eq!(hook_runs(&fx), 2);
+    }
+
+    // tool recommends docs, which needs fonts; libfoo (a hard dependency) recommends extras.
+    fn recommending_fixture() -> Fixture {
+        let mut fx = Fixture::new();
+        let mut libfoo = package_info("libfoo", "1.0.0", PackageType::System, &[]);
+        libfoo.optional_dependencies = Some(vec!["extras".to_string()]);
+        fx.publish(libfoo, &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("extras", "1.0.0", PackageType::System, &[]), &[("usr/share/extras", "extras")]);
+        fx.publish(package_info("fonts", "1.0.0", PackageType::System, &[]), &[("usr/share/fonts/font", "font")]);
+        fx.publish(package_info("docs", "1.0.0", PackageType::System, &["fonts"]), &[("usr/share/doc/tool", "docs")]);
+        let mut tool = package_info("tool", "1.0.0", PackageType::App, &["libfoo"]);
+        tool.optional_dependencies = Some(vec!["docs".to_string(), "missing".to_string()]);
+        fx.publish(tool, &[("bin/tool", "echo tool")]);
+        fx
+    }
+
+    fn names(installed: &[InstalledPackageInfo]) -> Vec<String> {
+        let mut names: Vec<String> = installed.iter().map(|p| p.name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn recommended_installs_the_optional_dependencies_of_the_requested_package() {
+        let fx = recommending_fixture();
+
+        install_matching("tool", &InstallOptions { recommended: true, ..InstallOptions::default() }, &fx.ctx).await.unwrap();
+
+        // Not recursively: libfoo's own recommendation stays out.
+        assert_eq!(names(&fx.installed().await), vec!["docs", "fonts", "libfoo", "tool"]);
+        assert_eq!(fx.read("usr/share/doc/tool"), "docs");
+    }
+
+    #[tokio::test]
+    async fn without_recommended_only_hard_dependencies_install() {
+        let fx = recommending_fixture();
+
+        install_matching("tool", &InstallOptions::default(), &fx.ctx).await.unwrap();
+
+        assert_eq!(names(&fx.installed().await), vec!["libfoo", "tool"]);
+        assert!(!fx.path("usr/share/doc/tool").exists());
+    }
+
+    #[tokio::test]
+    async fn a_missing_or_empty_config_loads_as_the_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flux.conf");
+        for content in [None, Some(""), Some("# nothing configured yet\n")] {
+            if let Some(content) = content {
+                std::fs::write(&path, content).unwrap();
+            }
+            let config = FluxConfig::load(&path).await.unwrap();
+            assert!(config.repository_url.is_none() && config.hooks.is_none() && !config.batch_hooks, "{:?}", content);
+        }
+    }
+
+    #[tokio::test]
+    async fn list_works_without_a_config_and_update_says_what_is_missing() {
+        let mut fx = Fixture::with_config(FluxConfig::load(Path::new("/nonexistent/flux.conf")).await.unwrap());
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.install(&["libfoo"]).await.unwrap();
+
+        handle_list(ListOptions { format: ListFormat::Text, json_lines: false, reverse_deps: false, verbose: false, orphans: false, sort: None, newer_than: None }, &fx.ctx).await.unwrap();
+
+        let err = handle_update(false, &mut fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::Config(message) if message.contains("repository_url")), "{}", err);
+        assert!(fx.installed_package("libfoo").await.is_some());
+    }
+
+    // Tests that replace stdin hold this, so they don't see each other's input.
+    static STDIN: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    // Stdin replaced, until dropped, by a pseudo-terminal with `answers` typed into it, or by
+    // /dev/null (not a terminal) for None.
+    struct ScriptedStdin {
+        saved: i32,
+        master: Option<i32>,
+    }
+
+    impl ScriptedStdin {
+        fn new(answers: Option<&str>) -> Self {
+            unsafe {
+                let saved = libc::dup(0);
+                let (fd, master) = match answers {
+                    Some(answers) => {
+                        let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+                        assert!(master >= 0 && libc::grantpt(master) == 0 && libc::unlockpt(master) == 0);
+                        let terminal = libc::open(libc::ptsname(master), libc::O_RDWR | libc::O_NOCTTY);
+                        assert_eq!(libc::write(master, answers.as_ptr().cast(), answers.len()), answers.len() as isize);
+                        (terminal, Some(master))
+                    }
+                    None => (libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY), None),
+                };
+                assert!(fd >= 0 && libc::dup2(fd, 0) == 0);
+                libc::close(fd);
+                ScriptedStdin { saved, master }
+            }
+        }
+    }
+
+    impl Drop for ScriptedStdin {
+        fn drop(&mut self) {
+            unsafe {
+                libc::dup2(self.saved, 0);
+                libc::close(self.saved);
+                if let Some(master) = self.master {
+                    libc::close(master);
+                }
+            }
+        }
+    }
+
+    // a, b and c installed at 1.0.0, with 2.0.0 of each in the index.
+    async fn upgradable_fixture() -> Fixture {
+        let mut fx = Fixture::new();
+        for name in ["a", "b", "c"] {
+            let file = format!("usr/share/{}", name);
+            fx.publish(package_info(name, "1.0.0", PackageType::System, &[]), &[(&file, "1")]);
+            fx.install(&[name]).await.unwrap();
+            fx.publish(package_info(name, "2.0.0", PackageType::System, &[]), &[(&file, "2")]);
+        }
+        fx.ctx.assume_yes = false;
+        fx
+    }
+
+    fn versions(installed: &[InstalledPackageInfo]) -> Vec<(String, String)> {
+        let mut versions: Vec<(String, String)> = installed.iter().map(|p| (p.name.clone(), p.version.clone())).collect();
+        versions.sort();
+        versions
+    }
+
+    #[tokio::test]
+    async fn interactive_upgrade_applies_only_the_accepted_packages() {
+        let _stdin_lock = STDIN.lock().await;
+        let fx = upgradable_fixture().await;
+
+        let _stdin = ScriptedStdin::new(Some("y\nn\nyes\n"));
+        handle_upgrade(&[], &UpgradeOptions { interactive: true, ..UpgradeOptions::default() }, &fx.ctx).await.unwrap();
+
+        let expected = [("a", "2.0.0"), ("b", "1.0.0"), ("c", "2.0.0")].map(|(n, v)| (n.to_string(), v.to_string()));
+        assert_eq!(versions(&fx.installed().await), expected);
+        assert_eq!((fx.read("usr/share/a"), fx.read("usr/share/b"), fx.read("usr/share/c")), ("2".into(), "1".into(), "2".into()));
+    }
+
+    #[tokio::test]
+    async fn interactive_upgrade_without_a_terminal_refuses_and_changes_nothing() {
+        let _stdin_lock = STDIN.lock().await;
+        let fx = upgradable_fixture().await;
+
+        let _stdin = ScriptedStdin::new(None);
+        let err = handle_upgrade(&[], &UpgradeOptions { interactive: true, ..UpgradeOptions::default() }, &fx.ctx).await.unwrap_err();
+
+        assert!(matches!(&err, FluxError::PromptUnavailable(message) if message.contains("--yes")), "{}", err);
+        assert!(fx.installed().await.iter().all(|p| p.version == "1.0.0"));
+    }
+
+    fn inode(path: &Path) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(path).unwrap().ino()
+    }
+
+    fn blob_count(fx: &Fixture) -> usize {
+        std::fs::read_dir(&fx.ctx.target_store_dir).map(|dir| dir.count()).unwrap_or(0)
+    }
+
+    fn content_store_fixture() -> Fixture {
+        Fixture::with_config(FluxConfig { content_store: true, ..FluxConfig::default() })
+    }
+
+    #[tokio::test]
+    async fn the_content_store_shares_identical_files_until_the_last_owner_is_removed() {
+        let mut fx = content_store_fixture();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo"), ("usr/share/foo/COPYING", "license")]);
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar"), ("usr/share/bar/COPYING", "license")]);
+        fx.install(&["libfoo", "libbar"]).await.unwrap();
+
+        assert_eq!(inode(&fx.path("usr/share/foo/COPYING")), inode(&fx.path("usr/share/bar/COPYING")));
+        assert_eq!(blob_count(&fx), 3);
+        let license = fx.installed_package("libbar").await.unwrap().content_hashes[Path::new("usr/share/bar/COPYING")].clone();
+
+        handle_remove("libfoo", false, &fx.ctx).await.unwrap();
+        assert_eq!(blob_count(&fx), 2);
+        assert!(fx.ctx.target_store_dir.join(&license).exists());
+        assert_eq!(fx.read("usr/share/bar/COPYING"), "license");
+
+        handle_remove("libbar", false, &fx.ctx).await.unwrap();
+        assert_eq!(blob_count(&fx), 0);
+    }
+
+    #[tokio::test]
+    async fn the_content_store_keeps_files_with_a_different_mode_and_config_files_apart() {
+        use std::os::unix::fs::PermissionsExt;
+        let mut fx = content_store_fixture();
+        for (name, mode) in [("libfoo", 0o644), ("libbar", 0o755)] {
+            let files = [(format!("usr/share/{}/run", name), "#!/bin/sh"), (format!("etc/{}.conf", name), "setting")];
+            let files: Vec<(&str, &str)> = files.iter().map(|(path, contents)| (path.as_str(), *contents)).collect();
+            fx.publish_archive(package_info(name, "1.0.0", PackageType::System, &[]), tar_zst_with_mode(&files, mode));
+        }
+        fx.install(&["libfoo"]).await.unwrap();
+
+        // libbar's executable copy keeps its own mode instead of joining the 0644 blob.
+        fx.install(&["libbar"]).await.unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_ne!(inode(&fx.path("usr/share/libbar/run")), inode(&fx.path("usr/share/libfoo/run")));
+        assert_eq!(mode(&fx.path("usr/share/libfoo/run")), 0o644);
+        assert_eq!(mode(&fx.path("usr/share/libbar/run")), 0o755);
+        let blob = fx.installed_package("libfoo").await.unwrap().content_hashes[Path::new("usr/share/libfoo/run")].clone();
+        assert_eq!(mode(&fx.ctx.target_store_dir.join(blob)), 0o644);
+        assert!(!fx.installed_package("libbar").await.unwrap().content_hashes.contains_key(Path::new("usr/share/libbar/run")));
+        for name in ["libfoo", "libbar"] {
+            let package = fx.installed_package(name).await.unwrap();
+            assert!(!package.content_hashes.contains_key(Path::new(&format!("etc/{}.conf", name))), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_dependency_marked_explicit_survives_autoremove() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo"]), &[("bin/tool", "echo tool")]);
+        fx.install(&["tool"]).await.unwrap();
+
+        handle_mark("libfoo", InstallReason::Explicit, &fx.ctx).await.unwrap();
+        handle_remove("tool", false, &fx.ctx).await.unwrap();
+        handle_autoremove(&fx.ctx).await.unwrap();
+
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().install_reason, InstallReason::Explicit);
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo");
+    }
+
+    #[tokio::test]
+    async fn a_package_marked_as_a_dependency_is_reaped_by_autoremove_once_unneeded() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo"]), &[("bin/tool", "echo tool")]);
+        fx.install(&["libfoo", "tool"]).await.unwrap();
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().install_reason, InstallReason::Explicit);
+
+        handle_mark("libfoo", InstallReason::Dependency, &fx.ctx).await.unwrap();
+        handle_autoremove(&fx.ctx).await.unwrap();
+        // tool still needs it.
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().install_reason, InstallReason::Dependency);
+
+        handle_remove("tool", false, &fx.ctx).await.unwrap();
+        handle_autoremove(&fx.ctx).await.unwrap();
+        assert!(fx.installed_package("libfoo").await.is_none());
+        assert!(!fx.path("usr/lib/libfoo.so").exists());
+    }
+
+    #[tokio::test]
+    async fn marking_a_package_that_is_not_installed_fails() {
+        let fx = Fixture::new();
+        let err = handle_mark("ghost", InstallReason::Explicit, &fx.ctx).await.unwrap_err();
+        assert!(matches!(err, FluxError::PackageNotFound(_)), "{}", err);
+    }
+
+    // A file outside the target root that escaping archive entries and records aim at.
+    fn outside_victim(fx: &Fixture) -> PathBuf {
+        let victim = fx.dir.path().join("outside/victim");
+        std::fs::create_dir_all(victim.parent().unwrap()).unwrap();
+        std::fs::write(&victim, "original").unwrap();
+        victim
+    }
+
+    #[tokio::test]
+    async fn an_absolute_archive_entry_is_installed_and_removed_below_the_root() {
+        let mut fx = Fixture::new();
+        let victim = outside_victim(&fx);
+        let name = victim.to_str().unwrap().to_string();
+        fx.publish_archive(package_info("abs", "1.0.0", PackageType::System, &[]), raw_tar_zst(&[(name.as_bytes(), None, "packaged")]));
+
+        fx.install(&["abs"]).await.unwrap();
+        let inside = fx.ctx.target_root.join(name.trim_start_matches('/'));
+        assert_eq!(std::fs::read_to_string(&inside).unwrap(), "packaged");
+        assert_eq!(std::fs::read_to_string(&victim).unwrap(), "original");
+        assert!(fx.installed_package("abs").await.unwrap().files.iter().all(|f| f.path.is_relative()));
+
+        handle_remove("abs", false, &fx.ctx).await.unwrap();
+        assert!(!inside.exists());
+        assert_eq!(std::fs::read_to_string(&victim).unwrap(), "original");
+    }
+
+    #[tokio::test]
+    async fn archive_entries_that_climb_out_or_go_through_a_symlink_are_refused() {
+        let mut fx = Fixture::new();
+        let victim = outside_victim(&fx);
+        let outside = victim.parent().unwrap().to_str().unwrap().to_string();
+        fx.publish_archive(package_info("dotdot", "1.0.0", PackageType::System, &[]), raw_tar_zst(&[(b"../outside/victim", None, "evil")]));
+        fx.publish_archive(package_info("symlink", "1.0.0", PackageType::System, &[]), raw_tar_zst(&[
+            (b"usr/evil", Some(&outside), ""),
+            (b"usr/evil/victim", None, "evil"),
+        ]));
+
+        for name in ["dotdot", "symlink"] {
+            let err = fx.install(&[name]).await.unwrap_err();
+            assert!(matches!(err, FluxError::Archive(_)), "{}: {}", name, err);
+            assert_eq!(std::fs::read_to_string(&victim).unwrap(), "original", "{}", name);
+            assert!(fx.installed_package(name).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn removal_skips_recorded_paths_that_resolve_outside_the_root() {
+        let fx = Fixture::new();
+        let victim = outside_victim(&fx);
+        let record = serde_json::json!({
+            "name": "tampered", "version": "1.0.0", "package_type": "system", "install_reason": "Explicit",
+            "files": ["../outside/victim", victim],
+        });
+        std::fs::create_dir_all(fx.ctx.target_db_path.parent().unwrap()).unwrap();
+        std::fs::write(&fx.ctx.target_db_path, serde_json::json!([record]).to_string()).unwrap();
+
+        handle_remove("tampered", false, &fx.ctx).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&victim).unwrap(), "original");
+        assert!(fx.installed().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn status_of_an_empty_system_only_reports_the_missing_index() {
+        let fx = Fixture::new();
+
+        let report = status_report(&[], &fx.ctx).await.unwrap();
+
+        assert!(report.upgradable.is_empty() && report.orphans.is_empty() && report.integrity_problems.is_empty());
+        assert_eq!(report.index_age_hours, None);
+        assert!(report.index_stale);
+        handle_status(OutputFormat::Json, &fx.ctx).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn status_counts_upgrades_orphans_and_missing_files() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1"), ("usr/share/foo", "foo")]);
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo"]), &[("bin/tool", "echo tool")]);
+        fx.publish(package_info("editor", "1.0.0", PackageType::App, &[]), &[("bin/editor", "echo editor")]);
+        fx.install(&["tool", "libbar", "editor"]).await.unwrap();
+        handle_mark("libbar", InstallReason::Dependency, &fx.ctx).await.unwrap();
+        fx.publish(package_info("libfoo", "1.1.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1.1")]);
+        std::fs::remove_file(fx.path("usr/share/foo")).unwrap();
+        std::fs::remove_dir_all(fx.path("flux/apps/editor-1.0.0")).unwrap();
+        fx.ctx.config.repository_url = Some("https://example.com/index.yaml".to_string());
+        std::fs::write(fx.index_cache_path(), "packages: []\n").unwrap();
+
+        let report = status_report(&fx.installed().await, &fx.ctx).await.unwrap();
+
+        assert_eq!(report.upgradable, vec!["libfoo"]);
+        assert_eq!(report.orphans, vec!["libbar"]);
+        assert_eq!((report.index_age_hours, report.index_stale), (Some(0), false));
+        let mut problems = report.integrity_problems.clone();
+        problems.sort();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("editor: missing install directory"), "{:?}", problems);
+        assert_eq!(problems[1], "libfoo: 1 missing file(s)");
+
+        fx.ctx.config.index_ttl_hours = Some(0);
+        assert!(status_report(&fx.installed().await, &fx.ctx).await.unwrap().index_stale);
+    }
+
+    // broken's post-install script fails; it depends on libfoo, whose install is fine.
+    fn failing_script_fixture() -> Fixture {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        let mut broken = package_info("broken", "1.0.0", PackageType::System, &["libfoo"]);
+        broken.post_install = Some(ScriptSpec::Script("usr/share/broken/post-install".to_string()));
+        fx.publish(broken, &[("usr/lib/broken.so", "broken"), ("usr/share/broken/post-install", "echo failing >&2; exit 1")]);
+        fx
+    }
+
+    async fn install_with_policy(fx: &Fixture, policy: ScriptFailurePolicy) -> Result<(), FluxError> {
+        let options = InstallOptions { on_script_failure: Some(policy), ..InstallOptions::default() };
+        install_matching("broken", &options, &fx.ctx).await
+    }
+
+    #[tokio::test]
+    async fn abort_and_rollback_removes_the_failed_package_and_records_nothing_of_it() {
+        let fx = failing_script_fixture();
+        fx.install(&["libfoo"]).await.unwrap();
+
+        let err = install_with_policy(&fx, ScriptFailurePolicy::AbortAndRollback).await.unwrap_err();
+
+        assert!(matches!(&err, FluxError::PostInstallScriptFailed { package_name, message } if package_name == "broken" && message.contains("failing")), "{}", err);
+        assert!(!fx.path("usr/lib/broken.so").exists());
+        assert!(!fx.path("usr/share/broken").exists());
+        assert!(fx.installed_package("broken").await.is_none());
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo");
+    }
+
+    #[tokio::test]
+    async fn abort_keep_records_the_failed_package_with_its_files() {
+        let fx = failing_script_fixture();
+        fx.install(&["libfoo"]).await.unwrap();
+
+        let err = install_with_policy(&fx, ScriptFailurePolicy::AbortKeep).await.unwrap_err();
+
+        assert!(matches!(err, FluxError::PostInstallScriptFailed { .. }), "{}", err);
+        assert_eq!(fx.read("usr/lib/broken.so"), "broken");
+        let broken = fx.installed_package("broken").await.unwrap();
+        assert!(broken.files.iter().any(|f| f.path == Path::new("usr/lib/broken.so")));
+        assert_eq!(broken.install_reason, InstallReason::Explicit);
+    }
+
+    #[tokio::test]
+    async fn warn_continue_installs_the_whole_batch_despite_the_failure() {
+        let fx = failing_script_fixture();
+
+        install_with_policy(&fx, ScriptFailurePolicy::WarnContinue).await.unwrap();
+
+        assert_eq!(fx.read("usr/lib/broken.so"), "broken");
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo");
+        assert_eq!(names(&fx.installed().await), vec!["broken", "libfoo"]);
+    }
+
+    #[tokio::test]
+    async fn the_configured_policy_applies_without_the_flag() {
+        let mut fx = failing_script_fixture();
+        fx.ctx.config.on_script_failure = Some(ScriptFailurePolicy::WarnContinue);
+
+        fx.install(&["broken"]).await.unwrap();
+
+        assert!(fx.installed_package("broken").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn cache_verify_classifies_good_corrupt_and_unknown_archives() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar")]);
+        let options = InstallOptions { keep_downloads: true, ..InstallOptions::default() };
+        install_matching("libfoo", &options, &fx.ctx).await.unwrap();
+        install_matching("libbar", &options, &fx.ctx).await.unwrap();
+        let cache = fx.ctx.host_cache_dir.clone();
+        std::fs::write(cache.join("libbar-1.0.0.tar.zst"), "truncated").unwrap();
+        std::fs::write(cache.join("ghost-1.0.0.tar.zst"), "whatever").unwrap();
+
+        let results = classify_cached_archives(&fx.ctx).await.unwrap();
+        let summary: Vec<(String, &str)> = results.iter()
+            .map(|(path, status)| (path.file_name().unwrap().to_string_lossy().to_string(), match status {
+                CachedArchiveStatus::Valid => "valid",
+                CachedArchiveStatus::Corrupt { .. } => "corrupt",
+                CachedArchiveStatus::Unknown => "unknown",
+            }))
+            .collect();
+        assert_eq!(summary, vec![
+            ("ghost-1.0.0.tar.zst".to_string(), "unknown"),
+            ("libbar-1.0.0.tar.zst".to_string(), "corrupt"),
+            ("libfoo-1.0.0.tar.zst".to_string(), "valid"),
+        ]);
+
+        handle_cache_verify(true, &fx.ctx).await.unwrap();
+        assert!(!cache.join("libbar-1.0.0.tar.zst").exists());
+        assert!(cache.join("libfoo-1.0.0.tar.zst").exists() && cache.join("ghost-1.0.0.tar.zst").exists());
+    }
+
+    fn python_fixture() -> Fixture {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("python3", "3.12.0", PackageType::System, &[]), &[("usr/bin/python3", "python")]);
+        for name in ["python3-yaml", "python3-requests", "python3-idna"] {
+            let file = format!("usr/lib/python3/{}", name);
+            fx.publish(package_info(name, "1.0.0", PackageType::System, &["python3"]), &[(&file, name)]);
+        }
+        fx.publish(package_info("pythonic", "1.0.0", PackageType::System, &[]), &[("usr/share/pythonic", "pythonic")]);
+        fx
+    }
+
+    #[tokio::test]
+    async fn a_glob_installs_every_matching_package_with_its_dependencies() {
+        let fx = python_fixture();
+
+        install_matching("python3-*", &InstallOptions::default(), &fx.ctx).await.unwrap();
+
+        let installed = fx.installed().await;
+        assert_eq!(names(&installed), vec!["python3", "python3-idna", "python3-requests", "python3-yaml"]);
+        for pkg in &installed {
+            let expected = if pkg.name == "python3" { InstallReason::Dependency } else { InstallReason::Explicit };
+            assert_eq!(pkg.install_reason, expected, "{}", pkg.name);
+        }
+        assert_eq!(fx.read("usr/lib/python3/python3-idna"), "python3-idna");
+    }
+
+    #[tokio::test]
+    async fn a_glob_that_matches_nothing_is_an_error() {
+        let fx = python_fixture();
+
+        let err = install_matching("perl-*", &InstallOptions::default(), &fx.ctx).await.unwrap_err();
+
+        assert!(matches!(&err, FluxError::NoPackagesMatch(pattern) if pattern == "perl-*"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn a_declined_glob_expansion_installs_nothing() {
+        let _stdin_lock = STDIN.lock().await;
+        let mut fx = python_fixture();
+        fx.ctx.assume_yes = false;
+
+        let _stdin = ScriptedStdin::new(Some("n\n"));
+        install_matching("python3-[iy]*", &InstallOptions::default(), &fx.ctx).await.unwrap();
+
+        assert!(fx.installed().await.is_empty());
+    }
+
+    #[test]
+    fn glob_match_handles_wildcards_and_classes() {
+        for (pattern, name, expected) in [
+            ("python3-*", "python3-yaml", true),
+            ("python3-*", "python3", false),
+            ("lib?oo", "libfoo", true),
+            ("lib[a-c]ar", "libbar", true),
+            ("lib[!b]ar", "libbar", false),
+            ("lib[", "lib[", true),
+            ("*", "", true),
+        ] {
+            assert_eq!(glob_match(pattern, name), expected, "{} vs {}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn compare_versions_orders_numerically_with_prereleases_first() {
+        use std::cmp::Ordering::*;
+        for (a, b, expected) in [
+            ("1.10", "1.9", Greater),
+            ("1.0", "1.0.0", Less),
+            ("2.0-rc1", "2.0", Less),
+            ("2.0-rc.2", "2.0-rc.10", Less),
+            ("1.2+build5", "1.2", Equal),
+            ("1.2.beta", "1.2.1", Less),
+        ] {
+            assert_eq!(compare_versions(a, b), expected, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn dependency_specs_parse_names_operators_and_versions() {
+        for (spec, name, constraint) in [
+            ("libc", "libc", None),
+            ("libssl >= 1.1", "libssl", Some((VersionOp::Ge, "1.1"))),
+            ("zlib=1.2.13", "zlib", Some((VersionOp::Eq, "1.2.13"))),
+            ("zlib == 1.2", "zlib", Some((VersionOp::Eq, "1.2"))),
+            ("libfoo != 2", "libfoo", Some((VersionOp::Ne, "2"))),
+            ("libfoo<3", "libfoo", Some((VersionOp::Lt, "3"))),
+        ] {
+            let parsed = DependencySpec::parse(spec).unwrap();
+            assert_eq!(parsed.name, name, "{}", spec);
+            assert_eq!(parsed.constraint.map(|c| (c.op, c.version)), constraint.map(|(op, v)| (op, v.to_string())), "{}", spec);
+        }
+        for spec in ["", ">= 1.0", "libfoo >=", "lib foo", "libfoo >= 1 2", "libfoo => 1"] {
+            assert!(matches!(DependencySpec::parse(spec), Err(FluxError::InvalidDependencySpec(_))), "{:?}", spec);
+        }
+    }
+
+    // libc 2.1 is the only version. Among the apps' requirements on it only app-b's can't be met;
+    // app-a, app-c and app-d are satisfiable and must not show up in the conflict.
+    fn conflicting_fixture() -> Fixture {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libc", "2.1", PackageType::System, &[]), &[("usr/lib/libc.so", "libc")]);
+        fx.publish(package_info("zlib", "1.3", PackageType::System, &[]), &[("usr/lib/libz.so", "zlib")]);
+        for (name, dependency) in [("app-a", "libc >= 2.0"), ("app-b", "libc < 2.0"), ("app-c", "libc != 1.5"), ("app-d", "libc >= 1.0")] {
+            let file = format!("bin/{}", name);
+            fx.publish(package_info(name, "1.0", PackageType::App, &[dependency, "zlib >= 1.0"]), &[(&file, name)]);
+        }
+        fx
+    }
+
+    fn requirements_of(names: &[&str], ctx: &AppContext) -> Requirements {
+        let (mut resolved, mut requirements) = (Vec::new(), Requirements::new());
+        for name in names {
+            resolve_dependencies(name, ctx, &mut resolved, &mut requirements).unwrap();
+        }
+        requirements
+    }
+
+    #[tokio::test]
+    async fn an_unsatisfiable_install_reports_only_the_requirement_that_conflicts() {
+        let fx = conflicting_fixture();
+
+        let err = fx.install(&["app-a", "app-b", "app-c", "app-d"]).await.unwrap_err();
+
+        match &err {
+            FluxError::UnsatisfiableDependency { package_name, dependency, available } => {
+                assert_eq!(package_name, "app-b");
+                assert_eq!(dependency, "libc < 2.0");
+                assert_eq!(available, "2.1");
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(fx.installed().await.is_empty());
+        assert!(!fx.path("usr/lib/libc.so").exists());
+    }
+
+    #[tokio::test]
+    async fn satisfiable_requirements_pass_the_check_and_install() {
+        let fx = conflicting_fixture();
+        check_requirements(&requirements_of(&["app-a", "app-c", "app-d"], &fx.ctx), &fx.ctx).unwrap();
+
+        fx.install(&["app-a", "app-c", "app-d"]).await.unwrap();
+
+        assert_eq!(names(&fx.installed().await), vec!["app-a", "app-c", "app-d", "libc", "zlib"]);
+    }
+
+    #[test]
+    fn the_conflict_no_longer_mentions_requirements_that_were_dropped() {
+        let fx = conflicting_fixture();
+        let mut requirements = requirements_of(&["app-a", "app-b", "app-c", "app-d"], &fx.ctx);
+        // A second unmeetable requirement: either one alone already conflicts, so the minimal set keeps just one.
+        requirements.get_mut("libc").unwrap().push(Requirement {
+            required_by: "app-e".to_string(),
+            constraint: VersionConstraint { op: VersionOp::Eq, version: "1.0".to_string() },
+        });
+
+        let err = check_requirements(&requirements, &fx.ctx).unwrap_err();
+
+        assert!(matches!(&err, FluxError::UnsatisfiableDependency { package_name, .. } if package_name == "app-b" || package_name == "app-e"), "{}", err);
+    }
+
+    #[test]
+    fn the_minimal_conflict_is_found_against_every_indexed_version() {
+        let mut fx = Fixture::new();
+        for version in ["1.0", "1.5", "2.1"] {
+            fx.publish(package_info("libc", version, PackageType::System, &[]), &[("usr/lib/libc.so", version)]);
+        }
+        let requirement = |required_by: &str, op, version: &str| Requirement {
+            required_by: required_by.to_string(),
+            constraint: VersionConstraint { op, version: version.to_string() },
+        };
+        let mut requirements = Requirements::new();
+        requirements.insert("libc".to_string(), vec![
+            requirement("app-x", VersionOp::Ge, "2.0"),
+            requirement("app-y", VersionOp::Lt, "1.2"),
+            requirement("app-z", VersionOp::Lt, "2.0"),
+        ]);
+
+        // 1.0 meets app-z on its own, so app-z alone is no conflict even though 2.1 is the newest.
+        let err = check_requirements(&requirements, &fx.ctx).unwrap_err();
+
+        match &err {
+            FluxError::DependencyConflict { package_name, conflict } => {
+                assert_eq!(package_name, "libc");
+                assert_eq!(conflict, "app-x needs libc >= 2.0 but app-z needs libc < 2.0 (available: 1.0, 1.5, 2.1)");
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn json_lines_output_is_one_complete_record_per_line() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("app", "2.1.0", PackageType::App, &["libfoo"]), &[("bin/app", "app")]);
+        fx.install(&["app"]).await.unwrap();
+        let installed = fx.installed().await;
+
+        let mut out = Vec::new();
+        write_json_lines_to(&mut out, installed.iter().map(ListEntry::from)).unwrap();
+
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2);
+        let mut records: Vec<serde_json::Value> = lines.iter().map(|line| serde_json::from_str(line).unwrap()).collect();
+        records.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        assert_eq!(records[0]["name"], "app");
+        assert_eq!(records[0]["version"], "2.1.0");
+        assert_eq!(records[0]["install_reason"], serde_json::to_value(InstallReason::Explicit).unwrap());
+        assert_eq!(records[1]["name"], "libfoo");
+        assert_eq!(records[1]["install_reason"], serde_json::to_value(InstallReason::Dependency).unwrap());
+    }
+
+    #[tokio::test]
+    async fn rebuild_db_recovers_packages_from_the_filesystem_after_corruption() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar")]);
+        fx.publish(package_info("hello-tool", "2.0.0", PackageType::App, &[]), &[("bin/hello", "hi")]);
+        let options = InstallOptions { keep_downloads: true, ..InstallOptions::default() };
+        install_packages(&["libfoo".to_string(), "libbar".to_string(), "hello-tool".to_string()], &options, &fx.ctx).await.unwrap();
+        std::fs::remove_file(fx.ctx.host_cache_dir.join("libbar-1.0.0.tar.zst")).unwrap();
+        std::fs::write(&fx.ctx.target_db_path, "{ not json").unwrap();
+        assert!(matches!(fx.ctx.get_installed_packages().await, Err(FluxError::CorruptDatabase { .. })));
+
+        handle_rebuild_db(false, &fx.ctx).await.unwrap();
+
+        let installed = fx.installed().await;
+        assert_eq!(names(&installed), vec!["hello-tool", "libfoo"]);
+        assert!(installed.iter().all(|p| p.install_reason == InstallReason::Explicit));
+        let libfoo = fx.installed_package("libfoo").await.unwrap();
+        assert!(libfoo.files.iter().any(|f| f.path == Path::new("usr/lib/libfoo.so")));
+        assert_eq!(fx.installed_package("hello-tool").await.unwrap().version, "2.0.0");
+
+        let db_dir = fx.ctx.target_db_path.parent().unwrap();
+        let backups: Vec<_> = std::fs::read_dir(db_dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with("db.json.corrupt-"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(std::fs::read_to_string(&backups[0]).unwrap(), "{ not json");
+    }
+
+    fn write_index(path: &Path, packages: Vec<PackageInfo>) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, serde_yaml::to_string(&PackageIndex { packages }).unwrap()).unwrap();
+    }
+
+    fn index_fixture(urls: &[PathBuf]) -> Fixture {
+        let config = FluxConfig {
+            repository_url: Some(Url::from_file_path(&urls[0]).unwrap().to_string()),
+            index_mirrors: urls[1..].iter().map(|p| Url::from_file_path(p).unwrap().to_string()).collect(),
+            index_retries: Some(2),
+            ..FluxConfig::default()
+        };
+        Fixture::with_config(config)
+    }
+
+    #[tokio::test]
+    async fn update_retries_a_transient_failure_and_then_succeeds() {
+        let dir = tempfile::tempdir().unwrap();
+        let index_path = dir.path().join("index.yaml");
+        let mut fx = index_fixture(std::slice::from_ref(&index_path));
+        let writer = {
+            let index_path = index_path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                write_index(&index_path, vec![package_info("libfoo", "1.0.0", PackageType::System, &[])]);
+            })
+        };
+
+        handle_update(false, &mut fx.ctx).await.unwrap();
+        writer.join().unwrap();
+
+        assert!(fx.ctx.package_index.contains_key("libfoo"));
+        assert!(std::fs::read_to_string(fx.index_cache_path()).unwrap().contains("libfoo"));
+    }
+
+    #[tokio::test]
+    async fn update_falls_back_to_a_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        let mirror = dir.path().join("mirror.yaml");
+        write_index(&mirror, vec![package_info("libbar", "1.0.0", PackageType::System, &[])]);
+        let mut fx = index_fixture(&[dir.path().join("missing.yaml"), mirror]);
+
+        handle_update(false, &mut fx.ctx).await.unwrap();
+
+        assert!(fx.ctx.package_index.contains_key("libbar"));
+    }
+
+    #[tokio::test]
+    async fn a_failed_update_keeps_the_cached_index_unless_strict() {
+        let dir = tempfile::tempdir().unwrap();
+        let garbage = dir.path().join("garbage.yaml");
+        std::fs::write(&garbage, "packages: [not, a, package").unwrap();
+        let mut fx = index_fixture(&[garbage, dir.path().join("missing.yaml")]);
+        write_index(&fx.index_cache_path(), vec![package_info("libold", "1.0.0", PackageType::System, &[])]);
+        let cached = std::fs::read_to_string(fx.index_cache_path()).unwrap();
+
+        handle_update(false, &mut fx.ctx).await.unwrap();
+        assert_eq!(std::fs::read_to_string(fx.index_cache_path()).unwrap(), cached);
+        assert!(!partial_download_path(&fx.index_cache_path()).exists());
+
+        let err = handle_update(true, &mut fx.ctx).await.unwrap_err();
+        assert!(matches!(err, FluxError::AllMirrorsFailed { .. }), "{}", err);
+        assert_eq!(std::fs::read_to_string(fx.index_cache_path()).unwrap(), cached);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_optional_repository_only_warns_during_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let public = dir.path().join("public.yaml");
+        write_index(&public, vec![package_info("libfoo", "1.0.0", PackageType::System, &[])]);
+        let mut fx = index_fixture(std::slice::from_ref(&public));
+        let internal = RepoEntry { name: "internal".to_string(), url: Url::from_file_path(dir.path().join("offline.yaml")).unwrap().to_string(), priority: 10, optional: true };
+        fx.ctx.config.repositories = vec![internal.clone()];
+
+        handle_update(false, &mut fx.ctx).await.unwrap();
+        assert!(fx.ctx.package_index.contains_key("libfoo"));
+        assert_eq!(index_age_hours(&fx.ctx).await.unwrap(), Some(0));
+
+        fx.ctx.config.repositories = vec![RepoEntry { optional: false, ..internal }];
+        let err = handle_update(false, &mut fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::IndexUpdateFailed(reason) if reason.starts_with("internal: ")), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn info_aggregates_the_transitive_closure_and_reports_upgrades() {
+        let mut fx = Fixture::new();
+        let sized = |name: &str, version: &str, deps: &[&str], size: u64| PackageInfo {
+            size: Some(size),
+            installed_size: Some(size * 3),
+            ..package_info(name, version, PackageType::System, deps)
+        };
+        fx.publish(sized("libc", "1.0.0", &[], 10), &[("usr/lib/libc.so", "c")]);
+        fx.publish(sized("liba", "1.0.0", &["libc"], 20), &[("usr/lib/liba.so", "a")]);
+        fx.publish(sized("libb", "1.0.0", &["libc"], 40), &[("usr/lib/libb.so", "b")]);
+        fx.publish(sized("app", "1.0.0", &["liba", "libb"], 100), &[("usr/bin/app", "app")]);
+        fx.install(&["app"]).await.unwrap();
+        fx.publish(sized("app", "1.1.0", &["liba", "libb"], 100), &[("usr/bin/app", "app")]);
+
+        let details = package_details("app", &fx.ctx).await.unwrap();
+
+        assert_eq!(details.dependency_closure, vec!["liba", "libb", "libc"]);
+        assert_eq!(details.total_download_size, Some(170));
+        assert_eq!(details.total_installed_size, Some(510));
+        assert_eq!(details.installed_version.as_deref(), Some("1.0.0"));
+        assert!(details.upgrade_available);
+
+        fx.publish(package_info("libd", "1.0.0", PackageType::System, &[]), &[("usr/lib/libd.so", "d")]);
+        fx.publish(sized("tool", "1.0.0", &["libd"], 5), &[("usr/bin/tool", "tool")]);
+        let details = package_details("tool", &fx.ctx).await.unwrap();
+        assert_eq!(details.total_download_size, None);
+        assert_eq!(details.installed_version, None);
+        assert!(!details.upgrade_available);
+    }
+
+    #[tokio::test]
+    async fn sandboxed_scripts_get_a_scrubbed_environment_and_privileged_ones_do_not() {
+        let mut fx = Fixture::with_config(FluxConfig { sandbox_scripts: true, ..FluxConfig::default() });
+        let out = fx.dir.path().join("out");
+        std::fs::create_dir_all(&out).unwrap();
+        for (name, privileged) in [("sandboxed", false), ("privileged", true)] {
+            let dump = format!("env > {0}/{1}.env; pwd > {0}/{1}.cwd; ls /proc/$$/fd > {0}/{1}.fds", out.display(), name);
+            let mut info = package_info(name, "1.0.0", PackageType::System, &[]);
+            info.post_install = Some(ScriptSpec::Script(format!("usr/share/{}/post-install", name)));
+            info.privileged_scripts = privileged;
+            fx.publish(info, &[(&format!("usr/share/{}/post-install", name), &dump)]);
+        }
+        // Opened without close-on-exec, so a child inherits it unless the sandbox closes it.
+        let leaked_fd = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY) };
+        assert!(leaked_fd > 2);
+
+        let result = fx.install(&["sandboxed", "privileged"]).await;
+        unsafe { libc::close(leaked_fd) };
+        result.unwrap();
+
+        let read = |file: &str| std::fs::read_to_string(out.join(file)).unwrap();
+        let env_names = |file: &str| -> std::collections::BTreeSet<String> {
+            read(file).lines().filter_map(|line| line.split_once('=')).map(|(key, _)| key.to_string()).collect()
+        };
+        let fds = |file: &str| -> Vec<i32> { read(file).lines().filter_map(|fd| fd.trim().parse().ok()).collect() };
+        let shell_set = ["PWD", "OLDPWD", "SHLVL", "_"];
+
+        let sandboxed_env: std::collections::BTreeSet<String> = env_names("sandboxed.env").into_iter().filter(|key| !shell_set.contains(&key.as_str())).collect();
+        assert_eq!(sandboxed_env, ["HOME", "LANG", "PATH"].into_iter().map(String::from).collect());
+        assert!(read("sandboxed.env").contains(&format!("HOME={}\n", fx.ctx.target_root.display())));
+        assert_eq!(read("sandboxed.cwd").trim(), fx.ctx.target_root.to_str().unwrap());
+        assert!(!fds("sandboxed.fds").contains(&leaked_fd), "{:?}", fds("sandboxed.fds"));
+
+        assert!(env_names("privileged.env").len() > sandboxed_env.len() + shell_set.len());
+        assert_ne!(read("privileged.cwd").trim(), fx.ctx.target_root.to_str().unwrap());
+        assert!(fds("privileged.fds").contains(&leaked_fd), "{:?}", fds("privileged.fds"));
+    }
+
+    #[tokio::test]
+    async fn reverse_deps_counts_every_installed_dependent_of_a_library() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libc", "1.0.0", PackageType::System, &[]), &[("usr/lib/libc.so", "c")]);
+        fx.publish(package_info("zlib", "1.0.0", PackageType::System, &["libc"]), &[("usr/lib/libz.so", "z")]);
+        fx.publish(package_info("curl", "1.0.0", PackageType::System, &["libc", "zlib"]), &[("usr/bin/curl", "curl")]);
+        fx.publish(package_info("git", "1.0.0", PackageType::System, &["libc", "curl"]), &[("usr/bin/git", "git")]);
+        fx.publish(package_info("unused", "1.0.0", PackageType::System, &["libc"]), &[("usr/bin/unused", "unused")]);
+        fx.install(&["git"]).await.unwrap();
+        let installed = fx.installed().await;
+
+        let mut dependents = find_dependents("libc", &installed, &fx.ctx);
+        dependents.sort();
+        assert_eq!(dependents, vec!["curl", "git", "zlib"]);
+        assert!(find_dependents("git", &installed, &fx.ctx).is_empty());
+
+        let options = ListOptions { format: ListFormat::Json, json_lines: false, reverse_deps: true, verbose: true, orphans: false, sort: None, newer_than: None };
+        handle_list(options, &fx.ctx).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failed_download_leaves_neither_the_archive_nor_a_part_file() {
+        let mut fx = Fixture::new();
+        let mut info = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        info.checksum = format!("{:x}", Sha256::digest(b"something else"));
+        fx.ctx.package_index.insert(info.name.clone(), info);
+
+        let err = fx.install(&["libfoo"]).await.unwrap_err();
+
+        assert!(matches!(err, FluxError::ChecksumMismatch { .. }), "{}", err);
+        let cached: Vec<_> = std::fs::read_dir(&fx.ctx.host_cache_dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert!(cached.is_empty(), "{:?}", cached);
+    }
+
+    #[tokio::test]
+    async fn stale_part_files_are_removed_and_finished_archives_kept() {
+        let fx = Fixture::new();
+        let cache = &fx.ctx.host_cache_dir;
+        std::fs::write(cache.join("libfoo-1.0.0.tar.zst.part"), "trunc").unwrap();
+        std::fs::write(cache.join("libbar-1.0.0.tar.zst"), "done").unwrap();
+
+        remove_stale_partial_downloads(cache).await.unwrap();
+
+        assert!(!cache.join("libfoo-1.0.0.tar.zst.part").exists());
+        assert!(!cache.join("libfoo-1.0.0.tar.zst").exists());
+        assert!(cache.join("libbar-1.0.0.tar.zst").exists());
+    }
+
+    #[tokio::test]
+    async fn the_upgrade_plan_shows_changelog_excerpts_and_notes_missing_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let changelog = dir.path().join("CHANGES");
+        let long: String = (1..=40).map(|n| format!("- change {}\n", n)).collect();
+        std::fs::write(&changelog, format!("libfoo 1.1.0\n{}", long)).unwrap();
+        let changelog_url = Url::from_file_path(&changelog).unwrap().to_string();
+        let downloader = Downloader::new(&FluxConfig::default(), false, IpPreference::Dual).unwrap();
+        let mut cache = HashMap::new();
+
+        let lines = changelog_plan_lines(&downloader, &changelog_url, &mut cache).await;
+        assert_eq!(lines[0], "    libfoo 1.1.0");
+        assert_eq!(lines[1], "    - change 1");
+        assert_eq!(lines.len(), CHANGELOG_EXCERPT_LINES + 1);
+        assert_eq!(lines.last().unwrap(), "    ...");
+
+        // Fetched once per run.
+        std::fs::remove_file(&changelog).unwrap();
+        assert_eq!(changelog_plan_lines(&downloader, &changelog_url, &mut cache).await, lines);
+
+        let unreachable = Url::from_file_path(dir.path().join("missing")).unwrap().to_string();
+        for url in ["", unreachable.as_str()] {
+            assert_eq!(changelog_plan_lines(&downloader, url, &mut cache).await, vec!["    No changelog available."]);
+        }
+    }
+
+    // Serves HTTP on localhost, answering request number n (from 0) with whatever `respond` returns.
+    async fn http_server<F, R>(respond: F) -> Url
+    where
+        F: Fn(usize, String) -> R + Send + Sync + 'static,
+        R: std::future::Future<Output = Vec<u8>> + Send + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let respond = std::sync::Arc::new(respond);
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (respond, requests) = (respond.clone(), requests.clone());
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        let n = socket.read(&mut buf).await.unwrap();
+                        if n == 0 { return; }
+                        head.extend_from_slice(&buf[..n]);
+                    }
+                    let number = requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    let response = respond(number, String::from_utf8_lossy(&head).into_owned()).await;
+                    let _ = socket.write_all(&response).await;
+                });
+            }
+        });
+        url
+    }
+
+    fn http_response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[tokio::test]
+    async fn a_429_shrinks_the_hosts_connection_limit_and_the_downloads_still_finish() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        let active = std::sync::Arc::new(AtomicUsize::new(0));
+        let throttled = std::sync::Arc::new(AtomicBool::new(false));
+        let peak_after_throttling = std::sync::Arc::new(AtomicUsize::new(0));
+        let url = {
+            let (active, throttled, peak) = (active.clone(), throttled.clone(), peak_after_throttling.clone());
+            http_server(move |number, _| {
+                let (active, throttled, peak) = (active.clone(), throttled.clone(), peak.clone());
+                async move {
+                    if number == 0 {
+                        throttled.store(true, Ordering::SeqCst);
+                        return http_response("429 Too Many Requests", &[("Retry-After", "0")], b"");
+                    }
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    if throttled.load(Ordering::SeqCst) { peak.fetch_max(now, Ordering::SeqCst); }
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    http_response("200 OK", &[], b"payload")
+                }
+            }).await
+        };
+        let downloader = Downloader::new(&FluxConfig { max_connections_per_host: Some(2), ..FluxConfig::default() }, false, IpPreference::Dual).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let destinations: Vec<PathBuf> = (0..3).map(|n| dir.path().join(format!("file{}", n))).collect();
+        let downloads = destinations.iter().map(|dest| downloader.download_file(&url, dest));
+        for result in futures_util::future::join_all(downloads).await {
+            result.unwrap();
+        }
+
+        for dest in &destinations {
+            assert_eq!(std::fs::read(dest).unwrap(), b"payload");
+        }
+        let slots = downloader.slots_for(url.host_str().unwrap());
+        assert_eq!(slots.limit.load(Ordering::SeqCst), 1);
+        assert_eq!(peak_after_throttling.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn changelogs_are_fetched_through_the_shared_downloader() {
+        let url = http_server(|number, _| async move {
+            match number {
+                0 => http_response("429 Too Many Requests", &[("Retry-After", "0")], b""),
+                _ => http_response("200 OK", &[], b"Fixed the frobnicator"),
+            }
+        }).await;
+        let downloader = Downloader::new(&FluxConfig { max_connections_per_host: Some(2), ..FluxConfig::default() }, false, IpPreference::Dual).unwrap();
+
+        let lines = changelog_plan_lines(&downloader, url.join("CHANGES").unwrap().as_str(), &mut HashMap::new()).await;
+
+        assert_eq!(lines, vec!["    Fixed the frobnicator"]);
+        assert_eq!(downloader.slots_for(url.host_str().unwrap()).limit.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn the_dot_graph_has_a_node_per_package_and_an_edge_per_dependency() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libc", "1.0.0", PackageType::System, &[]), &[("usr/lib/libc.so", "c")]);
+        fx.publish(package_info("libfoo", "1.2.0", PackageType::System, &["libc"]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("viewer", "2.0.0", PackageType::App, &["libfoo"]), &[("bin/viewer", "viewer")]);
+        fx.publish(package_info("retired", "0.9.0", PackageType::System, &[]), &[("usr/bin/retired", "retired")]);
+        fx.install(&["viewer", "retired"]).await.unwrap();
+        fx.ctx.package_index.remove("retired");
+
+        let dot = dependency_graph(None, &fx.ctx).await.unwrap().to_dot();
+
+        assert!(dot.starts_with("digraph flux {\n") && dot.ends_with("}\n"), "{}", dot);
+        assert!(dot.contains(r#"  "viewer" [label="viewer 2.0.0", type="app", shape=box, reason="explicit"];"#), "{}", dot);
+        assert!(dot.contains(r#"  "libfoo" [label="libfoo 1.2.0", type="system", reason="dependency"];"#), "{}", dot);
+        assert!(dot.contains(r#"  "retired" [label="retired 0.9.0", type="system", reason="explicit", unknown_deps=true, style=dashed];"#), "{}", dot);
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains(" -> ")).collect();
+        assert_eq!(edges, vec![r#"  "libfoo" -> "libc";"#, r#"  "viewer" -> "libfoo";"#]);
+
+        let closure = dependency_graph(Some("libfoo"), &fx.ctx).await.unwrap();
+        let ids: Vec<&str> = closure.nodes.iter().map(|node| node.id.as_str()).collect();
+        assert_eq!(ids, vec!["libc", "libfoo"]);
+    }
+
+    #[test]
+    fn layouts_that_overlap_system_or_each_other_are_refused() {
+        let root = Path::new("/target");
+        let cache = Path::new("/home/user/.cache/flux");
+        let layout = |apps: &str, db: &str| -> Result<(), FluxError> {
+            let apps_root = root.join(layout_dir(Some(Path::new(apps)), "flux/apps", "apps_dir")?);
+            let db_dir = root.join(layout_dir(Some(Path::new(db)), "var/lib/flux", "db_dir")?);
+            validate_layout(root, &apps_root, &db_dir, cache)
+        };
+
+        layout("flux/apps", "var/lib/flux").unwrap();
+        layout("apps", "var/db/flux").unwrap();
+        for (apps, db, complaint) in [
+            ("usr/share/apps", "var/lib/flux", "inside /usr"),
+            ("flux/apps", "etc/flux", "inside /etc"),
+            ("flux", "flux/apps/db", "must not overlap"),
+            ("flux/apps", "flux", "must not overlap"),
+            ("../escape", "var/lib/flux", "below the target root"),
+            ("flux/apps", ".", "below the target root"),
+        ] {
+            let err = layout(apps, db).unwrap_err();
+            assert!(matches!(&err, FluxError::Config(message) if message.contains(complaint)), "{} {}: {}", apps, db, err);
+        }
+
+        let err = validate_layout(root, &root.join("flux/apps"), &root.join("var/lib/flux"), &root.join("flux/apps/cache")).unwrap_err();
+        assert!(err.to_string().contains("overlaps the download cache"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn no_verify_installs_an_archive_whose_checksum_does_not_match() {
+        let mut fx = Fixture::new();
+        let mut info = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "rebuilt")]);
+        info.checksum = format!("{:x}", Sha256::digest(b"the archive the index was built from"));
+        fx.ctx.package_index.insert(info.name.clone(), info);
+        let err = fx.install(&["libfoo"]).await.unwrap_err();
+        assert!(matches!(err, FluxError::ChecksumMismatch { .. }), "{}", err);
+
+        fx.ctx.no_verify = true;
+        fx.install(&["libfoo"]).await.unwrap();
+
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "rebuilt");
+        assert!(fx.installed_package("libfoo").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn a_system_scoped_remove_hook_runs_for_system_removals_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("flux.conf");
+        std::fs::write(&config_path, "hooks:\n  \"*\":\n    - script: etc/flux/hooks/remove-system\n      type: system\n      phase: remove\n").unwrap();
+        let mut fx = Fixture::with_config(FluxConfig::load(&config_path).await.unwrap());
+        let log = fx.dir.path().join("hook.log");
+        fx.write_script("etc/flux/hooks/remove-system", &format!("echo ran >> '{}'", log.display()));
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("viewer", "1.0.0", PackageType::App, &[]), &[("bin/viewer", "viewer")]);
+        fx.install(&["libfoo", "viewer"]).await.unwrap();
+        assert_eq!(hook_runs(&fx), 0);
+
+        handle_remove("viewer", false, &fx.ctx).await.unwrap();
+        assert_eq!(hook_runs(&fx), 0);
+
+        handle_remove("libfoo", false, &fx.ctx).await.unwrap();
+        assert_eq!(hook_runs(&fx), 1);
+    }
+
+    #[tokio::test]
+    async fn an_exported_index_installs_the_same_packages_on_another_machine() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("viewer", "2.0.0", PackageType::App, &["libfoo"]), &[("bin/viewer", "viewer")]);
+        let options = InstallOptions { keep_downloads: true, ..InstallOptions::default() };
+        install_packages(&["viewer".to_string()], &options, &fx.ctx).await.unwrap();
+
+        let mirror = tempfile::tempdir().unwrap();
+        for archive in ["libfoo-1.0.0.tar.zst", "viewer-2.0.0.tar.zst"] {
+            std::fs::copy(fx.ctx.host_cache_dir.join(archive), mirror.path().join(archive)).unwrap();
+        }
+        let mirror_url = Url::from_directory_path(mirror.path()).unwrap();
+        let exported = export_installed_index(true, Some(mirror_url.as_str()), &fx.ctx).await.unwrap();
+        let yaml = serde_yaml::to_string(&exported).unwrap();
+
+        let index: PackageIndex = serde_yaml::from_str(&yaml).unwrap();
+        let mut replica = Fixture::new();
+        replica.ctx.package_index = index.packages.into_iter().map(|p| (p.name.clone(), p)).collect();
+        assert_eq!(replica.ctx.package_index["viewer"].checksum, fx.ctx.package_index["viewer"].checksum);
+        replica.install(&["viewer"]).await.unwrap();
+
+        assert_eq!(names(&replica.installed().await), vec!["libfoo", "viewer"]);
+        assert_eq!(replica.read("usr/lib/libfoo.so"), "foo");
+    }
+
+    #[tokio::test]
+    async fn exported_json_lines_are_each_a_complete_package_record() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("viewer", "2.0.0", PackageType::App, &["libfoo"]), &[("bin/viewer", "viewer")]);
+        fx.install(&["viewer"]).await.unwrap();
+        let index = export_installed_index(false, None, &fx.ctx).await.unwrap();
+
+        let mut out = Vec::new();
+        write_json_lines_to(&mut out, &index.packages).unwrap();
+
+        let records: Vec<PackageInfo> = std::str::from_utf8(&out).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let exported: Vec<(&str, &str)> = records.iter().map(|p| (p.name.as_str(), p.version.as_str())).collect();
+        assert_eq!(exported, vec![("libfoo", "1.0.0"), ("viewer", "2.0.0")]);
+        assert_eq!(records[1].dependencies, Some(vec!["libfoo".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn relative_package_urls_resolve_against_the_index_directory() {
+        let repo = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(repo.path().join("packages")).unwrap();
+        let archive = tar_zst(&[("usr/lib/libfoo.so", "foo")]);
+        std::fs::write(repo.path().join("packages/libfoo.tar.zst"), &archive).unwrap();
+        let mut info = package_info("libfoo", "1.0.0", PackageType::System, &[]);
+        info.url = "file://./packages/libfoo.tar.zst".to_string();
+        info.checksum = format!("{:x}", Sha256::digest(&archive));
+        write_index(&repo.path().join("index.yaml"), vec![info]);
+        let mut fx = index_fixture(&[repo.path().join("index.yaml")]);
+        assert_ne!(std::env::current_dir().unwrap(), repo.path());
+
+        handle_update(true, &mut fx.ctx).await.unwrap();
+        fx.install(&["libfoo"]).await.unwrap();
+
+        assert_eq!(fx.ctx.package_index["libfoo"].url, Url::from_file_path(repo.path().join("packages/libfoo.tar.zst")).unwrap().as_str());
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo");
+    }
+
+    #[tokio::test]
+    async fn a_failure_mid_extraction_leaves_the_live_root_untouched_and_no_staging_behind() {
+        let mut fx = Fixture::new();
+        let mut seed = 0x2545_f491_u64;
+        let noise: Vec<String> = (0..8).map(|_| (0..32 * 1024).map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            char::from_digit((seed >> 60) as u32, 16).unwrap()
+        }).collect()).collect();
+        let files: Vec<(String, &str)> = noise.iter().enumerate().map(|(n, data)| (format!("usr/share/big/part{}", n), data.as_str())).collect();
+        let files: Vec<(&str, &str)> = files.iter().map(|(path, data)| (path.as_str(), *data)).collect();
+        let mut archive = tar_zst(&files);
+        archive.truncate(archive.len() * 3 / 4);
+        // Unpacked directly, the archive gets partway before it fails.
+        let direct = tempfile::tempdir().unwrap();
+        std::fs::write(direct.path().join("big.tar.zst"), &archive).unwrap();
+        std::fs::create_dir(direct.path().join("out")).unwrap();
+        let out = direct.path().join("out");
+        assert!(extract_package(&direct.path().join("big.tar.zst"), &out, &out, HardlinkPolicy::Link, 1).await.is_err());
+        assert!(direct.path().join("out/usr/share/big/part0").exists());
+        fx.publish_archive(package_info("big", "1.0.0", PackageType::System, &[]), archive);
+
+        let err = fx.install(&["big"]).await.unwrap_err();
+
+        assert!(matches!(err, FluxError::Archive(_) | FluxError::Io(_)), "{}", err);
+        assert!(!fx.path("usr").exists());
+        let staging = &fx.ctx.target_staging_dir;
+        assert!(!staging.exists() || std::fs::read_dir(staging).unwrap().next().is_none());
+        assert!(fx.installed_package("big").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn the_printed_plan_is_what_the_install_then_does() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libc", "1.0.0", PackageType::System, &[]), &[("usr/lib/libc.so", "c")]);
+        fx.publish(package_info("liba", "1.1.0", PackageType::System, &["libc"]), &[("usr/lib/liba.so", "a")]);
+        fx.publish(package_info("extra", "0.3.0", PackageType::System, &[]), &[("usr/share/extra", "extra")]);
+        let mut app = package_info("app", "2.0.0", PackageType::App, &["liba"]);
+        app.optional_dependencies = Some(vec!["extra".to_string()]);
+        fx.publish(app, &[("bin/app", "app")]);
+        fx.install(&["libc"]).await.unwrap();
+        let options = InstallOptions { recommended: true, ..InstallOptions::default() };
+        let requested = vec!["app".to_string()];
+
+        let plan = plan_install(&requested, &options, &fx.installed().await, &fx.ctx).unwrap();
+
+        let json = serde_json::to_value(&plan).unwrap();
+        let summary: Vec<(&str, &str, &str)> = json["packages"].as_array().unwrap().iter()
+            .map(|p| (p["name"].as_str().unwrap(), p["action"].as_str().unwrap(), p["reason"].as_str().unwrap()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("libc", "skip", "dependency of liba"),
+            ("liba", "install", "dependency of app"),
+            ("app", "install", "requested"),
+            ("extra", "install", "recommended by app"),
+        ]);
+        assert_eq!(json["packages"][1]["version"], "1.1.0");
+        assert_eq!(json["packages"][1]["url"], fx.ctx.package_index["liba"].url.as_str());
+
+        let planned: Vec<&str> = plan.packages.iter().filter(|p| p.action == PlanAction::Install).map(|p| p.name.as_str()).collect();
+        install_packages(&requested, &options, &fx.ctx).await.unwrap();
+        let newly_installed: Vec<String> = fx.installed().await.into_iter().skip(1).map(|p| p.name).collect();
+        assert_eq!(newly_installed, planned);
+    }
+
+    // A package whose only entry is a hardlink to a file it doesn't ship itself.
+    fn hardlink_fixture(policy: HardlinkPolicy) -> Fixture {
+        let mut fx = Fixture::with_config(FluxConfig { cross_package_hardlinks: Some(policy), ..FluxConfig::default() });
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so.1", "foo")]);
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        header.set_mode(0o644);
+        builder.append_link(&mut header, "usr/lib/libfoo.so", "usr/lib/libfoo.so.1").unwrap();
+        let archive = zstd::encode_all(&builder.into_inner().unwrap()[..], 0).unwrap();
+        fx.publish_archive(package_info("libfoo-dev", "1.0.0", PackageType::System, &["libfoo"]), archive);
+        fx
+    }
+
+    #[tokio::test]
+    async fn a_hardlink_to_another_packages_file_installs_and_removes_cleanly() {
+        let fx = hardlink_fixture(HardlinkPolicy::Link);
+
+        fx.install(&["libfoo-dev"]).await.unwrap();
+
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo");
+        assert_eq!(inode(&fx.path("usr/lib/libfoo.so")), inode(&fx.path("usr/lib/libfoo.so.1")));
+        let dev = fx.installed_package("libfoo-dev").await.unwrap();
+        assert_eq!(dev.files.iter().map(|f| f.path.as_path()).collect::<Vec<_>>(), vec![Path::new("usr/lib/libfoo.so")]);
+
+        handle_remove("libfoo-dev", false, &fx.ctx).await.unwrap();
+        assert!(!fx.path("usr/lib/libfoo.so").exists());
+        assert_eq!(fx.read("usr/lib/libfoo.so.1"), "foo");
+
+        handle_remove("libfoo", false, &fx.ctx).await.unwrap();
+        assert!(!fx.path("usr/lib/libfoo.so.1").exists());
+    }
+
+    #[tokio::test]
+    async fn cross_package_hardlinks_can_be_copied_or_refused() {
+        let fx = hardlink_fixture(HardlinkPolicy::Copy);
+        fx.install(&["libfoo-dev"]).await.unwrap();
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo");
+        assert_ne!(inode(&fx.path("usr/lib/libfoo.so")), inode(&fx.path("usr/lib/libfoo.so.1")));
+
+        let fx = hardlink_fixture(HardlinkPolicy::Reject);
+        fx.install(&["libfoo"]).await.unwrap();
+        let err = fx.install(&["libfoo-dev"]).await.unwrap_err();
+        assert!(matches!(err, FluxError::Archive(_)), "{}", err);
+        assert!(!fx.path("usr/lib/libfoo.so").exists());
+        assert_eq!(fx.read("usr/lib/libfoo.so.1"), "foo");
+    }
+
+    #[tokio::test]
+    async fn verify_checksums_flags_a_modified_file_that_a_presence_check_misses() {
+        let mut fx = Fixture::new();
+        fx.ctx.jobs = 2;
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo"), ("usr/share/foo/data", "data")]);
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar")]);
+        fx.install(&["libfoo", "libbar"]).await.unwrap();
+        handle_verify(None, true, &fx.ctx).await.unwrap();
+        assert!(fx.installed_package("libfoo").await.unwrap().files.iter().any(|f| f.sha256.is_some()));
+
+        std::fs::write(fx.path("usr/lib/libfoo.so"), "FOO").unwrap();
+        handle_verify(None, false, &fx.ctx).await.unwrap();
+
+        let err = handle_verify(None, true, &fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::VerificationFailed(message) if message == "0 missing and 1 modified file(s) in libfoo"), "{}", err);
+
+        std::fs::remove_file(fx.path("usr/lib/libbar.so")).unwrap();
+        let err = handle_verify(None, true, &fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::VerificationFailed(message) if message == "1 missing and 1 modified file(s) in libbar, libfoo"), "{}", err);
+        handle_verify(Some("libbar"), false, &fx.ctx).await.unwrap_err();
+    }
+
+    // libfoo is only in the repository's index, not in the fixture's cached one.
+    fn stale_index_fixture() -> Fixture {
+        let mut fx = Fixture::new();
+        let index_path = fx.repo_dir().join("index.yaml");
+        fx.ctx.config.repository_url = Some(Url::from_file_path(&index_path).unwrap().to_string());
+        let info = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        write_index(&index_path, vec![info]);
+        fx.ctx.package_index.clear();
+        fx
+    }
+
+    #[tokio::test]
+    async fn installing_a_package_missing_from_a_stale_index_refreshes_it_and_retries() {
+        let mut fx = stale_index_fixture();
+
+        handle_install("libfoo", &InstallOptions::default(), &mut fx.ctx).await.unwrap();
+
+        assert!(fx.ctx.package_index.contains_key("libfoo"));
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo");
+    }
+
+    #[tokio::test]
+    async fn no_auto_update_and_offline_fail_without_refreshing() {
+        let mut fx = stale_index_fixture();
+        let options = InstallOptions { no_auto_update: true, ..InstallOptions::default() };
+        let err = handle_install("libfoo", &options, &mut fx.ctx).await.unwrap_err();
+        assert!(matches!(err, FluxError::PackageNotFound(_)), "{}", err);
+
+        fx.ctx.downloader = Downloader::new(&fx.ctx.config, true, IpPreference::Dual).unwrap();
+        let err = handle_install("libfoo", &InstallOptions::default(), &mut fx.ctx).await.unwrap_err();
+        assert!(matches!(err, FluxError::PackageNotFound(_)), "{}", err);
+        assert!(fx.ctx.package_index.is_empty());
+    }
+
+    #[tokio::test]
+    async fn offline_upgrade_plans_say_no_changelog_without_touching_the_network() {
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let url = {
+            let requests = requests.clone();
+            http_server(move |_, _| {
+                requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async { http_response("200 OK", &[], b"Fixed the frobnicator") }
+            }).await
+        };
+        let downloader = Downloader::new(&FluxConfig::default(), true, IpPreference::Dual).unwrap();
+
+        let lines = changelog_plan_lines(&downloader, url.join("CHANGES").unwrap().as_str(), &mut HashMap::new()).await;
+
+        assert_eq!(lines, vec!["    No changelog available."]);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn orphans_include_dependencies_only_other_orphans_need_and_match_autoremove() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libb", "1.0.0", PackageType::System, &[]), &[("usr/lib/libb.so", "b")]);
+        fx.publish(package_info("liba", "1.0.0", PackageType::System, &["libb"]), &[("usr/lib/liba.so", "a")]);
+        fx.publish(package_info("libkept", "1.0.0", PackageType::System, &[]), &[("usr/lib/libkept.so", "kept")]);
+        fx.publish(package_info("app", "1.0.0", PackageType::App, &["liba", "libkept"]), &[("bin/app", "app")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::System, &["libkept"]), &[("usr/bin/tool", "tool")]);
+        fx.install(&["app", "tool"]).await.unwrap();
+        handle_remove("app", false, &fx.ctx).await.unwrap();
+
+        let orphans = find_orphans(&fx.installed().await, &fx.ctx);
+        assert_eq!(orphans, vec!["liba", "libb"]);
+        let options = ListOptions { format: ListFormat::Json, json_lines: false, reverse_deps: false, verbose: false, orphans: true, sort: None, newer_than: None };
+        handle_list(options, &fx.ctx).await.unwrap();
+
+        handle_autoremove(&fx.ctx).await.unwrap();
+        assert_eq!(names(&fx.installed().await), vec!["libkept", "tool"]);
+    }
+
+    #[tokio::test]
+    async fn post_install_runs_the_legacy_string_form_and_forwards_array_arguments() {
+        let mut fx = Fixture::new();
+        let out = fx.dir.path().join("args.log");
+        let script = format!("echo \"$#:$*\" >> '{}'", out.display());
+        for (name, spec) in [("legacy", "post_install: usr/share/legacy/log"), ("with-args", "post_install: [usr/share/with-args/log, --mode, fast and loose]")] {
+            let parsed: PackageInfo = serde_yaml::from_str(&format!("{{name: {}, version: '1.0', url: '', checksum: '', description: '', icon_url: '', changelog_url: ''}}", name)).unwrap();
+            let spec: HashMap<String, ScriptSpec> = serde_yaml::from_str(spec).unwrap();
+            let info = PackageInfo { post_install: spec.get("post_install").cloned(), ..parsed };
+            fx.publish(info, &[(&format!("usr/share/{}/marker", name), name), (&format!("usr/share/{}/log", name), &script)]);
+            fx.install(&[name]).await.unwrap();
+        }
+
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "0:\n2:--mode fast and loose\n");
+
+        let mut empty = package_info("empty", "1.0", PackageType::System, &[]);
+        empty.post_install = Some(ScriptSpec::WithArgs(Vec::new()));
+        fx.publish(empty, &[("usr/share/empty/marker", "empty")]);
+        let err = fx.install(&["empty"]).await.unwrap_err();
+        assert!(matches!(err, FluxError::PostInstallScriptFailed { .. }), "{}", err);
+    }
+
+    // Missing directories, a leftover staging dir, a stray cached archive, a malformed empty
+    // database and no cached index, with a repository to fetch one from.
+    fn broken_fixture() -> Fixture {
+        let mut fx = Fixture::new();
+        let index_path = fx.repo_dir().join("index.yaml");
+        write_index(&index_path, vec![package_info("libfoo", "1.0.0", PackageType::System, &[])]);
+        fx.ctx.config.repository_url = Some(Url::from_file_path(&index_path).unwrap().to_string());
+        std::fs::create_dir_all(fx.ctx.target_staging_dir.join("libfoo-1.0.0.tar.zst.42/usr")).unwrap();
+        std::fs::write(fx.ctx.host_cache_dir.join("gone-1.0.0.tar.zst"), "stray").unwrap();
+        std::fs::write(&fx.ctx.target_db_path, "[").unwrap();
+        fx
+    }
+
+    #[tokio::test]
+    async fn doctor_without_fix_changes_nothing() {
+        let mut fx = broken_fixture();
+
+        handle_doctor(false, &mut fx.ctx).await.unwrap();
+
+        assert!(!fx.ctx.target_apps_root.exists());
+        assert!(fx.ctx.target_staging_dir.join("libfoo-1.0.0.tar.zst.42").exists());
+        assert!(fx.ctx.host_cache_dir.join("gone-1.0.0.tar.zst").exists());
+        assert_eq!(std::fs::read_to_string(&fx.ctx.target_db_path).unwrap(), "[");
+        assert!(!fx.index_cache_path().exists());
+    }
+
+    #[tokio::test]
+    async fn doctor_fix_repairs_each_problem_and_backs_up_the_database() {
+        let mut fx = broken_fixture();
+
+        handle_doctor(true, &mut fx.ctx).await.unwrap();
+
+        assert!(fx.ctx.target_apps_root.is_dir());
+        assert_eq!(std::fs::read_dir(&fx.ctx.target_staging_dir).unwrap().count(), 0);
+        assert!(!fx.ctx.host_cache_dir.join("gone-1.0.0.tar.zst").exists());
+        assert!(fx.installed().await.is_empty());
+        let db_dir = fx.ctx.target_db_path.parent().unwrap();
+        let backup = std::fs::read_dir(db_dir).unwrap().map(|e| e.unwrap().path())
+            .find(|p| p.file_name().unwrap().to_string_lossy().starts_with("db.json.corrupt-")).unwrap();
+        assert_eq!(std::fs::read_to_string(backup).unwrap(), "[");
+        assert!(fx.index_cache_path().exists());
+        assert!(fx.ctx.package_index.contains_key("libfoo"));
+    }
+
+    #[tokio::test]
+    async fn doctor_fix_leaves_a_corrupt_database_with_records_for_rebuild_db() {
+        let mut fx = broken_fixture();
+        std::fs::write(&fx.ctx.target_db_path, r#"[{"name": "libfoo", "#).unwrap();
+
+        handle_doctor(true, &mut fx.ctx).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&fx.ctx.target_db_path).unwrap(), r#"[{"name": "libfoo", "#);
+    }
+
+    #[tokio::test]
+    async fn list_sorts_by_name_regardless_of_install_order() {
+        let mut fx = Fixture::new();
+        for name in ["zed", "alpha", "mid"] {
+            fx.publish(package_info(name, "1.0.0", PackageType::System, &[]), &[(&format!("usr/share/{}/marker", name), name)]);
+            fx.install(&[name]).await.unwrap();
+        }
+        let installed = fx.installed().await;
+        let listed = |sort| {
+            let options = ListOptions { format: ListFormat::Json, json_lines: false, reverse_deps: false, verbose: false, orphans: false, sort, newer_than: None };
+            list_order(&installed, &options, &fx.ctx).iter().map(|pkg| pkg.name.clone()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(listed(None), vec!["alpha", "mid", "zed"]);
+        assert_eq!(listed(Some(ListSort::Installed)), vec!["zed", "alpha", "mid"]);
+    }
+
+    #[tokio::test]
+    async fn verify_cache_on_start_rejects_a_tampered_cached_archive() {
+        let mut fx = Fixture::new();
+        let info = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        verify_cache_on_start(&fx.ctx).await.unwrap();
+
+        std::fs::write(fx.ctx.host_cache_dir.join(archive_file_name(&info)), "tampered").unwrap();
+
+        let err = verify_cache_on_start(&fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::CorruptCache(names) if names == "libfoo-1.0.0.tar.zst"), "{}", err);
+        let cli = Cli::try_parse_from(["flux", "install", "libfoo"]).unwrap();
+        assert!(cli.command.is_mutating());
+        let cli = Cli::try_parse_from(["flux", "install", "--print-plan-json", "libfoo"]).unwrap();
+        assert!(!cli.command.is_mutating());
+    }
+
+    #[tokio::test]
+    async fn remove_recursive_orphans_removes_the_unused_subtree_and_keeps_shared_dependencies() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libc", "1.0.0", PackageType::System, &[]), &[("usr/lib/libc.so", "c")]);
+        fx.publish(package_info("libb", "1.0.0", PackageType::System, &["libc"]), &[("usr/lib/libb.so", "b")]);
+        fx.publish(package_info("liba", "1.0.0", PackageType::System, &["libb"]), &[("usr/lib/liba.so", "a")]);
+        fx.publish(package_info("libshared", "1.0.0", PackageType::System, &[]), &[("usr/lib/libshared.so", "shared")]);
+        fx.publish(package_info("stale", "1.0.0", PackageType::System, &[]), &[("usr/lib/stale.so", "stale")]);
+        fx.publish(package_info("old", "1.0.0", PackageType::System, &["stale"]), &[("usr/bin/old", "old")]);
+        fx.publish(package_info("app", "1.0.0", PackageType::App, &["liba", "libshared"]), &[("bin/app", "app")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::System, &["libshared"]), &[("usr/bin/tool", "tool")]);
+        fx.install(&["app", "tool", "old"]).await.unwrap();
+        handle_remove("old", false, &fx.ctx).await.unwrap();
+
+        handle_remove("app", true, &fx.ctx).await.unwrap();
+
+        assert_eq!(names(&fx.installed().await), vec!["libshared", "stale", "tool"]);
+        for file in ["usr/lib/liba.so", "usr/lib/libb.so", "usr/lib/libc.so"] {
+            assert!(!fx.ctx.target_root.join(file).exists(), "{}", file);
+        }
+        assert!(fx.ctx.target_root.join("usr/lib/libshared.so").exists());
+    }
+
+    #[tokio::test]
+    async fn resolution_falls_back_to_a_compatible_arch_and_prefers_native() {
+        let build = |name: &str, version: &str, arch: Option<&str>| PackageInfo { arch: arch.map(str::to_string), ..package_info(name, version, PackageType::System, &[]) };
+        let accepted = vec!["x86_64".to_string(), "i686".to_string()];
+        let index = newest_versions(&select_for_arches(vec![
+            build("legacy", "1.0.0", Some("i686")),
+            build("both", "1.0.0", Some("i686")),
+            build("both", "2.0.0", Some("x86_64")),
+            build("anywhere", "1.0.0", None),
+            build("foreign", "1.0.0", Some("aarch64")),
+        ], &accepted));
+
+        assert_eq!(index["legacy"].arch.as_deref(), Some("i686"));
+        assert_eq!(index["both"].version, "2.0.0");
+        assert!(index.contains_key("anywhere"));
+        assert!(!index.contains_key("foreign"));
+
+        let mut fx = Fixture::new();
+        fx.ctx.accepted_arches = accepted;
+        fx.publish(build("legacy", "1.0.0", Some("i686")), &[("usr/lib/legacy.so", "legacy")]);
+        fx.install(&["legacy"]).await.unwrap();
+        assert_eq!(names(&fx.installed().await), vec!["legacy"]);
+    }
+
+    #[tokio::test]
+    async fn recovery_removes_files_an_interrupted_install_extracted_but_keeps_existing_ones() {
+        let mut fx = Fixture::new();
+        let info = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo"), ("usr/share/libfoo/data", "data"), ("etc/shared.conf", "foo's")]);
+        let root = fx.ctx.target_root.clone();
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/shared.conf"), "already here").unwrap();
+        let archive = fx.repo_dir().join("libfoo-1.0.0.tar.zst");
+        // A crash after extraction, before the database write.
+        fx.ctx.journal_install(&info, &root, &archive).await.unwrap();
+        extract_package(&archive, &root, &root, HardlinkPolicy::Link, 1).await.unwrap();
+
+        fx.ctx.config.on_interrupted_install = Some(InterruptedInstallPolicy::Never);
+        recover_interrupted_installs(true, &fx.ctx).await.unwrap();
+        assert!(root.join("usr/lib/libfoo.so").exists());
+
+        fx.ctx.config.on_interrupted_install = Some(InterruptedInstallPolicy::Auto);
+        recover_interrupted_installs(true, &fx.ctx).await.unwrap();
+        assert!(!root.join("usr/lib/libfoo.so").exists());
+        assert!(!root.join("usr/share/libfoo").exists());
+        assert!(root.join("etc/shared.conf").exists());
+        assert!(fx.ctx.read_install_journal().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn completed_installs_clear_the_journal() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.install(&["libfoo"]).await.unwrap();
+
+        assert!(!fx.ctx.install_journal_path().exists());
+        recover_interrupted_installs(true, &fx.ctx).await.unwrap();
+        assert!(fx.ctx.target_root.join("usr/lib/libfoo.so").exists());
+    }
+
+    fn large_file(dir: &Path, len: usize) -> (PathBuf, Vec<u8>) {
+        let data: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+        let path = dir.join("large.bin");
+        std::fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    #[tokio::test]
+    async fn checksums_and_downloads_are_correct_at_any_buffer_size() {
+        let fx = Fixture::new();
+        let (path, data) = large_file(fx.dir.path(), 200_003);
+        let expected = format!("{:x}", Sha256::digest(&data));
+        for buffer_size in [1, 7, 1024, DEFAULT_IO_BUFFER_SIZE, 1 << 20] {
+            assert_eq!(compute_checksum(&path, "", buffer_size).await.unwrap(), expected, "{}", buffer_size);
+        }
+
+        let body = data.clone();
+        let url = http_server(move |_, _| {
+            let body = body.clone();
+            async move { http_response("200 OK", &[], &body) }
+        }).await;
+        for buffer_size in [3, DEFAULT_IO_BUFFER_SIZE] {
+            let downloader = Downloader::new(&FluxConfig { io_buffer_size: Some(buffer_size), ..FluxConfig::default() }, false, IpPreference::Dual).unwrap();
+            let dest = fx.dir.path().join(format!("download-{}", buffer_size));
+            downloader.download_file(&url.join("large.bin").unwrap(), &dest).await.unwrap();
+            assert!(std::fs::read(&dest).unwrap() == data, "{}", buffer_size);
+        }
+    }
+
+    // Throughput comparison; run with `cargo test --release -- --ignored --nocapture hashing_throughput`.
+    #[tokio::test]
+    #[ignore]
+    async fn hashing_throughput_by_buffer_size() {
+        let fx = Fixture::new();
+        let (path, _) = large_file(fx.dir.path(), 256 << 20);
+        for buffer_size in [1024, DEFAULT_IO_BUFFER_SIZE] {
+            let started = std::time::Instant::now();
+            compute_checksum(&path, "", buffer_size).await.unwrap();
+            let elapsed = started.elapsed();
+            println!("{:>6} byte buffer: {:.0} MiB/s", buffer_size, 256.0 / elapsed.as_secs_f64());
+        }
+    }
+
+    const COMMENTED_CONFIG: &str = "# Where packages come from\nrepository_url: https://example.com/index.yaml\nhooks:\n  # rebuild the linker cache\n  libc: /usr/sbin/ldconfig\nindex_retries: 3 # slow mirror\n";
+
+    #[tokio::test]
+    async fn config_set_changes_only_the_key_and_keeps_comments() {
+        let fx = Fixture::new();
+        let path = fx.dir.path().join("flux.conf");
+        std::fs::write(&path, COMMENTED_CONFIG).unwrap();
+
+        handle_config_set(&path, "repository_url", "https://mirror.example.com/index.yaml").await.unwrap();
+        handle_config_set(&path, "hooks.gtk", "/usr/bin/gtk-update-icon-cache").await.unwrap();
+        handle_config_set(&path, "keep_downloads", "true").await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "# Where packages come from\nrepository_url: https://mirror.example.com/index.yaml\nhooks:\n  # rebuild the linker cache\n  libc: /usr/sbin/ldconfig\n  gtk: /usr/bin/gtk-update-icon-cache\nindex_retries: 3 # slow mirror\nkeep_downloads: true\n");
+        let config: FluxConfig = serde_yaml::from_str(&content).unwrap();
+        assert_eq!(config.repository_url.as_deref(), Some("https://mirror.example.com/index.yaml"));
+        assert_eq!(config.index_retries, Some(3));
+        assert!(config.keep_downloads);
+        assert!(matches!(&config.hooks.as_ref().unwrap()["libc"], HookSpec::Script(script) if script == "/usr/sbin/ldconfig"));
+        assert!(config.hooks.unwrap().contains_key("gtk"));
+    }
+
+    #[tokio::test]
+    async fn config_set_rejects_invalid_values_and_unknown_keys_without_writing() {
+        let fx = Fixture::new();
+        let path = fx.dir.path().join("flux.conf");
+        std::fs::write(&path, COMMENTED_CONFIG).unwrap();
+
+        assert!(handle_config_set(&path, "index_retries", "lots").await.is_err());
+        assert!(handle_config_set(&path, "no_such_key", "1").await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), COMMENTED_CONFIG);
+
+        handle_config_set(&path, "index_retries", "null").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), COMMENTED_CONFIG.replace("index_retries: 3 # slow mirror\n", ""));
+    }
+
+    #[tokio::test]
+    async fn config_set_rewrites_files_it_cannot_edit_line_by_line() {
+        let fx = Fixture::new();
+        let path = fx.dir.path().join("flux.conf");
+        std::fs::write(&path, "# flow style\nhooks: {libc: /usr/sbin/ldconfig}\n").unwrap();
+
+        handle_config_set(&path, "hooks.gtk", "/usr/bin/gtk-update-icon-cache").await.unwrap();
+
+        let config: FluxConfig = serde_yaml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(config.hooks.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn conflicting_optionals_drop_the_lower_priority_one_but_hard_conflicts_fail() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("pdf-gtk", "1.0.0", PackageType::System, &[]), &[("usr/lib/pdf-gtk.so", "gtk")]);
+        fx.publish(package_info("qt", "5.0.0", PackageType::System, &[]), &[("usr/lib/qt.so", "qt")]);
+        let mut pdf_qt = package_info("pdf-qt", "1.0.0", PackageType::System, &["qt"]);
+        pdf_qt.conflicts = Some(vec!["pdf-gtk".to_string()]);
+        fx.publish(pdf_qt, &[("usr/lib/pdf-qt.so", "qt")]);
+        let mut viewer = package_info("viewer", "1.0.0", PackageType::App, &[]);
+        viewer.optional_dependencies = Some(vec!["pdf-gtk".to_string()]);
+        fx.publish(viewer, &[("bin/viewer", "viewer")]);
+        let mut editor = package_info("editor", "1.0.0", PackageType::App, &[]);
+        editor.optional_dependencies = Some(vec!["pdf-qt".to_string()]);
+        fx.publish(editor, &[("bin/editor", "editor")]);
+        let recommended = InstallOptions { recommended: true, ..InstallOptions::default() };
+
+        install_packages(&["viewer".to_string(), "editor".to_string()], &recommended, &fx.ctx).await.unwrap();
+        assert_eq!(names(&fx.installed().await), vec!["editor", "pdf-gtk", "viewer"]);
+
+        let err = fx.install(&["pdf-qt"]).await.unwrap_err();
+        assert!(matches!(&err, FluxError::PackageConflict { package_name, conflicts_with } if package_name == "pdf-qt" && conflicts_with == "pdf-gtk"), "{}", err);
+        assert_eq!(names(&fx.installed().await), vec!["editor", "pdf-gtk", "viewer"]);
+    }
+
+    #[tokio::test]
+    async fn the_timing_report_has_every_phase_for_every_package() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libc", "1.0.0", PackageType::System, &[]), &[("usr/lib/libc.so", "c")]);
+        fx.publish(package_info("viewer", "1.0.0", PackageType::App, &["libc"]), &[("bin/viewer", "viewer")]);
+        let mut timings = InstallTimings::default();
+
+        install_packages_timed(&["viewer".to_string()], &InstallOptions::default(), &fx.ctx, &mut timings).await.unwrap();
+
+        let recorded: Vec<&str> = timings.packages.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(recorded, vec!["libc", "viewer"]);
+        assert!(timings.packages.iter().all(|(_, phases)| phases[..3].iter().all(|d| !d.is_zero())));
+        let report = timings.report();
+        let rows: Vec<Vec<&str>> = report.lines().skip(2).map(|line| line.split_whitespace().collect()).collect();
+        assert_eq!(rows[0], vec!["package", "download", "verify", "extract", "scripts", "total"]);
+        assert_eq!(rows.iter().skip(1).map(|row| row[0]).collect::<Vec<_>>(), vec!["libc", "viewer", "resolution", "total"]);
+        assert!(rows.iter().skip(1).filter(|row| row[0] != "resolution").all(|row| row.len() == 6));
+    }
+
+    #[test]
+    fn search_matches_names_and_descriptions_ignoring_case() {
+        let mut fx = Fixture::new();
+        let mut described = |name: &str, description: &str| {
+            let info = PackageInfo { description: description.to_string(), ..package_info(name, "1.0.0", PackageType::System, &[]) };
+            fx.ctx.package_index.insert(name.to_string(), info);
+        };
+        described("zlib", "Compression library");
+        described("pdfview", "Lightweight PDF viewer with a very long description that goes on and on about features");
+        described("libPDF", "Rendering");
+        described("unrelated", "Nothing to see");
+
+        let names: Vec<&str> = search_index("pdf", &fx.ctx).iter().map(|info| info.name.as_str()).collect();
+        assert_eq!(names, vec!["libPDF", "pdfview"]);
+        assert_eq!(search_index("COMPRESSION", &fx.ctx).len(), 1);
+        assert!(search_index("missing", &fx.ctx).is_empty());
+        assert_eq!(search_result_line(&fx.ctx.package_index["pdfview"]), "pdfview 1.0.0 - Lightweight PDF viewer with a very long description that goe...");
+        assert_eq!(search_result_line(&fx.ctx.package_index["zlib"]), "zlib 1.0.0 - Compression library");
+    }
+
+    #[tokio::test]
+    async fn archives_are_fetched_through_the_cache_proxy_and_still_verified() {
+        let mut fx = Fixture::new();
+        let archive = tar_zst(&[("usr/lib/libfoo.so", "foo")]);
+        let served = archive.clone();
+        let requested = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = requested.clone();
+        let proxy = http_server(move |_, head| {
+            let path = head.split_whitespace().nth(1).unwrap_or_default().to_string();
+            seen.lock().unwrap().push(path.clone());
+            let body = if path.ends_with("tampered-1.0.0.tar.zst") { b"not the archive".to_vec() } else { served.clone() };
+            async move { http_response("200 OK", &[], &body) }
+        }).await;
+        fx.ctx.config.cache_proxy_url = Some(proxy.join("cache").unwrap().to_string());
+        for name in ["libfoo", "tampered"] {
+            let mut info = fx.publish_archive(package_info(name, "1.0.0", PackageType::System, &[]), archive.clone());
+            info.url = format!("https://packages.example.com:8443/pool/{}-1.0.0.tar.zst", name);
+            fx.ctx.package_index.insert(name.to_string(), info);
+        }
+
+        fx.install(&["libfoo"]).await.unwrap();
+        let err = fx.install(&["tampered"]).await.unwrap_err();
+
+        assert!(matches!(err, FluxError::ChecksumMismatch { .. }), "{}", err);
+        assert_eq!(*requested.lock().unwrap(), vec![
+            "/cache/packages.example.com:8443/pool/libfoo-1.0.0.tar.zst",
+            "/cache/packages.example.com:8443/pool/tampered-1.0.0.tar.zst",
+        ]);
+        assert!(fx.ctx.target_root.join("usr/lib/libfoo.so").exists());
+    }
+
+    #[tokio::test]
+    async fn upgrade_skips_index_versions_below_the_installed_one_unless_allowed() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "2.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "two")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        fx.publish(package_info("libfoo", "1.5.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "one and a half")]);
+        fx.unpublish("libfoo", "2.0.0");
+
+        handle_upgrade(&[], &UpgradeOptions::default(), &fx.ctx).await.unwrap();
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().version, "2.0.0");
+        assert!(status_report(&fx.installed().await, &fx.ctx).await.unwrap().upgradable.is_empty());
+
+        handle_upgrade(&[], &UpgradeOptions { allow_downgrade: true, ..UpgradeOptions::default() }, &fx.ctx).await.unwrap();
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().version, "1.5.0");
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "one and a half");
+    }
+
+    // Minisign keys and signatures made with fixed Ed25519 seeds; the signed data is the plain strings below.
+    const TRUSTED_KEY: &str = "untrusted comment: minisign public key\nRWQBAgMEBQYHCIqI4910CfGV/VLbLTy6XXLKZwm/HZQSG/N0iAG0D29c\n";
+    const GOOD_SIGNATURE: &str = "untrusted comment: signature\nRUQBAgMEBQYHCFu7hglCtTkNB+VxVE8kpvlj2Xa73OCFw90MtG2gTYr+NHOjpOcJcoOhp5izW8HfYaXM3ScD7xLl1XJXdYKHHAo=\ntrusted comment: libgood\nCQrjGQNFt9jTUOCz3LsCivIrDjstHTd4q+fNNxQPDMaJbx3ept+zt4n+BLs5Ihpn0BPXVbHfNQSw0/6toJV5Dg==\n";
+    // Over "original archive".
+    const TAMPERED_SIGNATURE: &str = "untrusted comment: signature\nRUQBAgMEBQYHCIQVyLlW6IjQZ6/1/9UJnDd4cf8Gx4VC/BCUUH7b84y+7A5m0VRXrS4/+/kJEphSs6LzzA+AEhkOeATe1oQWYwU=\ntrusted comment: libtampered\nVpXci1CftJ5fzGoCnEoP6DOLQ5IqdRvjV2KdrlRkuU2v0vMTYQJpD4Y776YK3u3h6blnOGDstqJHIQWVzqE9Cw==\n";
+    // By a second key that is not trusted.
+    const FOREIGN_SIGNATURE: &str = "untrusted comment: signature\nRUQLDA0ODxAREp2dHozGGjpNZLhOSpy6QPfP7rVqlZP2bkWKR/zMXE2sXmOJ0c5By/e0BaX23Hz5q79QbT8CfKiHHk+XV34nFAo=\ntrusted comment: libforeign\nbpkBg4GgJdmgU8EWKCHci8VPOKA1CxfALYEB4jgilIovPyBcBQpZx9NjFx9qLestucY0R/X81KxKReyPF/EfDQ==\n";
+
+    #[tokio::test]
+    async fn signature_audit_classifies_valid_tampered_untrusted_and_unsigned_archives() {
+        let mut fx = Fixture::new();
+        let key_path = fx.dir.path().join("trusted.pub");
+        std::fs::write(&key_path, TRUSTED_KEY).unwrap();
+        fx.ctx.config.trusted_keys = vec![key_path.clone()];
+        let cases = [
+            ("libgood", "signed archive", Some(GOOD_SIGNATURE)),
+            ("libtampered", "tampered archive", Some(TAMPERED_SIGNATURE)),
+            ("libforeign", "foreign archive", Some(FOREIGN_SIGNATURE)),
+            ("libplain", "plain archive", None),
+        ];
+        for (name, _, _) in cases {
+            fx.publish(package_info(name, "1.0.0", PackageType::System, &[]), &[(&format!("usr/lib/{}.so", name), name)]);
+        }
+        fx.install(&cases.map(|(name, _, _)| name)).await.unwrap();
+        // Only the cached files are checked, so their contents stand in for real archives.
+        for (name, archive, signature) in cases {
+            let info = &fx.ctx.package_index[name];
+            std::fs::write(fx.ctx.host_cache_dir.join(archive_file_name(info)), archive).unwrap();
+            if let Some(signature) = signature {
+                std::fs::write(fx.ctx.host_cache_dir.join(signature_file_name(info)), signature).unwrap();
+            }
+        }
+
+        let keys = load_trusted_keys(&fx.ctx).unwrap();
+        let mut statuses = Vec::new();
+        for pkg in fx.installed().await {
+            let status = check_package_signature(&pkg, &keys, &fx.ctx).await.unwrap();
+            statuses.push(serde_json::to_value(SignatureReport { name: pkg.name, version: pkg.version, status }).unwrap());
+        }
+
+        let summary: Vec<(&str, &str)> = statuses.iter().map(|r| (r["name"].as_str().unwrap(), r["status"].as_str().unwrap())).collect();
+        assert_eq!(summary, vec![("libgood", "valid"), ("libtampered", "invalid"), ("libforeign", "untrusted_key"), ("libplain", "unsigned")]);
+        assert_eq!(statuses[0]["key"], key_path.display().to_string());
+        assert!(matches!(handle_verify_signatures(OutputFormat::Json, &fx.ctx).await, Err(FluxError::VerificationFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn prefixed_checksums_are_verified_with_their_algorithm() {
+        let mut fx = Fixture::new();
+        type Checksum = fn(&[u8]) -> String;
+        let checksums: [(&str, Checksum); 5] = [
+            ("plain", |archive| format!("{:x}", Sha256::digest(archive))),
+            ("sha512", |archive| format!("sha512:{:x}", sha2::Sha512::digest(archive))),
+            ("blake3", |archive| format!("blake3:{}", blake3::hash(archive).to_hex())),
+            ("bad-blake3", |_| format!("blake3:{}", blake3::hash(b"something else").to_hex())),
+            ("md5", |_| "md5:d41d8cd98f00b204e9800998ecf8427e".to_string()),
+        ];
+        for (name, checksum) in checksums {
+            let archive = tar_zst(&[(&format!("usr/share/{}/marker", name), name)]);
+            let mut info = fx.publish_archive(package_info(name, "1.0.0", PackageType::System, &[]), archive.clone());
+            info.checksum = checksum(&archive);
+            fx.ctx.package_index.insert(name.to_string(), info);
+        }
+
+        fx.install(&["plain", "sha512", "blake3"]).await.unwrap();
+        assert_eq!(names(&fx.installed().await), vec!["blake3", "plain", "sha512"]);
+        let err = fx.install(&["bad-blake3"]).await.unwrap_err();
+        assert!(matches!(&err, FluxError::ChecksumMismatch { algorithm: "blake3", .. }), "{}", err);
+        assert!(matches!(fx.install(&["md5"]).await.unwrap_err(), FluxError::Config(_)));
+    }
+
+    #[tokio::test]
+    async fn the_preferred_address_family_is_tried_first() {
+        let resolved: Vec<std::net::SocketAddr> = ["[2001:db8::1]:0", "192.0.2.1:0", "[2001:db8::2]:0", "192.0.2.2:0"].iter().map(|a| a.parse().unwrap()).collect();
+        let ordered = |preference: IpPreference| {
+            let mut addrs = resolved.clone();
+            preference.order(&mut addrs);
+            addrs.iter().map(|a| a.ip().to_string()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(ordered(IpPreference::Dual), vec!["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"]);
+        assert_eq!(ordered(IpPreference::Ipv4), vec!["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"]);
+        assert_eq!(ordered(IpPreference::Ipv6), vec!["2001:db8::1", "2001:db8::2", "192.0.2.1", "192.0.2.2"]);
+
+        // An IPv4-only host still works when IPv6 is preferred.
+        let url = http_server(|_, _| async { http_response("200 OK", &[], b"ok") }).await;
+        let url = Url::parse(&url.as_str().replace("127.0.0.1", "localhost")).unwrap();
+        let downloader = Downloader::new(&FluxConfig { connect_timeout_secs: Some(1), ..FluxConfig::default() }, false, IpPreference::Ipv6).unwrap();
+        assert_eq!(downloader.fetch_text(&url).await.unwrap(), "ok");
+    }
+
+    #[test]
+    fn time_specs_parse_as_rfc3339_or_relative_ages() {
+        assert_eq!(parse_time_spec("2024-05-01"), Ok(1_714_521_600));
+        assert_eq!(parse_time_spec("2024-05-01T12:30:00Z"), Ok(1_714_566_600));
+        assert_eq!(parse_time_spec("2024-05-01T14:30:00.250+02:00"), Ok(1_714_566_600));
+        assert_eq!(parse_time_spec("2024-02-29T00:00:00-01:00"), Ok(1_709_168_400));
+        assert_eq!(format_unix_time(1_714_566_600), "2024-05-01T12:30:00Z");
+        for relative in ["30m", "24h", "7d", "2w"] {
+            let expected = unix_now() - match relative { "30m" => 1800, "24h" => 86_400, "7d" => 604_800, _ => 1_209_600 };
+            assert!(parse_time_spec(relative).unwrap().abs_diff(expected) <= 1, "{}", relative);
+        }
+        for invalid in ["", "7", "7y", "d", "2024-13-01", "24-05-01", "2024-05-01T25:00:00Z", "2024-05-01T12:00:00+0200", "yesterday"] {
+            assert!(parse_time_spec(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn newer_than_lists_only_packages_installed_after_the_time() {
+        let mut fx = Fixture::new();
+        for name in ["old", "recent", "unknown"] {
+            fx.publish(package_info(name, "1.0.0", PackageType::System, &[]), &[(&format!("usr/share/{}/marker", name), name)]);
+        }
+        fx.install(&["old", "recent", "unknown"]).await.unwrap();
+        let mut installed = fx.installed().await;
+        installed[0].installed_at = Some(parse_time_spec("2024-01-01").unwrap());
+        installed[2].installed_at = None;
+        fx.ctx.write_installed_packages(&installed).await.unwrap();
+        let installed = fx.installed().await;
+        let listed = |newer_than: &str| {
+            let options = ListOptions { format: ListFormat::Text, json_lines: false, reverse_deps: false, verbose: false, orphans: false, sort: None, newer_than: Some(parse_time_spec(newer_than).unwrap()) };
+            list_order(&installed, &options, &fx.ctx).iter().map(|pkg| pkg.name.as_str()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(listed("7d"), vec!["recent"]);
+        assert_eq!(listed("2023-12-31T23:00:00Z"), vec!["old", "recent"]);
+        assert!(listed("2099-01-01").is_empty());
+    }
+
+    #[tokio::test]
+    async fn system_packages_shipping_the_same_file_are_rejected_before_extraction() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("foo", "1.0.0", PackageType::System, &[]), &[("usr/bin/foo", "foo")]);
+        fx.publish(package_info("foo-compat", "1.0.0", PackageType::System, &[]), &[("usr/bin/foo", "compat"), ("usr/share/compat", "c")]);
+        fx.publish(package_info("foo-fork", "1.0.0", PackageType::System, &[]), &[("usr/bin/foo", "fork")]);
+        fx.install(&["foo"]).await.unwrap();
+
+        let err = fx.install(&["foo-compat"]).await.unwrap_err();
+        assert!(matches!(&err, FluxError::FileConflict { path, owning_package, incoming_package }
+            if path == Path::new("/usr/bin/foo") && owning_package == "foo" && incoming_package == "foo-compat"), "{}", err);
+        assert!(!fx.path("usr/share/compat").exists());
+        assert_eq!(fx.read("usr/bin/foo"), "foo");
+
+        handle_remove("foo", false, &fx.ctx).await.unwrap();
+        let err = fx.install(&["foo-compat", "foo-fork"]).await.unwrap_err();
+        assert!(matches!(&err, FluxError::FileConflict { owning_package, incoming_package, .. } if owning_package == "foo-compat" && incoming_package == "foo-fork"), "{}", err);
+        assert!(fx.installed().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_leaves_the_database_files_and_cache_untouched() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::System, &["libfoo"]), &[("usr/bin/tool", "tool")]);
+        fx.install(&["tool"]).await.unwrap();
+        let marker = fx.dir.path().join("post-install-ran");
+        let mut extra = package_info("extra", "1.0.0", PackageType::System, &[]);
+        extra.post_install = Some(ScriptSpec::Script("usr/share/extra/post-install".to_string()));
+        fx.publish(extra, &[("usr/share/extra/post-install", &format!("touch {}", marker.display()))]);
+        fx.publish(package_info("libfoo", "1.1.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1.1")]);
+        let database = std::fs::read(&fx.ctx.target_db_path).unwrap();
+        let cached = std::fs::read_dir(&fx.ctx.host_cache_dir).unwrap().count();
+
+        fx.ctx.dry_run = true;
+        fx.install(&["extra"]).await.unwrap();
+        handle_upgrade(&[], &UpgradeOptions::default(), &fx.ctx).await.unwrap();
+        handle_mark("libfoo", InstallReason::Explicit, &fx.ctx).await.unwrap();
+        handle_remove("tool", true, &fx.ctx).await.unwrap();
+
+        assert_eq!(std::fs::read(&fx.ctx.target_db_path).unwrap(), database);
+        assert_eq!(std::fs::read_dir(&fx.ctx.host_cache_dir).unwrap().count(), cached);
+        assert_eq!(fx.read("usr/bin/tool"), "tool");
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+        assert!(!fx.path("usr/share/extra/post-install").exists());
+        assert!(!marker.exists());
+    }
+
+    #[tokio::test]
+    async fn reconcile_converges_the_system_on_the_desired_state() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("zlib", "1.0.0", PackageType::System, &[]), &[("usr/lib/libz.so", "z")]);
+        fx.publish(package_info("curl", "7.0.0", PackageType::System, &["zlib"]), &[("usr/bin/curl", "curl 7")]);
+        fx.publish(package_info("stray", "1.0.0", PackageType::System, &[]), &[("usr/bin/stray", "stray")]);
+        fx.install(&["curl", "zlib", "stray"]).await.unwrap();
+        fx.publish(package_info("curl", "8.1.0", PackageType::System, &["zlib"]), &[("usr/bin/curl", "curl 8")]);
+        fx.publish(package_info("hello", "1.0.0", PackageType::System, &[]), &[("usr/bin/hello", "hello")]);
+        let desired = fx.dir.path().join("desired.yaml");
+        std::fs::write(&desired, "packages:\n  - \"curl >= 8.0\"\n  - hello\ndependencies:\n  - zlib\n").unwrap();
+
+        handle_reconcile(&desired, &mut fx.ctx).await.unwrap();
+
+        let mut state: Vec<(String, String, InstallReason)> = fx.installed().await.into_iter().map(|p| (p.name, p.version, p.install_reason)).collect();
+        state.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(state, vec![
+            ("curl".to_string(), "8.1.0".to_string(), InstallReason::Explicit),
+            ("hello".to_string(), "1.0.0".to_string(), InstallReason::Explicit),
+            ("zlib".to_string(), "1.0.0".to_string(), InstallReason::Dependency),
+        ]);
+        assert_eq!(fx.read("usr/bin/curl"), "curl 8");
+        assert!(!fx.path("usr/bin/stray").exists());
+
+        std::fs::write(&desired, "packages:\n  - \"curl >= 9.0\"\n").unwrap();
+        let err = handle_reconcile(&desired, &mut fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::PackageNotFound(message) if message.starts_with("curl >= 9.0")), "{}", err);
+        assert_eq!(fx.installed().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn reconcile_installs_an_older_version_when_the_spec_excludes_the_newest() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.2.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1.2")]);
+        fx.publish(package_info("libfoo", "2.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 2")]);
+        let desired = fx.dir.path().join("desired.yaml");
+        std::fs::write(&desired, "packages:\n  - \"libfoo < 2.0\"\n").unwrap();
+
+        handle_reconcile(&desired, &mut fx.ctx).await.unwrap();
+
+        assert_eq!(fx.installed().await[0].version, "1.2.0");
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1.2");
+
+        // What a wanted package requires narrows its dependencies the same way.
+        let mut fx = Fixture::new();
+        for version in ["1.1.0", "1.2.0", "2.0.0"] {
+            fx.publish(package_info("libfoo", version, PackageType::System, &[]), &[("usr/lib/libfoo.so", version)]);
+        }
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo < 1.2"]), &[("bin/tool", "echo tool")]);
+        let desired = fx.dir.path().join("desired.yaml");
+        std::fs::write(&desired, "packages:\n  - tool\n").unwrap();
+
+        handle_reconcile(&desired, &mut fx.ctx).await.unwrap();
+
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().version, "1.1.0");
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "1.1.0");
+    }
+
+    // flock locks belong to the open file, not the process, so a second lock_database call here
+    // contends exactly as a second flux process would.
+    #[test]
+    fn a_second_invocation_is_refused_while_the_database_is_locked() {
+        let fx = Fixture::new();
+        let held = fx.ctx.lock_database(false).unwrap();
+
+        let second = fx.ctx.lock_database(false);
+        assert!(matches!(&second, Err(FluxError::Locked(path)) if *path == fx.ctx.target_db_dir.join("db.lock")), "{:?}", second.err());
+        // Profiles share the lock, since they share the files on disk.
+        assert!(matches!(fx.profile("dev").lock_database(false), Err(FluxError::Locked(_))));
+
+        drop(held);
+        assert!(fx.ctx.lock_database(false).is_ok());
+    }
+
+    #[test]
+    fn wait_blocks_until_the_lock_is_released() {
+        let fx = Fixture::new();
+        let held = fx.ctx.lock_database(false).unwrap();
+        let released = std::time::Instant::now() + std::time::Duration::from_millis(300);
+        let holder = std::thread::spawn(move || {
+            std::thread::sleep(released - std::time::Instant::now());
+            drop(held);
+        });
+
+        let _lock = fx.ctx.lock_database(true).unwrap();
+
+        assert!(std::time::Instant::now() >= released);
+        holder.join().unwrap();
+    }
+
+    // Deterministic, compressible file contents.
+    fn sample_text(seed: u64, len: usize) -> String {
+        let words = ["flux", "package", "archive", "frame", "thread", "zstd", "index", "root"];
+        let mut state = seed;
+        let mut text = String::with_capacity(len + 16);
+        while text.len() < len {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            text.push_str(words[(state >> 60) as usize % words.len()]);
+            text.push(if state & 0x10 == 0 { ' ' } else { '\n' });
+        }
+        text
+    }
+
+    // Compresses `bytes` as independent frames of `frame_size` input bytes each, like pzstd does.
+    fn zstd_in_frames(bytes: &[u8], frame_size: usize) -> Vec<u8> {
+        bytes.chunks(frame_size).flat_map(|chunk| zstd::encode_all(chunk, 3).unwrap()).collect()
+    }
+
+    fn sample_tar(files: usize, file_size: usize) -> Vec<u8> {
+        let contents: Vec<(String, String)> = (0..files).map(|i| (format!("usr/share/sample/{:04}.txt", i), sample_text(i as u64, file_size))).collect();
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, text) in &contents {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(text.len() as u64);
+            builder.append_data(&mut header, path, text.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn read_tree(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut files = BTreeMap::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    files.insert(path.strip_prefix(root).unwrap().to_path_buf(), std::fs::read(&path).unwrap());
+                }
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn zstd_frames_splits_multi_frame_streams_only() {
+        let tar = sample_tar(40, 4096);
+        assert_eq!(zstd_frames(&zstd_in_frames(&tar, 32 * 1024)).map(|frames| frames.len()), Some(tar.len().div_ceil(32 * 1024)));
+        assert!(zstd_frames(&zstd::encode_all(&tar[..], 3).unwrap()).is_none());
+        let mut truncated = zstd_in_frames(&tar, 32 * 1024);
+        truncated.truncate(truncated.len() - 10);
+        assert!(zstd_frames(&truncated).is_none());
+    }
+
+    // Every thread count has to produce the same tree, for single- and multi-frame archives alike.
+    #[tokio::test]
+    async fn extraction_is_identical_with_one_or_many_decompress_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar = sample_tar(200, 8 * 1024);
+        let expected = {
+            let unpacked = dir.path().join("expected");
+            tar::Archive::new(&tar[..]).unpack(&unpacked).unwrap();
+            read_tree(&unpacked)
+        };
+        for (label, compressed) in [("single", zstd::encode_all(&tar[..], 3).unwrap()), ("multi", zstd_in_frames(&tar, 64 * 1024))] {
+            let archive_path = dir.path().join(format!("{}.tar.zst", label));
+            std::fs::write(&archive_path, compressed).unwrap();
+            for threads in [1, 2, 4, 64] {
+                let dest = dir.path().join(format!("{}-{}", label, threads));
+                std::fs::create_dir_all(&dest).unwrap();
+                let files = extract_package(&archive_path, &dest, &dest, HardlinkPolicy::Link, threads).await.unwrap();
+                assert_eq!(files.iter().filter(|path| dest.join(path).is_file()).count(), 200, "{} frame(s), {} thread(s)", label, threads);
+                assert!(read_tree(&dest) == expected, "{} frame(s), {} thread(s) unpacked different contents", label, threads);
+            }
+        }
+    }
+
+    // Not run by default: cargo test --release -- --ignored --nocapture decompression_benchmark
+    #[test]
+    #[ignore]
+    fn decompression_benchmark() {
+        let tar = sample_tar(4096, 64 * 1024);
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get()).max(4);
+        for (label, compressed) in [("single-frame", zstd::encode_all(&tar[..], 3).unwrap()), ("1 MiB frames", zstd_in_frames(&tar, 1024 * 1024))] {
+            for threads in [1, threads] {
+                let started = std::time::Instant::now();
+                let mut decoded = Vec::with_capacity(tar.len());
+                std::io::Read::read_to_end(&mut decompressed_reader(compressed.clone(), threads).unwrap(), &mut decoded).unwrap();
+                let elapsed = started.elapsed();
+                assert_eq!(decoded.len(), tar.len());
+                println!("{:>13}, {:>2} thread(s): {:>8.1} ms, {:>7.1} MiB/s", label, threads, elapsed.as_secs_f64() * 1000.0,
+                    tar.len() as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dependency_only_installs_everything_but_the_target() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libc", "1.0.0", PackageType::System, &[]), &[("usr/lib/libc.so", "c")]);
+        fx.publish(package_info("zlib", "1.0.0", PackageType::System, &["libc"]), &[("usr/lib/libz.so", "z")]);
+        fx.publish(package_info("curl", "1.0.0", PackageType::System, &["zlib"]), &[("usr/bin/curl", "curl")]);
+        let request = vec!["curl".to_string()];
+
+        install_packages(&request, &InstallOptions { dependency_only: true, ..InstallOptions::default() }, &fx.ctx).await.unwrap();
+
+        let reasons: Vec<(String, InstallReason)> = fx.installed().await.into_iter().map(|p| (p.name, p.install_reason)).collect();
+        assert!(reasons.contains(&("zlib".to_string(), InstallReason::Explicit)), "{:?}", names(&fx.installed().await));
+        assert!(reasons.contains(&("libc".to_string(), InstallReason::Dependency)));
+        assert_eq!(reasons.len(), 2);
+        assert!(!fx.path("usr/bin/curl").exists());
+
+        handle_remove("zlib", false, &fx.ctx).await.unwrap();
+        install_packages(&request, &InstallOptions { dependency_only: true, as_dependency: true, ..InstallOptions::default() }, &fx.ctx).await.unwrap();
+        assert_eq!(fx.installed_package("zlib").await.unwrap().install_reason, InstallReason::Dependency);
+        assert!(fx.installed_package("curl").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn restoring_a_snapshot_undoes_the_changes_made_since() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::System, &["libfoo"]), &[("usr/bin/tool", "tool")]);
+        fx.publish(package_info("editor", "1.0.0", PackageType::System, &[]), &[("usr/bin/editor", "editor")]);
+        fx.publish(package_info("extra", "1.0.0", PackageType::System, &[]), &[("usr/bin/extra", "extra")]);
+        fx.install(&["tool", "editor"]).await.unwrap();
+        let before = fx.installed().await;
+        handle_snapshot_create("before", &fx.ctx).await.unwrap();
+        let snapshots: Vec<PathBuf> = std::fs::read_dir(&fx.ctx.target_snapshot_dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(snapshots, vec![fx.ctx.target_snapshot_dir.join("before.json")]);
+
+        fx.publish(package_info("tool", "1.1.0", PackageType::System, &["libfoo"]), &[("usr/bin/tool", "tool 1.1")]);
+        handle_upgrade(&[], &UpgradeOptions::default(), &fx.ctx).await.unwrap();
+        handle_remove("editor", false, &fx.ctx).await.unwrap();
+        fx.install(&["extra"]).await.unwrap();
+        handle_mark("libfoo", InstallReason::Explicit, &fx.ctx).await.unwrap();
+
+        handle_restore("before", &mut fx.ctx).await.unwrap();
+
+        let state = |packages: &[InstalledPackageInfo]| {
+            let mut state: Vec<(String, String, InstallReason)> = packages.iter().map(|p| (p.name.clone(), p.version.clone(), p.install_reason.clone())).collect();
+            state.sort_by(|a, b| a.0.cmp(&b.0));
+            state
+        };
+        assert_eq!(state(&fx.installed().await), state(&before));
+        assert_eq!(fx.read("usr/bin/tool"), "tool");
+        assert_eq!(fx.read("usr/bin/editor"), "editor");
+        assert!(!fx.path("usr/bin/extra").exists());
+    }
+
+    #[tokio::test]
+    async fn file_conflict_policies_decide_which_copy_stays_and_who_owns_it() {
+        for policy in [FileConflictPolicy::Skip, FileConflictPolicy::Overwrite, FileConflictPolicy::BackupBoth] {
+            let mut fx = Fixture::new();
+            fx.publish(package_info("foo", "1.0.0", PackageType::System, &[]), &[("usr/bin/foo", "foo")]);
+            fx.publish(package_info("foo-fork", "1.0.0", PackageType::System, &[]), &[("usr/bin/foo", "fork"), ("usr/share/fork", "f")]);
+            fx.install(&["foo"]).await.unwrap();
+
+            let options = InstallOptions { on_file_conflict: Some(policy), ..InstallOptions::default() };
+            install_packages(&["foo-fork".to_string()], &options, &fx.ctx).await.unwrap();
+
+            let owns = |pkg: &InstalledPackageInfo, path: &str| pkg.files.iter().any(|f| f.path.ends_with(path));
+            let foo = fx.installed_package("foo").await.unwrap();
+            let fork = fx.installed_package("foo-fork").await.unwrap();
+            assert_eq!(fx.read("usr/share/fork"), "f", "{:?}", policy);
+            match policy {
+                FileConflictPolicy::Skip => {
+                    assert_eq!(fx.read("usr/bin/foo"), "foo");
+                    assert!(owns(&foo, "usr/bin/foo") && !owns(&fork, "usr/bin/foo"));
+                    handle_remove("foo-fork", false, &fx.ctx).await.unwrap();
+                    assert_eq!(fx.read("usr/bin/foo"), "foo");
+                }
+                FileConflictPolicy::Overwrite => {
+                    assert_eq!(fx.read("usr/bin/foo"), "fork");
+                    assert_eq!(fx.read("usr/bin/foo.flux-old"), "foo");
+                    assert!(!owns(&foo, "usr/bin/foo") && owns(&fork, "usr/bin/foo"));
+                    handle_remove("foo", false, &fx.ctx).await.unwrap();
+                    assert_eq!(fx.read("usr/bin/foo"), "fork");
+                }
+                FileConflictPolicy::BackupBoth => {
+                    assert_eq!(fx.read("usr/bin/foo"), "foo");
+                    assert_eq!(fx.read("usr/bin/foo.flux-new"), "fork");
+                    assert!(owns(&foo, "usr/bin/foo") && owns(&fork, "usr/bin/foo.flux-new") && !owns(&fork, "usr/bin/foo"));
+                    handle_remove("foo-fork", false, &fx.ctx).await.unwrap();
+                    assert_eq!(fx.read("usr/bin/foo"), "foo");
+                    assert!(!fx.path("usr/bin/foo.flux-new").exists());
+                }
+                FileConflictPolicy::Error => unreachable!(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn embedded_metadata_that_disagrees_with_the_index_is_reported_but_not_installed() {
+        let mut fx = Fixture::new();
+        let embedded = "name: libfoo\nversion: 1.0.1\ndependencies: [\"libc>=2\"]\n";
+        let info = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &["libc >= 2"]), &[("usr/lib/libfoo.so", "foo"), (".flux/metadata.yaml", embedded)]);
+        fx.publish(package_info("libc", "2.0.0", PackageType::System, &[]), &[("usr/lib/libc.so", "c")]);
+        let archive = Url::parse(&info.url).unwrap().to_file_path().unwrap();
+
+        let metadata = read_embedded_metadata(&archive).await.unwrap().unwrap();
+        assert_eq!(embedded_metadata_mismatches(&info, &metadata), vec!["version 1.0.1 (index: 1.0.0)"]);
+        let matching = EmbeddedMetadata { version: "1.0.0".to_string(), ..metadata };
+        assert!(embedded_metadata_mismatches(&info, &matching).is_empty());
+
+        fx.install(&["libfoo"]).await.unwrap();
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().version, "1.0.0");
+        assert!(!fx.path(".flux").exists());
+        assert!(!fx.installed_package("libfoo").await.unwrap().files.iter().any(|f| f.path.to_string_lossy().contains(".flux")));
+    }
+
+    #[tokio::test]
+    async fn upgrade_list_reports_available_upgrades_without_applying_them() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        let list = UpgradeOptions { list: true, ..UpgradeOptions::default() };
+        assert!(!handle_upgrade_list(&[], &list, &fx.ctx).await.unwrap());
+
+        fx.publish(package_info("libfoo", "1.1.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1.1")]);
+        let database = std::fs::read(&fx.ctx.target_db_path).unwrap();
+        assert!(handle_upgrade_list(&[], &list, &fx.ctx).await.unwrap());
+
+        assert_eq!(std::fs::read(&fx.ctx.target_db_path).unwrap(), database);
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+        let cli = Cli::try_parse_from(["flux", "upgrade", "--list"]).unwrap();
+        assert!(!cli.command.is_mutating());
+        assert!(![0, 1].contains(&UPGRADES_AVAILABLE_EXIT_CODE));
+    }
+
+    #[tokio::test]
+    async fn a_non_utf8_file_name_survives_the_database_and_is_removed() {
+        use std::os::unix::ffi::OsStrExt;
+        let mut fx = Fixture::new();
+        let name: &[u8] = b"usr/share/caf\xe9.txt";
+        fx.publish_archive(package_info("latin1", "1.0.0", PackageType::System, &[]), raw_tar_zst(&[(name, None, "latin-1")]));
+        fx.install(&["latin1"]).await.unwrap();
+        let installed = fx.ctx.target_root.join(std::ffi::OsStr::from_bytes(name));
+        assert_eq!(std::fs::read_to_string(&installed).unwrap(), "latin-1");
+
+        let database = std::fs::read_to_string(&fx.ctx.target_db_path).unwrap();
+        serde_json::from_str::<serde_json::Value>(&database).unwrap();
+        let record = fx.installed_package("latin1").await.unwrap();
+        assert!(record.files.iter().any(|f| f.path.as_os_str().as_bytes().ends_with(b"caf\xe9.txt")), "{}", database);
+
+        handle_remove("latin1", false, &fx.ctx).await.unwrap();
+        assert!(!installed.exists());
+    }
+
+    #[tokio::test]
+    async fn index_attempts_are_counted_per_mirror_and_in_total() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let index = serde_yaml::to_string(&PackageIndex { packages: vec![package_info("libfoo", "1.0.0", PackageType::System, &[])] }).unwrap();
+        let url = {
+            let requests = requests.clone();
+            http_server(move |_, head| {
+                let path = head.split_whitespace().nth(1).unwrap_or_default().to_string();
+                let mut requests = requests.lock().unwrap();
+                requests.push(path.clone());
+                let flaky_retry = path == "/flaky.yaml" && requests.iter().filter(|p| **p == path).count() > 1;
+                let response = if flaky_retry { http_response("200 OK", &[], index.as_bytes()) } else { http_response("503 Service Unavailable", &[], b"") };
+                async move { response }
+            }).await
+        };
+        let sources: Vec<String> = ["down.yaml", "broken.yaml", "flaky.yaml"].iter().map(|p| url.join(p).unwrap().to_string()).collect();
+        let config = FluxConfig { repository_url: Some(sources[0].clone()), index_mirrors: sources[1..].to_vec(), index_retries: Some(2), ..FluxConfig::default() };
+        let mut fx = Fixture::with_config(config);
+        let count = |path: &str| requests.lock().unwrap().iter().filter(|p| *p == path).count();
+
+        handle_update(true, &mut fx.ctx).await.unwrap();
+        assert!(fx.ctx.package_index.contains_key("libfoo"));
+        assert_eq!((count("/down.yaml"), count("/broken.yaml"), count("/flaky.yaml")), (2, 2, 2));
+
+        requests.lock().unwrap().clear();
+        fx.ctx.config.index_max_total_attempts = Some(3);
+        let err = handle_update(true, &mut fx.ctx).await.unwrap_err();
+        let FluxError::AllMirrorsFailed { failures, untried } = &err else { panic!("unexpected error: {}", err) };
+        let attempts: Vec<(&str, u32)> = failures.iter().map(|f| (f.source.as_str(), f.attempts)).collect();
+        assert_eq!(attempts, vec![(sources[0].as_str(), 2), (sources[1].as_str(), 1)]);
+        assert_eq!(*untried, 1);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    // Splits CSV text into rows of fields, undoing RFC 4180 quoting.
+    fn parse_csv(text: &str) -> Vec<Vec<String>> {
+        let (mut rows, mut row, mut field) = (Vec::new(), Vec::new(), String::new());
+        let (mut quoted, mut chars) = (false, text.chars().peekable());
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => { chars.next(); field.push('"'); }
+                ('"', _) => quoted = !quoted,
+                (',', false) => row.push(std::mem::take(&mut field)),
+                ('\n', false) => { row.push(std::mem::take(&mut field)); rows.push(std::mem::take(&mut row)); }
+                (c, _) => field.push(c),
+            }
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn csv_list_quotes_fields_with_commas_and_parses_back() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libc", "1.0.0", PackageType::System, &[]), &[("usr/lib/libc.so", "c")]);
+        fx.publish(package_info("curl", "1.0.0", PackageType::System, &["libc"]), &[("usr/bin/curl", "curl")]);
+        fx.publish(package_info("git", "2.0.0-\"rc\"", PackageType::System, &["libc"]), &[("usr/bin/git", "git")]);
+        fx.install(&["curl", "git"]).await.unwrap();
+        let installed = fx.installed().await;
+        let options = ListOptions { format: ListFormat::Csv, json_lines: false, reverse_deps: true, verbose: true, orphans: false, sort: None, newer_than: None };
+        let entries = list_order(&installed, &options, &fx.ctx).into_iter().map(|pkg| ListEntry {
+            dependents: Some(find_dependents(&pkg.name, &installed, &fx.ctx)),
+            ..ListEntry::from(pkg)
+        });
+
+        let csv = list_csv(entries, &options);
+
+        assert!(csv.contains(",2,\"curl, git\"\n"), "{}", csv);
+        assert!(csv.contains(",\"2.0.0-\"\"rc\"\"\","), "{}", csv);
+        let rows = parse_csv(&csv);
+        assert_eq!(rows[0], ["name", "version", "type", "reason", "dependents", "dependent_names"]);
+        let libc = rows.iter().find(|row| row[0] == "libc").unwrap();
+        assert_eq!(libc[1..], ["1.0.0", "System", "Dependency", "2", "curl, git"]);
+        assert_eq!(rows.iter().find(|row| row[0] == "git").unwrap()[1], "2.0.0-\"rc\"");
+        assert!(rows.iter().all(|row| row.len() == 6));
+    }
+
+    #[tokio::test]
+    async fn every_missing_dependency_of_the_closure_is_reported_at_once() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("liba", "1.0.0", PackageType::System, &["libx", "liby"]), &[("usr/lib/liba.so", "a")]);
+        fx.publish(package_info("app", "1.0.0", PackageType::System, &["liba", "liby >= 2", "libz"]), &[("usr/bin/app", "app")]);
+
+        let err = fx.install(&["app"]).await.unwrap_err();
+
+        let FluxError::MissingDependencies(missing) = &err else { panic!("unexpected error: {}", err) };
+        let mut reported: Vec<(&str, Vec<&str>)> = missing.iter().map(|m| (m.name.as_str(), m.required_by.iter().map(String::as_str).collect())).collect();
+        reported.sort();
+        assert_eq!(reported, vec![("libx", vec!["liba"]), ("liby", vec!["liba", "app"]), ("libz", vec!["app"])]);
+        assert!(err.to_string().contains("liby (required by liba, app)"), "{}", err);
+        assert!(fx.installed().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove_scripts_run_around_the_file_removal_from_a_private_copy() {
+        let mut fx = Fixture::new();
+        let log = fx.dir.path().join("remove.log");
+        let binary = fx.path("usr/bin/svc");
+        let pre = format!("test -f {} && echo pre >> {}", binary.display(), log.display());
+        let post = format!("test -e {0} || echo post >> {1}; stat -c %a \"$(dirname \"$0\")\" >> {1}; dirname \"$0\" >> {1}", binary.display(), log.display());
+        let mut info = package_info("svc", "1.0.0", PackageType::System, &[]);
+        info.pre_remove = Some(ScriptSpec::Script("usr/share/svc/pre-remove".to_string()));
+        info.post_remove = Some(ScriptSpec::Script("usr/share/svc/post-remove".to_string()));
+        fx.publish(info, &[("usr/bin/svc", "svc"), ("usr/share/svc/pre-remove", &pre), ("usr/share/svc/post-remove", &post)]);
+        fx.install(&["svc"]).await.unwrap();
+
+        handle_remove("svc", false, &fx.ctx).await.unwrap();
+
+        let log = std::fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines[..3], ["pre", "post", "700"], "{}", log);
+        let copy_dir = Path::new(lines[3]);
+        assert!(!copy_dir.starts_with(&fx.ctx.target_root) && !copy_dir.exists(), "{}", log);
+        assert!(!binary.exists() && fx.installed().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn undo_reverses_a_mid_history_transaction_and_refuses_an_inconsistent_one() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar")]);
+        fx.publish(package_info("extra", "1.0.0", PackageType::System, &[]), &[("usr/bin/extra", "extra")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::System, &["libbar"]), &[("usr/bin/tool", "tool")]);
+        for name in ["libbar", "extra", "tool"] {
+            let before = fx.installed().await;
+            fx.install(&[name]).await.unwrap();
+            record_history(&before, &format!("install {}", name), &fx.ctx).await.unwrap();
+        }
+        let ids: Vec<u64> = read_history(&fx.ctx).await.unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        handle_history_undo(2, &mut fx.ctx).await.unwrap();
+        assert_eq!(names(&fx.installed().await), vec!["libbar", "tool"]);
+        assert!(!fx.path("usr/bin/extra").exists());
+
+        let err = handle_history_undo(1, &mut fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::UndoRefused { id: 1, reason } if reason.contains("'tool' depends on 'libbar'")), "{}", err);
+        assert_eq!(names(&fx.installed().await), vec!["libbar", "tool"]);
+    }
+
+    #[tokio::test]
+    async fn install_extracts_and_records_the_dependency_closure() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.publish(package_info("tool", "2.0.0", PackageType::App, &["libfoo"]), &[("bin/tool", "echo tool")]);
+
+        fx.install(&["tool"]).await.unwrap();
+
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+        assert_eq!(fx.read("flux/apps/tool-2.0.0/bin/tool"), "echo tool");
+        let libfoo = fx.installed_package("libfoo").await.unwrap();
+        assert_eq!(libfoo.install_reason, InstallReason::Dependency);
+        let file = libfoo.files.iter().find(|f| f.path == Path::new("usr/lib/libfoo.so")).unwrap();
+        assert_eq!(file.sha256.as_deref(), Some(hash_file_sync(&fx.path("usr/lib/libfoo.so")).unwrap().as_str()));
+        assert_eq!(fx.installed_package("tool").await.unwrap().install_reason, InstallReason::Explicit);
+        assert!(!fx.ctx.install_journal_path().exists());
+    }
+
+    #[tokio::test]
+    async fn a_failing_post_install_rolls_back_the_whole_batch() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        let mut broken = package_info("broken", "1.0.0", PackageType::System, &["libfoo"]);
+        broken.post_install = Some(ScriptSpec::Script("usr/share/broken/setup.sh".to_string()));
+        fx.publish(broken, &[("usr/bin/broken", "x"), ("usr/share/broken/setup.sh", "#!/bin/sh\nexit 1\n")]);
+
+        let result = fx.install(&["broken"]).await;
+
+        assert!(matches!(result, Err(FluxError::PostInstallScriptFailed { .. })), "{:?}", result.err());
+        assert!(fx.installed().await.is_empty());
+        for path in ["usr/lib/libfoo.so", "usr/bin/broken", "usr/share/broken"] {
+            assert!(!fx.path(path).exists(), "{} was left behind", path);
+        }
+        assert!(!fx.ctx.install_journal_path().exists());
+    }
+
+    #[tokio::test]
+    async fn a_rolled_back_batch_releases_only_the_blobs_it_created() {
+        let mut fx = content_store_fixture();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo"), ("usr/share/foo/COPYING", "license")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        let mut broken = package_info("broken", "1.0.0", PackageType::System, &[]);
+        broken.post_install = Some(ScriptSpec::Script("usr/share/broken/setup.sh".to_string()));
+        fx.publish(broken, &[("usr/bin/broken", "x"), ("usr/share/broken/COPYING", "license"), ("usr/share/broken/setup.sh", "#!/bin/sh\nexit 1\n")]);
+
+        let err = fx.install(&["broken"]).await.unwrap_err();
+
+        assert!(matches!(err, FluxError::PostInstallScriptFailed { .. }), "{}", err);
+        assert_eq!(blob_count(&fx), 2);
+        assert_eq!(fx.read("usr/share/foo/COPYING"), "license");
+        assert!(!fx.path("usr/share/broken").exists());
+    }
+
+    #[tokio::test]
+    async fn verify_checks_only_the_named_package_and_finds_app_files_in_their_install_dir() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &[]), &[("bin/tool", "echo tool"), ("share/tool.txt", "docs")]);
+        fx.install(&["libfoo", "tool"]).await.unwrap();
+        handle_verify(Some("tool"), true, &fx.ctx).await.unwrap();
+
+        std::fs::remove_file(fx.path("usr/lib/libfoo.so")).unwrap();
+        std::fs::write(fx.path("flux/apps/tool-1.0.0/share/tool.txt"), "changed").unwrap();
+
+        let err = handle_verify(Some("libfoo"), true, &fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::VerificationFailed(message) if message == "1 missing and 0 modified file(s) in libfoo"), "{}", err);
+        let err = handle_verify(Some("tool"), true, &fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::VerificationFailed(message) if message == "0 missing and 1 modified file(s) in tool"), "{}", err);
+        let err = handle_verify(Some("absent"), true, &fx.ctx).await.unwrap_err();
+        assert!(matches!(err, FluxError::PackageNotFound(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn profiles_keep_separate_package_databases() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("devtool", "1.0.0", PackageType::App, &[]), &[("bin/devtool", "echo dev")]);
+        fx.publish(package_info("citool", "1.0.0", PackageType::App, &[]), &[("bin/citool", "echo ci")]);
+        let (dev, ci) = (fx.profile("dev"), fx.profile("ci"));
+
+        install_packages(&["devtool".to_string()], &InstallOptions::default(), &dev).await.unwrap();
+        install_packages(&["citool".to_string()], &InstallOptions::default(), &ci).await.unwrap();
+
+        assert_eq!(names(&dev.get_installed_packages().await.unwrap()), ["devtool"]);
+        assert_eq!(names(&ci.get_installed_packages().await.unwrap()), ["citool"]);
+        assert!(fx.installed().await.is_empty());
+        assert!(fx.path("var/lib/flux/profiles/dev/db.json").exists());
+        assert!(fx.path("var/lib/flux/profiles/ci/db.json").exists());
+        assert!(!fx.ctx.target_db_path.exists());
+        assert!(matches!(handle_remove("citool", false, &dev).await, Err(FluxError::PackageNotFound(_))));
+    }
+
+    // Files on disk are shared, so removing a package from one profile keeps what another still has.
+    #[tokio::test]
+    async fn removing_from_one_profile_keeps_files_another_profile_has_installed() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &[]), &[("bin/tool", "echo tool")]);
+        let (dev, ci) = (fx.profile("dev"), fx.profile("ci"));
+        install_packages(&["tool".to_string()], &InstallOptions::default(), &dev).await.unwrap();
+        install_packages(&["tool".to_string()], &InstallOptions::default(), &ci).await.unwrap();
+
+        handle_remove("tool", false, &dev).await.unwrap();
+        assert!(fx.path("flux/apps/tool-1.0.0/bin/tool").exists());
+        assert_eq!(ci.get_installed_packages().await.unwrap().len(), 1);
+
+        handle_remove("tool", false, &ci).await.unwrap();
+        assert!(!fx.path("flux/apps/tool-1.0.0").exists());
+    }
+
+    #[tokio::test]
+    async fn content_blobs_stay_while_any_profile_references_them() {
+        let mut fx = content_store_fixture();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo"), ("usr/share/foo/COPYING", "license")]);
+        let mut broken = package_info("broken", "1.0.0", PackageType::System, &[]);
+        broken.post_install = Some(ScriptSpec::Script("usr/share/broken/setup.sh".to_string()));
+        fx.publish(broken, &[("usr/share/broken/COPYING", "license"), ("usr/share/broken/setup.sh", "#!/bin/sh\nexit 1\n")]);
+        let (dev, ci) = (fx.profile("dev"), fx.profile("ci"));
+        install_packages(&["libfoo".to_string()], &InstallOptions::default(), &dev).await.unwrap();
+        install_packages(&["libfoo".to_string()], &InstallOptions::default(), &ci).await.unwrap();
+        let license = dev.get_installed_packages().await.unwrap()[0].content_hashes[Path::new("usr/share/foo/COPYING")].clone();
+
+        handle_remove("libfoo", false, &ci).await.unwrap();
+        assert!(fx.ctx.target_store_dir.join(&license).exists());
+
+        // ci rolls back a batch whose only shared blob is one dev still references.
+        install_packages(&["broken".to_string()], &InstallOptions::default(), &ci).await.unwrap_err();
+        assert!(fx.ctx.target_store_dir.join(&license).exists());
+        assert_eq!(blob_count(&fx), 2);
+        assert_eq!(fx.read("usr/share/foo/COPYING"), "license");
+
+        handle_remove("libfoo", false, &dev).await.unwrap();
+        assert_eq!(blob_count(&fx), 0);
+    }
+
+    // A restored file is renamed into place from staging, so it comes back with a new inode.
+    #[tokio::test]
+    async fn reinstalling_an_intact_package_rewrites_nothing() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1"), ("usr/share/doc/libfoo", "docs")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        let before = [inode(&fx.path("usr/lib/libfoo.so")), inode(&fx.path("usr/share/doc/libfoo"))];
+
+        reinstall_package("libfoo", &InstallOptions::default(), &fx.ctx).await.unwrap();
+
+        assert_eq!([inode(&fx.path("usr/lib/libfoo.so")), inode(&fx.path("usr/share/doc/libfoo"))], before);
+    }
+
+    #[tokio::test]
+    async fn reinstall_restores_only_the_modified_file() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1"), ("usr/share/doc/libfoo", "docs")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        std::fs::write(fx.path("usr/lib/libfoo.so"), "tampered").unwrap();
+        let (modified, intact) = (inode(&fx.path("usr/lib/libfoo.so")), inode(&fx.path("usr/share/doc/libfoo")));
+
+        reinstall_package("libfoo", &InstallOptions::default(), &fx.ctx).await.unwrap();
+
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+        assert_ne!(inode(&fx.path("usr/lib/libfoo.so")), modified);
+        assert_eq!(inode(&fx.path("usr/share/doc/libfoo")), intact);
+    }
+
+    #[tokio::test]
+    async fn database_records_without_hashes_still_load_and_verify() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar 1")]);
+        fx.install(&["libfoo", "libbar"]).await.unwrap();
+        assert!(fx.installed_package("libfoo").await.unwrap().files.iter().any(|f| f.sha256.is_some()));
+
+        // Rewrite the database as older versions left it: libfoo with bare paths, libbar with
+        // entries that have no hash.
+        let mut records: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&fx.ctx.target_db_path).unwrap()).unwrap();
+        for record in records.as_array_mut().unwrap() {
+            let bare = record["name"] == "libfoo";
+            for file in record["files"].as_array_mut().unwrap() {
+                if bare {
+                    *file = file["path"].clone();
+                } else {
+                    file.as_object_mut().unwrap().remove("sha256");
+                }
+            }
+        }
+        std::fs::write(&fx.ctx.target_db_path, serde_json::to_string(&records).unwrap()).unwrap();
+
+        let installed = fx.installed().await;
+        assert!(installed.iter().all(|p| !p.files.is_empty() && p.files.iter().all(|f| f.sha256.is_none())));
+        assert!(installed.iter().any(|p| p.files.iter().any(|f| f.path == Path::new("usr/lib/libfoo.so"))));
+
+        // Without a recorded hash a modification can't be told apart, but a missing file still can.
+        std::fs::write(fx.path("usr/lib/libfoo.so"), "tampered").unwrap();
+        handle_verify(None, true, &fx.ctx).await.unwrap();
+        std::fs::remove_file(fx.path("usr/lib/libbar.so")).unwrap();
+        let err = handle_verify(None, true, &fx.ctx).await.unwrap_err();
+        assert!(matches!(&err, FluxError::VerificationFailed(message) if message == "1 missing and 0 modified file(s) in libbar"), "{}", err);
+
+        // Written back, the old records stay readable and gain no empty hashes.
+        fx.ctx.write_installed_packages(&installed).await.unwrap();
+        assert!(!std::fs::read_to_string(&fx.ctx.target_db_path).unwrap().contains("sha256"));
+        assert_eq!(fx.installed().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn search_by_dependency_finds_every_declared_dependent() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libssl", "3.0.0", PackageType::System, &[]), &[("usr/lib/libssl.so", "ssl")]);
+        fx.publish(package_info("libssl-dev", "3.0.0", PackageType::System, &[]), &[("usr/include/ssl.h", "h")]);
+        fx.publish(package_info("curl", "8.0.0", PackageType::System, &["libssl >= 3.0"]), &[("usr/bin/curl", "curl")]);
+        fx.publish(package_info("wget", "1.21.0", PackageType::System, &["libssl"]), &[("usr/bin/wget", "wget")]);
+        fx.publish(package_info("zip", "3.0.0", PackageType::System, &["libssl-dev"]), &[("usr/bin/zip", "zip")]);
+        let mut git = package_info("git", "2.40.0", PackageType::System, &["curl"]);
+        git.optional_dependencies = Some(vec!["libssl".to_string()]);
+        fx.publish(git, &[("usr/bin/git", "git")]);
+        fx.install(&["wget"]).await.unwrap();
+        let installed = fx.installed().await;
+
+        let found: Vec<(&str, &str, bool, bool)> = dependents_in_index("libssl", false, &installed, &fx.ctx).iter()
+            .map(|m| (m.name, m.requirement, m.optional, m.installed))
+            .collect();
+        assert_eq!(found, vec![("curl", "libssl >= 3.0", false, false), ("git", "libssl", true, false), ("wget", "libssl", false, true)]);
+        let installed_only: Vec<&str> = dependents_in_index("libssl", true, &installed, &fx.ctx).iter().map(|m| m.name).collect();
+        assert_eq!(installed_only, vec!["wget"]);
+        assert!(dependents_in_index("zip", false, &installed, &fx.ctx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_json_lines_are_each_a_complete_record() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("zlib", "1.3.0", PackageType::System, &[]), &[("usr/lib/libz.so", "z")]);
+        fx.publish(package_info("zstd", "1.5.0", PackageType::System, &["zlib"]), &[("usr/bin/zstd", "zstd")]);
+        fx.publish(package_info("curl", "8.0.0", PackageType::System, &["zlib >= 1.2"]), &[("usr/bin/curl", "curl")]);
+
+        let mut out = Vec::new();
+        write_json_lines_to(&mut out, search_index("z", &fx.ctx)).unwrap();
+        let records: Vec<serde_json::Value> = std::str::from_utf8(&out).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let found: Vec<(&str, &str)> = records.iter().map(|r| (r["name"].as_str().unwrap(), r["version"].as_str().unwrap())).collect();
+        assert_eq!(found, vec![("zlib", "1.3.0"), ("zstd", "1.5.0")]);
+        assert_eq!(records[1]["dependencies"], serde_json::json!(["zlib"]));
+
+        let mut out = Vec::new();
+        write_json_lines_to(&mut out, dependents_in_index("zlib", false, &[], &fx.ctx)).unwrap();
+        let records: Vec<serde_json::Value> = std::str::from_utf8(&out).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0]["name"].as_str(), records[0]["requirement"].as_str()), (Some("curl"), Some("zlib >= 1.2")));
+        assert_eq!(records[1]["installed"], false);
+    }
+
+    // A service account may have no home directory; --cache-dir alone must be enough.
+    #[tokio::test]
+    async fn an_explicit_cache_dir_is_used_without_consulting_the_home_directory() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        let cache = fx.dir.path().join("service-cache");
+        let root = fx.ctx.target_root.to_str().unwrap().to_string();
+        let cli = Cli::try_parse_from(["flux", "--root", &root, "--cache-dir", cache.to_str().unwrap(), "list"]).unwrap();
+        assert_eq!(host_cache_dir(&cli).unwrap(), cache);
+
+        let mut ctx = AppContext::new(&cli).await.unwrap();
+        assert_eq!(ctx.host_cache_dir, cache);
+        assert!(cache.is_dir());
+        ctx.package_index = fx.ctx.package_index.clone();
+        install_packages(&["libfoo".to_string()], &InstallOptions { keep_downloads: true, ..InstallOptions::default() }, &ctx).await.unwrap();
+
+        assert!(cache.join(archive_file_name(&ctx.package_index["libfoo"])).exists());
+        assert_eq!(names(&fx.installed().await), ["libfoo"]);
+    }
+
+    #[tokio::test]
+    async fn install_picks_the_highest_version_every_requirement_allows() {
+        let mut fx = Fixture::new();
+        for version in ["1.0.0", "1.5.0", "2.0.0"] {
+            fx.publish(package_info("libfoo", version, PackageType::System, &[]), &[("usr/lib/libfoo.so", version)]);
+        }
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &["libfoo < 2.0"]), &[("usr/lib/libbar.so", "bar")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo >= 1.2", "libbar"]), &[("bin/tool", "echo tool")]);
+
+        handle_install("tool", &InstallOptions::default(), &mut fx.ctx).await.unwrap();
+
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().version, "1.5.0");
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "1.5.0");
+        // Without a requirement against it the newest version is installed.
+        let mut fx = Fixture::new();
+        for version in ["1.0.0", "2.0.0"] {
+            fx.publish(package_info("libfoo", version, PackageType::System, &[]), &[("usr/lib/libfoo.so", version)]);
+        }
+        handle_install("libfoo", &InstallOptions::default(), &mut fx.ctx).await.unwrap();
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "2.0.0");
+    }
+
+    #[tokio::test]
+    async fn recheck_existing_refuses_an_installed_dependency_that_is_too_old() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        fx.publish(package_info("libfoo", "2.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 2")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo >= 2.0"]), &[("bin/tool", "echo tool")]);
+        let recheck = InstallOptions { recheck_existing: true, ..InstallOptions::default() };
+
+        let error = install_packages(&["tool".to_string()], &recheck, &fx.ctx).await.unwrap_err();
+
+        assert!(matches!(&error, FluxError::OutdatedDependency { package_name, dependency, installed }
+            if package_name == "tool" && dependency == "libfoo >= 2.0" && installed == "libfoo 1.0.0"), "{}", error);
+        assert!(fx.installed_package("tool").await.is_none());
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+
+        // Once the dependency is upgraded the same install goes through.
+        handle_upgrade(&["libfoo".to_string()], &UpgradeOptions::default(), &fx.ctx).await.unwrap();
+        install_packages(&["tool".to_string()], &recheck, &fx.ctx).await.unwrap();
+        assert!(fx.installed_package("tool").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn recheck_existing_restores_a_damaged_dependency() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1"), ("usr/share/doc/libfoo", "docs")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo"]), &[("bin/tool", "echo tool")]);
+        std::fs::remove_file(fx.path("usr/lib/libfoo.so")).unwrap();
+        std::fs::write(fx.path("usr/share/doc/libfoo"), "edited").unwrap();
+
+        let recheck = InstallOptions { recheck_existing: true, ..InstallOptions::default() };
+        install_packages(&["tool".to_string()], &recheck, &fx.ctx).await.unwrap();
+
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+        assert_eq!(fx.read("usr/share/doc/libfoo"), "docs");
+        assert!(fx.installed_package("tool").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn autoremove_reaps_a_chain_of_orphans_in_one_pass() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("app", "1.0.0", PackageType::App, &["top"]), &[("bin/app", "echo app")]);
+        fx.publish(package_info("top", "1.0.0", PackageType::System, &["mid"]), &[("usr/lib/libtop.so", "top")]);
+        fx.publish(package_info("mid", "1.0.0", PackageType::System, &["base", "shared"]), &[("usr/lib/libmid.so", "mid")]);
+        fx.publish(package_info("base", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbase.so", "base")]);
+        fx.publish(package_info("shared", "1.0.0", PackageType::System, &[]), &[("usr/lib/libshared.so", "shared")]);
+        fx.publish(package_info("keeper", "1.0.0", PackageType::App, &["shared"]), &[("bin/keeper", "echo keeper")]);
+        fx.install(&["app", "keeper"]).await.unwrap();
+        handle_autoremove(&fx.ctx).await.unwrap();
+        assert_eq!(fx.installed().await.len(), 6);
+
+        // Only app is unneeded at first; top, mid and base each become orphans once the one above goes.
+        handle_mark("app", InstallReason::Dependency, &fx.ctx).await.unwrap();
+        handle_autoremove(&fx.ctx).await.unwrap();
+
+        let mut remaining: Vec<String> = fx.installed().await.into_iter().map(|p| p.name).collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["keeper", "shared"]);
+        for gone in ["usr/lib/libtop.so", "usr/lib/libmid.so", "usr/lib/libbase.so"] {
+            assert!(!fx.path(gone).exists(), "{}", gone);
+        }
+        assert_eq!(fx.read("usr/lib/libshared.so"), "shared");
+    }
+
+    // Answers each webhook request with the next status in `statuses` and records the bodies.
+    async fn mock_webhook(statuses: Vec<u16>) -> (String, std::sync::Arc<std::sync::Mutex<Vec<serde_json::Value>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let bodies = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = bodies.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                let body_start = loop {
+                    let n = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") { break end + 4; }
+                };
+                let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+                let length: usize = headers.lines().find_map(|line| line.strip_prefix("content-length:")).unwrap().trim().parse().unwrap();
+                while request.len() < body_start + length {
+                    let n = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+                received.lock().unwrap().push(serde_json::from_slice(&request[body_start..]).unwrap());
+                let response = format!("HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, bodies)
+    }
+
+    // Installs libfoo and returns the history entry the install recorded.
+    async fn installed_transaction(fx: &mut Fixture) -> HistoryEntry {
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        record_history(&[], "install libfoo", &fx.ctx).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_webhook_is_retried_and_receives_the_transaction() {
+        let (url, bodies) = mock_webhook(vec![500, 200]).await;
+        let notify = NotifyConfig { webhook: Some(url), command: None, webhook_attempts: Some(3) };
+        let mut fx = Fixture::with_config(FluxConfig { notify: Some(notify), ..FluxConfig::default() });
+        let entry = installed_transaction(&mut fx).await;
+
+        notify_transaction(&entry, &fx.ctx).await;
+
+        let bodies = bodies.lock().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], bodies[1]);
+        let payload = &bodies[1];
+        assert_eq!(payload["host"], host_name());
+        assert_eq!(payload["root"], fx.ctx.target_root.to_str().unwrap());
+        assert_eq!(payload["profile"], serde_json::Value::Null);
+        assert_eq!(payload["id"], 1);
+        assert_eq!(payload["command"], "install libfoo");
+        assert_eq!(payload["changes"], serde_json::json!([{ "name": "libfoo", "after": { "version": "1.0.0", "install_reason": "Explicit" } }]));
+    }
+
+    #[tokio::test]
+    async fn an_undeliverable_webhook_gives_up_after_the_configured_attempts() {
+        let (url, bodies) = mock_webhook(vec![503, 503, 503]).await;
+        let notify = NotifyConfig { webhook: Some(url.clone()), command: None, webhook_attempts: Some(2) };
+        let mut fx = Fixture::with_config(FluxConfig { notify: Some(notify), ..FluxConfig::default() });
+        let entry = installed_transaction(&mut fx).await;
+
+        let payload = serde_json::to_vec(&entry).unwrap();
+        assert!(post_webhook(&url, &payload, 2, &fx.ctx).await.is_err());
+        assert_eq!(bodies.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn the_notify_command_reads_the_transaction_on_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("payload.json");
+        let notify = NotifyConfig { webhook: None, command: Some(format!("cat > '{}'", output.display())), webhook_attempts: None };
+        let mut fx = Fixture::with_config(FluxConfig { notify: Some(notify), ..FluxConfig::default() });
+        let entry = installed_transaction(&mut fx).await;
+
+        notify_transaction(&entry, &fx.ctx).await;
+
+        let payload: serde_json::Value = serde_json::from_slice(&std::fs::read(&output).unwrap()).unwrap();
+        assert_eq!(payload["command"], "install libfoo");
+        assert_eq!(payload["changes"][0]["name"], "libfoo");
+    }
+
+    // Lists every path under the target root, to catch leftovers such as .flux-replaced files.
+    fn target_paths(fx: &Fixture) -> Vec<PathBuf> {
+        read_tree(&fx.ctx.target_root).into_keys().filter(|path| !path.starts_with("var/lib/flux")).collect()
+    }
+
+    #[tokio::test]
+    async fn upgrade_swaps_a_library_in_place_without_running_remove_scripts() {
+        let mut fx = Fixture::new();
+        let marker = fx.dir.path().join("pre-remove-ran");
+        let mut libfoo = package_info("libfoo", "1.0.0", PackageType::System, &[]);
+        libfoo.pre_remove = Some(ScriptSpec::Script("usr/share/libfoo/pre-remove.sh".to_string()));
+        let script = format!("#!/bin/sh\ntouch '{}'\n", marker.display());
+        fx.publish(libfoo, &[("usr/lib/libfoo.so", "foo 1"), ("usr/lib/libfoo-old.so", "legacy"), ("usr/share/libfoo/pre-remove.sh", &script)]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo"]), &[("bin/tool", "echo tool")]);
+        fx.install(&["tool"]).await.unwrap();
+        fx.publish(package_info("libfoo", "2.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 2")]);
+
+        handle_upgrade(&["libfoo".to_string()], &UpgradeOptions::default(), &fx.ctx).await.unwrap();
+
+        let libfoo = fx.installed_package("libfoo").await.unwrap();
+        assert_eq!((libfoo.version.as_str(), &libfoo.install_reason), ("2.0.0", &InstallReason::Dependency));
+        assert!(fx.installed_package("tool").await.is_some());
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 2");
+        assert!(!marker.exists());
+        assert_eq!(target_paths(&fx), [PathBuf::from("flux/apps/tool-1.0.0/bin/tool"), PathBuf::from("usr/lib/libfoo.so")]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_upgrade_leaves_the_old_version_installed() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1"), ("usr/lib/libfoo-old.so", "legacy")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &["libfoo"]), &[("bin/tool", "echo tool")]);
+        fx.install(&["tool"]).await.unwrap();
+        let before = target_paths(&fx);
+        let mut broken = package_info("libfoo", "2.0.0", PackageType::System, &[]);
+        broken.post_install = Some(ScriptSpec::Script("usr/share/libfoo/setup.sh".to_string()));
+        fx.publish(broken, &[("usr/lib/libfoo.so", "foo 2"), ("usr/share/libfoo/setup.sh", "#!/bin/sh\nexit 1\n")]);
+
+        let result = handle_upgrade(&[], &UpgradeOptions::default(), &fx.ctx).await;
+
+        assert!(matches!(result, Err(FluxError::PostInstallScriptFailed { .. })), "{:?}", result.err());
+        let libfoo = fx.installed_package("libfoo").await.unwrap();
+        assert_eq!((libfoo.version.as_str(), &libfoo.install_reason), ("1.0.0", &InstallReason::Dependency));
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+        assert_eq!(target_paths(&fx), before);
+        handle_verify(Some("libfoo"), true, &fx.ctx).await.unwrap();
+    }
+
+    // The index keeps 1.0.0 next to the newer 2.0.0, so each of these finds the older build.
+    #[tokio::test]
+    async fn an_older_indexed_version_can_be_reinstalled_verified_and_gone_back_to() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        record_history(&[], "install libfoo", &fx.ctx).await.unwrap();
+        fx.publish(package_info("libfoo", "2.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 2")]);
+
+        std::fs::write(fx.path("usr/lib/libfoo.so"), "tampered").unwrap();
+        reinstall_package("libfoo", &InstallOptions::default(), &fx.ctx).await.unwrap();
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+        let status = check_package_signature(&fx.installed_package("libfoo").await.unwrap(), &[], &fx.ctx).await.unwrap();
+        assert!(!matches!(&status, SignatureStatus::Unavailable { reason } if reason.contains("no longer in the index")));
+
+        let before = fx.installed().await;
+        handle_upgrade(&[], &UpgradeOptions::default(), &fx.ctx).await.unwrap();
+        record_history(&before, "upgrade", &fx.ctx).await.unwrap();
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 2");
+
+        handle_history_undo(2, &mut fx.ctx).await.unwrap();
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().version, "1.0.0");
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+    }
+
+    #[tokio::test]
+    async fn a_failed_upgrade_download_deletes_only_the_archives_it_downloaded() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.install(&["libfoo"]).await.unwrap();
+        let cached = fx.publish(package_info("libcached", "1.0.0", PackageType::System, &[]), &[("usr/lib/libcached.so", "cached")]);
+        let new = fx.publish(package_info("libnew", "1.0.0", PackageType::System, &[]), &[("usr/lib/libnew.so", "new")]);
+        let missing = fx.publish(package_info("libmissing", "1.0.0", PackageType::System, &[]), &[("usr/lib/libmissing.so", "missing")]);
+        std::fs::remove_file(fx.repo_dir().join("libmissing-1.0.0.tar.zst")).unwrap();
+        fx.publish(package_info("libfoo", "2.0.0", PackageType::System, &["libcached", "libnew", "libmissing"]), &[("usr/lib/libfoo.so", "foo 2")]);
+        // Cached earlier, as prefetching the updates would.
+        fetch_archive(&cached, &fx.ctx, &mut InstallTimings::default()).await.unwrap();
+        let archive = |info: &PackageInfo| fx.ctx.host_cache_dir.join(archive_file_name(info));
+
+        handle_upgrade(&["libfoo".to_string()], &UpgradeOptions::default(), &fx.ctx).await.unwrap_err();
+        assert!(archive(&cached).exists());
+        assert!(!archive(&new).exists());
+        assert!(!archive(&missing).exists());
+        assert_eq!(fx.installed_package("libfoo").await.unwrap().version, "1.0.0");
+
+        handle_upgrade(&["libfoo".to_string()], &UpgradeOptions { keep_downloads: true, ..UpgradeOptions::default() }, &fx.ctx).await.unwrap_err();
+        assert!(archive(&cached).exists() && archive(&new).exists());
+    }
+
+    // The same name and version as `info`, built again into a different archive.
+    fn rebuilt(fx: &Fixture, info: &PackageInfo, files: &[(&str, &str)]) -> PackageInfo {
+        let archive_path = fx.repo_dir().join(format!("{}-{}-rebuild.tar.zst", info.name, info.version));
+        std::fs::write(&archive_path, tar_zst(files)).unwrap();
+        PackageInfo { url: Url::from_file_path(&archive_path).unwrap().to_string(), checksum: hash_file_sync(&archive_path).unwrap(), ..info.clone() }
+    }
+
+    #[tokio::test]
+    async fn update_keeps_the_first_of_duplicate_entries_by_default() {
+        let mut fx = Fixture::new();
+        let first = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "first")]);
+        let second = rebuilt(&fx, &first, &[("usr/lib/libfoo.so", "second")]);
+        let repo = RepoEntry { name: DEFAULT_REPO_NAME.to_string(), url: String::new(), priority: 0, optional: false };
+        assert!(check_duplicate_entries(&[first.clone(), second.clone()], &repo, DuplicateEntryPolicy::default()).is_ok());
+        fx.write_index(vec![first.clone(), second]);
+
+        handle_update(false, &mut fx.ctx).await.unwrap();
+
+        assert_eq!(fx.ctx.package_index["libfoo"].url, first.url);
+        assert_eq!(fx.ctx.package_versions["libfoo"].len(), 1);
+        fx.install(&["libfoo"]).await.unwrap();
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "first");
+    }
+
+    #[tokio::test]
+    async fn update_refuses_duplicate_entries_in_error_mode() {
+        let mut fx = Fixture::with_config(FluxConfig { duplicate_index_entries: Some(DuplicateEntryPolicy::Error), ..FluxConfig::default() });
+        let first = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "first")]);
+        let second = rebuilt(&fx, &first, &[("usr/lib/libfoo.so", "second")]);
+        // Repeating an entry exactly is harmless either way.
+        fx.write_index(vec![first.clone(), first.clone()]);
+        handle_update(false, &mut fx.ctx).await.unwrap();
+        fx.write_index(vec![first.clone(), second]);
+
+        let error = handle_update(true, &mut fx.ctx).await.unwrap_err();
+        assert!(error.to_string().contains("lists libfoo 1.0.0 more than once"), "{}", error);
+        // Without --strict-update the refused index only warns, and the cached one stays in use.
+        handle_update(false, &mut fx.ctx).await.unwrap();
+        assert_eq!(fx.ctx.package_index["libfoo"].url, first.url);
+    }
+
+    #[tokio::test]
+    async fn a_package_installed_as_a_dependency_is_reaped_by_autoremove() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &[]), &[("bin/tool", "echo tool")]);
+        fx.publish(package_info("keeper", "1.0.0", PackageType::App, &[]), &[("bin/keeper", "echo keeper")]);
+        fx.install(&["keeper"]).await.unwrap();
+        let options = InstallOptions { as_dependency: true, ..InstallOptions::default() };
+        install_packages(&["tool".to_string()], &options, &fx.ctx).await.unwrap();
+        assert_eq!(fx.installed_package("tool").await.unwrap().install_reason, InstallReason::Dependency);
+
+        handle_autoremove(&fx.ctx).await.unwrap();
+
+        assert!(fx.installed_package("tool").await.is_none());
+        assert!(!fx.path("flux/apps/tool-1.0.0").exists());
+        assert!(fx.installed_package("keeper").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn an_upgraded_dependency_is_still_reaped_by_autoremove() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        let options = InstallOptions { as_dependency: true, ..InstallOptions::default() };
+        install_packages(&["libfoo".to_string()], &options, &fx.ctx).await.unwrap();
+        fx.publish(package_info("libfoo", "2.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 2")]);
+
+        handle_upgrade(&[], &UpgradeOptions::default(), &fx.ctx).await.unwrap();
+        let libfoo = fx.installed_package("libfoo").await.unwrap();
+        assert_eq!((libfoo.version.as_str(), libfoo.install_reason), ("2.0.0", InstallReason::Dependency));
+
+        handle_autoremove(&fx.ctx).await.unwrap();
+        assert!(fx.installed().await.is_empty());
+        assert!(!fx.path("usr/lib/libfoo.so").exists());
+    }
+
+    #[test]
+    fn check_index_entry_rejects_names_and_versions_unsafe_as_paths() {
+        let long = "a".repeat(DEFAULT_MAX_NAME_LENGTH + 1);
+        let longest = "a".repeat(DEFAULT_MAX_NAME_LENGTH);
+        let cases: &[(&str, &str, Option<&str>)] = &[
+            ("libfoo", "1.0.0-rc.1+build.5", None),
+            ("lib_foo.bar-2", "2024.01", None),
+            ("bibliothèque", "1.0", None),
+            (&longest, "1.0", None),
+            ("", "1.0", Some("the name is empty")),
+            ("libfoo", "", Some("the version is empty")),
+            (".", "1.0", Some("the name is '.'")),
+            ("..", "1.0", Some("the name is '..'")),
+            ("libfoo", "..", Some("the version is '..'")),
+            ("../../etc/cron.d/evil", "1.0", Some("the name contains a path separator")),
+            ("libfoo", "1.0/../../../tmp", Some("the version contains a path separator")),
+            ("lib\\foo", "1.0", Some("the name contains a path separator")),
+            ("/etc", "1.0", Some("the name contains a path separator")),
+            ("lib foo", "1.0", Some("the name contains whitespace or control characters")),
+            ("libfoo", "1.0\t", Some("the version contains whitespace or control characters")),
+            ("libfoo\n", "1.0", Some("the name contains whitespace or control characters")),
+            ("lib\u{1b}[31mfoo", "1.0", Some("the name contains whitespace or control characters")),
+            ("lib\0foo", "1.0", Some("the name contains whitespace or control characters")),
+            (&long, "1.0", Some("the name is longer than 128 characters")),
+            ("libfoo", &long, Some("the version is longer than 128 characters")),
+        ];
+        for (name, version, expected) in cases {
+            let result = check_index_entry(&package_info(name, version, PackageType::System, &[]), DEFAULT_MAX_NAME_LENGTH);
+            assert_eq!(result.err().as_deref(), *expected, "name {:?}, version {:?}", name, version);
+        }
+        assert!(check_index_entry(&package_info("libfoo", "1.0", PackageType::System, &[]), 3).is_err());
+    }
+
+    #[tokio::test]
+    async fn update_refuses_an_index_with_an_unsafe_entry_before_caching_it() {
+        let mut fx = Fixture::new();
+        let good = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        let evil = PackageInfo { name: "../../escape".to_string(), ..good.clone() };
+        fx.write_index(vec![good, evil]);
+
+        let error = handle_update(true, &mut fx.ctx).await.unwrap_err();
+
+        assert!(error.to_string().contains("unusable entry \"../../escape\": the name contains a path separator"), "{}", error);
+        let repo = &fx.ctx.config.repositories().unwrap()[0];
+        assert!(!repo_cache_path(&fx.ctx.host_cache_dir, repo).exists());
+        assert!(std::fs::read_dir(&fx.ctx.host_cache_dir).unwrap().next().is_none());
+    }
+
+    // A cached index from before the check existed keeps working without its unsafe entries.
+    #[tokio::test]
+    async fn loading_a_cached_index_drops_unsafe_entries() {
+        let mut fx = Fixture::new();
+        let good = fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        let evil = PackageInfo { version: "../1.0".to_string(), ..package_info("other", "1.0", PackageType::System, &[]) };
+        fx.write_index(vec![good, evil]);
+        let repos = fx.ctx.config.repositories().unwrap();
+        std::fs::copy(fx.repo_dir().join("packages.yaml"), repo_cache_path(&fx.ctx.host_cache_dir, &repos[0])).unwrap();
+
+        let versions = load_package_index(&repos, &fx.ctx.host_cache_dir, &fx.ctx.accepted_arches, DEFAULT_MAX_NAME_LENGTH).await.unwrap();
+
+        assert_eq!(versions.keys().collect::<Vec<_>>(), ["libfoo"]);
+    }
+
+    #[tokio::test]
+    async fn owns_finds_the_package_behind_system_and_app_files() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &[]), &[("bin/tool", "echo tool")]);
+        fx.install(&["libfoo", "tool"]).await.unwrap();
+        let installed = fx.installed().await;
+        let owner = |path: &Path| {
+            let wanted = root_relative_path(path, &fx.ctx.target_root).unwrap();
+            owners_of(&wanted, &installed, &fx.ctx).iter().map(|p| p.name.clone()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(owner(&fx.path("usr/lib/libfoo.so")), vec!["libfoo"]);
+        assert_eq!(owner(&fx.path("usr/share/../lib/./libfoo.so")), vec!["libfoo"]);
+        assert_eq!(owner(&fx.path("flux/apps/tool-1.0.0/bin/tool")), vec!["tool"]);
+        // Paths may be given as seen from inside the root.
+        assert_eq!(owner(Path::new("/usr/lib/libfoo.so")), vec!["libfoo"]);
+        assert!(owner(&fx.path("usr/lib/libbar.so")).is_empty());
+        handle_owns(&fx.path("usr/lib/libbar.so"), &fx.ctx).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn files_lists_recorded_paths_and_walks_apps_without_a_file_list() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo"), ("usr/share/foo/README", "readme")]);
+        fx.publish(package_info("tool", "1.0.0", PackageType::App, &[]), &[("bin/tool", "echo tool")]);
+        fx.install(&["libfoo", "tool"]).await.unwrap();
+        let installed = fx.installed().await;
+        let libfoo = installed.iter().find(|p| p.name == "libfoo").unwrap();
+
+        let paths = installed_file_paths(libfoo, &fx.ctx).await.unwrap();
+        assert!(paths.contains(&fx.path("usr/lib/libfoo.so")) && paths.contains(&fx.path("usr/share/foo/README")), "{:?}", paths);
+
+        let mut tool = installed.iter().find(|p| p.name == "tool").unwrap().clone();
+        tool.files.clear();
+        assert_eq!(installed_file_paths(&tool, &fx.ctx).await.unwrap(), vec![fx.path("flux/apps/tool-1.0.0/bin"), fx.path("flux/apps/tool-1.0.0/bin/tool")]);
+
+        let err = handle_files("libbar", &fx.ctx).await.unwrap_err();
+        assert!(matches!(err, FluxError::PackageNotFound(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn upgrade_only_touches_packages_the_filters_let_through() {
+        let mut fx = Fixture::new();
+        for name in ["kernel-core", "kernel-modules", "libfoo"] {
+            let file = format!("usr/lib/{}", name);
+            fx.publish(package_info(name, "1.0.0", PackageType::System, &[]), &[(&file, "1")]);
+        }
+        fx.install(&["kernel-core", "kernel-modules", "libfoo"]).await.unwrap();
+        for name in ["kernel-core", "kernel-modules", "libfoo"] {
+            let file = format!("usr/lib/{}", name);
+            fx.publish(package_info(name, "2.0.0", PackageType::System, &[]), &[(&file, "2")]);
+        }
+        let options = UpgradeOptions { only: vec!["kernel-*".to_string()], exclude: vec!["*-modules".to_string()], ..UpgradeOptions::default() };
+
+        handle_upgrade(&[], &options, &fx.ctx).await.unwrap();
+
+        let versions: Vec<(String, String)> = fx.installed().await.into_iter().map(|p| (p.name, p.version)).collect();
+        assert!(versions.contains(&("kernel-core".to_string(), "2.0.0".to_string())), "{:?}", versions);
+        assert!(versions.contains(&("kernel-modules".to_string(), "1.0.0".to_string())), "{:?}", versions);
+        assert!(versions.contains(&("libfoo".to_string(), "1.0.0".to_string())), "{:?}", versions);
+        assert_eq!((fx.read("usr/lib/kernel-core"), fx.read("usr/lib/kernel-modules"), fx.read("usr/lib/libfoo")), ("2".to_string(), "1".to_string(), "1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn prefetched_upgrades_apply_without_the_repository() {
+        let mut fx = Fixture::new();
+        fx.publish(package_info("libfoo", "1.0.0", PackageType::System, &[]), &[("usr/lib/libfoo.so", "foo 1")]);
+        fx.publish(package_info("libbar", "1.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar 1")]);
+        fx.install(&["libfoo", "libbar"]).await.unwrap();
+        let database = std::fs::read(&fx.ctx.target_db_path).unwrap();
+        let libzip = fx.publish(package_info("libzip", "1.0.0", PackageType::System, &[]), &[("usr/lib/libzip.so", "zip")]);
+        let libfoo = fx.publish(package_info("libfoo", "2.0.0", PackageType::System, &["libzip"]), &[("usr/lib/libfoo.so", "foo 2")]);
+
+        handle_prefetch_updates(&fx.ctx).await.unwrap();
+
+        assert_eq!(std::fs::read(&fx.ctx.target_db_path).unwrap(), database);
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 1");
+        for info in [&libfoo, &libzip] {
+            assert!(cached_archive(info, &fx.ctx).await.unwrap().is_some(), "{} is not cached", info.name);
+        }
+
+        // libbar 2.0 appears only after the prefetch, so --use-cached leaves it for later.
+        fx.publish(package_info("libbar", "2.0.0", PackageType::System, &[]), &[("usr/lib/libbar.so", "bar 2")]);
+        for entry in std::fs::read_dir(fx.repo_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.to_string_lossy().ends_with(".tar.zst") { std::fs::remove_file(path).unwrap(); }
+        }
+        handle_upgrade(&[], &UpgradeOptions { use_cached: true, ..UpgradeOptions::default() }, &fx.ctx).await.unwrap();
+
+        assert_eq!(fx.read("usr/lib/libfoo.so"), "foo 2");
+        assert_eq!(fx.read("usr/lib/libzip.so"), "zip");
+        assert_eq!(fx.read("usr/lib/libbar.so"), "bar 1");
+    }
 }